@@ -2,10 +2,14 @@ use criterion::BatchSize;
 use criterion::BenchmarkId;
 use criterion::Criterion;
 use criterion::{criterion_group, criterion_main};
-use kvs::{EngineType, KvStore, KvsEngine, SledKvsEngine};
+use kvs::{EngineType, KvStore, KvStoreOptions, KvsEngine, LogFormat, SledKvsEngine};
 use rand;
 use rand::distributions::Standard;
 use rand::Rng;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
 use tempfile::TempDir;
 
 enum Engine {
@@ -101,6 +105,305 @@ fn read(c: &mut Criterion) {
     group.finish();
 }
 
+fn read_small_values(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_small_values");
+
+    for &inline in &[true, false] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default().inline_values(inline);
+        let store =
+            KvStore::open_with_options(temp_dir.path(), options).expect("unable to open KvStore");
+
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{}", i)).collect();
+        for key in &keys {
+            store.set(key.clone(), "value".to_owned()).unwrap();
+        }
+
+        let id = if inline { "inline" } else { "on disk" };
+        group.bench_function(BenchmarkId::from_parameter(id), |b| {
+            let mut i = 0;
+            b.iter(|| {
+                store.get(keys[i % keys.len()].clone()).unwrap();
+                i += 1;
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn read_bulk(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_bulk");
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).expect("unable to open KvStore");
+
+    let keys: Vec<String> = (0..100).map(|i| format!("key{}", i)).collect();
+    for key in &keys {
+        store.set(key.clone(), "value".to_owned()).unwrap();
+    }
+
+    group.bench_function(BenchmarkId::from_parameter("get"), |b| {
+        b.iter(|| {
+            for key in &keys {
+                store.get(key.clone()).unwrap();
+            }
+        })
+    });
+    group.bench_function(BenchmarkId::from_parameter("get_bulk"), |b| {
+        b.iter(|| store.get_bulk(&keys).unwrap())
+    });
+
+    group.finish();
+}
+
+/// 8 threads reading while another writes, so readers contend for the store's lock.
+fn read_contended(c: &mut Criterion) {
+    const READERS: usize = 8;
+    const READS_PER_THREAD: usize = 1000;
+
+    let mut group = c.benchmark_group("read_contended");
+
+    for &inline in &[true, false] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default().inline_values(inline);
+        let store =
+            KvStore::open_with_options(temp_dir.path(), options).expect("unable to open KvStore");
+
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{}", i)).collect();
+        for key in &keys {
+            store.set(key.clone(), "value".to_owned()).unwrap();
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let store = store.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut i = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    store
+                        .set(format!("written{}", i % 1000), "value".to_owned())
+                        .unwrap();
+                    i += 1;
+                }
+            })
+        };
+
+        let id = if inline { "inline" } else { "on disk" };
+        group.bench_function(BenchmarkId::from_parameter(id), |b| {
+            b.iter(|| {
+                thread::scope(|scope| {
+                    for reader in 0..READERS {
+                        let store = &store;
+                        let keys = &keys;
+                        scope.spawn(move || {
+                            for i in 0..READS_PER_THREAD {
+                                let key = &keys[(reader * READS_PER_THREAD + i) % keys.len()];
+                                store.get(key.clone()).unwrap();
+                            }
+                        });
+                    }
+                })
+            })
+        });
+
+        stop.store(true, Ordering::SeqCst);
+        writer.join().unwrap();
+    }
+
+    group.finish();
+}
+
+fn open(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open");
+
+    // every open starts a new log file, which has to be replayed by the next
+    let template_dir = TempDir::new().expect("unable to create temporary working directory");
+    for i in 0..50 {
+        let store = KvStore::open(template_dir.path()).expect("unable to open KvStore");
+        for j in 0..200 {
+            store
+                .set(format!("key{}", j), format!("value{}", i))
+                .unwrap();
+        }
+    }
+
+    group.bench_function(BenchmarkId::from_parameter("50 log files"), |b| {
+        b.iter_batched(
+            // a fresh copy each time, as opening adds another log file
+            || copy_kvs_dir(&template_dir, &[]),
+            |temp_dir| {
+                let store = KvStore::open(temp_dir.path()).expect("unable to open KvStore");
+                // dropped after timing, as outputs are
+                (store, temp_dir)
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    // overwriting every key triggers compaction, which saves the index
+    let compacted_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(compacted_dir.path()).expect("unable to open KvStore");
+    for i in 0..2 {
+        for j in 0..20_000 {
+            store
+                .set(format!("key{}", j), format!("{:0>100}", i))
+                .unwrap();
+        }
+    }
+    drop(store);
+
+    // warm opens load the saved index, cold ones replay the compacted log instead
+    for &(name, skip) in &[
+        ("saved index", &[][..]),
+        ("no saved index", &["index.bin"][..]),
+    ] {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_batched(
+                || copy_kvs_dir(&compacted_dir, skip),
+                |temp_dir| {
+                    let store = KvStore::open(temp_dir.path()).expect("unable to open KvStore");
+                    (store, temp_dir)
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+/// Writing, reading and replaying logs in each of the built in formats.
+fn log_format(c: &mut Criterion) {
+    let mut group = c.benchmark_group("log_format");
+
+    let keys: Vec<String> = (0..1000).map(|i| format!("key{}", i)).collect();
+    for &format in &[LogFormat::Json, LogFormat::Bincode] {
+        let name = format!("{:?}", format);
+        // values stay on disk, so reads have to deserialise them
+        let options = KvStoreOptions::default()
+            .inline_values(false)
+            .format(format);
+
+        group.bench_function(BenchmarkId::new("write", &name), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir =
+                        TempDir::new().expect("unable to create temporary working directory");
+                    let store = KvStore::open_with_options(temp_dir.path(), options.clone())
+                        .expect("unable to open KvStore");
+                    (store, temp_dir)
+                },
+                |(store, temp_dir)| {
+                    for key in &keys {
+                        store.set(key.clone(), format!("{:0>100}", key)).unwrap();
+                    }
+                    (store, temp_dir)
+                },
+                BatchSize::SmallInput,
+            )
+        });
+
+        let template_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_options(template_dir.path(), options.clone())
+            .expect("unable to open KvStore");
+        for key in &keys {
+            store.set(key.clone(), format!("{:0>100}", key)).unwrap();
+        }
+
+        group.bench_function(BenchmarkId::new("read", &name), |b| {
+            let mut i = 0;
+            b.iter(|| {
+                store.get(keys[i % keys.len()].clone()).unwrap();
+                i += 1;
+            })
+        });
+        drop(store);
+
+        group.bench_function(BenchmarkId::new("open", &name), |b| {
+            b.iter_batched(
+                || copy_kvs_dir(&template_dir, &[]),
+                |temp_dir| {
+                    let store = KvStore::open_with_options(temp_dir.path(), options.clone())
+                        .expect("unable to open KvStore");
+                    (store, temp_dir)
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+/// Writes with and without an `fsync` after each one.
+fn sync_writes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sync_writes");
+
+    let keys: Vec<String> = (0..100).map(|i| format!("key{}", i)).collect();
+    for &sync in &[false, true] {
+        let options = KvStoreOptions::default().sync_writes(sync);
+        group.bench_function(BenchmarkId::from_parameter(sync), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir =
+                        TempDir::new().expect("unable to create temporary working directory");
+                    let store = KvStore::open_with_options(temp_dir.path(), options.clone())
+                        .expect("unable to open KvStore");
+                    (store, temp_dir)
+                },
+                |(store, temp_dir)| {
+                    for key in &keys {
+                        store.set(key.clone(), format!("{:0>100}", key)).unwrap();
+                    }
+                    (store, temp_dir)
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+/// Reading the same key over and over, with and without the read cache.
+fn read_cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_cache");
+
+    for &capacity in &[0, 1] {
+        // values stay on disk, so uncached reads have to seek
+        let options = KvStoreOptions::default()
+            .inline_values(false)
+            .read_cache(capacity);
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store =
+            KvStore::open_with_options(temp_dir.path(), options).expect("unable to open KvStore");
+        store.set("key".to_owned(), "v".repeat(1000)).unwrap();
+
+        group.bench_function(BenchmarkId::from_parameter(capacity), |b| {
+            b.iter(|| store.get("key".to_owned()).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+/// Copy the `.kvs` directory from `template_dir` into a new temporary directory, apart from
+/// the files named in `skip`.
+fn copy_kvs_dir(template_dir: &TempDir, skip: &[&str]) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs_dir = temp_dir.path().join(".kvs");
+    fs::create_dir(&kvs_dir).unwrap();
+    for entry in fs::read_dir(template_dir.path().join(".kvs")).unwrap() {
+        let entry = entry.unwrap();
+        if !skip.iter().any(|name| entry.file_name() == *name) {
+            fs::copy(entry.path(), kvs_dir.join(entry.file_name())).unwrap();
+        }
+    }
+    temp_dir
+}
+
 fn gen_random_string() -> String {
     let mut rng = rand::thread_rng();
     let length = rng.gen_range(1, 100_001);
@@ -110,5 +413,16 @@ fn gen_random_string() -> String {
         .collect::<String>()
 }
 
-criterion_group!(benches, write, read);
+criterion_group!(
+    benches,
+    write,
+    read,
+    read_small_values,
+    read_bulk,
+    read_contended,
+    open,
+    log_format,
+    sync_writes,
+    read_cache
+);
 criterion_main!(benches);
@@ -1,6 +1,5 @@
 extern crate clap;
 use clap::{crate_version, App, AppSettings, Arg, SubCommand};
-use failure;
 use kvs;
 use kvs::KvsClient;
 use std::env;
@@ -62,6 +61,47 @@ fn run_kvs() -> kvs::Result<()> {
                 .arg(&key_arg)
                 .arg(&addr_arg),
         )
+        .subcommand(
+            SubCommand::with_name("exists")
+                .about("Check whether a given key has a value")
+                .arg(&key_arg)
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("rename")
+                .about("Move the value of a key to a new key, overwriting any existing value")
+                .arg(
+                    Arg::with_name("old")
+                        .takes_value(true)
+                        .value_name("OLD")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .takes_value(true)
+                        .value_name("NEW")
+                        .required(true),
+                )
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("ping")
+                .about("Check the server is answering, printing the round trip time")
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("compact")
+                .about("Compact the server's store, printing how many bytes were freed")
+                .arg(
+                    Arg::with_name("token")
+                        .help("The server's admin token")
+                        .long("token")
+                        .takes_value(true)
+                        .value_name("TOKEN")
+                        .required(true),
+                )
+                .arg(&addr_arg),
+        )
         .get_matches();
 
     match matches.subcommand() {
@@ -96,6 +136,42 @@ fn run_kvs() -> kvs::Result<()> {
             }
             _ => Err(KvsClientCliError::UnexpectedArgs.into()),
         },
+        ("ping", Some(command_matches)) => {
+            let address = command_matches.value_of("addr").unwrap();
+            let client = KvsClient::connect(address)?;
+            let rtt = client.ping()?;
+            println!("PONG (RTT: {}ms)", rtt.as_millis());
+            Ok(())
+        }
+        ("compact", Some(command_matches)) => match command_matches.value_of("token") {
+            Some(token) => {
+                let address = command_matches.value_of("addr").unwrap();
+                let client = KvsClient::connect(address)?;
+                println!("{}", client.compact(token.to_string())?);
+                Ok(())
+            }
+            _ => Err(KvsClientCliError::UnexpectedArgs.into()),
+        },
+        ("exists", Some(command_matches)) => match command_matches.value_of("key") {
+            Some(key) => {
+                let address = command_matches.value_of("addr").unwrap();
+                let client = KvsClient::connect(address)?;
+                println!("{}", client.exists(key.to_string())?);
+                Ok(())
+            }
+            _ => Err(KvsClientCliError::UnexpectedArgs.into()),
+        },
+        ("rename", Some(command_matches)) => match (
+            command_matches.value_of("old"),
+            command_matches.value_of("new"),
+        ) {
+            (Some(old_key), Some(new_key)) => {
+                let address = command_matches.value_of("addr").unwrap();
+                let client = KvsClient::connect(address)?;
+                client.rename(old_key.to_string(), new_key.to_string())
+            }
+            _ => Err(KvsClientCliError::UnexpectedArgs.into()),
+        },
         (cmd, _) => Err(KvsClientCliError::UnknownCommand {
             command: cmd.to_string(),
         }
@@ -103,11 +179,11 @@ fn run_kvs() -> kvs::Result<()> {
     }
 }
 
-#[derive(Debug, failure::Fail)]
+#[derive(Debug, thiserror::Error)]
 enum KvsClientCliError {
-    #[fail(display = "Unknown command: {}", command)]
+    #[error("Unknown command: {command}")]
     UnknownCommand { command: String },
 
-    #[fail(display = "Unexpected CLI arguments")]
+    #[error("Unexpected CLI arguments")]
     UnexpectedArgs,
 }
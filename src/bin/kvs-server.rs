@@ -3,16 +3,19 @@ extern crate clap;
 extern crate slog;
 extern crate slog_term;
 
-use clap::{crate_version, App, Arg};
+use clap::{crate_version, App, Arg, ArgMatches};
 use kvs::{
     existing_engine,
     thread_pool::{SharedQueueThreadPool, ThreadPool},
-    EngineType, KvStore, KvsServer, SledKvsEngine,
+    EngineType, KvStore, KvsEngine, KvsServer, SledKvsEngine,
 };
 use num_cpus;
 use slog::Drain;
+#[cfg(feature = "signal-handler")]
+use slog::Logger;
 use std::convert::TryInto;
 use std::env;
+use std::time::Duration;
 
 fn main() -> kvs::Result<()> {
     if let Err(e) = run_kvs() {
@@ -32,7 +35,7 @@ fn run_kvs() -> kvs::Result<()> {
 
     let log = slog::Logger::root(drain, o!("version" => version));
 
-    let matches = App::new(&[env!("CARGO_PKG_NAME"), "-server"].concat())
+    let app = App::new(&[env!("CARGO_PKG_NAME"), "-server"].concat())
         .version(crate_version!())
         .author(env!("CARGO_PKG_AUTHORS"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
@@ -53,9 +56,50 @@ fn run_kvs() -> kvs::Result<()> {
                 .possible_values(&["kvs", "sled"])
                 .value_name("ENGINE"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("slow-request-threshold-ms")
+                .help("Log a warning for requests taking longer than this many milliseconds")
+                .long("slow-request-threshold-ms")
+                .takes_value(true)
+                .value_name("MS")
+                .default_value("100"),
+        )
+        .arg(
+            Arg::with_name("max-connections")
+                .help("Refuse connections as busy while this many are open")
+                .long("max-connections")
+                .takes_value(true)
+                .value_name("N"),
+        )
+        .arg(
+            Arg::with_name("max-connections-per-ip")
+                .help("Refuse connections from an IP address which already has this many open")
+                .long("max-connections-per-ip")
+                .takes_value(true)
+                .value_name("N"),
+        )
+        .arg(
+            Arg::with_name("admin-token")
+                .help("Accept admin commands, such as compact, which carry this token")
+                .long("admin-token")
+                .takes_value(true)
+                .value_name("TOKEN"),
+        );
+    #[cfg(feature = "http")]
+    let app = app.arg(
+        Arg::with_name("http")
+            .help("Serve HTTP requests instead of the KVS protocol")
+            .long("http"),
+    );
+    let matches = app.get_matches();
 
     let addr = matches.value_of("addr").unwrap();
+    let slow_request_threshold = Duration::from_millis(
+        matches
+            .value_of("slow-request-threshold-ms")
+            .unwrap()
+            .parse()?,
+    );
     let engine_arg = matches.value_of("engine").map(|e| match e {
         "kvs" => EngineType::Kvs,
         "sled" => EngineType::Sled,
@@ -86,21 +130,79 @@ fn run_kvs() -> kvs::Result<()> {
     )?;
     match engine_type {
         EngineType::Kvs => {
-            let server = KvsServer::new(log, KvStore::open(&curr_dir)?, pool)?;
-            server.run(addr)?;
-            Ok(())
+            let store = KvStore::open(&curr_dir)?;
+            #[cfg(feature = "signal-handler")]
+            handle_shutdown(log.clone(), {
+                let store = store.clone();
+                move || store.close()
+            })?;
+
+            let server = KvsServer::new(log, store, pool)?
+                .with_slow_request_threshold(slow_request_threshold);
+            run(server, addr, &matches)
         }
 
         EngineType::Sled => {
-            let server = KvsServer::new(log, SledKvsEngine::open(&curr_dir)?, pool)?;
-            server.run(addr)?;
-            Ok(())
+            // sled flushes every write, so there is nothing left to close
+            #[cfg(feature = "signal-handler")]
+            handle_shutdown(log.clone(), || Ok(()))?;
+
+            let server = KvsServer::new(log, SledKvsEngine::open(&curr_dir)?, pool)?
+                .with_slow_request_threshold(slow_request_threshold);
+            run(server, addr, &matches)
+        }
+    }
+}
+
+fn run<E: KvsEngine + Sync, P: ThreadPool>(
+    server: KvsServer<E, P>,
+    addr: &str,
+    matches: &ArgMatches<'_>,
+) -> kvs::Result<()> {
+    let server = match matches.value_of("max-connections") {
+        Some(max) => server.with_max_connections(max.parse()?),
+        None => server,
+    };
+    let server = match matches.value_of("max-connections-per-ip") {
+        Some(max) => server.with_max_connections_per_ip(max.parse()?),
+        None => server,
+    };
+    let server = match matches.value_of("admin-token") {
+        Some(token) => server.with_admin_token(token),
+        None => server,
+    };
+
+    #[cfg(feature = "http")]
+    {
+        if matches.is_present("http") {
+            return server.run_http(addr);
         }
     }
+
+    server.run(addr)
+}
+
+/// On SIGINT or SIGTERM, stop accepting connections, close the store and exit.
+#[cfg(feature = "signal-handler")]
+fn handle_shutdown<F>(log: Logger, close: F) -> kvs::Result<()>
+where
+    F: Fn() -> kvs::Result<()> + Send + 'static,
+{
+    use std::sync::atomic::Ordering;
+
+    ctrlc::set_handler(move || {
+        info!(log, "Received shutdown signal");
+        kvs::SHUTDOWN.store(true, Ordering::SeqCst);
+        if let Err(e) = close() {
+            error!(log, "Failed to close store"; "error" => %e);
+        }
+        std::process::exit(0);
+    })?;
+    Ok(())
 }
 
-#[derive(Debug, failure::Fail)]
+#[derive(Debug, thiserror::Error)]
 enum KvsServerError {
-    #[fail(display = "Chosen engine does not match existing data")]
+    #[error("Chosen engine does not match existing data")]
     EngineMismatch {},
 }
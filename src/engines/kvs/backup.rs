@@ -0,0 +1,25 @@
+use super::file;
+use std::path::{Path, PathBuf};
+
+/// A backup made by `KvStore::backup_nonblocking`.
+#[derive(Debug, Clone)]
+pub struct BackupHandle {
+    dest_dir: PathBuf,
+    file_ids: Vec<file::Id>,
+}
+
+impl BackupHandle {
+    pub(super) fn new(dest_dir: PathBuf, file_ids: Vec<file::Id>) -> BackupHandle {
+        BackupHandle { dest_dir, file_ids }
+    }
+
+    /// The directory the backup was made in, which can be opened as a `KvStore`.
+    pub fn dest_dir(&self) -> &Path {
+        &self.dest_dir
+    }
+
+    /// The log files in the backup, oldest first.
+    pub fn file_ids(&self) -> &[file::Id] {
+        &self.file_ids
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Chance of a key which was never inserted being reported as present, at full capacity
+const FALSE_POSITIVE_RATE: f64 = 0.01;
+/// Smallest number of keys a filter is sized for, so small stores don't rebuild often
+const MIN_CAPACITY: usize = 1024;
+
+/// Set membership which can have false positives but never false negatives.
+///
+/// Keys can't be removed, so the filter is rebuilt from the live keys by compaction, and when
+/// more keys have been inserted than it was sized for.
+#[derive(Debug)]
+pub(super) struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+    capacity: usize,
+    len: usize,
+}
+
+impl BloomFilter {
+    /// A filter holding `keys`, with room for as many again.
+    pub(super) fn with_keys<'a>(keys: impl ExactSizeIterator<Item = &'a String>) -> BloomFilter {
+        let mut filter = BloomFilter::new((keys.len() * 2).max(MIN_CAPACITY));
+        for key in keys {
+            filter.insert(key);
+        }
+        filter
+    }
+
+    fn new(capacity: usize) -> BloomFilter {
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(capacity as f64) * FALSE_POSITIVE_RATE.ln() / (ln2 * ln2)).ceil() as u64;
+        let num_hashes = ((num_bits as f64 / capacity as f64) * ln2).round().max(1.0) as u32;
+        BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+            capacity,
+            len: 0,
+        }
+    }
+
+    pub(super) fn insert(&mut self, key: &str) {
+        let mut added = false;
+        for bit in self.bit_positions(key) {
+            let (word, mask) = ((bit / 64) as usize, 1 << (bit % 64));
+            added |= self.bits[word] & mask == 0;
+            self.bits[word] |= mask;
+        }
+        // keys already present, such as overwrites, take no more room
+        if added {
+            self.len += 1;
+        }
+    }
+
+    /// `false` if `key` was definitely never inserted.
+    pub(super) fn might_contain(&self, key: &str) -> bool {
+        self.bit_positions(key)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Have at least as many keys been inserted as the filter was sized for?
+    pub(super) fn is_full(&self) -> bool {
+        self.len >= self.capacity
+    }
+
+    /// Positions of the bits for `key`, derived from two hashes by double hashing.
+    fn bit_positions(&self, key: &str) -> impl Iterator<Item = u64> {
+        let first = hash(key, 0);
+        // odd, so the positions don't repeat while there are fewer hashes than bits
+        let second = hash(key, 1) | 1;
+        let num_bits = self.num_bits;
+        (0..u64::from(self.num_hashes))
+            .map(move |i| first.wrapping_add(i.wrapping_mul(second)) % num_bits)
+    }
+}
+
+fn hash(key: &str, seed: u8) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
@@ -1,9 +1,9 @@
-use failure;
+use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
 use std::convert::TryInto;
 
 /// Was this worth it? Maybe not. Maybe a type alias would have been fine.
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct Bytes(pub u64);
 
 impl std::ops::Add<Bytes> for Bytes {
@@ -40,7 +40,7 @@ impl std::ops::Sub<Bytes> for Bytes {
 }
 
 impl TryFrom<usize> for Bytes {
-    type Error = failure::Error;
+    type Error = anyhow::Error;
     fn try_from(n: usize) -> Result<Self, Self::Error> {
         Ok(Bytes(n.try_into()?))
     }
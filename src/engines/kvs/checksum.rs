@@ -0,0 +1,80 @@
+use super::options::ChecksumAlgorithm;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use xxhash_rust::xxh3::Xxh3;
+
+/// Checksum of a log entry, tagged with the algorithm which produced it so it can be checked
+/// whichever algorithm the store is using now.
+///
+/// Written as the algorithm's name and a fixed width hex digest, for example
+/// `xxh3:0123456789abcdef`, so every entry's size is independent of its checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Checksum {
+    Crc32(u32),
+    XxHash3(u64),
+}
+
+impl Serialize for Checksum {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let tagged = match self {
+            Checksum::Crc32(digest) => format!("crc32:{:08x}", digest),
+            Checksum::XxHash3(digest) => format!("xxh3:{:016x}", digest),
+        };
+        serializer.serialize_str(&tagged)
+    }
+}
+
+impl<'de> Deserialize<'de> for Checksum {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Checksum, D::Error> {
+        let tagged = String::deserialize(deserializer)?;
+        let (algorithm, digest) = match tagged.find(':') {
+            Some(colon) => (&tagged[..colon], &tagged[colon + 1..]),
+            None => return Err(D::Error::custom("checksum has no algorithm")),
+        };
+        match algorithm {
+            "crc32" => u32::from_str_radix(digest, 16).map(Checksum::Crc32),
+            "xxh3" => u64::from_str_radix(digest, 16).map(Checksum::XxHash3),
+            _ => return Err(D::Error::custom("unknown checksum algorithm")),
+        }
+        .map_err(D::Error::custom)
+    }
+}
+
+/// Feeds the parts of a log entry through one of the checksum algorithms.
+pub(super) enum Hasher {
+    Crc32(crc32fast::Hasher),
+    XxHash3(Box<Xxh3>),
+}
+
+impl Hasher {
+    /// A hasher for `algorithm`, or `None` for `ChecksumAlgorithm::None`.
+    pub(super) fn new(algorithm: ChecksumAlgorithm) -> Option<Hasher> {
+        match algorithm {
+            ChecksumAlgorithm::None => None,
+            ChecksumAlgorithm::Crc32 => Some(Hasher::Crc32(crc32fast::Hasher::new())),
+            ChecksumAlgorithm::XxHash3 => Some(Hasher::XxHash3(Box::new(Xxh3::new()))),
+        }
+    }
+
+    /// A hasher for the algorithm which produced `checksum`.
+    pub(super) fn matching(checksum: Checksum) -> Hasher {
+        match checksum {
+            Checksum::Crc32(_) => Hasher::Crc32(crc32fast::Hasher::new()),
+            Checksum::XxHash3(_) => Hasher::XxHash3(Box::new(Xxh3::new())),
+        }
+    }
+
+    pub(super) fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Crc32(hasher) => hasher.update(bytes),
+            Hasher::XxHash3(hasher) => hasher.update(bytes),
+        }
+    }
+
+    pub(super) fn finish(self) -> Checksum {
+        match self {
+            Hasher::Crc32(hasher) => Checksum::Crc32(hasher.finalize()),
+            Hasher::XxHash3(hasher) => Checksum::XxHash3(hasher.digest()),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use super::checksum::{Checksum, Hasher};
+use super::file;
+use super::options::ChecksumAlgorithm;
+use serde::{Deserialize, Serialize};
+
+/// An entry in a `KvStore`'s log: a `set` or `remove` of one key, or a marker of the store
+/// being opened or compacted. A 'remove' command has `value` equal to `None`.
+///
+/// The fields are private, so a `CommandSerializer` encodes commands through their serde
+/// implementations. Optional fields are left out when they are empty, which only formats
+/// describing their own fields, such as JSON or MessagePack, can read back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Command {
+    #[serde(rename = "k")]
+    pub(super) key: String,
+
+    #[serde(rename = "v")]
+    pub(super) value: Option<String>,
+
+    /// Set when a large value is split across several consecutive commands
+    #[serde(rename = "c", default, skip_serializing_if = "Option::is_none")]
+    pub(super) chunk: Option<Chunk>,
+
+    /// Nanoseconds since the Unix epoch when the command was written, or 0 in older logs
+    #[serde(rename = "t", default)]
+    pub(super) written_at: u64,
+
+    /// Random bytes making every entry unique, or all zero in older logs
+    #[serde(rename = "n", default, with = "hex_nonce")]
+    pub(super) nonce: [u8; 8],
+
+    /// Marks the store being opened at `written_at`, rather than a change to any key
+    #[serde(rename = "o", default, skip_serializing_if = "is_false")]
+    pub(super) open: bool,
+
+    /// Nanoseconds since the Unix epoch after which the value reads as removed, see
+    /// `KvStoreExt::set_with_ttl`
+    #[serde(rename = "x", default, skip_serializing_if = "Option::is_none")]
+    pub(super) expires_at: Option<u64>,
+
+    /// Marks a compaction finishing, rather than a change to any key
+    #[serde(rename = "r", default, skip_serializing_if = "Option::is_none")]
+    pub(super) compaction: Option<CompactionRecord>,
+
+    /// Covers every other field, see `KvStoreOptions::checksum_algorithm`
+    #[serde(rename = "s", default, skip_serializing_if = "Option::is_none")]
+    pub(super) checksum: Option<Checksum>,
+}
+
+impl Command {
+    /// A `set` of one chunk of a value split into `count` chunks, or of the whole value if
+    /// `count` is 1.
+    pub(super) fn set_chunk(
+        key: &str,
+        chunk: &str,
+        index: u32,
+        count: u32,
+        written_at: u64,
+        nonce: [u8; 8],
+        expires_at: Option<u64>,
+    ) -> Command {
+        Command {
+            key: key.to_owned(),
+            value: Some(chunk.to_owned()),
+            chunk: if count > 1 {
+                Some(Chunk { index, count })
+            } else {
+                None
+            },
+            written_at,
+            nonce,
+            open: false,
+            expires_at,
+            compaction: None,
+            checksum: None,
+        }
+    }
+
+    pub(super) fn open_marker(timestamp: u64) -> Command {
+        Command {
+            key: String::new(),
+            value: None,
+            chunk: None,
+            written_at: timestamp,
+            nonce: rand::random(),
+            open: true,
+            expires_at: None,
+            compaction: None,
+            checksum: None,
+        }
+    }
+
+    pub(super) fn compaction_record(record: CompactionRecord) -> Command {
+        Command {
+            key: String::new(),
+            value: None,
+            chunk: None,
+            written_at: record.finished_at,
+            nonce: rand::random(),
+            open: false,
+            expires_at: None,
+            compaction: Some(record),
+            checksum: None,
+        }
+    }
+
+    /// Is this a marker of something happening to the store, rather than a change to a key?
+    pub(super) fn is_marker(&self) -> bool {
+        self.open || self.compaction.is_some()
+    }
+
+    pub(super) fn with_checksum(mut self, algorithm: ChecksumAlgorithm) -> Command {
+        self.checksum = Hasher::new(algorithm).map(|hasher| self.compute_checksum(hasher));
+        self
+    }
+
+    /// Does the command match its checksum? Always true if it doesn't have one.
+    pub(super) fn checksum_matches(&self) -> bool {
+        match self.checksum {
+            Some(checksum) => self.compute_checksum(Hasher::matching(checksum)) == checksum,
+            None => true,
+        }
+    }
+
+    fn compute_checksum(&self, mut hasher: Hasher) -> Checksum {
+        // lengths first, so moving bytes between the key and value changes the checksum
+        hasher.update(&(self.key.len() as u64).to_le_bytes());
+        hasher.update(self.key.as_bytes());
+        match &self.value {
+            Some(value) => {
+                hasher.update(&[1]);
+                hasher.update(&(value.len() as u64).to_le_bytes());
+                hasher.update(value.as_bytes());
+            }
+            None => hasher.update(&[0]),
+        }
+        match self.chunk {
+            Some(Chunk { index, count }) => {
+                hasher.update(&[1]);
+                hasher.update(&index.to_le_bytes());
+                hasher.update(&count.to_le_bytes());
+            }
+            None => hasher.update(&[0]),
+        }
+        hasher.update(&self.written_at.to_le_bytes());
+        hasher.update(&self.nonce);
+        hasher.update(&[self.open as u8]);
+        // only when present, so entries checksummed before expiry was added still match
+        if let Some(expires_at) = self.expires_at {
+            hasher.update(&expires_at.to_le_bytes());
+        }
+        if let Some(record) = &self.compaction {
+            hasher.update(&(record.files_removed.len() as u64).to_le_bytes());
+            for file_id in &record.files_removed {
+                hasher.update(&file_id.to_le_bytes());
+            }
+            hasher.update(&record.keys_preserved.to_le_bytes());
+            hasher.update(&record.bytes_reclaimed.to_le_bytes());
+            hasher.update(&record.started_at.to_le_bytes());
+            hasher.update(&record.finished_at.to_le_bytes());
+        }
+        hasher.finish()
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// Nonces are written as fixed width hex, so every entry's size is independent of its nonce.
+mod hex_nonce {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        nonce: &[u8; 8],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:016x}", u64::from_be_bytes(*nonce)))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<[u8; 8], D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        u64::from_str_radix(&hex, 16)
+            .map(u64::to_be_bytes)
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Position of a command's value within a value split into `count` chunks.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(super) struct Chunk {
+    #[serde(rename = "i")]
+    pub(super) index: u32,
+
+    #[serde(rename = "n")]
+    pub(super) count: u32,
+}
+
+/// A compaction, recorded at the start of the log file written to after it finished, see
+/// `KvStore::list_compactions`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionRecord {
+    /// Log files the compaction replaced, oldest first
+    #[serde(rename = "f")]
+    pub files_removed: Vec<file::Id>,
+
+    /// Live values copied into the new log files
+    #[serde(rename = "k")]
+    pub keys_preserved: u64,
+
+    /// How much smaller the logs are than the files they replaced
+    #[serde(rename = "b")]
+    pub bytes_reclaimed: u64,
+
+    /// Nanoseconds since the Unix epoch when the compaction started
+    #[serde(rename = "s")]
+    pub started_at: u64,
+
+    /// Nanoseconds since the Unix epoch when the compaction finished
+    #[serde(rename = "e")]
+    pub finished_at: u64,
+}
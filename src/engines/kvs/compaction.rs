@@ -0,0 +1,78 @@
+use super::bytes::Bytes;
+use std::time::Instant;
+
+/// Starting threshold, before any adjustment for write rate
+pub(super) const DEFAULT_INITIAL_THRESHOLD: Bytes = Bytes(1024 * 1024);
+pub(super) const DEFAULT_MIN_THRESHOLD: Bytes = Bytes(64 * 1024);
+pub(super) const DEFAULT_MAX_THRESHOLD: Bytes = Bytes(64 * 1024 * 1024);
+
+/// Above this many writes per second the threshold is raised, so compaction runs less often
+const HIGH_WRITE_RATE: f64 = 1000.0;
+/// Below this many writes per second the threshold is lowered, so space is reclaimed sooner
+const LOW_WRITE_RATE: f64 = 10.0;
+/// Weight given to the latest write when updating the moving average
+const SMOOTHING: f64 = 0.1;
+
+/// Decides when to compact, based on how much of the log is redundant.
+///
+/// The threshold adapts to the write rate, measured as an exponential moving average.
+/// Optionally compaction is also triggered when too little of the log is live.
+#[derive(Debug)]
+pub(super) struct CompactionThreshold {
+    current: Bytes,
+    min: Bytes,
+    max: Bytes,
+    min_live_ratio: Option<f64>,
+    writes_per_sec: f64,
+    last_write: Option<Instant>,
+}
+
+impl CompactionThreshold {
+    pub(super) fn new(
+        initial: Bytes,
+        min: Bytes,
+        max: Bytes,
+        min_live_ratio: Option<f64>,
+    ) -> CompactionThreshold {
+        CompactionThreshold {
+            current: clamp(initial, min, max),
+            min,
+            max,
+            min_live_ratio,
+            writes_per_sec: 0.0,
+            last_write: None,
+        }
+    }
+
+    /// Update the write rate with a write happening now.
+    pub(super) fn record_write(&mut self) {
+        let now = Instant::now();
+        if let Some(last_write) = self.last_write {
+            // avoid dividing by zero for writes closer together than the clock resolution
+            let secs = now.duration_since(last_write).as_secs_f64().max(1e-6);
+            self.writes_per_sec = SMOOTHING / secs + (1.0 - SMOOTHING) * self.writes_per_sec;
+        }
+        self.last_write = Some(now);
+    }
+
+    pub(super) fn exceeded_by(&self, uncompacted: Bytes, live_ratio: f64) -> bool {
+        let too_little_live = match self.min_live_ratio {
+            Some(min_live_ratio) => live_ratio < min_live_ratio,
+            None => false,
+        };
+        uncompacted > self.current || too_little_live
+    }
+
+    /// Adjust the threshold for the next compaction, based on the current write rate.
+    pub(super) fn adjust(&mut self) {
+        if self.writes_per_sec > HIGH_WRITE_RATE {
+            self.current = clamp(Bytes(self.current.0.saturating_mul(2)), self.min, self.max);
+        } else if self.writes_per_sec < LOW_WRITE_RATE {
+            self.current = clamp(Bytes(self.current.0 / 2), self.min, self.max);
+        }
+    }
+}
+
+fn clamp(threshold: Bytes, min: Bytes, max: Bytes) -> Bytes {
+    Bytes(threshold.0.max(min.0).min(max.0))
+}
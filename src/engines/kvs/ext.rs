@@ -0,0 +1,13 @@
+use crate::Result;
+use std::time::Duration;
+
+/// Operations the `KvStore` engine supports beyond those of every `KvsEngine`.
+pub trait KvStoreExt {
+    /// Set `key` to `value`, which reads as removed once `ttl` has passed.
+    ///
+    /// The expiry is written to the log with the value, so it survives reopening the store.
+    /// A value past its time to live is forgotten when it is next read, by
+    /// `KvStore::purge_expired`, or by compaction, whichever comes first. Setting the key
+    /// again without a time to live keeps the new value indefinitely.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()>;
+}
@@ -0,0 +1,23 @@
+/// A point during compaction where `KvStoreOptions::inject_fault` can make the store panic,
+/// to test recovery from a crash at that point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultPoint {
+    /// The compacted log has been written, but not yet moved into place.
+    BeforeCommittingCompaction,
+
+    /// The compacted log is in place, but none of the logs it replaces have been removed.
+    BeforeRemovingCompactedFiles,
+
+    /// The oldest of the logs being replaced has been removed, but the rest remain.
+    AfterRemovingFirstCompactedFile,
+
+    /// The replaced logs have been removed, but the index has not been saved.
+    BeforeSavingIndex,
+}
+
+/// Panic if `point` is the fault to inject.
+pub(super) fn inject(fault: Option<FaultPoint>, point: FaultPoint) {
+    if fault == Some(point) {
+        panic!("injected fault at {:?}", point);
+    }
+}
@@ -1,3 +1,4 @@
+use super::options::FileNamingScheme;
 use crate::errors::KvsError;
 use crate::Result;
 use std::ffi::OsStr;
@@ -8,50 +9,193 @@ use std::fs::OpenOptions;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::Write;
-use std::path::PathBuf;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-/// Identifies a log file
+/// Identifies a log file, numbered in the order the files were created
 pub type Id = u64;
 
-fn format_name(id: Id) -> String {
-    format!("{}.log", id)
+fn format_name(id: Id, naming: FileNamingScheme) -> Result<String> {
+    Ok(match naming {
+        FileNamingScheme::Numeric => format!("{}.log", id),
+        FileNamingScheme::TimestampedNumeric => {
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            format!("{}_{}.log", timestamp, id)
+        }
+    })
 }
 
-pub fn get_log_file_ids(kvs_dir: &PathBuf) -> Result<Vec<Id>> {
-    fs::read_dir(&kvs_dir)?
-        .flat_map(|f| f)
-        .map(|file| file.path())
-        .filter(|path| path.extension() == Some(&OsString::from("log")))
-        .flat_map(|path| path.file_stem().and_then(OsStr::to_str).map(String::from))
-        .map(|file_stem| {
-            Ok(file_stem
-                .parse::<Id>()
-                .map_err(|_| KvsError::UnexpectedFileName)?)
-        })
-        .collect::<Result<Vec<Id>>>()
+/// Get the ID from a log file stem, named by either `FileNamingScheme`.
+fn parse_id(file_stem: &str) -> Result<Id> {
+    let id = match file_stem.find('_') {
+        Some(separator) => {
+            file_stem[..separator]
+                .parse::<u64>()
+                .map_err(|_| KvsError::UnexpectedFileName)?;
+            &file_stem[separator + 1..]
+        }
+        None => file_stem,
+    };
+    Ok(id.parse::<Id>().map_err(|_| KvsError::UnexpectedFileName)?)
+}
+
+/// The directories holding log files.
+///
+/// New logs are created in `write`, and compacted logs in `compacted`, which may be the same.
+/// Logs are found by ID whichever directory they are in.
+#[derive(Debug, Clone)]
+pub struct LogDirs {
+    pub write: PathBuf,
+    pub compacted: PathBuf,
+}
+
+impl LogDirs {
+    fn all(&self) -> Vec<&PathBuf> {
+        if self.write == self.compacted {
+            vec![&self.write]
+        } else {
+            vec![&self.write, &self.compacted]
+        }
+    }
+}
+
+/// Every log file in `dirs`, with its ID.
+pub fn list_log_files(dirs: &LogDirs) -> Result<Vec<(Id, PathBuf)>> {
+    let mut files = Vec::new();
+    for dir in dirs.all() {
+        let dir_files = fs::read_dir(dir)?
+            .flat_map(|f| f)
+            .map(|file| file.path())
+            .filter(|path| path.extension() == Some(&OsString::from("log")))
+            .flat_map(|path| {
+                path.file_stem()
+                    .and_then(OsStr::to_str)
+                    .map(String::from)
+                    .map(|file_stem| (file_stem, path))
+            })
+            .map(|(file_stem, path)| Ok((parse_id(&file_stem)?, path)))
+            .collect::<Result<Vec<(Id, PathBuf)>>>()?;
+        files.extend(dir_files);
+    }
+    Ok(files)
+}
+
+pub fn get_log_file_ids(dirs: &LogDirs) -> Result<Vec<Id>> {
+    Ok(list_log_files(dirs)?
+        .into_iter()
+        .map(|(id, _path)| id)
+        .collect())
+}
+
+/// Find the log file with the given ID, whichever naming scheme created it.
+pub fn path(dirs: &LogDirs, id: Id) -> Result<PathBuf> {
+    list_log_files(dirs)?
+        .into_iter()
+        .find(|(file_id, _path)| *file_id == id)
+        .map(|(_id, path)| path)
+        .ok_or_else(|| KvsError::LogFileNotFound.into())
+}
+
+/// When the log file was created, or last modified if the platform doesn't record creation.
+pub fn created(dirs: &LogDirs, id: Id) -> Result<SystemTime> {
+    let metadata = fs::metadata(path(dirs, id)?)?;
+    Ok(metadata.created().or_else(|_e| metadata.modified())?)
 }
 
-pub fn remove(kvs_dir: &PathBuf, id: Id) -> Result<()> {
-    Ok(fs::remove_file(kvs_dir.join(format_name(id)))?)
+pub fn remove(dirs: &LogDirs, id: Id) -> Result<()> {
+    Ok(fs::remove_file(path(dirs, id)?)?)
 }
 
-pub fn new_reader(dir: &PathBuf, id: Id) -> Result<BufReader<File>> {
-    let file_path = dir.join(format_name(id));
+pub fn new_reader(dirs: &LogDirs, id: Id) -> Result<BufReader<File>> {
+    let file_path = path(dirs, id)?;
     Ok(BufReader::new(
         OpenOptions::new().read(true).open(&file_path)?,
     ))
 }
 
+/// Open a reader for reading the whole log file from start to end, such as replaying it.
+///
+/// The kernel is told the file will be read sequentially, so it can read further ahead.
+pub fn new_reader_sequential(dirs: &LogDirs, id: Id) -> Result<BufReader<File>> {
+    let file = OpenOptions::new().read(true).open(path(dirs, id)?)?;
+    advise_sequential(&file);
+    Ok(BufReader::new(file))
+}
+
+// the advice is only a hint, so there is nothing to do if it fails
+#[cfg(all(unix, any(target_os = "linux", target_os = "android")))]
+#[allow(unsafe_code)]
+fn advise_sequential(file: &File) {
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+    }
+}
+
+#[cfg(all(unix, any(target_os = "macos", target_os = "ios")))]
+#[allow(unsafe_code)]
+fn advise_sequential(file: &File) {
+    unsafe {
+        libc::fcntl(file.as_raw_fd(), libc::F_RDAHEAD, 1);
+    }
+}
+
+#[cfg(not(all(
+    unix,
+    any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios"
+    )
+)))]
+fn advise_sequential(_file: &File) {}
+
+/// Suffix for log files which are still being written, and shouldn't be read yet
+const TEMP_SUFFIX: &str = ".tmp";
+
+fn temp_path(final_path: &Path) -> PathBuf {
+    let mut path = final_path.as_os_str().to_owned();
+    path.push(TEMP_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Remove temporary log and index files left behind by a crash.
+pub fn remove_temp_files(kvs_dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(kvs_dir)? {
+        let path = entry?.path();
+        let is_temp = match path.file_name().and_then(OsStr::to_str) {
+            Some(name) => name.ends_with(TEMP_SUFFIX),
+            None => false,
+        };
+        if is_temp {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Appends commands to a log file, keeping track of where the next one will start.
 #[derive(Debug)]
 pub struct KvsWriter {
+    /// ID of the log file being written
     pub id: Id,
+    /// Bytes written so far, counting any still in the buffer
     pub offset: u64,
-    writer: BufWriter<File>,
+    /// `None` for a store opened read only, which never creates the file
+    writer: Option<BufWriter<File>>,
+    /// Where the file will be moved by `commit`, if it is temporary
+    final_path: Option<PathBuf>,
+    retry_on_interrupt: bool,
+    /// Call `sync_data` after every flush
+    sync: bool,
 }
 
 impl KvsWriter {
-    pub fn new(dir: &PathBuf, file_id: Id) -> Result<KvsWriter> {
-        let file_path = dir.join(format_name(file_id));
+    /// Open the log file `file_id` in `dir` for appending, creating it if necessary.
+    pub fn new(dir: &PathBuf, file_id: Id, naming: FileNamingScheme) -> Result<KvsWriter> {
+        let file_path = dir.join(format_name(file_id, naming)?);
 
         let writer = BufWriter::new(
             OpenOptions::new()
@@ -63,20 +207,118 @@ impl KvsWriter {
         Ok(KvsWriter {
             id: file_id,
             offset: 0,
-            writer,
+            writer: Some(writer),
+            final_path: None,
+            retry_on_interrupt: false,
+            sync: false,
+        })
+    }
+
+    /// A writer for a store opened read only, which fails every write without creating a file.
+    pub fn read_only(file_id: Id) -> KvsWriter {
+        KvsWriter {
+            id: file_id,
+            offset: 0,
+            writer: None,
+            final_path: None,
+            retry_on_interrupt: false,
+            sync: false,
+        }
+    }
+
+    /// Create a log file which isn't visible to `get_log_file_ids` until `commit` is called.
+    pub fn new_temp(dir: &Path, file_id: Id, naming: FileNamingScheme) -> Result<KvsWriter> {
+        let final_path = dir.join(format_name(file_id, naming)?);
+
+        let writer = BufWriter::new(
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(temp_path(&final_path))?,
+        );
+
+        Ok(KvsWriter {
+            id: file_id,
+            offset: 0,
+            writer: Some(writer),
+            final_path: Some(final_path),
+            retry_on_interrupt: false,
+            sync: false,
         })
     }
+
+    /// Retry writes which are interrupted before writing anything, see
+    /// `KvStoreOptions::retry_on_interrupt`.
+    pub fn retry_on_interrupt(mut self, retry: bool) -> KvsWriter {
+        self.retry_on_interrupt = retry;
+        self
+    }
+
+    /// Sync the file's data to disk after every flush, see `KvStoreOptions::sync_writes`.
+    pub fn sync_writes(mut self, sync: bool) -> KvsWriter {
+        self.sync = sync;
+        self
+    }
+
+    /// Make a temporary log file visible, once its contents are safely on disk.
+    ///
+    /// Renaming is atomic, so after a crash either the whole file is there or none of it is.
+    pub fn commit(mut self) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+            let file = writer.get_ref();
+            retry_interrupted(self.retry_on_interrupt, || file.sync_all())?;
+        }
+
+        if let Some(final_path) = self.final_path.take() {
+            fs::rename(temp_path(&final_path), final_path)?;
+        }
+        Ok(())
+    }
 }
 
 impl Write for KvsWriter {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let bytes_written = self.writer.write(&buf)?;
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "read only",
+                ))
+            }
+        };
+        let bytes_written = retry_interrupted(self.retry_on_interrupt, || writer.write(buf))?;
         self.offset += bytes_written as u64;
 
         Ok(bytes_written)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        self.writer.flush()
+        match &mut self.writer {
+            Some(writer) => {
+                retry_interrupted(self.retry_on_interrupt, || writer.flush())?;
+                if self.sync {
+                    let file = writer.get_ref();
+                    retry_interrupted(self.retry_on_interrupt, || file.sync_data())?;
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Run `op` until it fails with something other than `ErrorKind::Interrupted`, if `retry` is set.
+fn retry_interrupted<T>(
+    retry: bool,
+    mut op: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    loop {
+        match op() {
+            Err(ref e) if retry && e.kind() == std::io::ErrorKind::Interrupted => {}
+            result => return result,
+        }
     }
 }
@@ -0,0 +1,386 @@
+use super::checksum::Checksum;
+use super::command::{Chunk, Command, CompactionRecord};
+use crate::errors::KvsError;
+use crate::Result;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Records the format of a store's log files, see `KvStoreOptions::serializer`
+pub(super) const FORMAT_FILE: &str = "FORMAT";
+
+/// The name of `JsonCommandSerializer`, and the format of stores without a `FORMAT` file
+const JSON: &str = "json";
+
+/// How every JSON entry starts, as the key is always the first field
+const JSON_ENTRY_START: &[u8] = br#"{"k":"#;
+
+/// The built in log formats, see `KvStoreOptions::format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Entries written with `JsonCommandSerializer`, the default
+    Json,
+    /// Entries written with `BincodeCommandSerializer`
+    Bincode,
+}
+
+impl LogFormat {
+    pub(super) fn serializer(self) -> Arc<dyn CommandSerializer> {
+        match self {
+            LogFormat::Json => Arc::new(JsonCommandSerializer),
+            LogFormat::Bincode => Arc::new(BincodeCommandSerializer),
+        }
+    }
+}
+
+/// Converts log entries to and from bytes, see `KvStoreOptions::serializer`.
+pub trait CommandSerializer: Send + Sync {
+    /// Identifies the format in the store's `FORMAT` file, so the logs are never read with a
+    /// different one. `"json"` is reserved for `JsonCommandSerializer`.
+    fn name(&self) -> &str;
+
+    /// Encode a single entry.
+    fn serialize(&self, command: &Command) -> Result<Vec<u8>>;
+
+    /// Decode a single entry from all of `bytes`, as returned by `serialize`.
+    fn deserialize(&self, bytes: &[u8]) -> Result<Command>;
+}
+
+/// Writes entries as JSON objects, one after another, as every store did before the format
+/// could be chosen. The default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCommandSerializer;
+
+impl CommandSerializer for JsonCommandSerializer {
+    fn name(&self) -> &str {
+        JSON
+    }
+
+    fn serialize(&self, command: &Command) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(command)?)
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<Command> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// Writes entries with bincode, which is smaller and quicker to decode than JSON.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCommandSerializer;
+
+/// Every field of a `Command` in order, as bincode can't skip the empty ones
+type BincodeFields = (
+    String,
+    Option<String>,
+    Option<(u32, u32)>,
+    u64,
+    [u8; 8],
+    bool,
+    Option<u64>,
+    Option<Checksum>,
+    Option<CompactionRecord>,
+);
+
+/// The fields written before compactions were recorded, ending with the checksum
+type LegacyBincodeFields = (
+    String,
+    Option<String>,
+    Option<(u32, u32)>,
+    u64,
+    [u8; 8],
+    bool,
+    Option<u64>,
+    Option<Checksum>,
+);
+
+impl CommandSerializer for BincodeCommandSerializer {
+    fn name(&self) -> &str {
+        "bincode"
+    }
+
+    fn serialize(&self, command: &Command) -> Result<Vec<u8>> {
+        let chunk = command.chunk.map(|Chunk { index, count }| (index, count));
+        Ok(bincode::serialize(&(
+            &command.key,
+            &command.value,
+            chunk,
+            command.written_at,
+            command.nonce,
+            command.open,
+            command.expires_at,
+            command.checksum,
+            &command.compaction,
+        ))?)
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<Command> {
+        // entries written before compactions were recorded stop short of the last field
+        let fields: BincodeFields = match bincode::deserialize(bytes) {
+            Ok(fields) => fields,
+            Err(_) => {
+                let (key, value, chunk, written_at, nonce, open, expires_at, checksum): LegacyBincodeFields =
+                    bincode::deserialize(bytes)?;
+                (
+                    key, value, chunk, written_at, nonce, open, expires_at, checksum, None,
+                )
+            }
+        };
+        let (key, value, chunk, written_at, nonce, open, expires_at, checksum, compaction) = fields;
+        Ok(Command {
+            key,
+            value,
+            chunk: chunk.map(|(index, count)| Chunk { index, count }),
+            written_at,
+            nonce,
+            open,
+            expires_at,
+            compaction,
+            checksum,
+        })
+    }
+}
+
+/// The format of a store's logs.
+///
+/// JSON entries are written back to back, as JSON marks where each one ends. Every other
+/// format's entries are prefixed with their length as 4 little endian bytes.
+#[derive(Clone)]
+pub(super) struct Format(Arc<dyn CommandSerializer>);
+
+impl Format {
+    pub(super) fn new(serializer: Arc<dyn CommandSerializer>) -> Format {
+        Format(serializer)
+    }
+
+    pub(super) fn json() -> Format {
+        Format(Arc::new(JsonCommandSerializer))
+    }
+
+    /// The built in format called `name`, if there is one.
+    fn built_in(name: &str) -> Option<Format> {
+        match name {
+            JSON => Some(Format::json()),
+            "bincode" => Some(Format(Arc::new(BincodeCommandSerializer))),
+            _ => None,
+        }
+    }
+
+    pub(super) fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    pub(super) fn is_json(&self) -> bool {
+        self.name() == JSON
+    }
+
+    /// The bytes written to the log for `command`.
+    pub(super) fn encode(&self, command: &Command) -> Result<Vec<u8>> {
+        let bytes = self.0.serialize(command)?;
+        if self.is_json() {
+            return Ok(bytes);
+        }
+        let len: u32 = bytes.len().try_into()?;
+        let mut framed = Vec::with_capacity(4 + bytes.len());
+        framed.extend_from_slice(&len.to_le_bytes());
+        framed.extend_from_slice(&bytes);
+        Ok(framed)
+    }
+
+    pub(super) fn write(&self, writer: &mut impl Write, command: &Command) -> Result<()> {
+        Ok(writer.write_all(&self.encode(command)?)?)
+    }
+
+    /// Read entries written in this format from `reader`, one at a time.
+    pub(super) fn reader<R: Read>(&self, reader: R) -> CommandReader<R> {
+        let inner = if self.is_json() {
+            Entries::Json(serde_json::Deserializer::from_reader(reader).into_iter())
+        } else {
+            Entries::Framed {
+                reader,
+                serializer: self.0.clone(),
+                offset: 0,
+            }
+        };
+        CommandReader(inner)
+    }
+}
+
+impl fmt::Debug for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Format({})", self.name())
+    }
+}
+
+/// Why an entry couldn't be read.
+#[derive(Debug, thiserror::Error)]
+pub(super) enum ReadError {
+    /// The data ended part way through the entry, as a crash during a write leaves it
+    #[error("Log entry cut short")]
+    Truncated,
+    #[error("Invalid log entry")]
+    Invalid(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Entries read one at a time by `Format::reader`.
+pub(super) struct CommandReader<R: Read>(Entries<R>);
+
+enum Entries<R: Read> {
+    Json(serde_json::StreamDeserializer<'static, serde_json::de::IoRead<R>, Command>),
+    Framed {
+        reader: R,
+        serializer: Arc<dyn CommandSerializer>,
+        offset: u64,
+    },
+}
+
+impl<R: Read> CommandReader<R> {
+    /// Bytes read up to the end of the last entry returned.
+    pub(super) fn byte_offset(&self) -> u64 {
+        match &self.0 {
+            Entries::Json(commands) => commands.byte_offset() as u64,
+            Entries::Framed { offset, .. } => *offset,
+        }
+    }
+}
+
+impl<R: Read> Iterator for CommandReader<R> {
+    type Item = std::result::Result<Command, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.0 {
+            Entries::Json(commands) => Some(commands.next()?.map_err(|e| {
+                if e.is_eof() {
+                    ReadError::Truncated
+                } else {
+                    ReadError::Invalid(e.into())
+                }
+            })),
+            Entries::Framed {
+                reader,
+                serializer,
+                offset,
+            } => {
+                let mut len = [0; 4];
+                match read_fully(reader, &mut len) {
+                    Ok(0) => return None,
+                    Ok(n) if n < len.len() => return Some(Err(ReadError::Truncated)),
+                    Ok(_) => {}
+                    Err(e) => return Some(Err(ReadError::Invalid(e.into()))),
+                }
+
+                // read through `take`, so a corrupt length can't allocate more than the file
+                let len = u32::from_le_bytes(len);
+                let mut bytes = Vec::new();
+                if let Err(e) = reader.by_ref().take(len.into()).read_to_end(&mut bytes) {
+                    return Some(Err(ReadError::Invalid(e.into())));
+                }
+                if bytes.len() < len as usize {
+                    return Some(Err(ReadError::Truncated));
+                }
+
+                match serializer.deserialize(&bytes) {
+                    Ok(command) => {
+                        *offset += 4 + u64::from(len);
+                        Some(Ok(command))
+                    }
+                    Err(e) => Some(Err(ReadError::Invalid(e.into()))),
+                }
+            }
+        }
+    }
+}
+
+/// Fill `buf` unless the reader ends first, returning how many bytes were read.
+fn read_fully(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// The formats to open the store in `kvs_dir` with.
+#[derive(Debug)]
+pub(super) struct ResolvedFormats {
+    /// Every new entry is written in this format, `serializer` if it is given or else the
+    /// recorded one
+    pub(super) write: Format,
+    /// The format of existing log files which aren't JSON, see `detect_format`
+    pub(super) framed: Format,
+}
+
+/// Choose the formats to open the store in `kvs_dir` with.
+///
+/// A store without a `FORMAT` file is JSON if it already has logs, as every store was
+/// before formats could be chosen. Its files can be converted from the recorded format to
+/// `serializer` if the recorded one is built in and one of them is JSON, as JSON files can
+/// be told apart from any other. Otherwise `serializer` must match it.
+///
+/// The format isn't recorded here, as the files may still need converting.
+pub(super) fn resolve_format(
+    kvs_dir: &Path,
+    serializer: Option<Format>,
+    has_logs: bool,
+) -> Result<ResolvedFormats> {
+    let recorded = match fs::read_to_string(kvs_dir.join(FORMAT_FILE)) {
+        Ok(name) => Some(name.trim().to_owned()),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let recorded = recorded.or_else(|| Some(JSON.to_owned()).filter(|_| has_logs));
+    let recorded = match recorded {
+        Some(name) => Some(
+            serializer
+                .clone()
+                .filter(|format| format.name() == name)
+                .or_else(|| Format::built_in(&name))
+                .ok_or(KvsError::FormatMismatch)?,
+        ),
+        None => None,
+    };
+
+    let write = serializer
+        .or_else(|| recorded.clone())
+        .unwrap_or_else(Format::json);
+    let framed = match recorded {
+        Some(recorded) if !recorded.is_json() => {
+            // two framed formats can't be told apart
+            if !write.is_json() && write.name() != recorded.name() {
+                return Err(KvsError::FormatMismatch.into());
+            }
+            recorded
+        }
+        _ => write.clone(),
+    };
+    Ok(ResolvedFormats { write, framed })
+}
+
+/// The format of the log file read by `reader`: JSON if it starts like a JSON entry, or
+/// `framed` otherwise.
+///
+/// A framed entry would need a length prefix of nearly 1 GB to look like JSON.
+pub(super) fn detect_format(reader: &mut impl BufRead, framed: &Format) -> Result<Format> {
+    let start = reader.fill_buf()?;
+    if start.starts_with(JSON_ENTRY_START) {
+        Ok(Format::json())
+    } else {
+        Ok(framed.clone())
+    }
+}
+
+/// Record the format of the logs in `kvs_dir`.
+pub(super) fn save_format(kvs_dir: &Path, format: &Format) -> Result<()> {
+    let tmp_path = kvs_dir.join(format!("{}.tmp", FORMAT_FILE));
+    fs::write(&tmp_path, format.name())?;
+
+    Ok(fs::rename(tmp_path, kvs_dir.join(FORMAT_FILE))?)
+}
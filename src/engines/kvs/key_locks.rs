@@ -0,0 +1,67 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Number of locks keys are partitioned between.
+const NUM_KEY_LOCKS: usize = 64;
+
+/// A fixed set of spinlocks, each guarding every key which hashes to it.
+#[derive(Debug)]
+pub(super) struct KeyLocks {
+    locks: Vec<AtomicBool>,
+}
+
+impl KeyLocks {
+    pub(super) fn new() -> KeyLocks {
+        KeyLocks {
+            locks: (0..NUM_KEY_LOCKS).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    fn slot(key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % NUM_KEY_LOCKS as u64) as usize
+    }
+}
+
+/// Holds the locks for a set of keys, created by `KvStore::lock_keys`.
+///
+/// The locks are released when the guard is dropped.
+#[derive(Debug)]
+pub struct KeyGuard {
+    locks: Arc<KeyLocks>,
+    held: Vec<usize>,
+}
+
+impl KeyGuard {
+    pub(super) fn lock(locks: Arc<KeyLocks>, keys: &[&str]) -> KeyGuard {
+        // taking locks in a consistent order means overlapping guards can't deadlock,
+        // and deduplicating means keys sharing a lock don't deadlock with themselves
+        let mut slots: Vec<usize> = keys.iter().map(|key| KeyLocks::slot(key)).collect();
+        slots.sort_unstable();
+        slots.dedup();
+
+        for &slot in &slots {
+            let lock = &locks.locks[slot];
+            while lock
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                thread::yield_now();
+            }
+        }
+
+        KeyGuard { locks, held: slots }
+    }
+}
+
+impl Drop for KeyGuard {
+    fn drop(&mut self) {
+        for &slot in self.held.iter().rev() {
+            self.locks.locks[slot].store(false, Ordering::Release);
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use super::bytes::Bytes;
+use super::file;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Which log files are in level 1, and the keys in each
+pub(super) const LEVELS_FILE: &str = "LEVELS";
+
+/// Level 1 files are split once they grow past this, so a merge only rewrites the part of
+/// level 1 it overlaps
+pub(super) const LEVEL1_FILE_BYTES: Bytes = Bytes(2 * 1024 * 1024);
+
+/// A log file written by leveled compaction, holding one value for each key in its range,
+/// sorted by key. See `KvStoreOptions::leveled_compaction`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(super) struct Level1File {
+    first_key: String,
+    last_key: String,
+    /// Values written to the file. Fewer live ones means some were overwritten or removed.
+    pub(super) entries: usize,
+}
+
+impl Level1File {
+    /// Is `key` within the file's key range?
+    pub(super) fn covers(&self, key: &str) -> bool {
+        self.entries > 0 && self.first_key.as_str() <= key && key <= self.last_key.as_str()
+    }
+
+    /// Does the file's key range end before `key`?
+    pub(super) fn ends_before(&self, key: &str) -> bool {
+        self.entries > 0 && self.last_key.as_str() < key
+    }
+
+    pub(super) fn first_key(&self) -> &str {
+        &self.first_key
+    }
+
+    /// Record the next value written to the file, which must be for a later key.
+    pub(super) fn push(&mut self, key: &str) {
+        if self.entries == 0 {
+            self.first_key = key.to_owned();
+        }
+        self.last_key = key.to_owned();
+        self.entries += 1;
+    }
+}
+
+pub(super) type Level1 = BTreeMap<file::Id, Level1File>;
+
+/// Record the level 1 files, replacing the previous record.
+///
+/// The record is only a hint. A log file missing from it is treated as level 0, and merged
+/// by the next compaction, so a crash before it is saved costs a bigger merge and no data.
+pub(super) fn save_levels(kvs_dir: &Path, level1: &Level1) -> Result<()> {
+    let tmp_path = kvs_dir.join(format!("{}.tmp", LEVELS_FILE));
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer(&mut writer, level1)?;
+    writer.flush()?;
+    drop(writer);
+
+    Ok(fs::rename(tmp_path, kvs_dir.join(LEVELS_FILE))?)
+}
+
+/// Load the level 1 files recorded by the last leveled compaction, if there was one.
+pub(super) fn load_levels(kvs_dir: &Path) -> Result<Level1> {
+    match File::open(kvs_dir.join(LEVELS_FILE)) {
+        Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Level1::new()),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,103 @@
+use std::ops::{Deref, DerefMut};
+
+/// The lock around a `KvStore`, either the standard library's or a fair one.
+///
+/// Reads which can be answered without changing the store share the lock, everything else
+/// takes it exclusively.
+#[derive(Debug)]
+pub(super) enum StoreLock<T> {
+    Std(std::sync::RwLock<T>),
+    /// Hands the lock directly to the longest waiting thread on every unlock
+    Fair(parking_lot::RwLock<T>),
+}
+
+impl<T> StoreLock<T> {
+    pub(super) fn new(value: T, fair: bool) -> StoreLock<T> {
+        if fair {
+            StoreLock::Fair(parking_lot::RwLock::new(value))
+        } else {
+            StoreLock::Std(std::sync::RwLock::new(value))
+        }
+    }
+
+    /// Take the lock exclusively.
+    pub(super) fn lock(&self) -> StoreGuard<'_, T> {
+        match self {
+            StoreLock::Std(lock) => StoreGuard::Std(lock.write().unwrap()),
+            StoreLock::Fair(lock) => StoreGuard::Fair(Some(lock.write())),
+        }
+    }
+
+    /// Take the lock shared with other readers.
+    pub(super) fn read(&self) -> StoreReadGuard<'_, T> {
+        match self {
+            StoreLock::Std(lock) => StoreReadGuard::Std(lock.read().unwrap()),
+            StoreLock::Fair(lock) => StoreReadGuard::Fair(Some(lock.read())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(super) enum StoreGuard<'a, T> {
+    Std(std::sync::RwLockWriteGuard<'a, T>),
+    /// Only `None` while being dropped
+    Fair(Option<parking_lot::RwLockWriteGuard<'a, T>>),
+}
+
+impl<'a, T> Deref for StoreGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            StoreGuard::Std(guard) => guard,
+            StoreGuard::Fair(guard) => guard.as_ref().expect("guard already unlocked"),
+        }
+    }
+}
+
+impl<'a, T> DerefMut for StoreGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match self {
+            StoreGuard::Std(guard) => guard,
+            StoreGuard::Fair(guard) => guard.as_mut().expect("guard already unlocked"),
+        }
+    }
+}
+
+impl<'a, T> Drop for StoreGuard<'a, T> {
+    fn drop(&mut self) {
+        if let StoreGuard::Fair(guard) = self {
+            if let Some(guard) = guard.take() {
+                parking_lot::RwLockWriteGuard::unlock_fair(guard);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(super) enum StoreReadGuard<'a, T> {
+    Std(std::sync::RwLockReadGuard<'a, T>),
+    /// Only `None` while being dropped
+    Fair(Option<parking_lot::RwLockReadGuard<'a, T>>),
+}
+
+impl<'a, T> Deref for StoreReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            StoreReadGuard::Std(guard) => guard,
+            StoreReadGuard::Fair(guard) => guard.as_ref().expect("guard already unlocked"),
+        }
+    }
+}
+
+impl<'a, T> Drop for StoreReadGuard<'a, T> {
+    fn drop(&mut self) {
+        if let StoreReadGuard::Fair(guard) = self {
+            if let Some(guard) = guard.take() {
+                parking_lot::RwLockReadGuard::unlock_fair(guard);
+            }
+        }
+    }
+}
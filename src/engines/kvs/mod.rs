@@ -1,7 +1,45 @@
 //! Implementation of the `KvStore` engine.
 
+mod backup;
+#[cfg(feature = "bloom_filter")]
+mod bloom;
 mod bytes;
+mod checksum;
+mod command;
+mod compaction;
+mod ext;
+#[cfg(feature = "fault_injection")]
+mod fault;
 mod file;
+mod format;
+mod key_locks;
+mod level;
+mod lock;
+mod options;
+mod read_cache;
+mod replica;
+mod secondary_index;
+mod snapshot;
+mod stats;
 mod store;
+mod telemetry;
 
+pub use self::backup::BackupHandle;
+pub use self::command::{Command, CompactionRecord};
+pub use self::ext::KvStoreExt;
+#[cfg(feature = "fault_injection")]
+pub use self::fault::FaultPoint;
+pub use self::file::Id as LogFileId;
+#[cfg(feature = "testing")]
+pub use self::file::KvsWriter;
+pub use self::format::{
+    BincodeCommandSerializer, CommandSerializer, JsonCommandSerializer, LogFormat,
+};
+pub use self::key_locks::KeyGuard;
+pub use self::options::{
+    ChecksumAlgorithm, FileNamingScheme, IsolationLevel, KvStoreOptions, ValidationMode,
+};
+pub use self::snapshot::Snapshot;
+pub use self::stats::{CompactionStats, KvStoreStats, LogFileStats};
 pub use self::store::{KvStore, KVS_DIR};
+pub use self::telemetry::TelemetrySink;
@@ -0,0 +1,486 @@
+use super::bytes::Bytes;
+use super::compaction::{DEFAULT_INITIAL_THRESHOLD, DEFAULT_MAX_THRESHOLD, DEFAULT_MIN_THRESHOLD};
+#[cfg(feature = "fault_injection")]
+use super::fault::FaultPoint;
+use super::format::{CommandSerializer, Format, LogFormat};
+use super::secondary_index::SecondaryIndex;
+use super::telemetry::{Sink, TelemetrySink};
+use slog::Logger;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Options for opening a `KvStore`.
+///
+/// # Examples
+///
+/// ```
+/// # use kvs::{IsolationLevel, KvStore, KvStoreOptions};
+/// let options = KvStoreOptions::default().isolation_level(IsolationLevel::RepeatableRead);
+/// let store = KvStore::open_with_options(".", options)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(super) isolation_level: IsolationLevel,
+    pub(super) replica_lag_limit: Option<Duration>,
+    pub(super) strict_mode: bool,
+    pub(super) inline_values: bool,
+    pub(super) max_inline_value_bytes: usize,
+    pub(super) max_pending_writes: usize,
+    pub(super) permanent_delete: bool,
+    pub(super) arc_cache_capacity: usize,
+    pub(super) read_cache_capacity: usize,
+    pub(super) max_uncompacted_bytes: Bytes,
+    pub(super) min_compaction_bytes: Bytes,
+    pub(super) max_compaction_bytes: Bytes,
+    pub(super) compaction_live_ratio_threshold: Option<f64>,
+    pub(super) sink: Sink,
+    pub(super) log: Option<Logger>,
+    pub(super) file_naming: FileNamingScheme,
+    pub(super) write_path: Option<PathBuf>,
+    pub(super) compacted_path: Option<PathBuf>,
+    pub(super) validation_mode: ValidationMode,
+    pub(super) max_log_age: Option<Duration>,
+    pub(super) max_log_file_bytes: Option<Bytes>,
+    pub(super) nonblocking_flush_interval: Duration,
+    pub(super) purge_expired_interval: Option<Duration>,
+    pub(super) secondary_indexes: HashMap<String, SecondaryIndex>,
+    pub(super) fair_locking: bool,
+    pub(super) retry_on_interrupt: bool,
+    pub(super) sync_writes: bool,
+    pub(super) max_l0_files: Option<usize>,
+    pub(super) max_index_memory_bytes: Option<usize>,
+    pub(super) checksum_algorithm: ChecksumAlgorithm,
+    pub(super) serializer: Option<Format>,
+    /// Set by `KvStore::open_read_only`
+    pub(super) read_only: bool,
+    #[cfg(feature = "fault_injection")]
+    pub(super) fault: Option<FaultPoint>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            isolation_level: IsolationLevel::default(),
+            replica_lag_limit: None,
+            strict_mode: false,
+            inline_values: true,
+            max_inline_value_bytes: 1024 * 1024,
+            max_pending_writes: 0,
+            permanent_delete: false,
+            arc_cache_capacity: 0,
+            read_cache_capacity: 0,
+            max_uncompacted_bytes: DEFAULT_INITIAL_THRESHOLD,
+            min_compaction_bytes: DEFAULT_MIN_THRESHOLD,
+            max_compaction_bytes: DEFAULT_MAX_THRESHOLD,
+            compaction_live_ratio_threshold: None,
+            sink: Sink::default(),
+            log: None,
+            file_naming: FileNamingScheme::default(),
+            write_path: None,
+            compacted_path: None,
+            validation_mode: ValidationMode::default(),
+            max_log_age: None,
+            max_log_file_bytes: None,
+            nonblocking_flush_interval: Duration::from_millis(100),
+            purge_expired_interval: None,
+            secondary_indexes: HashMap::new(),
+            fair_locking: false,
+            retry_on_interrupt: false,
+            sync_writes: false,
+            max_l0_files: None,
+            max_index_memory_bytes: None,
+            checksum_algorithm: ChecksumAlgorithm::default(),
+            serializer: None,
+            read_only: false,
+            #[cfg(feature = "fault_injection")]
+            fault: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Set the isolation level used by snapshots taken from the store.
+    pub fn isolation_level(mut self, level: IsolationLevel) -> Self {
+        self.isolation_level = level;
+        self
+    }
+
+    /// When enabled, `get` returns `KvsError::KeyNotFound` for missing keys instead of `Ok(None)`.
+    pub fn strict_mode(mut self, strict: bool) -> Self {
+        self.strict_mode = strict;
+        self
+    }
+
+    /// Keep small values in memory so they can be read without disk access. Enabled by default.
+    pub fn inline_values(mut self, enabled: bool) -> Self {
+        self.inline_values = enabled;
+        self
+    }
+
+    /// Split values longer than `bytes` across several log entries of at most this size.
+    ///
+    /// Smaller entries keep individual reads from the log short, at the cost of some overhead
+    /// for every extra entry. Defaults to 1 MiB. Zero disables splitting.
+    pub fn max_inline_value_bytes(mut self, bytes: usize) -> Self {
+        self.max_inline_value_bytes = bytes;
+        self
+    }
+
+    /// Hold back up to `max_pending` `set` calls in memory before writing them to disk.
+    ///
+    /// Repeated writes to the same key while it is pending only write the final value,
+    /// reducing log growth under update-heavy workloads. Pending writes are lost if the
+    /// process crashes; use `KvStore::flush_pending_writes` to write them early.
+    /// Zero, the default, disables coalescing.
+    pub fn coalesce_writes(mut self, max_pending: usize) -> Self {
+        self.max_pending_writes = max_pending;
+        self
+    }
+
+    /// Make `remove` permanent, so the key can never be set again.
+    ///
+    /// Setting a removed key returns `KvsError::PermanentlyDeleted`. Removed keys are recorded
+    /// in a `DELETED` file alongside the logs, and stay deleted even if the store is later
+    /// opened without this option. Writes queued by `KvStore::set_nonblocking` for them are
+    /// dropped. Off by default.
+    pub fn permanent_delete(mut self, enabled: bool) -> Self {
+        self.permanent_delete = enabled;
+        self
+    }
+
+    /// Keep up to `capacity` values read by `KvStore::get_arc` in memory, shared between callers.
+    ///
+    /// Suited to a few hot keys: once the cache is full, other keys aren't cached until cached
+    /// keys are removed. Cached values are kept up to date by `set`. Zero, the default,
+    /// disables the cache.
+    pub fn arc_cache(mut self, capacity: usize) -> Self {
+        self.arc_cache_capacity = capacity;
+        self
+    }
+
+    /// Keep up to `capacity` values read from disk in memory, evicting the least recently
+    /// used, so reading them again needs no disk access. Also lets `KvStore::prefetch` read
+    /// values ahead of time.
+    ///
+    /// Values already kept in the index, see `inline_values`, aren't counted. Zero, the
+    /// default, disables the cache.
+    pub fn read_cache(mut self, capacity: usize) -> Self {
+        self.read_cache_capacity = capacity;
+        self
+    }
+
+    /// The redundant data, in bytes, to allow in the log before the first compaction.
+    ///
+    /// Later compactions adjust the threshold for the write rate, between
+    /// `min_compaction_bytes` and `max_compaction_bytes`. Defaults to 1 MiB.
+    pub fn max_uncompacted_bytes(mut self, bytes: u64) -> Self {
+        self.max_uncompacted_bytes = Bytes(bytes);
+        self
+    }
+
+    /// The least redundant data, in bytes, to allow in the log before compacting.
+    ///
+    /// The compaction threshold starts at `max_uncompacted_bytes` and is lowered towards this
+    /// minimum while the store is written to slowly. Defaults to 64 KiB.
+    pub fn min_compaction_bytes(mut self, bytes: u64) -> Self {
+        self.min_compaction_bytes = Bytes(bytes);
+        self
+    }
+
+    /// The most redundant data, in bytes, to allow in the log before compacting.
+    ///
+    /// The compaction threshold is raised towards this maximum while the store is written to
+    /// quickly, trading disk space for less time spent compacting. Defaults to 64 MiB.
+    pub fn max_compaction_bytes(mut self, bytes: u64) -> Self {
+        self.max_compaction_bytes = Bytes(bytes);
+        self
+    }
+
+    /// Also compact whenever less than `ratio` of the log on disk is live, as reported by
+    /// `KvStore::live_ratio`, however few bytes are redundant.
+    ///
+    /// For example 0.5 compacts once more than half the disk space is wasted. Off by default.
+    pub fn compaction_live_ratio_threshold(mut self, ratio: f64) -> Self {
+        self.compaction_live_ratio_threshold = Some(ratio);
+        self
+    }
+
+    /// Maintain an index named `name`, from secondary keys to the keys whose values map to them.
+    ///
+    /// `extractor` is given each key and value as it is written, and returns the secondary key
+    /// to index it under, if any. Query the index with `KvStore::query_secondary`. The index is
+    /// kept in memory and rebuilt when the store is opened, which reads every value.
+    #[allow(clippy::type_complexity)]
+    pub fn with_secondary_index(
+        mut self,
+        name: &str,
+        extractor: Arc<dyn Fn(&str, &str) -> Option<String> + Send + Sync>,
+    ) -> Self {
+        self.secondary_indexes
+            .insert(name.to_owned(), SecondaryIndex::new(extractor));
+        self
+    }
+
+    /// Report operations on the store to `sink`. By default events are discarded.
+    pub fn sink(mut self, sink: Arc<dyn TelemetrySink + Send + Sync>) -> Self {
+        self.sink = Sink::new(sink);
+        self
+    }
+
+    /// Log every index lookup and write to `log`, at trace level, for profiling.
+    ///
+    /// Nothing is logged unless `log` is enabled for `slog::Level::Trace`.
+    pub fn logger(mut self, log: Logger) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    /// Choose how new log files are named. Files named by either scheme can be read.
+    pub fn file_naming(mut self, naming: FileNamingScheme) -> Self {
+        self.file_naming = naming;
+        self
+    }
+
+    /// Create new log files in `path` instead of the `.kvs` directory, for example to put the
+    /// logs being written on faster storage.
+    ///
+    /// The saved index and other metadata stay in `.kvs`. Logs are only looked for in the
+    /// configured directories, so the store must be opened with the same paths each time.
+    pub fn write_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.write_path = Some(path.into());
+        self
+    }
+
+    /// Write the logs produced by compaction to `path` instead of the `.kvs` directory,
+    /// for example to keep long-lived data on cheaper storage. See `write_path`.
+    pub fn compacted_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.compacted_path = Some(path.into());
+        self
+    }
+
+    /// Choose what happens when opening the store finds a corrupt log entry.
+    ///
+    /// Defaults to `ValidationMode::ErrorOnCorrupt`.
+    pub fn validate_on_open(mut self, mode: ValidationMode) -> Self {
+        self.validation_mode = mode;
+        self
+    }
+
+    /// Checksum each log entry as it is written, so corruption which still leaves valid JSON
+    /// behind, such as a flipped bit in a value, is caught when the entry is read.
+    ///
+    /// A bad checksum is treated like any other corrupt entry when the store is opened, see
+    /// `validate_on_open`, and makes reading the value fail with `KvsError::ChecksumMismatch`.
+    /// Each checksum records its algorithm, so changing this only affects new entries.
+    /// Defaults to `ChecksumAlgorithm::None`.
+    pub fn checksum_algorithm(mut self, algorithm: ChecksumAlgorithm) -> Self {
+        self.checksum_algorithm = algorithm;
+        self
+    }
+
+    /// Write log entries with `serializer`, for example `BincodeCommandSerializer` for smaller
+    /// logs which are quicker to replay.
+    ///
+    /// The format is recorded in the store's `FORMAT` file. Opening the store with a different
+    /// one compacts it, rewriting every log in the new format, if the recorded one is built in
+    /// and one of them is JSON. Otherwise it fails with `KvsError::FormatMismatch`.
+    /// Without this the recorded format is used, or `JsonCommandSerializer` for a new store.
+    pub fn serializer(mut self, serializer: Arc<dyn CommandSerializer>) -> Self {
+        self.serializer = Some(Format::new(serializer));
+        self
+    }
+
+    /// Write log entries in one of the built in formats, see `serializer`.
+    pub fn format(self, format: LogFormat) -> Self {
+        self.serializer(format.serializer())
+    }
+
+    /// How often writes queued by `KvStore::set_nonblocking` are applied in the background.
+    /// Defaults to 100 ms.
+    pub fn nonblocking_flush_interval(mut self, interval: Duration) -> Self {
+        self.nonblocking_flush_interval = interval;
+        self
+    }
+
+    /// Purge values past their time to live, see `KvStoreExt::set_with_ttl`, on a background
+    /// thread every `interval`. Without this they stay in memory until they are read,
+    /// compacted or purged with `KvStore::purge_expired`.
+    pub fn purge_expired_interval(mut self, interval: Duration) -> Self {
+        self.purge_expired_interval = Some(interval);
+        self
+    }
+
+    /// Start a new log file once the current one grows past `bytes`, without waiting for
+    /// compaction. A single `set` or `remove` is never split between files, so a file can
+    /// exceed the limit by one write.
+    pub fn max_log_file_bytes(mut self, bytes: u64) -> Self {
+        self.max_log_file_bytes = Some(Bytes(bytes));
+        self
+    }
+
+    /// Expire values once the log file they were written to is older than `age`.
+    ///
+    /// Reading an expired value returns `KvsError::DataExpired`, and compaction discards it.
+    /// Expired log files which hold no live values are deleted when the store is opened.
+    /// Compaction keeps the age of the values it copies, as the creation time of the log
+    /// which was being written when it ran, so surviving compaction doesn't extend a value's life.
+    pub fn max_log_age(mut self, age: Duration) -> Self {
+        self.max_log_age = Some(age);
+        self
+    }
+
+    /// Hand the store's lock to waiting threads in the order they asked for it.
+    ///
+    /// The standard lock lets a thread which has just unlocked take the lock straight back,
+    /// so a flood of readers can starve a writer. Fair locking prevents that, at some cost
+    /// to throughput.
+    pub fn fair_locking(mut self, fair: bool) -> Self {
+        self.fair_locking = fair;
+        self
+    }
+
+    /// Retry log file writes and syncs which fail with `ErrorKind::Interrupted` (`EINTR`).
+    ///
+    /// The standard library already retries inside `write_all`, `read_exact` and the like, which
+    /// covers reads. This is for platforms where a single `write` or `fsync` can still be
+    /// interrupted, and is off by default.
+    pub fn retry_on_interrupt(mut self, retry: bool) -> Self {
+        self.retry_on_interrupt = retry;
+        self
+    }
+
+    /// Call `fsync` on the active log after every flush, so a write has reached the disk
+    /// before it is acknowledged.
+    ///
+    /// By default writes are only flushed to the OS, and those since the last `fsync` can
+    /// be lost if the machine crashes. Syncing only the data, not the file's metadata, keeps
+    /// the cost down, but it is still far slower. Off by default.
+    pub fn sync_writes(mut self, sync: bool) -> Self {
+        self.sync_writes = sync;
+        self
+    }
+
+    /// Compact into two levels, rather than rewriting every value into a single file.
+    ///
+    /// Level 0 is the logs written since the last compaction, and level 1 is files of values
+    /// sorted by key, each covering its own range of keys. Compaction merges level 0 into only
+    /// the level 1 files whose range it touches, so it copies roughly what was written since,
+    /// not the whole store. It runs once there are more than `max_l0_files` level 0 logs,
+    /// counting the active one, as well as when the usual thresholds are exceeded.
+    pub fn leveled_compaction(mut self, max_l0_files: usize) -> Self {
+        self.max_l0_files = Some(max_l0_files);
+        self
+    }
+
+    /// Refuse to set new keys, with `KvsError::IndexMemoryLimitExceeded`, once the index
+    /// would use more than `bytes` of memory. Keys already in the index can still be
+    /// overwritten or removed.
+    ///
+    /// The index is kept entirely in memory, so without a limit it grows with the number of
+    /// keys until the process runs out. Its size is estimated, see
+    /// `KvStore::estimated_index_memory_bytes`.
+    pub fn max_index_memory_bytes(mut self, bytes: usize) -> Self {
+        self.max_index_memory_bytes = Some(bytes);
+        self
+    }
+
+    /// Panic when compaction reaches `point`, leaving the files on disk as a crash would.
+    #[cfg(feature = "fault_injection")]
+    pub fn inject_fault(mut self, point: FaultPoint) -> Self {
+        self.fault = Some(point);
+        self
+    }
+
+    /// Block writes while a replica added with `KvStore::with_replica` is more than `limit` behind.
+    pub fn replica_lag_limit(mut self, limit: Duration) -> Self {
+        self.replica_lag_limit = Some(limit);
+        self
+    }
+}
+
+/// Controls which state reads made through a `Snapshot` observe.
+///
+/// `KvStore::get` always sees the latest committed state, regardless of this setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+    /// Every read sees the latest committed state.
+    ///
+    /// Snapshots are free to create, but two reads of the same key may return different values.
+    ReadCommitted,
+
+    /// All reads see the state at the time the snapshot was taken.
+    ///
+    /// Creating a snapshot copies the index, which costs memory proportional to the number of keys.
+    /// The values themselves stay on disk, so a snapshot expires once compaction removes the files
+    /// it points into.
+    RepeatableRead,
+}
+
+impl Default for IsolationLevel {
+    fn default() -> Self {
+        IsolationLevel::ReadCommitted
+    }
+}
+
+/// How `KvStore` names the log files it creates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileNamingScheme {
+    /// `{id}.log`, where `id` increases with each new file.
+    Numeric,
+
+    /// `{timestamp}_{id}.log`, where `timestamp` is the Unix time in seconds when the file
+    /// was created. Useful for matching log files up with other records by time.
+    TimestampedNumeric,
+}
+
+impl Default for FileNamingScheme {
+    fn default() -> Self {
+        FileNamingScheme::Numeric
+    }
+}
+
+/// What `KvStore::open` does when it finds a log entry it can't read.
+///
+/// This doesn't apply to a log which ends part way through an entry, as left by a crash during
+/// a write. That entry is always dropped and reported to `TelemetrySink::on_truncated_log`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationMode {
+    /// Ignore the rest of the log file, from the corrupt entry onwards.
+    SkipCorrupt,
+
+    /// Fail to open the store. Appropriate where any corruption should be investigated.
+    ErrorOnCorrupt,
+
+    /// Skip like `SkipCorrupt`, but report each corrupt entry to the store's
+    /// `TelemetrySink::on_corrupt_entry`.
+    ReportCorrupt,
+}
+
+impl Default for ValidationMode {
+    fn default() -> Self {
+        ValidationMode::ErrorOnCorrupt
+    }
+}
+
+/// How log entries are checksummed, see `KvStoreOptions::checksum_algorithm`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    /// Entries aren't checksummed, so only corruption which breaks the JSON is detected.
+    None,
+
+    /// CRC-32, as used by zlib and Ethernet.
+    Crc32,
+
+    /// XXH3, which is several times faster than CRC-32 on CPUs with SIMD support.
+    XxHash3,
+}
+
+impl Default for ChecksumAlgorithm {
+    fn default() -> Self {
+        ChecksumAlgorithm::None
+    }
+}
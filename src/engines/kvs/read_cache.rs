@@ -0,0 +1,86 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Values read from disk, evicting the least recently used, see `KvStoreOptions::read_cache`.
+///
+/// Each value is stored with the nonce of the log entry it was read from, and is only
+/// returned for an index entry with the same nonce, so a key which has been written since
+/// is never answered from the cache however it changed.
+#[derive(Debug)]
+pub(super) struct ReadCache {
+    capacity: usize,
+    entries: HashMap<String, CachedValue>,
+    /// Keys by when they were last used, oldest first
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct CachedValue {
+    nonce: [u8; 8],
+    value: String,
+    last_used: u64,
+}
+
+impl ReadCache {
+    pub(super) fn new(capacity: usize) -> ReadCache {
+        ReadCache {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub(super) fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// The value of `key` read from the log entry with `nonce`, if it is cached.
+    pub(super) fn get(&mut self, key: &str, nonce: [u8; 8]) -> Option<String> {
+        self.clock += 1;
+        let entry = self
+            .entries
+            .get_mut(key)
+            .filter(|entry| entry.nonce == nonce)?;
+        self.recency.remove(&entry.last_used);
+        self.recency.insert(self.clock, key.to_owned());
+        entry.last_used = self.clock;
+        Some(entry.value.clone())
+    }
+
+    pub(super) fn contains(&self, key: &str, nonce: [u8; 8]) -> bool {
+        matches!(self.entries.get(key), Some(entry) if entry.nonce == nonce)
+    }
+
+    pub(super) fn insert(&mut self, key: String, nonce: [u8; 8], value: String) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.clock += 1;
+        if let Some(old) = self.entries.remove(&key) {
+            self.recency.remove(&old.last_used);
+        } else if self.entries.len() >= self.capacity {
+            let oldest = self.recency.keys().next().copied();
+            if let Some(oldest_key) = oldest.and_then(|tick| self.recency.remove(&tick)) {
+                self.entries.remove(&oldest_key);
+            }
+        }
+
+        self.recency.insert(self.clock, key.clone());
+        self.entries.insert(
+            key,
+            CachedValue {
+                nonce,
+                value,
+                last_used: self.clock,
+            },
+        );
+    }
+
+    /// Forget the value of `key`, once it has been overwritten or removed.
+    pub(super) fn remove(&mut self, key: &str) {
+        if let Some(old) = self.entries.remove(key) {
+            self.recency.remove(&old.last_used);
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use crate::KvsEngine;
+use crossbeam_channel::{unbounded, Sender};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A write to be replayed on the secondary.
+#[derive(Debug)]
+pub(super) enum ReplicaOp {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+/// Times at which each operation not yet applied to the secondary was sent, oldest first.
+type Pending = (Mutex<VecDeque<Instant>>, Condvar);
+
+/// Streams writes to a secondary engine, which applies them on a background thread.
+#[derive(Debug)]
+pub(super) struct Replica {
+    sender: Sender<ReplicaOp>,
+    pending: Arc<Pending>,
+}
+
+impl Replica {
+    pub(super) fn spawn(secondary: impl KvsEngine) -> Replica {
+        let (sender, receiver) = unbounded::<ReplicaOp>();
+        let pending = Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
+
+        let worker_pending = pending.clone();
+        thread::spawn(move || {
+            // exits once the primary is dropped and all queued operations are applied
+            for op in receiver {
+                // replication is fire-and-forget, so errors from the secondary are dropped
+                let _ = match op {
+                    ReplicaOp::Set { key, value } => secondary.set(key, value),
+                    ReplicaOp::Remove { key } => secondary.remove(key),
+                };
+
+                let (queue, applied) = &*worker_pending;
+                queue.lock().unwrap().pop_front();
+                applied.notify_all();
+            }
+        });
+
+        Replica { sender, pending }
+    }
+
+    /// Queue an operation for the secondary.
+    ///
+    /// If `lag_limit` is set, first blocks until the oldest unapplied operation is younger than it.
+    pub(super) fn send(&self, op: ReplicaOp, lag_limit: Option<Duration>) {
+        let (queue, applied) = &*self.pending;
+        let mut queue = queue.lock().unwrap();
+
+        if let Some(limit) = lag_limit {
+            while queue.front().map_or(false, |sent| sent.elapsed() > limit) {
+                queue = applied.wait(queue).unwrap();
+            }
+        }
+
+        // sending while holding the queue lock keeps the queue in step with the channel
+        if self.sender.send(op).is_ok() {
+            queue.push_back(Instant::now());
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Arc;
+
+/// Maps a key and value to the secondary key to index them by, if any.
+pub(super) type Extractor = Arc<dyn Fn(&str, &str) -> Option<String> + Send + Sync>;
+
+/// Keys grouped by a secondary key taken from their values, see
+/// `KvStoreOptions::with_secondary_index`.
+#[derive(Clone)]
+pub(super) struct SecondaryIndex {
+    extractor: Extractor,
+    keys: BTreeMap<String, BTreeSet<String>>,
+    /// The secondary key each key is currently indexed under, for removing it again
+    secondary_keys: HashMap<String, String>,
+}
+
+impl SecondaryIndex {
+    pub(super) fn new(extractor: Extractor) -> SecondaryIndex {
+        SecondaryIndex {
+            extractor,
+            keys: BTreeMap::new(),
+            secondary_keys: HashMap::new(),
+        }
+    }
+
+    /// Index the new value of `key`, replacing its old entry.
+    pub(super) fn insert(&mut self, key: &str, value: &str) {
+        self.remove(key);
+        if let Some(secondary_key) = (self.extractor)(key, value) {
+            self.keys
+                .entry(secondary_key.clone())
+                .or_default()
+                .insert(key.to_owned());
+            self.secondary_keys.insert(key.to_owned(), secondary_key);
+        }
+    }
+
+    pub(super) fn remove(&mut self, key: &str) {
+        if let Some(secondary_key) = self.secondary_keys.remove(key) {
+            if let Some(keys) = self.keys.get_mut(&secondary_key) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.keys.remove(&secondary_key);
+                }
+            }
+        }
+    }
+
+    /// Keys indexed under `secondary_key`, in order.
+    pub(super) fn query(&self, secondary_key: &str) -> Vec<String> {
+        match self.keys.get(secondary_key) {
+            Some(keys) => keys.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Debug for SecondaryIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecondaryIndex")
+            .field("keys", &self.keys)
+            .finish()
+    }
+}
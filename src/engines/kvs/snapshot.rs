@@ -0,0 +1,65 @@
+use super::lock::StoreLock;
+use super::store::{Index, InternalKvStore};
+use crate::errors::KvsError;
+use crate::Result;
+use std::sync::Arc;
+
+/// A read-only view of a `KvStore`, created by `KvStore::snapshot`.
+///
+/// What a snapshot observes depends on the store's `IsolationLevel`.
+#[derive(Debug)]
+pub struct Snapshot {
+    store: Arc<StoreLock<InternalKvStore>>,
+
+    /// Index captured when the snapshot was taken, or `None` to read the latest state.
+    index: Option<Index>,
+
+    /// The store's generation when the snapshot was taken
+    generation: u64,
+}
+
+impl Snapshot {
+    pub(super) fn new(
+        store: Arc<StoreLock<InternalKvStore>>,
+        index: Option<Index>,
+        generation: u64,
+    ) -> Snapshot {
+        Snapshot {
+            store,
+            index,
+            generation,
+        }
+    }
+
+    /// The store's generation when the snapshot was taken, see `KvStore::current_generation`.
+    ///
+    /// Once the store has moved on to a later generation, values in a `RepeatableRead`
+    /// snapshot may have been moved by compaction.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Get the value for the given key, if it exists.
+    ///
+    /// Returns `KvsError::SnapshotExpired` if the value has since been moved by compaction.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let mut guard = self.store.lock();
+        let store = &mut *guard;
+
+        let index = match &self.index {
+            Some(index) => index,
+            None => return store.get(&key),
+        };
+        let val_info = match index.get(&key) {
+            Some(val_info) => val_info,
+            None => return Ok(None),
+        };
+
+        if val_info.cached_value.is_none() && !store.readers.contains_key(&val_info.file_id) {
+            return Err(KvsError::SnapshotExpired.into());
+        }
+        val_info
+            .read_value(&key, &store.readers, &store.format)
+            .map(Some)
+    }
+}
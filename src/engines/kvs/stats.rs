@@ -0,0 +1,123 @@
+use crate::metrics::LatencyHistogram;
+use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// Statistics about a `KvStore`, returned by `KvStore::detailed_stats`.
+///
+/// Byte counters cover activity since the store was opened.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KvStoreStats {
+    /// Bytes written to the log by `set` and `remove`
+    pub user_bytes_written: u64,
+
+    /// Bytes written by compaction when copying live values into a new file
+    pub compaction_bytes_written: u64,
+
+    /// Bytes of log made redundant by tombstones and overwritten values
+    pub overhead_bytes: u64,
+
+    /// Sets and removes recorded in the log files, including those since made redundant.
+    /// Compared with the number of live keys, this shows how much history the log holds.
+    pub estimated_num_operations: u64,
+
+    /// Fraction of the log on disk holding current values, from `KvStore::live_ratio`
+    pub live_ratio: f64,
+
+    /// Mean bytes of log per set or remove, from `KvStore::avg_entry_bytes`
+    pub avg_entry_bytes: f64,
+
+    /// Calls to `get` in progress, including those waiting for the store's lock
+    pub current_readers: i32,
+
+    /// The most calls to `get` in progress at once since the store was opened.
+    /// Well above 1 means readers are queueing for the lock.
+    pub max_concurrent_readers: u32,
+
+    /// When the oldest log file was created, which after compaction is the compacted log.
+    /// `None` if it couldn't be found.
+    pub oldest_log_file_created_at: Option<SystemTime>,
+
+    /// How long calls to `get` took, from `KvStore::get_latency_histogram`
+    pub get_latency: LatencyHistogram,
+}
+
+impl Default for KvStoreStats {
+    fn default() -> Self {
+        KvStoreStats {
+            user_bytes_written: 0,
+            compaction_bytes_written: 0,
+            overhead_bytes: 0,
+            estimated_num_operations: 0,
+            live_ratio: 1.0,
+            avg_entry_bytes: 0.0,
+            current_readers: 0,
+            max_concurrent_readers: 0,
+            oldest_log_file_created_at: None,
+            get_latency: LatencyHistogram::default(),
+        }
+    }
+}
+
+/// Counts calls to `get` in progress, for `KvStoreStats::current_readers`.
+#[derive(Debug, Default)]
+pub(super) struct ReaderCount {
+    pub(super) current: AtomicI32,
+    pub(super) max: AtomicU32,
+}
+
+impl ReaderCount {
+    /// Count a reader until the guard is dropped.
+    pub(super) fn enter(&self) -> ReaderGuard<'_> {
+        let current = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max.fetch_max(current as u32, Ordering::SeqCst);
+        ReaderGuard(self)
+    }
+}
+
+pub(super) struct ReaderGuard<'a>(&'a ReaderCount);
+
+impl Drop for ReaderGuard<'_> {
+    fn drop(&mut self) {
+        self.0.current.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Results of checking a single log file, returned by `KvStore::verify_log_file`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogFileStats {
+    /// Commands which could be read, including tombstones
+    pub total_commands: usize,
+
+    /// `set` commands holding the current value for their key
+    pub live_commands: usize,
+
+    /// `remove` commands
+    pub tombstones: usize,
+
+    /// Stretches of the file which could not be read as commands
+    pub corrupt_entries: usize,
+
+    /// Live commands whose nonce doesn't match their index entry, so the index points at the
+    /// wrong command
+    pub index_mismatches: usize,
+
+    /// Size of the file
+    pub bytes_total: u64,
+}
+
+/// Details of a single compaction, reported to `TelemetrySink::on_compact`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// Live values copied into the compacted log
+    pub keys_copied: usize,
+
+    /// Bytes written to the compacted log
+    pub bytes_copied: u64,
+
+    /// Old log files deleted
+    pub files_removed: usize,
+
+    /// Time taken to compact
+    pub duration: Duration,
+}
@@ -1,32 +1,74 @@
-use super::file;
-use super::file::{get_log_file_ids, KvsWriter};
+use super::backup::BackupHandle;
+#[cfg(feature = "bloom_filter")]
+use super::bloom::BloomFilter;
 use super::bytes::Bytes;
-use crate::errors::KvsError;
-use crate::KvsEngine;
+use super::command::{Chunk, Command, CompactionRecord};
+use super::compaction::CompactionThreshold;
+use super::ext::KvStoreExt;
+#[cfg(feature = "fault_injection")]
+use super::fault::{self, FaultPoint};
+use super::file;
+use super::file::{get_log_file_ids, KvsWriter, LogDirs};
+use super::format::{self, CommandReader, Format, ReadError, FORMAT_FILE};
+use super::key_locks::{KeyGuard, KeyLocks};
+use super::level::{self, Level1, Level1File, LEVEL1_FILE_BYTES, LEVELS_FILE};
+use super::lock::StoreLock;
+use super::options::{
+    ChecksumAlgorithm, FileNamingScheme, IsolationLevel, KvStoreOptions, ValidationMode,
+};
+use super::read_cache::ReadCache;
+use super::replica::{Replica, ReplicaOp};
+use super::secondary_index::SecondaryIndex;
+use super::snapshot::Snapshot;
+use super::stats::{CompactionStats, KvStoreStats, LogFileStats, ReaderCount};
+use super::telemetry::Sink;
+use crate::errors::{classify_io_error, KvsError};
+use crate::metrics::LatencyHistogram;
 use crate::Result;
+use crate::{KvsEngine, StoreStats};
+use fs2::{lock_contended_error, FileExt};
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::collections::HashMap;
+use slog::{Drain, Logger};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::TryInto;
 use std::fs;
 use std::fs::File;
 use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Cursor;
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
+use std::io::Take;
 use std::io::Write;
-use std::path::PathBuf;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 pub const KVS_DIR: &str = ".kvs";
-const MAX_UNCOMPACTED: Bytes = Bytes(1024 * 1024);
+/// Copy of the index saved by compaction, so opening the store needn't replay compacted logs
+const INDEX_FILE: &str = "index.bin";
+/// Keys removed with `KvStoreOptions::permanent_delete`, which can never be set again
+const DELETED_FILE: &str = "DELETED";
+/// Highest log file ID ever used, so IDs aren't reused after log files are deleted
+const MAX_FILE_ID_FILE: &str = "MAX_FILE_ID";
+/// Values up to this size are kept in the index, so reading them needs no disk access
+const MAX_INLINE_VALUE: Bytes = Bytes(64);
 
 /// Implementation of a simple, persistent key-value store.
 ///
 /// The data is stored in multiple files in a single directory.
 /// Only the latest log file is actively written to.
 ///
-/// New files are created when compaction occurs.
+/// New files are created when compaction occurs. Compaction also saves a copy of the index,
+/// so opening the store only needs to replay the logs written since.
+/// `KvStoreOptions::leveled_compaction` instead keeps the compacted values in several files
+/// sorted by key, and only rewrites the ones affected by recent writes.
 ///
 /// # Examples
 ///
@@ -41,113 +83,2534 @@ const MAX_UNCOMPACTED: Bytes = Bytes(1024 * 1024);
 /// store.set(key.clone(), "value".to_owned());
 ///
 /// let saved_val = store.get(key.clone());
-/// # Ok::<(), failure::Error>(())
+/// # Ok::<(), anyhow::Error>(())
 /// ```
 #[allow(clippy::module_name_repetitions)]
 #[derive(Debug, Clone)]
 pub struct KvStore {
-    store: Arc<Mutex<InternalKvStore>>,
+    store: Arc<StoreLock<InternalKvStore>>,
+    key_locks: Arc<KeyLocks>,
+    /// Writes from `set_nonblocking`, shared with the store which applies them
+    queued_writes: Arc<Mutex<Vec<Command>>>,
+    flusher_started: Arc<AtomicBool>,
+    nonblocking_flush_interval: Duration,
+    /// Counted outside the lock, so callers waiting for it are included
+    readers: Arc<ReaderCount>,
+}
+
+impl KvStore {
+    /// Create a new KvStore, using the given `path` directory.
+    /// The log files will be stored in a directory named `.kvs` inside `path`.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Open the KvStore in the `path` directory for reading only, alongside any other read only
+    /// stores.
+    ///
+    /// The data directory is locked as shared, so a store can't open it for writing meanwhile,
+    /// and nothing is written to it: no log is started, logs aren't compacted or expired, and
+    /// `set` and `remove` fail with `KvsError::ReadOnly`. Fails with `KvsError::FormatMismatch`
+    /// if any log would need converting to the current format.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        let options = KvStoreOptions {
+            read_only: true,
+            ..KvStoreOptions::default()
+        };
+        KvStore::open_with_options(path, options)
+    }
+
+    /// Create a new KvStore, using the given `path` directory and `options`.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let fair_locking = options.fair_locking;
+        let nonblocking_flush_interval = options.nonblocking_flush_interval;
+        let purge_expired_interval = options.purge_expired_interval;
+        let store = InternalKvStore::open(path, options)?;
+        let queued_writes = store.queued_writes.clone();
+        let store = KvStore {
+            store: Arc::new(StoreLock::new(store, fair_locking)),
+            key_locks: Arc::new(KeyLocks::new()),
+            queued_writes,
+            flusher_started: Arc::new(AtomicBool::new(false)),
+            nonblocking_flush_interval,
+            readers: Arc::new(ReaderCount::default()),
+        };
+        if let Some(interval) = purge_expired_interval {
+            store.spawn_purger(interval);
+        }
+        Ok(store)
+    }
+
+    /// Queue a `set` and return immediately, without waiting for the store's lock or the disk.
+    ///
+    /// Queued writes are applied in order by a background thread, every
+    /// `KvStoreOptions::nonblocking_flush_interval`, or before any other operation on the store,
+    /// so they are never reordered with later writes. An error applying them is returned by
+    /// that other operation, or discarded in the background, so only use this where losing
+    /// the write is acceptable.
+    pub fn set_nonblocking(&self, key: String, value: String) {
+        self.queued_writes.lock().unwrap().push(Command {
+            key,
+            value: Some(value),
+            chunk: None,
+            // replaced when the write is applied
+            written_at: 0,
+            nonce: [0; 8],
+            open: false,
+            expires_at: None,
+            compaction: None,
+            checksum: None,
+        });
+
+        if !self.flusher_started.swap(true, Ordering::SeqCst) {
+            self.spawn_flusher();
+        }
+    }
+
+    /// Apply queued writes on a background thread until the store is dropped.
+    fn spawn_flusher(&self) {
+        let store = Arc::downgrade(&self.store);
+        let interval = self.nonblocking_flush_interval;
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let store = match store.upgrade() {
+                Some(store) => store,
+                None => return,
+            };
+            // the writes were fire-and-forget, so there is nobody to report an error to
+            let _ = store.lock().apply_queued_writes();
+        });
+    }
+
+    /// Purge values past their time to live every `interval` on a background thread, until
+    /// the store is dropped.
+    fn spawn_purger(&self, interval: Duration) {
+        let store = Arc::downgrade(&self.store);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let store = match store.upgrade() {
+                Some(store) => store,
+                None => return,
+            };
+            // nothing is lost if this fails, the values are purged when next read instead
+            let _ = store.lock().purge_expired();
+        });
+    }
+
+    /// Forget every value past its time to live, see `KvStoreExt::set_with_ttl`, returning
+    /// how many there were.
+    ///
+    /// No tombstones are written, as the expiry is already in the log. The space the values
+    /// take up on disk is reclaimed by the next compaction.
+    pub fn purge_expired(&self) -> Result<usize> {
+        self.store.lock().purge_expired()
+    }
+
+    /// Replicate every successful `set` and `remove` to `secondary`.
+    ///
+    /// Writes are applied to the secondary in order on a background thread, without waiting
+    /// for them to complete. Use `KvStoreOptions::replica_lag_limit` to bound how far behind
+    /// the secondary may fall.
+    pub fn with_replica(self, secondary: impl KvsEngine) -> KvStore {
+        self.store.lock().replica = Some(Replica::spawn(secondary));
+        self
+    }
+
+    /// Copy all data from `other` into this store, overwriting existing values.
+    ///
+    /// Returns the number of keys which were added or changed.
+    pub fn merge_from(&self, other: &KvStore) -> Result<usize> {
+        if Arc::ptr_eq(&self.store, &other.store) {
+            return Ok(0);
+        }
+
+        // always lock in address order, so concurrent merges in both directions can't deadlock
+        let (mut store, mut other_store) = if Arc::as_ptr(&self.store) < Arc::as_ptr(&other.store) {
+            let store = self.store.lock();
+            (store, other.store.lock())
+        } else {
+            let other_store = other.store.lock();
+            (self.store.lock(), other_store)
+        };
+
+        other_store.flush_pending_writes()?;
+        let entries: Vec<(String, ValueInfo)> = other_store
+            .index
+            .iter()
+            .map(|(key, val_info)| (key.clone(), val_info.clone()))
+            .collect();
+
+        let mut merged = 0;
+        for (key, val_info) in entries {
+            let value = val_info.read_value(&key, &other_store.readers, &other_store.format)?;
+            if store.get(&key)?.as_ref() != Some(&value) {
+                store.set(key, value)?;
+                merged += 1;
+            }
+        }
+
+        Ok(merged)
+    }
+
+    /// Lock the given keys against other callers of `lock_keys`, until the guard is dropped.
+    ///
+    /// This allows a sequence of operations on related keys to run without interference,
+    /// while callers working on disjoint keys proceed in parallel. Locks are advisory:
+    /// `get`, `set` and `remove` do not check them. Each lock covers a partition of keys,
+    /// so unrelated keys occasionally contend.
+    pub fn lock_keys(&self, keys: &[&str]) -> KeyGuard {
+        KeyGuard::lock(self.key_locks.clone(), keys)
+    }
+
+    /// Take a snapshot of the store for reading.
+    ///
+    /// With `IsolationLevel::RepeatableRead` the snapshot keeps a copy of the current index,
+    /// so its reads are unaffected by later writes.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let mut store = self.store.lock();
+        let index = match store.isolation_level {
+            IsolationLevel::ReadCommitted => None,
+            IsolationLevel::RepeatableRead => {
+                store.flush_pending_writes()?;
+                Some(store.index.clone())
+            }
+        };
+        Ok(Snapshot::new(
+            self.store.clone(),
+            index,
+            store.index_generation,
+        ))
+    }
+
+    /// The number of times the store has been compacted since it was opened.
+    ///
+    /// Compaction moves values to new file offsets, so anything holding on to the old
+    /// ones, such as a `Snapshot` or an external cache, can compare generations to tell
+    /// whether they are stale.
+    pub fn current_generation(&self) -> u64 {
+        self.store.lock().index_generation
+    }
+
+    /// Roughly how much memory the index uses, counting each key, the space it takes in the
+    /// hash table and any value kept in memory, but not spare capacity in the table.
+    pub fn estimated_index_memory_bytes(&self) -> usize {
+        self.store.lock().index_memory_bytes
+    }
+
+    /// Get a shared copy of the value for the given key.
+    ///
+    /// With `KvStoreOptions::arc_cache` enabled, the value is kept for later calls, which then
+    /// only clone the `Arc` rather than allocating a new `String`. Otherwise this is `get`.
+    pub fn get_arc(&self, key: String) -> Result<Option<Arc<String>>> {
+        let mut store = self.store.lock();
+        let value = store.get_arc(&key)?;
+
+        match value {
+            None if store.strict_mode => Err(KvsError::KeyNotFound.into()),
+            value => Ok(value),
+        }
+    }
+
+    /// Get a reader for the value for the given key, if it exists, instead of the whole value.
+    ///
+    /// Values split by `KvStoreOptions::max_inline_value_bytes` are read a chunk at a time,
+    /// so only one chunk is held in memory. The reader has its own handle on the log file,
+    /// so it stays valid after this returns, even if compaction removes the file.
+    pub fn get_reader(&self, key: String) -> Result<Option<impl Read + Send>> {
+        let mut store = self.store.lock();
+        let reader = store.get_reader(&key)?;
+
+        match reader {
+            None if store.strict_mode => Err(KvsError::KeyNotFound.into()),
+            reader => Ok(reader),
+        }
+    }
+
+    /// Read the values of `keys` into the read cache on a background thread, so later calls to
+    /// `get` for them needn't wait for the disk. Returns immediately.
+    ///
+    /// Only a hint: nothing happens without `KvStoreOptions::read_cache`, and errors reading
+    /// the values are ignored, leaving `get` to report them.
+    pub fn prefetch(&self, keys: &[String]) {
+        let store = self.store.read();
+        if !store.read_cache.lock().unwrap().is_enabled() {
+            return;
+        }
+        let (values, readers) = match store.uncached_values(keys) {
+            Ok(uncached) => uncached,
+            Err(_) => return,
+        };
+        if values.is_empty() {
+            return;
+        }
+        let read_cache = Arc::clone(&store.read_cache);
+        let format = store.format.clone();
+        drop(store);
+
+        thread::spawn(move || {
+            // values are read in the order they are in the logs, to avoid seeking back and forth
+            let mut values = values;
+            values.sort_unstable_by_key(|(_key, val_info)| {
+                (val_info.file_id, val_info.file_offset.0)
+            });
+            for (key, val_info) in values {
+                if let Ok(value) = val_info.read_value(&key, &readers, &format) {
+                    read_cache
+                        .lock()
+                        .unwrap()
+                        .insert(key, val_info.nonce, value);
+                }
+            }
+        });
+    }
+
+    /// Get the value for the given key along with when it was written, in nanoseconds since
+    /// the Unix epoch.
+    ///
+    /// Values written before timestamps were recorded report 0. Any writes held back by
+    /// `KvStoreOptions::coalesce_writes` are written first, to give them a timestamp.
+    pub fn get_with_metadata(&self, key: String) -> Result<Option<(String, u64)>> {
+        let mut store = self.store.lock();
+        let value = store.get_with_metadata(&key)?;
+
+        match value {
+            None if store.strict_mode => Err(KvsError::KeyNotFound.into()),
+            value => Ok(value),
+        }
+    }
+
+    /// Get the value the given key had at `timestamp`, in nanoseconds since the Unix epoch.
+    ///
+    /// This replays the log files, so it reads every entry on disk. Compaction discards
+    /// overwritten values and tombstones, so answers for times before the last compaction
+    /// may be missing or out of date. Entries written before timestamps were recorded count
+    /// as written at 0.
+    pub fn get_as_of(&self, key: String, timestamp: u64) -> Result<Option<String>> {
+        self.store.lock().get_as_of(&key, timestamp)
+    }
+
+    /// Get the times the store was opened, in nanoseconds since the Unix epoch, oldest first.
+    ///
+    /// Each `open` writes a marker at the start of the log file it creates, which compaction
+    /// carries over. Useful for lining up the history of a key with server restarts.
+    pub fn list_server_restarts(&self) -> Result<Vec<u64>> {
+        self.store.lock().list_server_restarts()
+    }
+
+    /// Get a record of each compaction, oldest first.
+    ///
+    /// Each compaction writes a record at the start of the log file it leaves open for
+    /// writing, which later compactions carry over like the markers of `list_server_restarts`.
+    pub fn list_compactions(&self) -> Result<Vec<CompactionRecord>> {
+        self.store.lock().list_compactions()
+    }
+
+    /// Write any `set` calls held back by `KvStoreOptions::coalesce_writes` or queued by
+    /// `set_nonblocking` to disk.
+    pub fn flush_pending_writes(&self) -> Result<()> {
+        self.store.lock().flush_pending_writes()
+    }
+
+    /// Write everything held in memory to disk, ready for the process to exit.
+    ///
+    /// This is done automatically when the last handle to the store is dropped, but `Drop` doesn't
+    /// run if the process is killed. The lock on the data directory is released, as it would be
+    /// on exit, so another store can open it. The store can still be used afterwards, but
+    /// shouldn't be while another store has the directory open.
+    pub fn close(&self) -> Result<()> {
+        let mut store = self.store.lock();
+        store.flush_pending_writes()?;
+        store.writer.flush()?;
+        Ok(store.dir_lock.unlock()?)
+    }
+
+    /// Move the store's files to `new_path`, carrying on with the same handle.
+    ///
+    /// The store is compacted first, so there are as few files to copy as possible, and is
+    /// locked until the move is complete. Afterwards every log is in `new_path`'s `.kvs`
+    /// directory, even if `KvStoreOptions::write_path` or `compacted_path` put them elsewhere.
+    /// If copying fails, the store carries on using its old directory.
+    pub fn swap_path(&self, new_path: &Path) -> Result<()> {
+        self.store.lock().swap_path(new_path)
+    }
+
+    /// Back up the store to `dest_dir` by hard-linking its log files, without holding the lock
+    /// while the files are linked.
+    ///
+    /// The lock is only taken to start a new active log, so every linked file is complete and
+    /// never written again. `dest_dir` gets the same layout as a store's directory, so it can be
+    /// opened with `KvStore::open`, and must be on the same filesystem as the logs. If
+    /// compaction removes a log file before it is linked, the partial backup is removed and
+    /// `KvsError::BackupInterrupted` is returned.
+    pub fn backup_nonblocking(&self, dest_dir: &Path) -> Result<BackupHandle> {
+        let backup_dir = dest_dir.join(KVS_DIR);
+        fs::create_dir_all(&backup_dir)?;
+        let sealed = self.store.lock().seal_for_backup(&backup_dir)?;
+
+        let mut file_ids = Vec::with_capacity(sealed.len());
+        let mut linked = Vec::with_capacity(sealed.len());
+        for (id, path) in sealed {
+            let dest = backup_dir.join(path.file_name().unwrap_or_default());
+            match fs::hard_link(&path, &dest) {
+                Ok(()) => {
+                    file_ids.push(id);
+                    linked.push(dest);
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    for dest in linked {
+                        fs::remove_file(dest)?;
+                    }
+                    return Err(KvsError::BackupInterrupted.into());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(BackupHandle::new(dest_dir.to_owned(), file_ids))
+    }
+
+    /// Check a single log file for corruption, counting the commands in it.
+    ///
+    /// Unreadable parts of the file are skipped up to the start of the next command,
+    /// so one damaged entry doesn't hide the rest of the file.
+    pub fn verify_log_file(&self, file_id: file::Id) -> Result<LogFileStats> {
+        self.store.lock().verify_log_file(file_id)
+    }
+
+    /// Throw away the in-memory index and rebuild it by replaying every log file, returning
+    /// the number of keys in the new index.
+    ///
+    /// For recovering from an index which no longer matches the logs. Corrupt entries are
+    /// handled as `KvStoreOptions::validate_on_open` says.
+    pub fn rebuild_index(&self) -> Result<usize> {
+        self.store.lock().rebuild_index()
+    }
+
+    /// Get the keys from `start` up to, but not including, `end`, sorted.
+    ///
+    /// Unlike `KvsEngine::get_range`, this only looks in the index, so no values are read
+    /// from disk.
+    pub fn get_range_keys_only(&self, start: &str, end: &str) -> Result<Vec<String>> {
+        self.store.lock().range_keys(KeyRange::Between(start, end))
+    }
+
+    /// Get the keys whose values the index `index_name` maps to `secondary_key`, in order.
+    ///
+    /// Returns `KvsError::IndexNotFound` if no index was added with
+    /// `KvStoreOptions::with_secondary_index` under that name.
+    pub fn query_secondary(&self, index_name: &str, secondary_key: &str) -> Result<Vec<String>> {
+        self.store.lock().query_secondary(index_name, secondary_key)
+    }
+
+    /// Get detailed statistics about the store, beyond the summary from `KvsEngine::stats`.
+    pub fn detailed_stats(&self) -> KvStoreStats {
+        let store = self.store.lock();
+        let get_latency = *store.get_latency.lock().unwrap();
+        KvStoreStats {
+            user_bytes_written: store.user_bytes_written.load(Ordering::SeqCst),
+            compaction_bytes_written: store.compaction_bytes_written.load(Ordering::SeqCst),
+            overhead_bytes: store.overhead_bytes.load(Ordering::SeqCst),
+            estimated_num_operations: store.estimated_num_operations.load(Ordering::SeqCst),
+            live_ratio: store.live_ratio(),
+            avg_entry_bytes: store.avg_entry_bytes(),
+            current_readers: self.readers.current.load(Ordering::SeqCst),
+            max_concurrent_readers: self.readers.max.load(Ordering::SeqCst),
+            oldest_log_file_created_at: store.oldest_log_created_at(),
+            get_latency,
+        }
+    }
+
+    /// How long calls to `get` have taken since the store was opened, including any time
+    /// spent waiting for the store's lock.
+    pub fn get_latency_histogram(&self) -> LatencyHistogram {
+        *self.store.read().get_latency.lock().unwrap()
+    }
+
+    /// The fraction of bytes in the log files which hold current values.
+    ///
+    /// The rest is redundant and would be reclaimed by compaction. An empty store is 1.0.
+    pub fn live_ratio(&self) -> f64 {
+        self.store.lock().live_ratio()
+    }
+
+    /// The mean size in bytes of the sets and removes in the log files, including their keys
+    /// and framing, or 0.0 for an empty store.
+    ///
+    /// Dividing a compaction threshold by this estimates how many redundant entries build up
+    /// before compacting.
+    pub fn avg_entry_bytes(&self) -> f64 {
+        self.store.lock().avg_entry_bytes()
+    }
+
+    /// The number of bytes a `set` of `key` to `value` would add to the log, without writing it.
+    ///
+    /// Values split across several commands by `KvStoreOptions::max_inline_value_bytes` are
+    /// counted in full, including the framing of every chunk.
+    pub fn estimate_set_cost(&self, key: &str, value: &str) -> usize {
+        self.store.lock().estimate_set_cost(key, value)
+    }
+
+    /// Would a `set` of `key` to `value` trigger compaction, if it were written now?
+    ///
+    /// Writes held back by `KvStoreOptions::coalesce_writes` aren't counted until they are
+    /// written.
+    pub fn set_would_compact(&self, key: &str, value: &str) -> bool {
+        self.store.lock().set_would_compact(key, value)
+    }
+
+    /// How long ago the oldest log file was created, or `None` if that can't be found.
+    ///
+    /// Compaction replaces the old logs with a new one, so this is at most the time since the
+    /// last compaction, or since the store was first opened if it has never compacted.
+    pub fn oldest_log_age(&self) -> Option<Duration> {
+        let created = self.store.lock().oldest_log_created_at()?;
+        SystemTime::now().duration_since(created).ok()
+    }
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug)]
+pub(super) struct InternalKvStore {
+    /// Path of directory containing the saved index and other metadata
+    path: PathBuf,
+    /// Exclusive `flock` on `path`, or shared if `read_only`, released when the store is dropped
+    dir_lock: File,
+    /// See `KvStore::open_read_only`
+    read_only: bool,
+    /// Directories containing log files, by default the same as `path`
+    dirs: LogDirs,
+    file_naming: FileNamingScheme,
+    writer: KvsWriter,
+    /// Creation time of each log file, for expiring old data
+    file_times: HashMap<file::Id, SystemTime>,
+    max_log_age: Option<Duration>,
+    max_log_file_bytes: Option<Bytes>,
+    pub(super) readers: Readers,
+    pub(super) index: Index,
+    uncompacted: Bytes,
+    /// Total size of the log files
+    disk_bytes: Bytes,
+    compaction_threshold: CompactionThreshold,
+    telemetry: Sink,
+    /// Only set if it logs at trace level, so there is no cost otherwise
+    log: Option<Logger>,
+    isolation_level: IsolationLevel,
+    replica: Option<Replica>,
+    replica_lag_limit: Option<Duration>,
+    strict_mode: bool,
+    inline_values: bool,
+    max_inline_value_bytes: usize,
+    validation_mode: ValidationMode,
+    retry_on_interrupt: bool,
+    sync_writes: bool,
+    checksum_algorithm: ChecksumAlgorithm,
+    /// See `KvStoreOptions::serializer`
+    pub(super) format: Format,
+    /// Log files written in another format, until compaction rewrites them in `format`
+    legacy_formats: HashMap<file::Id, Format>,
+    /// Set for `KvStoreOptions::leveled_compaction`
+    max_l0_files: Option<usize>,
+    /// Log files written by leveled compaction. Every other log is in level 0.
+    level1: Level1,
+    /// See `KvStore::estimated_index_memory_bytes`
+    index_memory_bytes: usize,
+    max_index_memory_bytes: Option<usize>,
+
+    /// Values from `set` calls not yet written to disk
+    pending_writes: HashMap<String, String>,
+    /// Writes from `KvStore::set_nonblocking`, not yet applied
+    queued_writes: Arc<Mutex<Vec<Command>>>,
+    secondary_indexes: HashMap<String, SecondaryIndex>,
+    max_pending_writes: usize,
+    permanent_delete: bool,
+    permanently_deleted: BTreeSet<String>,
+    /// Set once a write fails for lack of space, after which the store is read-only
+    disk_full: bool,
+    /// Shared copies of values read by `KvStore::get_arc`
+    arc_cache: HashMap<String, Arc<String>>,
+    arc_cache_capacity: usize,
+    /// See `KvStoreOptions::read_cache`, shared with the threads started by `KvStore::prefetch`
+    read_cache: Arc<Mutex<ReadCache>>,
+    /// Every key set since the filter was last rebuilt, which includes all live keys
+    #[cfg(feature = "bloom_filter")]
+    bloom_filter: BloomFilter,
+
+    /// Bytes written by `set` and `remove`
+    user_bytes_written: AtomicU64,
+    /// Bytes written by copying live values during compaction
+    compaction_bytes_written: AtomicU64,
+    /// Bytes made redundant by tombstones and overwrites
+    overhead_bytes: AtomicU64,
+    /// Commands in the log files, live or not
+    estimated_num_operations: AtomicU64,
+    /// Timestamp of the latest command written, so timestamps only increase
+    last_written_at: u64,
+    /// Number of compactions since the store was opened, each of which moves values
+    index_generation: u64,
+    /// Locked separately, so it can be updated by callers sharing the store's lock
+    get_latency: Mutex<LatencyHistogram>,
+    #[cfg(feature = "fault_injection")]
+    fault: Option<FaultPoint>,
+}
+
+/// Each reader has its own lock, so values in different files can be read at once by callers
+/// sharing the store's lock.
+pub(super) type Readers = HashMap<file::Id, Mutex<BufReader<File>>>;
+/// Sorted, so ranges of keys can be found without looking at the rest
+pub(super) type Index = BTreeMap<String, ValueInfo>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct ValueInfo {
+    /// Identifier for file the value is stored in
+    pub(super) file_id: file::Id,
+
+    /// Position of value in file
+    file_offset: Bytes,
+
+    /// Size of serialised command in file, or of all its chunks if the value was split
+    size: Bytes,
+
+    /// Number of commands the value was split into, see `KvStoreOptions::max_inline_value_bytes`
+    chunks: u32,
+
+    /// When the value was written, see `KvStore::get_with_metadata`
+    written_at: u64,
+
+    /// Nonce of the command holding the value, to check the index points at the right one
+    nonce: [u8; 8],
+
+    /// When the value's time to live runs out, see `KvStoreExt::set_with_ttl`
+    expires_at: Option<u64>,
+
+    /// Copy of the value, if it is small enough to keep in memory
+    pub(super) cached_value: Option<Box<str>>,
+}
+
+impl ValueInfo {
+    /// Has the value's time to live run out by `now`?
+    fn past_ttl(&self, now: u64) -> bool {
+        past_ttl(self.expires_at, now)
+    }
+
+    /// Get the value for `key`, reading it from disk if it is not cached.
+    ///
+    /// Debug builds check that the command read from disk really is for `key`.
+    pub(super) fn read_value(
+        &self,
+        key: &str,
+        readers: &Readers,
+        format: &Format,
+    ) -> Result<String> {
+        if let Some(value) = &self.cached_value {
+            return Ok(value.to_string());
+        }
+
+        let reader = &mut *readers
+            .get(&self.file_id)
+            .expect("Reader not found for file ID")
+            .lock()
+            .unwrap();
+        reader.seek(SeekFrom::Start(self.file_offset.0))?;
+
+        let mut commands = format.reader(reader.take(self.size.0));
+
+        let mut value = String::new();
+        for _ in 0..self.chunks {
+            let offset = self.file_offset.0 + commands.byte_offset();
+            let command = match commands.next() {
+                Some(command) => command?,
+                None => break,
+            };
+            if !command.checksum_matches() {
+                return Err(KvsError::ChecksumMismatch {
+                    file_id: self.file_id,
+                    offset,
+                }
+                .into());
+            }
+            debug_assert_eq!(
+                command.key, key,
+                "index entry for {:?} points at another key's command",
+                key
+            );
+            match command.value {
+                Some(chunk) => value.push_str(&chunk),
+                None => return Err(KvsError::UnexpectedCommand.into()),
+            }
+        }
+        Ok(value)
+    }
+}
+
+type CommandStream = CommandReader<Take<BufReader<File>>>;
+
+/// Reads a value one chunk at a time, returned by `KvStore::get_reader`.
+pub(super) struct ValueReader {
+    /// Part of the value which has been read from disk but not yet returned
+    chunk: Cursor<Vec<u8>>,
+
+    /// Commands holding the rest of the value, or `None` if it was already in memory
+    commands: Option<CommandStream>,
+    remaining_chunks: u32,
+    /// Where `commands` start, for reporting a checksum mismatch
+    file_id: file::Id,
+    file_offset: Bytes,
+}
+
+impl ValueReader {
+    fn in_memory(value: String) -> ValueReader {
+        ValueReader {
+            chunk: Cursor::new(value.into_bytes()),
+            commands: None,
+            remaining_chunks: 0,
+            file_id: 0,
+            file_offset: Bytes(0),
+        }
+    }
+
+    /// Read the next chunk of the value from disk, returning `false` if there are no more.
+    fn next_chunk(&mut self) -> std::io::Result<bool> {
+        let commands = match &mut self.commands {
+            Some(commands) if self.remaining_chunks > 0 => commands,
+            _ => return Ok(false),
+        };
+        self.remaining_chunks -= 1;
+
+        let offset = self.file_offset.0 + commands.byte_offset();
+        match commands.next() {
+            Some(Ok(command)) if !command.checksum_matches() => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                KvsError::ChecksumMismatch {
+                    file_id: self.file_id,
+                    offset,
+                },
+            )),
+            Some(Ok(Command {
+                value: Some(value), ..
+            })) => {
+                self.chunk = Cursor::new(value.into_bytes());
+                Ok(true)
+            }
+            Some(Ok(_tombstone)) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "expected a value, found a tombstone",
+            )),
+            Some(Err(ReadError::Truncated)) | None => Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Some(Err(e)) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+/// A run of consecutive keys, as found by `InternalKvStore::scan_range`.
+#[derive(Debug, Clone, Copy)]
+enum KeyRange<'a> {
+    /// Keys starting with the prefix
+    Prefix(&'a str),
+    /// Keys from the first up to, but not including, the second
+    Between(&'a str, &'a str),
+}
+
+impl<'a> KeyRange<'a> {
+    fn start(self) -> &'a str {
+        match self {
+            KeyRange::Prefix(prefix) => prefix,
+            KeyRange::Between(start, _end) => start,
+        }
+    }
+
+    fn contains(self, key: &str) -> bool {
+        match self {
+            KeyRange::Prefix(prefix) => key.starts_with(prefix),
+            KeyRange::Between(start, end) => start <= key && key < end,
+        }
+    }
+}
+
+/// Keys found by `InternalKvStore::scan_range`, whose values can be read without the lock.
+struct KeyScan {
+    /// Values from `set` calls not yet written to disk
+    pending: Vec<(String, String)>,
+    indexed: Vec<(String, ValueInfo)>,
+    readers: Readers,
+    format: Format,
+}
+
+impl KeyScan {
+    /// Read every value, sorted by key.
+    fn read_values(self) -> Result<Vec<(String, String)>> {
+        let mut entries = self.pending;
+        for (key, val_info) in self.indexed {
+            let value = val_info.read_value(&key, &self.readers, &self.format)?;
+            entries.push((key, value));
+        }
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(entries)
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let bytes_read = self.chunk.read(buf)?;
+            if bytes_read > 0 || buf.is_empty() || !self.next_chunk()? {
+                return Ok(bytes_read);
+            }
+        }
+    }
+}
+
+/// Copy `value` for storing in the index, if inlining is enabled and it is small enough.
+fn inline_value(value: &str, enabled: bool) -> Option<Box<str>> {
+    if fits_inline(value, enabled) {
+        Some(value.into())
+    } else {
+        None
+    }
+}
+
+fn fits_inline(value: &str, enabled: bool) -> bool {
+    enabled && value.len() as u64 <= MAX_INLINE_VALUE.0
+}
+
+/// Memory used by an index entry: its slot in a tree node, and the heap space of its key and
+/// copy of the value.
+fn index_entry_bytes(key: &str, cached_value: Option<&str>) -> usize {
+    size_of::<(String, ValueInfo)>() + key.len() + cached_value.map_or(0, str::len)
+}
+
+fn index_memory_bytes(index: &Index) -> usize {
+    index
+        .iter()
+        .map(|(key, val_info)| index_entry_bytes(key, val_info.cached_value.as_deref()))
+        .sum()
 }
 
-impl KvStore {
-    /// Create a new KvStore, using the given `path` directory.
-    /// The log files will be stored in a directory named `.kvs` inside `path`.
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let store = InternalKvStore::open(path)?;
-        Ok(KvStore {
-            store: Arc::new(Mutex::new(store)),
-        })
+impl InternalKvStore {
+    fn open(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<InternalKvStore> {
+        let path_dir = path.into();
+        if !path_dir.is_dir() {
+            return Err(KvsError::NotADirectory.into());
+        }
+        let kvs_dir = path_dir.join(KVS_DIR);
+
+        let read_only = options.read_only;
+        let dir_lock = if read_only {
+            lock_dir(&kvs_dir, true)?
+        } else {
+            // where the first write will happen, so the check is as close to it as possible
+            if kvs_dir.is_dir() {
+                check_writable(&kvs_dir)?;
+            } else {
+                check_writable(&path_dir)?;
+            }
+            fs::create_dir_all(&kvs_dir)?;
+            let dir_lock = lock_dir(&kvs_dir, false)?;
+            file::remove_temp_files(&kvs_dir)?;
+            dir_lock
+        };
+        let dirs = LogDirs {
+            write: options
+                .write_path
+                .clone()
+                .unwrap_or_else(|| kvs_dir.clone()),
+            compacted: options
+                .compacted_path
+                .clone()
+                .unwrap_or_else(|| kvs_dir.clone()),
+        };
+        if !read_only {
+            for dir in &[&dirs.write, &dirs.compacted] {
+                fs::create_dir_all(dir)?;
+                file::remove_temp_files(dir)?;
+            }
+        }
+
+        let mut file_ids = get_log_file_ids(&dirs)?;
+        file_ids.sort_unstable();
+        let formats =
+            format::resolve_format(&kvs_dir, options.serializer.clone(), !file_ids.is_empty())?;
+        let format = formats.write;
+        let mut legacy_formats = HashMap::new();
+
+        let mut readers = HashMap::new();
+        let mut file_times = HashMap::new();
+        let saved_index = load_saved_index(&kvs_dir, &file_ids, options.inline_values);
+        let (compacted_file_id, mut index) = match saved_index {
+            Some((file_id, file_time, index)) => {
+                file_times.insert(file_id, file_time);
+                (Some(file_id), index)
+            }
+            None => (None, Index::new()),
+        };
+        let mut uncompacted = Bytes(0);
+        let mut disk_bytes = Bytes(0);
+        // the compacted log holds one set for each key in the saved index
+        let mut num_operations = index.len() as u64;
+
+        for id in &file_ids {
+            let mut buffered_reader = file::new_reader(&dirs, *id)?;
+            let file_format = format::detect_format(&mut buffered_reader, &formats.framed)?;
+            // markers aren't counted, so they don't affect `live_ratio`
+            let (_, markers_len) = leading_markers(file::new_reader(&dirs, *id)?, &file_format)?;
+            disk_bytes += Bytes(buffered_reader.get_ref().metadata()?.len()) - markers_len;
+            if !file_times.contains_key(id) {
+                file_times.insert(*id, file::created(&dirs, *id)?);
+            }
+
+            // the saved index already covers the compacted log
+            if compacted_file_id != Some(*id) {
+                // a separate reader, so the hint doesn't outlast the replay
+                let mut replay_reader = file::new_reader_sequential(&dirs, *id)?;
+                let (file_uncompacted, file_operations) = load_file_into_index(
+                    *id,
+                    &mut replay_reader,
+                    &mut index,
+                    &file_format,
+                    &options,
+                )?;
+                uncompacted += file_uncompacted;
+                num_operations += file_operations;
+            }
+
+            if file_format.name() != format.name() {
+                legacy_formats.insert(*id, file_format);
+            }
+            readers.insert(*id, Mutex::new(buffered_reader));
+        }
+
+        let write_file_id = load_max_file_id(&kvs_dir)?.max(*file_ids.last().unwrap_or(&0)) + 1;
+        let writer = if read_only {
+            if !legacy_formats.is_empty() {
+                return Err(KvsError::FormatMismatch.into());
+            }
+            KvsWriter::read_only(write_file_id)
+        } else {
+            save_max_file_id(&kvs_dir, write_file_id)?;
+            let writer = KvsWriter::new(&dirs.write, write_file_id, options.file_naming)?
+                .retry_on_interrupt(options.retry_on_interrupt)
+                .sync_writes(options.sync_writes);
+            readers.insert(
+                write_file_id,
+                Mutex::new(file::new_reader(&dirs, write_file_id)?),
+            );
+            file_times.insert(write_file_id, SystemTime::now());
+            writer
+        };
+        let last_written_at = index
+            .values()
+            .map(|val_info| val_info.written_at)
+            .max()
+            .unwrap_or(0);
+
+        let permanently_deleted = load_deleted(&kvs_dir)?;
+        let level1 = match options.max_l0_files {
+            Some(_) => level::load_levels(&kvs_dir)?,
+            None => Level1::new(),
+        };
+        #[cfg(feature = "bloom_filter")]
+        let bloom_filter = BloomFilter::with_keys(index.keys());
+
+        let mut store = InternalKvStore {
+            path: kvs_dir,
+            dir_lock,
+            read_only,
+            dirs,
+            file_naming: options.file_naming,
+            writer,
+            file_times,
+            max_log_age: options.max_log_age,
+            max_log_file_bytes: options.max_log_file_bytes,
+            readers,
+
+            index_memory_bytes: index_memory_bytes(&index),
+            index,
+            uncompacted,
+            disk_bytes,
+            compaction_threshold: CompactionThreshold::new(
+                options.max_uncompacted_bytes,
+                options.min_compaction_bytes,
+                options.max_compaction_bytes,
+                options.compaction_live_ratio_threshold,
+            ),
+            telemetry: options.sink,
+            log: options.log.filter(|log| log.is_trace_enabled()),
+            isolation_level: options.isolation_level,
+            replica: None,
+            replica_lag_limit: options.replica_lag_limit,
+            strict_mode: options.strict_mode,
+            inline_values: options.inline_values,
+            max_inline_value_bytes: options.max_inline_value_bytes,
+            validation_mode: options.validation_mode,
+            retry_on_interrupt: options.retry_on_interrupt,
+            sync_writes: options.sync_writes,
+            checksum_algorithm: options.checksum_algorithm,
+            format,
+            legacy_formats,
+            max_l0_files: options.max_l0_files,
+            level1,
+            max_index_memory_bytes: options.max_index_memory_bytes,
+
+            pending_writes: HashMap::new(),
+            secondary_indexes: options.secondary_indexes,
+            queued_writes: Arc::new(Mutex::new(Vec::new())),
+            max_pending_writes: options.max_pending_writes,
+            permanent_delete: options.permanent_delete,
+            permanently_deleted,
+            disk_full: false,
+            arc_cache: HashMap::new(),
+            arc_cache_capacity: options.arc_cache_capacity,
+            read_cache: Arc::new(Mutex::new(ReadCache::new(options.read_cache_capacity))),
+            #[cfg(feature = "bloom_filter")]
+            bloom_filter,
+
+            user_bytes_written: AtomicU64::new(0),
+            compaction_bytes_written: AtomicU64::new(0),
+            overhead_bytes: AtomicU64::new(0),
+            estimated_num_operations: AtomicU64::new(num_operations),
+            last_written_at,
+            index_generation: 0,
+            get_latency: Mutex::new(LatencyHistogram::default()),
+            #[cfg(feature = "fault_injection")]
+            fault: options.fault,
+        };
+        if read_only {
+            store.build_secondary_indexes()?;
+            return Ok(store);
+        }
+        store.write_open_marker()?;
+        store.remove_expired_files(&file_ids)?;
+        let readers = &store.readers;
+        store.level1.retain(|id, _| readers.contains_key(id));
+        store
+            .legacy_formats
+            .retain(|id, _| readers.contains_key(id));
+        // until every file is converted the framed format must stay recorded, as it
+        // can't be detected
+        format::save_format(&store.path, &formats.framed)?;
+        if !store.legacy_formats.is_empty() {
+            store.compact()?;
+        }
+        format::save_format(&store.path, &store.format)?;
+        store.build_secondary_indexes()?;
+        if store.level0_exceeded() {
+            store.compact()?;
+        }
+
+        Ok(store)
+    }
+
+    fn live_ratio(&self) -> f64 {
+        live_ratio(self.disk_bytes, self.uncompacted)
+    }
+
+    /// Bytes a `set` would add to the log, serialised as `append_set` would write it.
+    fn estimate_set_cost(&self, key: &str, value: &str) -> usize {
+        let chunks = split_value(value, self.max_inline_value_bytes);
+        let count = chunks.len() as u32;
+        // the timestamp the write would get, which has as many digits as the real one
+        let written_at = now_nanos().max(self.last_written_at + 1);
+
+        (0..)
+            .zip(chunks)
+            .map(|(index, chunk)| {
+                let command =
+                    Command::set_chunk(key, chunk, index, count, written_at, [0; 8], None)
+                        .with_checksum(self.checksum_algorithm);
+                self.format.encode(&command).map_or(0, |bytes| bytes.len())
+            })
+            .sum()
+    }
+
+    /// The format `file_id` was written in.
+    fn file_format(&self, file_id: file::Id) -> &Format {
+        self.legacy_formats.get(&file_id).unwrap_or(&self.format)
+    }
+
+    /// Would writing this `set` to the log trigger compaction?
+    fn set_would_compact(&self, key: &str, value: &str) -> bool {
+        let cost = Bytes(self.estimate_set_cost(key, value) as u64);
+        let overwritten = self
+            .index
+            .get(key)
+            .map_or(Bytes(0), |val_info| val_info.size);
+        let uncompacted = self.uncompacted + overwritten;
+        self.compaction_threshold
+            .exceeded_by(uncompacted, live_ratio(self.disk_bytes + cost, uncompacted))
+    }
+
+    fn avg_entry_bytes(&self) -> f64 {
+        match self.estimated_num_operations.load(Ordering::SeqCst) {
+            0 => 0.0,
+            num_operations => self.disk_bytes.0 as f64 / num_operations as f64,
+        }
+    }
+
+    /// Read from the file system each time, as compaction and `max_log_age` remove old logs.
+    fn oldest_log_created_at(&self) -> Option<SystemTime> {
+        let oldest_id = self.readers.keys().min()?;
+        file::created(&self.dirs, *oldest_id).ok()
+    }
+
+    /// Fill the secondary indexes from the values already in the store.
+    fn build_secondary_indexes(&mut self) -> Result<()> {
+        if self.secondary_indexes.is_empty() {
+            return Ok(());
+        }
+
+        for (key, val_info) in &self.index {
+            let value = val_info.read_value(key, &self.readers, &self.format)?;
+            for secondary_index in self.secondary_indexes.values_mut() {
+                secondary_index.insert(key, &value);
+            }
+        }
+        Ok(())
+    }
+
+    fn index_secondary(&mut self, key: &str, value: &str) {
+        for secondary_index in self.secondary_indexes.values_mut() {
+            secondary_index.insert(key, value);
+        }
+    }
+
+    fn unindex_secondary(&mut self, key: &str) {
+        for secondary_index in self.secondary_indexes.values_mut() {
+            secondary_index.remove(key);
+        }
+    }
+
+    /// Keep a key already in the arc cache up to date with a new value.
+    fn update_arc_cache(&mut self, key: &str, value: &str) {
+        if let Some(cached) = self.arc_cache.get_mut(key) {
+            *cached = Arc::new(value.to_owned());
+        }
+    }
+
+    #[cfg(feature = "bloom_filter")]
+    fn add_to_bloom_filter(&mut self, key: &str) {
+        // before inserting, as the key isn't in the index yet for the rebuild to find
+        if self.bloom_filter.is_full() {
+            self.rebuild_bloom_filter();
+        }
+        self.bloom_filter.insert(key);
+    }
+
+    /// Replace the bloom filter with one holding only the live keys, sized for them.
+    #[cfg(feature = "bloom_filter")]
+    fn rebuild_bloom_filter(&mut self) {
+        self.bloom_filter = BloomFilter::with_keys(self.index.keys());
+        for key in self.pending_writes.keys() {
+            self.bloom_filter.insert(key);
+        }
+    }
+
+    fn get_arc(&mut self, key: &str) -> Result<Option<Arc<String>>> {
+        self.apply_queued_writes()?;
+        if !self.pending_writes.contains_key(key) {
+            self.remove_if_past_ttl(key);
+        }
+        if let Some(val_info) = self.index.get(key) {
+            if self.is_expired(val_info.file_id) {
+                return Err(KvsError::DataExpired.into());
+            }
+        }
+        if let Some(value) = self.arc_cache.get(key) {
+            return Ok(Some(value.clone()));
+        }
+
+        let value = match self.get(key)? {
+            Some(value) => Arc::new(value),
+            None => return Ok(None),
+        };
+        if self.arc_cache.len() < self.arc_cache_capacity {
+            self.arc_cache.insert(key.to_owned(), value.clone());
+        }
+        Ok(Some(value))
+    }
+
+    fn query_secondary(&self, index_name: &str, secondary_key: &str) -> Result<Vec<String>> {
+        match self.secondary_indexes.get(index_name) {
+            Some(secondary_index) => Ok(secondary_index.query(secondary_key)),
+            None => Err(KvsError::IndexNotFound.into()),
+        }
+    }
+
+    /// Record the store being opened, at the start of the log file it opened for writing.
+    fn write_open_marker(&mut self) -> Result<()> {
+        let marker =
+            Command::open_marker(self.next_timestamp()?).with_checksum(self.checksum_algorithm);
+        self.format.write(&mut self.writer, &marker)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Write a record of a compaction which just finished, at the start of the new active log.
+    fn write_compaction_record(&mut self, record: CompactionRecord) -> Result<()> {
+        let command = Command::compaction_record(record).with_checksum(self.checksum_algorithm);
+        self.format.write(&mut self.writer, &command)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn list_server_restarts(&self) -> Result<Vec<u64>> {
+        Ok(self
+            .markers()?
+            .into_iter()
+            .filter(|marker| marker.open)
+            .map(|marker| marker.written_at)
+            .collect())
+    }
+
+    fn list_compactions(&self) -> Result<Vec<CompactionRecord>> {
+        Ok(self
+            .markers()?
+            .into_iter()
+            .filter_map(|marker| marker.compaction)
+            .collect())
+    }
+
+    /// The markers at the start of every log file, oldest first.
+    fn markers(&self) -> Result<Vec<Command>> {
+        let mut file_ids: Vec<file::Id> = self.readers.keys().cloned().collect();
+        file_ids.sort_unstable();
+
+        let mut markers = Vec::new();
+        for file_id in file_ids {
+            let (file_markers, _) =
+                leading_markers(file::new_reader(&self.dirs, file_id)?, &self.format)?;
+            markers.extend(file_markers);
+        }
+        Ok(markers)
+    }
+
+    /// Total size of the log files `file_ids`.
+    fn log_files_bytes(&self, file_ids: &[file::Id]) -> Result<u64> {
+        let mut total = 0;
+        for file_id in file_ids {
+            total += fs::metadata(file::path(&self.dirs, *file_id)?)?.len();
+        }
+        Ok(total)
+    }
+
+    /// Timestamp for a new command, in nanoseconds since the Unix epoch.
+    ///
+    /// Always later than the last one, even if the clock goes backwards.
+    fn next_timestamp(&mut self) -> Result<u64> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+        let now: u64 = now.as_nanos().try_into()?;
+        self.last_written_at = now.max(self.last_written_at + 1);
+        Ok(self.last_written_at)
+    }
+
+    /// Seal the active log and start writing a new one, if it has grown past
+    /// `max_log_file_bytes`. Sealed logs are only read until compaction removes them.
+    fn roll_over_if_full(&mut self) -> Result<()> {
+        match self.max_log_file_bytes {
+            Some(max_bytes) if self.writer.offset > max_bytes.0 => {}
+            _ => return Ok(()),
+        }
+
+        self.start_new_log()?;
+        if self.level0_exceeded() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Are there more level 0 logs than `KvStoreOptions::leveled_compaction` allows?
+    fn level0_exceeded(&self) -> bool {
+        match self.max_l0_files {
+            Some(max_l0_files) => {
+                let level0_files = self.readers.len() - self.level1.len();
+                level0_files > max_l0_files
+            }
+            None => false,
+        }
+    }
+
+    fn start_new_log(&mut self) -> Result<()> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly.into());
+        }
+        let file_id = self.writer.id + 1;
+        save_max_file_id(&self.path, file_id)?;
+        let new_writer = KvsWriter::new(&self.dirs.write, file_id, self.file_naming)?
+            .retry_on_interrupt(self.retry_on_interrupt)
+            .sync_writes(self.sync_writes);
+        self.readers
+            .insert(file_id, Mutex::new(file::new_reader(&self.dirs, file_id)?));
+        self.file_times.insert(file_id, SystemTime::now());
+        self.writer.flush()?;
+        self.writer = new_writer;
+        Ok(())
+    }
+
+    fn swap_path(&mut self, new_path: &Path) -> Result<()> {
+        if !new_path.is_dir() {
+            return Err(KvsError::NotADirectory.into());
+        }
+        self.flush_pending_writes()?;
+        self.compact()?;
+        self.writer.flush()?;
+
+        let new_kvs_dir = new_path.join(KVS_DIR);
+        if !new_kvs_dir.is_dir() {
+            check_writable(new_path)?;
+        }
+        fs::create_dir_all(&new_kvs_dir)?;
+        let new_kvs_dir = new_kvs_dir.canonicalize()?;
+        // already held if the store is moving to where its metadata is
+        let new_dir_lock = if new_kvs_dir == self.path.canonicalize()? {
+            None
+        } else {
+            Some(lock_dir(&new_kvs_dir, false)?)
+        };
+
+        let mut old_files: Vec<PathBuf> = file::list_log_files(&self.dirs)?
+            .into_iter()
+            .map(|(_id, path)| path)
+            .collect();
+        for name in &[
+            INDEX_FILE,
+            DELETED_FILE,
+            MAX_FILE_ID_FILE,
+            LEVELS_FILE,
+            FORMAT_FILE,
+        ] {
+            let path = self.path.join(name);
+            if path.exists() {
+                old_files.push(path);
+            }
+        }
+        // files already in the new directory are neither copied onto themselves nor removed
+        let mut moved_files = Vec::with_capacity(old_files.len());
+        for path in old_files {
+            if path.canonicalize()?.parent() != Some(new_kvs_dir.as_path()) {
+                fs::copy(
+                    &path,
+                    new_kvs_dir.join(path.file_name().unwrap_or_default()),
+                )?;
+                moved_files.push(path);
+            }
+        }
+
+        let new_dirs = LogDirs {
+            write: new_kvs_dir.clone(),
+            compacted: new_kvs_dir.clone(),
+        };
+        let file_ids: Vec<file::Id> = self.readers.keys().cloned().collect();
+        for id in file_ids {
+            self.readers
+                .insert(id, Mutex::new(file::new_reader(&new_dirs, id)?));
+        }
+        // compaction just started the active log, so it is still empty
+        self.writer = KvsWriter::new(&new_dirs.write, self.writer.id, self.file_naming)?
+            .retry_on_interrupt(self.retry_on_interrupt)
+            .sync_writes(self.sync_writes);
+        self.path = new_kvs_dir;
+        self.dirs = new_dirs;
+        if let Some(new_dir_lock) = new_dir_lock {
+            // releases the lock on the old directory
+            self.dir_lock = new_dir_lock;
+        }
+
+        for path in moved_files {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Start a new active log, so the existing ones are never written again, and return them
+    /// for `KvStore::backup_nonblocking` to link. The permanently deleted keys are copied to
+    /// `backup_dir` now, as they are small and still being written to, along with the format.
+    fn seal_for_backup(&mut self, backup_dir: &Path) -> Result<Vec<(file::Id, PathBuf)>> {
+        self.flush_pending_writes()?;
+        self.start_new_log()?;
+        format::save_format(backup_dir, &self.format)?;
+        if !self.permanently_deleted.is_empty() {
+            save_deleted(backup_dir, &self.permanently_deleted)?;
+        }
+
+        let mut sealed: Vec<_> = file::list_log_files(&self.dirs)?
+            .into_iter()
+            .filter(|(id, _path)| *id < self.writer.id)
+            .collect();
+        sealed.sort_unstable_by_key(|(id, _path)| *id);
+        Ok(sealed)
+    }
+
+    /// Is the log file older than `max_log_age`?
+    fn is_expired(&self, file_id: file::Id) -> bool {
+        let age = self
+            .file_times
+            .get(&file_id)
+            .and_then(|created| created.elapsed().ok());
+        match (age, self.max_log_age) {
+            (Some(age), Some(max_age)) => age > max_age,
+            _ => false,
+        }
+    }
+
+    /// Delete the oldest log files while they are expired and hold no live values.
+    ///
+    /// Stopping at the first file which has to stay means no tombstone is deleted while an
+    /// older value it hides is kept.
+    fn remove_expired_files(&mut self, file_ids: &[file::Id]) -> Result<()> {
+        if self.max_log_age.is_none() {
+            return Ok(());
+        }
+
+        let live_file_ids: HashSet<file::Id> = self
+            .index
+            .values()
+            .map(|val_info| val_info.file_id)
+            .collect();
+        for id in file_ids {
+            if !self.is_expired(*id) || live_file_ids.contains(id) {
+                break;
+            }
+            self.readers.remove(id);
+            self.file_times.remove(id);
+            self.legacy_formats.remove(id);
+            file::remove(&self.dirs, *id)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn get(&mut self, key: &str) -> Result<Option<String>> {
+        self.apply_queued_writes()?;
+        if !self.pending_writes.contains_key(key) {
+            self.remove_if_past_ttl(key);
+        }
+        self.lookup(key)
+    }
+
+    /// `get`, for callers sharing the store's lock, or `None` if only `get` can answer, as
+    /// there are writes from `KvStore::set_nonblocking` to apply, or the value has run out of
+    /// time to live and should be forgotten.
+    pub(super) fn try_get(&self, key: &str) -> Option<Result<Option<String>>> {
+        if !self.queued_writes.lock().unwrap().is_empty() {
+            return None;
+        }
+        let past_ttl = !self.pending_writes.contains_key(key)
+            && matches!(self.index.get(key), Some(val_info) if val_info.past_ttl(now_nanos()));
+        if past_ttl {
+            return None;
+        }
+        Some(self.lookup(key))
+    }
+
+    /// Find the value for `key` in the pending writes or the index, once queued writes have
+    /// been applied.
+    fn lookup(&self, key: &str) -> Result<Option<String>> {
+        if let Some(value) = self.pending_writes.get(key) {
+            return Ok(Some(value.clone()));
+        }
+
+        #[cfg(feature = "bloom_filter")]
+        let val_info = if self.bloom_filter.might_contain(key) {
+            self.index.get(key)
+        } else {
+            None
+        };
+        #[cfg(not(feature = "bloom_filter"))]
+        let val_info = self.index.get(key);
+        // it ran out since `get` or `try_get` checked, so it is forgotten next time
+        let val_info = val_info.filter(|val_info| !val_info.past_ttl(now_nanos()));
+        if let Some(log) = &self.log {
+            match val_info {
+                Some(val_info) => trace!(log, "get";
+                    "key" => key,
+                    "found" => true,
+                    "file_id" => val_info.file_id,
+                    "offset" => val_info.file_offset.0,
+                    "bytes" => val_info.size.0,
+                ),
+                None => trace!(log, "get"; "key" => key, "found" => false),
+            }
+        }
+
+        match val_info {
+            Some(val_info) if self.is_expired(val_info.file_id) => {
+                Err(KvsError::DataExpired.into())
+            }
+            Some(val_info) if val_info.cached_value.is_some() => val_info
+                .read_value(key, &self.readers, &self.format)
+                .map(Some),
+            Some(val_info) => {
+                if let Some(value) = self.read_cache.lock().unwrap().get(key, val_info.nonce) {
+                    return Ok(Some(value));
+                }
+                // not locked while reading, so other `get` calls can use the cache
+                let value = val_info.read_value(key, &self.readers, &self.format)?;
+                let mut read_cache = self.read_cache.lock().unwrap();
+                if read_cache.is_enabled() {
+                    read_cache.insert(key.to_owned(), val_info.nonce, value.clone());
+                }
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Index entries for the values of `keys` which would be read from disk and aren't in
+    /// the read cache, along with readers for the log files holding them.
+    fn uncached_values(&self, keys: &[String]) -> Result<(Vec<(String, ValueInfo)>, Readers)> {
+        let read_cache = self.read_cache.lock().unwrap();
+        let mut uncached = Vec::new();
+        let mut readers = HashMap::new();
+        for key in keys {
+            let val_info = match self.index.get(key) {
+                Some(val_info) => val_info,
+                None => continue,
+            };
+            if self.pending_writes.contains_key(key)
+                || val_info.cached_value.is_some()
+                || self.is_expired(val_info.file_id)
+                || read_cache.contains(key, val_info.nonce)
+            {
+                continue;
+            }
+            if let Entry::Vacant(entry) = readers.entry(val_info.file_id) {
+                entry.insert(Mutex::new(file::new_reader(&self.dirs, val_info.file_id)?));
+            }
+            uncached.push((key.clone(), val_info.clone()));
+        }
+        Ok((uncached, readers))
+    }
+
+    /// Report a `get` which started at `start`, and apply `KvStoreOptions::strict_mode`.
+    fn finish_get(
+        &self,
+        key: &str,
+        value: Option<String>,
+        start: Instant,
+    ) -> Result<Option<String>> {
+        self.telemetry.on_get(key, value.is_some(), start.elapsed());
+        self.get_latency.lock().unwrap().record(start.elapsed());
+
+        match value {
+            None if self.strict_mode => Err(KvsError::KeyNotFound.into()),
+            value => Ok(value),
+        }
+    }
+
+    fn get_with_metadata(&mut self, key: &str) -> Result<Option<(String, u64)>> {
+        // pending writes aren't timestamped until they are written
+        self.flush_pending_writes()?;
+        self.remove_if_past_ttl(key);
+
+        match self.index.get(key) {
+            Some(val_info) if self.is_expired(val_info.file_id) => {
+                Err(KvsError::DataExpired.into())
+            }
+            Some(val_info) => {
+                let value = val_info.read_value(key, &self.readers, &self.format)?;
+                Ok(Some((value, val_info.written_at)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Replay every log file for commands on `key` written no later than `timestamp`.
+    fn get_as_of(&mut self, key: &str, timestamp: u64) -> Result<Option<String>> {
+        self.flush_pending_writes()?;
+
+        let mut file_ids: Vec<file::Id> = self.readers.keys().cloned().collect();
+        file_ids.sort_unstable();
+
+        let mut value = None;
+        // chunks of a split value read so far
+        let mut partial = String::new();
+        for file_id in file_ids {
+            let reader = file::new_reader(&self.dirs, file_id)?;
+            for command in self.format.reader(reader) {
+                // the rest of the file was skipped when the store was opened
+                let command = match command {
+                    Ok(command) => command,
+                    Err(_) => break,
+                };
+                if command.is_marker() || command.key != key || command.written_at > timestamp {
+                    continue;
+                }
+
+                match (command.value, command.chunk) {
+                    (None, _) => value = None,
+                    (Some(chunk_value), Some(Chunk { index, count })) => {
+                        if index == 0 {
+                            partial.clear();
+                        }
+                        partial.push_str(&chunk_value);
+                        if index + 1 == count {
+                            value = Some(std::mem::take(&mut partial));
+                        }
+                    }
+                    (Some(whole_value), None) => value = Some(whole_value),
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    /// Index entries for the keys in `range`, in order, including expired ones.
+    fn indexed_range<'a>(
+        &'a self,
+        range: KeyRange<'a>,
+    ) -> impl Iterator<Item = (&'a String, &'a ValueInfo)> + 'a {
+        // every key in the range sorts at or after its start, and they are all together
+        self.index
+            .range::<str, _>((Bound::Included(range.start()), Bound::Unbounded))
+            .take_while(move |(key, _)| range.contains(key))
+    }
+
+    /// Find the keys in `range`, skipping expired values and ones past their time to live, and
+    /// open a reader for each log file holding one of their values.
+    fn scan_range(&mut self, range: KeyRange<'_>) -> Result<KeyScan> {
+        self.apply_queued_writes()?;
+        let pending: Vec<_> = self
+            .pending_writes
+            .iter()
+            .filter(|(key, _)| range.contains(key))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+
+        let now = now_nanos();
+        let mut indexed = Vec::new();
+        let mut readers = HashMap::new();
+        for (key, val_info) in self.indexed_range(range) {
+            if self.pending_writes.contains_key(key)
+                || self.is_expired(val_info.file_id)
+                || val_info.past_ttl(now)
+            {
+                continue;
+            }
+            if val_info.cached_value.is_none() && !readers.contains_key(&val_info.file_id) {
+                readers.insert(
+                    val_info.file_id,
+                    Mutex::new(file::new_reader(&self.dirs, val_info.file_id)?),
+                );
+            }
+            indexed.push((key.clone(), val_info.clone()));
+        }
+
+        Ok(KeyScan {
+            pending,
+            indexed,
+            readers,
+            format: self.format.clone(),
+        })
+    }
+
+    /// The keys in `range`, sorted, skipping expired values and ones past their time to live.
+    fn range_keys(&mut self, range: KeyRange<'_>) -> Result<Vec<String>> {
+        self.apply_queued_writes()?;
+        let mut keys: Vec<String> = self
+            .pending_writes
+            .keys()
+            .filter(|key| range.contains(key))
+            .cloned()
+            .collect();
+        let now = now_nanos();
+        keys.extend(
+            self.indexed_range(range)
+                .filter(|(_, val_info)| {
+                    !self.is_expired(val_info.file_id) && !val_info.past_ttl(now)
+                })
+                .map(|(key, _)| key.clone()),
+        );
+        keys.sort_unstable();
+        // a pending write can be for a key already in the index
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// The number of keys `range_keys` would return, without collecting them.
+    fn count_range(&mut self, range: KeyRange<'_>) -> Result<u64> {
+        self.apply_queued_writes()?;
+        let pending = self
+            .pending_writes
+            .keys()
+            .filter(|key| range.contains(key) && !self.index.contains_key(*key))
+            .count();
+        let now = now_nanos();
+        let indexed = self
+            .indexed_range(range)
+            .filter(|(key, val_info)| {
+                // a pending write replaces the indexed value, even if that has expired
+                self.pending_writes.contains_key(*key)
+                    || !(self.is_expired(val_info.file_id) || val_info.past_ttl(now))
+            })
+            .count();
+        Ok((pending + indexed) as u64)
+    }
+
+    fn contains_key(&mut self, key: &str) -> Result<bool> {
+        self.apply_queued_writes()?;
+        if self.pending_writes.contains_key(key) {
+            return Ok(true);
+        }
+        self.remove_if_past_ttl(key);
+        match self.index.get(key) {
+            Some(val_info) if self.is_expired(val_info.file_id) => {
+                Err(KvsError::DataExpired.into())
+            }
+            Some(_) => Ok(true),
+            None => Ok(false),
+        }
+    }
+
+    fn get_reader(&mut self, key: &str) -> Result<Option<ValueReader>> {
+        self.apply_queued_writes()?;
+        if let Some(value) = self.pending_writes.get(key) {
+            return Ok(Some(ValueReader::in_memory(value.clone())));
+        }
+        self.remove_if_past_ttl(key);
+
+        let val_info = match self.index.get(key) {
+            Some(val_info) if self.is_expired(val_info.file_id) => {
+                return Err(KvsError::DataExpired.into())
+            }
+            Some(val_info) => val_info,
+            None => return Ok(None),
+        };
+        if let Some(value) = &val_info.cached_value {
+            return Ok(Some(ValueReader::in_memory(value.to_string())));
+        }
+
+        let mut reader = file::new_reader(&self.dirs, val_info.file_id)?;
+        reader.seek(SeekFrom::Start(val_info.file_offset.0))?;
+        let commands = self.format.reader(reader.take(val_info.size.0));
+
+        Ok(Some(ValueReader {
+            chunk: Cursor::new(Vec::new()),
+            commands: Some(commands),
+            remaining_chunks: val_info.chunks,
+            file_id: val_info.file_id,
+            file_offset: val_info.file_offset,
+        }))
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.check_settable(&key, &value)?;
+        self.buffer_or_append_set(key, value)
+    }
+
+    /// `set`, with the value reading as removed once `ttl` has passed.
+    fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.check_settable(&key, &value)?;
+        let ttl: u64 = ttl.as_nanos().try_into().unwrap_or(u64::MAX);
+        let expires_at = now_nanos().saturating_add(ttl);
+        // written straight to the log, as pending writes have nowhere to keep the expiry
+        self.pending_writes.remove(&key);
+        self.append_sets(vec![(key, value)], Some(expires_at))
+    }
+
+    /// Fail if the store can't be written at all, whatever the write.
+    fn check_accepting_writes(&self) -> Result<()> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly.into());
+        }
+        if self.disk_full {
+            return Err(KvsError::DiskFull.into());
+        }
+        Ok(())
+    }
+
+    /// Fail with the error `set` would return for this pair, if any.
+    fn check_settable(&mut self, key: &str, value: &str) -> Result<()> {
+        self.check_accepting_writes()?;
+        self.apply_queued_writes()?;
+        if self.permanently_deleted.contains(key) {
+            return Err(KvsError::PermanentlyDeleted.into());
+        }
+        if self.exceeds_index_memory_limit(self.index_memory_added(key, value)) {
+            return Err(KvsError::IndexMemoryLimitExceeded.into());
+        }
+        Ok(())
+    }
+
+    /// Set every pair, refusing them all if any would be refused by `set`.
+    fn batch_write(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.check_accepting_writes()?;
+        self.apply_queued_writes()?;
+        let mut new_keys = HashSet::new();
+        let mut memory_added = 0;
+        for (key, value) in &pairs {
+            if self.permanently_deleted.contains(key) {
+                return Err(KvsError::PermanentlyDeleted.into());
+            }
+            if new_keys.insert(key.as_str()) {
+                memory_added += self.index_memory_added(key, value);
+            }
+        }
+        if self.exceeds_index_memory_limit(memory_added) {
+            return Err(KvsError::IndexMemoryLimitExceeded.into());
+        }
+
+        if self.max_pending_writes > 0 {
+            for (key, value) in pairs {
+                self.buffer_or_append_set(key, value)?;
+            }
+            return Ok(());
+        }
+        self.append_sets(pairs, None)
+    }
+
+    /// Memory setting `key` would add to the index, which is none if it is already there.
+    fn index_memory_added(&self, key: &str, value: &str) -> usize {
+        if self.index.contains_key(key) || self.pending_writes.contains_key(key) {
+            return 0;
+        }
+        let cached_value = Some(value).filter(|value| fits_inline(value, self.inline_values));
+        index_entry_bytes(key, cached_value)
+    }
+
+    /// Would adding `bytes` to the index take it past `KvStoreOptions::max_index_memory_bytes`?
+    fn exceeds_index_memory_limit(&self, bytes: usize) -> bool {
+        match self.max_index_memory_bytes {
+            Some(limit) => bytes > 0 && self.index_memory_bytes + bytes > limit,
+            None => false,
+        }
+    }
+
+    /// Classify an error from `set` or `remove`, stopping further writes if the disk is full,
+    /// as they would fail too, and could leave partly written commands behind.
+    fn on_write_error(&mut self, e: anyhow::Error) -> anyhow::Error {
+        let e = classify_io_error(e);
+        if let Some(KvsError::DiskFull) = e.downcast_ref::<KvsError>() {
+            self.disk_full = true;
+        }
+        e
+    }
+
+    /// Apply writes from `KvStore::set_nonblocking`, in the order they were made.
+    fn apply_queued_writes(&mut self) -> Result<()> {
+        let queued_writes = std::mem::take(&mut *self.queued_writes.lock().unwrap());
+        if self.disk_full || self.read_only {
+            // they would fail, and there is nobody waiting to be told
+            return Ok(());
+        }
+        for command in queued_writes {
+            match command.value {
+                // there is nobody waiting to be told the write failed
+                Some(_) if self.permanently_deleted.contains(&command.key) => {}
+                Some(ref value)
+                    if self.exceeds_index_memory_limit(
+                        self.index_memory_added(&command.key, value),
+                    ) => {}
+                Some(value) => self.buffer_or_append_set(command.key, value)?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn buffer_or_append_set(&mut self, key: String, value: String) -> Result<()> {
+        if self.max_pending_writes > 0 {
+            // replaces any value already queued for this key, which would never be read
+            self.index_secondary(&key, &value);
+            self.update_arc_cache(&key, &value);
+            self.read_cache.lock().unwrap().remove(&key);
+            #[cfg(feature = "bloom_filter")]
+            self.add_to_bloom_filter(&key);
+            self.pending_writes.insert(key, value);
+            if self.pending_writes.len() >= self.max_pending_writes {
+                self.flush_pending_writes()?;
+            }
+            return Ok(());
+        }
+
+        self.append_set(key, value)
+    }
+
+    fn flush_pending_writes(&mut self) -> Result<()> {
+        self.apply_queued_writes()?;
+        let pending_writes: Vec<_> = self.pending_writes.drain().collect();
+        for (key, value) in pending_writes {
+            self.append_set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Write a `set` command to the log and point the index at it.
+    fn append_set(&mut self, key: String, value: String) -> Result<()> {
+        self.append_sets(vec![(key, value)], None)
+    }
+
+    /// Write `set` commands for every pair to the log, flushing once, then point the index
+    /// at them all. Pairs later in `pairs` overwrite earlier ones for the same key.
+    fn append_sets(&mut self, pairs: Vec<(String, String)>, expires_at: Option<u64>) -> Result<()> {
+        struct Written {
+            key: String,
+            value: String,
+            write_pos: u64,
+            cmd_len: u64,
+            written_at: u64,
+            nonce: [u8; 8],
+            chunks: u32,
+        }
+
+        let mut written = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            let write_pos = self.writer.offset;
+            let written_at = self.next_timestamp()?;
+            // shared by every chunk, as they make up a single entry
+            let nonce = rand::random();
+
+            let chunks = split_value(&value, self.max_inline_value_bytes);
+            let count = chunks.len().try_into()?;
+            for (index, chunk) in (0..).zip(chunks) {
+                self.format.write(
+                    &mut self.writer,
+                    &Command::set_chunk(&key, chunk, index, count, written_at, nonce, expires_at)
+                        .with_checksum(self.checksum_algorithm),
+                )?;
+            }
+            written.push(Written {
+                key,
+                value,
+                write_pos,
+                cmd_len: self.writer.offset - write_pos,
+                written_at,
+                nonce,
+                chunks: count,
+            });
+        }
+        self.writer.flush()?;
+
+        for Written {
+            key,
+            value,
+            write_pos,
+            cmd_len,
+            written_at,
+            nonce,
+            chunks,
+        } in written
+        {
+            self.disk_bytes += Bytes(cmd_len);
+            self.user_bytes_written.fetch_add(cmd_len, Ordering::SeqCst);
+            self.estimated_num_operations.fetch_add(1, Ordering::SeqCst);
+            if let Some(log) = &self.log {
+                trace!(log, "set"; "key" => &key, "file_id" => self.writer.id, "offset" => write_pos);
+            }
+
+            if let Some(&ValueInfo { size, .. }) = self.index.get(&key) {
+                self.uncompacted += size;
+                self.overhead_bytes.fetch_add(size.0, Ordering::SeqCst);
+            }
+
+            let cached_value = inline_value(&value, self.inline_values);
+            self.index_secondary(&key, &value);
+            self.update_arc_cache(&key, &value);
+            self.read_cache.lock().unwrap().remove(&key);
+            #[cfg(feature = "bloom_filter")]
+            self.add_to_bloom_filter(&key);
+
+            self.replicate(ReplicaOp::Set {
+                key: key.clone(),
+                value,
+            });
+
+            let val_info = ValueInfo {
+                file_offset: Bytes(write_pos),
+                size: Bytes(cmd_len),
+                chunks,
+                file_id: self.writer.id,
+                written_at,
+                nonce,
+                expires_at,
+                cached_value,
+            };
+            if let Some(old) = self.index.get(&key) {
+                self.index_memory_bytes -= index_entry_bytes(&key, old.cached_value.as_deref());
+            }
+            self.index_memory_bytes += index_entry_bytes(&key, val_info.cached_value.as_deref());
+            self.index.insert(key, val_info);
+            self.compaction_threshold.record_write();
+        }
+
+        self.roll_over_if_full()?;
+        if self
+            .compaction_threshold
+            .exceeded_by(self.uncompacted, self.live_ratio())
+        {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.check_accepting_writes()?;
+        if self.permanent_delete {
+            self.apply_queued_writes()?;
+            let exists = self.pending_writes.contains_key(&key) || self.index.contains_key(&key);
+            // recorded before the tombstone is written, so a crash can't leave the key settable
+            if exists && self.permanently_deleted.insert(key.clone()) {
+                save_deleted(&self.path, &self.permanently_deleted)?;
+            }
+        }
+        self.remove_key(key)
+    }
+
+    /// Remove every key from `start` up to, but not including, `end`, returning the keys.
+    fn delete_range(&mut self, start: &str, end: &str) -> Result<Vec<String>> {
+        self.check_accepting_writes()?;
+        let keys = self.range_keys(KeyRange::Between(start, end))?;
+        if self.permanent_delete && !keys.is_empty() {
+            // saved once for the whole range, before any tombstone is written, as in `remove`
+            self.permanently_deleted.extend(keys.iter().cloned());
+            save_deleted(&self.path, &self.permanently_deleted)?;
+        }
+        for key in &keys {
+            self.remove_key(key.clone())?;
+        }
+        Ok(keys)
+    }
+
+    fn remove_key(&mut self, key: String) -> Result<()> {
+        self.apply_queued_writes()?;
+        let was_pending = self.pending_writes.remove(&key).is_some();
+
+        match self.index.get(&key) {
+            None if was_pending => {
+                self.unindex_secondary(&key);
+                self.arc_cache.remove(&key);
+                Ok(())
+            }
+            None => Err(KvsError::KeyNotFound.into()),
+
+            Some(&ValueInfo {
+                size: prev_cmd_size,
+                ..
+            }) => {
+                let write_pos = self.writer.offset;
+                let written_at = self.next_timestamp()?;
+
+                self.format.write(
+                    &mut self.writer,
+                    // one tombstone covers every chunk of the value
+                    &Command {
+                        key: key.clone(),
+                        value: None,
+                        chunk: None,
+                        written_at,
+                        nonce: rand::random(),
+                        open: false,
+                        expires_at: None,
+                        compaction: None,
+                        checksum: None,
+                    }
+                    .with_checksum(self.checksum_algorithm),
+                )?;
+                self.writer.flush()?;
+
+                let cmd_len = self.writer.offset - write_pos;
+                self.uncompacted = self.uncompacted + prev_cmd_size + Bytes(cmd_len);
+                self.disk_bytes += Bytes(cmd_len);
+                self.user_bytes_written.fetch_add(cmd_len, Ordering::SeqCst);
+                self.estimated_num_operations.fetch_add(1, Ordering::SeqCst);
+                self.overhead_bytes
+                    .fetch_add(prev_cmd_size.0 + cmd_len, Ordering::SeqCst);
+
+                self.remove_from_index(&key);
+                self.unindex_secondary(&key);
+                self.arc_cache.remove(&key);
+                self.replicate(ReplicaOp::Remove { key });
+
+                self.roll_over_if_full()?;
+                self.compaction_threshold.record_write();
+                if self
+                    .compaction_threshold
+                    .exceeded_by(self.uncompacted, self.live_ratio())
+                {
+                    self.compact()?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    fn rebuild_index(&mut self) -> Result<usize> {
+        self.flush_pending_writes()?;
+        self.writer.flush()?;
+
+        // only the options replaying a log looks at
+        let options = KvStoreOptions {
+            inline_values: self.inline_values,
+            validation_mode: self.validation_mode,
+            sink: self.telemetry.clone(),
+            ..KvStoreOptions::default()
+        };
+        let mut file_ids: Vec<_> = self.readers.keys().copied().collect();
+        file_ids.sort_unstable();
+
+        let mut index = Index::new();
+        let mut uncompacted = Bytes(0);
+        let mut num_operations = 0;
+        for id in file_ids {
+            let mut replay_reader = file::new_reader_sequential(&self.dirs, id)?;
+            let (file_uncompacted, file_operations) =
+                load_file_into_index(id, &mut replay_reader, &mut index, &self.format, &options)?;
+            uncompacted += file_uncompacted;
+            num_operations += file_operations;
+        }
+
+        self.index_memory_bytes = index_memory_bytes(&index);
+        let old_index = std::mem::replace(&mut self.index, index);
+        for key in old_index.keys() {
+            if !self.index.contains_key(key) {
+                self.unindex_secondary(key);
+            }
+        }
+        self.build_secondary_indexes()?;
+        self.arc_cache.clear();
+        #[cfg(feature = "bloom_filter")]
+        self.rebuild_bloom_filter();
+
+        self.uncompacted = uncompacted;
+        self.estimated_num_operations
+            .store(num_operations, Ordering::SeqCst);
+        if let Some(latest) = self
+            .index
+            .values()
+            .map(|val_info| val_info.written_at)
+            .max()
+        {
+            self.last_written_at = self.last_written_at.max(latest);
+        }
+
+        Ok(self.index.len())
+    }
+
+    fn verify_log_file(&self, file_id: file::Id) -> Result<LogFileStats> {
+        if !self.readers.contains_key(&file_id) {
+            return Err(KvsError::LogFileNotFound.into());
+        }
+
+        let contents = fs::read(file::path(&self.dirs, file_id)?)?;
+        let mut stats = LogFileStats {
+            bytes_total: contents.len().try_into()?,
+            ..LogFileStats::default()
+        };
+
+        let mut offset = 0;
+        while offset < contents.len() {
+            let mut commands = self.format.reader(&contents[offset..]);
+
+            match commands.next() {
+                // nothing but whitespace left
+                None => break,
+
+                // not a command on the store, so not counted
+                Some(Ok(command)) if command.is_marker() => {
+                    offset += commands.byte_offset() as usize
+                }
+
+                Some(Ok(Command {
+                    key, value, nonce, ..
+                })) => {
+                    stats.total_commands += 1;
+                    match value {
+                        Some(_) => {
+                            // chunks of a value are all covered by its index entry
+                            let position = Bytes(offset.try_into()?);
+                            let live_entry = self.index.get(&key).filter(|val_info| {
+                                val_info.file_id == file_id
+                                    && val_info.file_offset <= position
+                                    && position < val_info.file_offset + val_info.size
+                            });
+                            if let Some(val_info) = live_entry {
+                                stats.live_commands += 1;
+                                if val_info.nonce != nonce {
+                                    stats.index_mismatches += 1;
+                                }
+                            }
+                        }
+                        None => stats.tombstones += 1,
+                    }
+                    offset += commands.byte_offset() as usize;
+                }
+
+                Some(Err(_e)) => {
+                    stats.corrupt_entries += 1;
+                    // only JSON can be searched for the start of the next entry
+                    offset = if self.format.is_json() {
+                        find_next_command(&contents, offset + 1)
+                    } else {
+                        contents.len()
+                    };
+                }
+            }
+        }
+
+        Ok(stats)
     }
-}
 
-#[allow(clippy::module_name_repetitions)]
-#[derive(Debug)]
-struct InternalKvStore {
-    /// Path of directory containing log files
-    path: PathBuf,
-    writer: KvsWriter,
-    readers: Readers,
-    index: Index,
-    uncompacted: Bytes,
-}
+    fn replicate(&self, op: ReplicaOp) {
+        if let Some(replica) = &self.replica {
+            replica.send(op, self.replica_lag_limit);
+        }
+    }
 
-type Readers = HashMap<file::Id, BufReader<File>>;
-type Index = HashMap<String, ValueInfo>;
+    fn remove_from_index(&mut self, key: &str) {
+        if let Some(val_info) = self.index.remove(key) {
+            self.index_memory_bytes -= index_entry_bytes(key, val_info.cached_value.as_deref());
+        }
+        self.read_cache.lock().unwrap().remove(key);
+    }
 
-#[derive(Debug, Clone, Copy)]
-struct ValueInfo {
-    /// Identifier for file the value is stored in
-    file_id: file::Id,
+    /// Remove expired values from the index, so compaction drops them rather than copying them.
+    fn drop_expired_values(&mut self) {
+        let now = now_nanos();
+        let expired_keys: Vec<String> = self
+            .index
+            .iter()
+            .filter(|(_key, val_info)| self.is_expired(val_info.file_id) || val_info.past_ttl(now))
+            .map(|(key, _val_info)| key.clone())
+            .collect();
+        for key in expired_keys {
+            self.remove_from_index(&key);
+            self.unindex_secondary(&key);
+            self.arc_cache.remove(&key);
+        }
+    }
 
-    /// Position of value in file
-    file_offset: Bytes,
+    /// Forget `key` if its value's time to live has run out, so it reads as missing.
+    ///
+    /// No tombstone is written, as the expiry is in the log, and the value is dropped again
+    /// when the log is replayed.
+    fn remove_if_past_ttl(&mut self, key: &str) {
+        let size = match self.index.get(key) {
+            Some(val_info) if val_info.past_ttl(now_nanos()) => val_info.size,
+            _ => return,
+        };
+        self.remove_from_index(key);
+        self.unindex_secondary(key);
+        self.arc_cache.remove(key);
+        self.uncompacted += size;
+        self.overhead_bytes.fetch_add(size.0, Ordering::SeqCst);
+        self.replicate(ReplicaOp::Remove {
+            key: key.to_owned(),
+        });
+    }
 
-    /// Size of serialised command in file
-    size: Bytes,
-}
+    /// Forget every key past its time to live, returning how many there were.
+    fn purge_expired(&mut self) -> Result<usize> {
+        self.apply_queued_writes()?;
+        let now = now_nanos();
+        let expired_keys: Vec<String> = self
+            .index
+            .iter()
+            .filter(|(key, val_info)| {
+                val_info.past_ttl(now) && !self.pending_writes.contains_key(*key)
+            })
+            .map(|(key, _val_info)| key.clone())
+            .collect();
+        for key in &expired_keys {
+            self.remove_if_past_ttl(key);
+        }
+        Ok(expired_keys.len())
+    }
 
-impl InternalKvStore {
-    fn open(path: impl Into<PathBuf>) -> Result<InternalKvStore> {
-        let path_dir = path.into();
-        if !path_dir.is_dir() {
-            return Err(KvsError::NotADirectory.into());
+    /// Merge the level 0 logs into level 1, see `KvStoreOptions::leveled_compaction`.
+    ///
+    /// A level 1 file is rewritten if a level 0 key falls in its range, or if any of its values
+    /// has been overwritten or removed. The values from level 0 and from those files are
+    /// written in key order to new level 1 files, which are split at the untouched files'
+    /// ranges so no two level 1 files overlap. Tombstones aren't copied, as every older value
+    /// for their keys is in a file being replaced.
+    fn compact_leveled(&mut self) -> Result<u64> {
+        let start = Instant::now();
+        let started_at = now_nanos();
+        let mut stats = CompactionStats::default();
+        self.writer.flush()?;
+        self.drop_expired_values();
+
+        let mut live_values: HashMap<file::Id, usize> = HashMap::new();
+        for val_info in self.index.values() {
+            *live_values.entry(val_info.file_id).or_default() += 1;
         }
-        let kvs_dir = path_dir.join(KVS_DIR);
+        let level1 = &self.level1;
+        let level0_keys: Vec<&String> = self
+            .index
+            .iter()
+            .filter(|(_key, val_info)| !level1.contains_key(&val_info.file_id))
+            .map(|(key, _val_info)| key)
+            .collect();
+        let legacy_formats = &self.legacy_formats;
+        let rewritten: HashSet<file::Id> = level1
+            .iter()
+            .filter(|(id, file)| {
+                live_values.get(id).copied().unwrap_or(0) < file.entries
+                    || level0_keys.iter().any(|key| file.covers(key))
+                    || legacy_formats.contains_key(id)
+            })
+            .map(|(id, _file)| *id)
+            .collect();
+        // where an untouched file's range starts, so new files can stop short of it
+        let untouched_starts: Vec<String> = level1
+            .iter()
+            .filter(|(id, _file)| !rewritten.contains(id))
+            .map(|(_id, file)| file.first_key().to_owned())
+            .collect();
 
-        fs::create_dir_all(&kvs_dir)?;
+        let mut keys: Vec<String> = self
+            .index
+            .iter()
+            .filter(|(_key, val_info)| {
+                !level1.contains_key(&val_info.file_id) || rewritten.contains(&val_info.file_id)
+            })
+            .map(|(key, _val_info)| key.clone())
+            .collect();
+        keys.sort_unstable();
 
-        let mut file_ids = get_log_file_ids(&kvs_dir)?;
-        file_ids.sort_unstable();
+        // every level 0 log is merged, the active one included
+        let mut replaced_file_ids: Vec<file::Id> = self
+            .readers
+            .keys()
+            .filter(|id| !level1.contains_key(id) || rewritten.contains(id))
+            .cloned()
+            .collect();
+        replaced_file_ids.sort_unstable();
 
-        let mut readers = HashMap::new();
-        let mut index = HashMap::<String, ValueInfo>::new();
-        let mut uncompacted = Bytes(0);
+        // values keep the age of the newest log they could have come from
+        let compaction_file_time = self
+            .file_times
+            .get(&self.writer.id)
+            .cloned()
+            .unwrap_or_else(SystemTime::now);
 
-        for id in &file_ids {
-            let mut buffered_reader = file::new_reader(&kvs_dir, *id)?;
+        let mut next_file_id = self.writer.id + 1;
+        let mut new_files: Vec<(KvsWriter, Level1File)> = Vec::new();
+        let mut moved: Vec<(String, file::Id, Bytes, Bytes)> = Vec::with_capacity(keys.len());
+
+        // keep the markers from the files being replaced, ahead of the values
+        let mut markers = Vec::new();
+        for file_id in &replaced_file_ids {
+            let reader = file::new_reader(&self.dirs, *file_id)?;
+            markers.extend(leading_markers(reader, self.file_format(*file_id))?.0);
+        }
+        if !markers.is_empty() {
+            save_max_file_id(&self.path, next_file_id)?;
+            let mut writer =
+                KvsWriter::new_temp(&self.dirs.compacted, next_file_id, self.file_naming)?
+                    .retry_on_interrupt(self.retry_on_interrupt);
+            next_file_id += 1;
+            for marker in markers {
+                self.format
+                    .write(&mut writer, &marker.with_checksum(self.checksum_algorithm))?;
+            }
+            new_files.push((writer, Level1File::default()));
+        }
+
+        for key in keys {
+            let start_new_file = match new_files.last() {
+                Some((writer, file)) => {
+                    (file.entries > 0 && writer.offset > LEVEL1_FILE_BYTES.0)
+                        || untouched_starts
+                            .iter()
+                            .any(|start| file.ends_before(start) && start.as_str() <= key.as_str())
+                }
+                None => true,
+            };
+            if start_new_file {
+                save_max_file_id(&self.path, next_file_id)?;
+                let writer =
+                    KvsWriter::new_temp(&self.dirs.compacted, next_file_id, self.file_naming)?
+                        .retry_on_interrupt(self.retry_on_interrupt);
+                next_file_id += 1;
+                new_files.push((writer, Level1File::default()));
+            }
+            let (writer, file) = new_files.last_mut().expect("a level 1 file was just added");
 
-            uncompacted += load_file_into_index(*id, &mut buffered_reader, &mut index)?;
+            let val_info = &self.index[&key];
+            let reader = self
+                .readers
+                .get_mut(&val_info.file_id)
+                .expect("Reader not found for file ID")
+                .get_mut()
+                .unwrap();
+            let new_offset = writer.offset;
+            let bytes_copied = copy_value(
+                reader,
+                val_info,
+                self.legacy_formats
+                    .get(&val_info.file_id)
+                    .unwrap_or(&self.format),
+                &self.format,
+                self.checksum_algorithm,
+                writer,
+            )?;
+            self.compaction_bytes_written
+                .fetch_add(bytes_copied, Ordering::SeqCst);
+            stats.keys_copied += 1;
+            stats.bytes_copied += bytes_copied;
 
-            readers.insert(*id, buffered_reader);
+            moved.push((
+                key.clone(),
+                writer.id,
+                Bytes(new_offset),
+                Bytes(bytes_copied),
+            ));
+            file.push(&key);
         }
 
-        let write_file_id = file_ids.last().unwrap_or(&0) + 1;
-        let writer = KvsWriter::new(&kvs_dir, write_file_id)?;
-        readers.insert(write_file_id, file::new_reader(&kvs_dir, write_file_id)?);
+        // only remove old files once the new ones are safely in place
+        #[cfg(feature = "fault_injection")]
+        fault::inject(self.fault, FaultPoint::BeforeCommittingCompaction);
+        let mut new_level1 = Vec::with_capacity(new_files.len());
+        for (writer, file) in new_files {
+            let file_id = writer.id;
+            writer.commit()?;
+            self.readers
+                .insert(file_id, Mutex::new(file::new_reader(&self.dirs, file_id)?));
+            self.file_times.insert(file_id, compaction_file_time);
+            new_level1.push((file_id, file));
+        }
+        for (key, file_id, file_offset, size) in moved {
+            if let Some(val_info) = self.index.get_mut(&key) {
+                val_info.file_id = file_id;
+                val_info.file_offset = file_offset;
+                val_info.size = size;
+            }
+        }
 
-        Ok(InternalKvStore {
-            path: kvs_dir,
-            writer,
-            readers,
+        // the active log was merged too, so a new one is needed
+        save_max_file_id(&self.path, next_file_id)?;
+        self.writer = KvsWriter::new(&self.dirs.write, next_file_id, self.file_naming)?
+            .retry_on_interrupt(self.retry_on_interrupt)
+            .sync_writes(self.sync_writes);
+        self.readers.insert(
+            next_file_id,
+            Mutex::new(file::new_reader(&self.dirs, next_file_id)?),
+        );
+        self.file_times.insert(next_file_id, SystemTime::now());
 
-            index,
-            uncompacted,
-        })
+        let new_file_ids: Vec<file::Id> = new_level1.iter().map(|(id, _file)| *id).collect();
+        self.level1.retain(|id, _file| !rewritten.contains(id));
+        self.level1.extend(new_level1);
+        level::save_levels(&self.path, &self.level1)?;
+
+        let bytes_reclaimed = self
+            .log_files_bytes(&replaced_file_ids)?
+            .saturating_sub(self.log_files_bytes(&new_file_ids)?);
+
+        // oldest first, so a crash part way through can't leave a value behind without the
+        // later tombstone which removed it
+        #[cfg(feature = "fault_injection")]
+        fault::inject(self.fault, FaultPoint::BeforeRemovingCompactedFiles);
+        for &id in &replaced_file_ids {
+            self.readers.remove(&id);
+            self.file_times.remove(&id);
+            self.legacy_formats.remove(&id);
+            file::remove(&self.dirs, id)?;
+            stats.files_removed += 1;
+            #[cfg(feature = "fault_injection")]
+            fault::inject(self.fault, FaultPoint::AfterRemovingFirstCompactedFile);
+        }
+        // it describes a single compacted log, which leveled compaction doesn't keep
+        match fs::remove_file(self.path.join(INDEX_FILE)) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+
+        // what is left is one live value for each key, with nothing redundant
+        self.disk_bytes = self
+            .index
+            .values()
+            .fold(Bytes(0), |total, val_info| total + val_info.size);
+        self.uncompacted = Bytes(0);
+        self.estimated_num_operations
+            .store(self.index.len() as u64, Ordering::SeqCst);
+        self.compaction_threshold.adjust();
+        self.index_generation += 1;
+        #[cfg(feature = "bloom_filter")]
+        self.rebuild_bloom_filter();
+
+        let finished_at = self.next_timestamp()?;
+        self.write_compaction_record(CompactionRecord {
+            files_removed: replaced_file_ids,
+            keys_preserved: stats.keys_copied as u64,
+            bytes_reclaimed,
+            started_at,
+            finished_at,
+        })?;
+        stats.duration = start.elapsed();
+        self.telemetry.on_compact(&stats);
+        Ok(bytes_reclaimed)
     }
 
-    fn compact(&mut self) -> Result<()> {
-        // create new file to write compacted logs into
+    /// Rewrite the live values into a single compacted log, and remove the logs it replaces,
+    /// returning how many bytes were freed.
+    ///
+    /// This runs with the store locked, so no write can land part way through. Writes made
+    /// meanwhile with `KvStore::set_nonblocking` wait in `queued_writes`, and are appended to
+    /// the new log in order once the lock is released.
+    fn compact(&mut self) -> Result<u64> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly.into());
+        }
+        if self.max_l0_files.is_some() {
+            return self.compact_leveled();
+        }
+        let start = Instant::now();
+        let started_at = now_nanos();
+        let mut stats = CompactionStats::default();
+
+        // create temporary file to write compacted logs into, until they are complete
         let compaction_file_id = self.writer.id + 1;
-        let mut compacted_log_writer = {
-            let writer = KvsWriter::new(&self.path, compaction_file_id)?;
-            self.readers.insert(
-                compaction_file_id,
-                file::new_reader(&self.path, compaction_file_id)?,
-            );
-            writer
-        };
+        save_max_file_id(&self.path, compaction_file_id + 1)?;
+        let mut compacted_log_writer =
+            KvsWriter::new_temp(&self.dirs.compacted, compaction_file_id, self.file_naming)?
+                .retry_on_interrupt(self.retry_on_interrupt);
 
         // create new file to write new logs into
         let new_log_writer = {
             let file_id = self.writer.id + 2;
-            let writer = KvsWriter::new(&self.path, file_id)?;
+            let writer = KvsWriter::new(&self.dirs.write, file_id, self.file_naming)?
+                .retry_on_interrupt(self.retry_on_interrupt)
+                .sync_writes(self.sync_writes);
             self.readers
-                .insert(file_id, file::new_reader(&self.path, file_id)?);
+                .insert(file_id, Mutex::new(file::new_reader(&self.dirs, file_id)?));
+            self.file_times.insert(file_id, SystemTime::now());
             writer
         };
 
+        // values keep the age of the newest log they could have come from
+        let compaction_file_time = self
+            .file_times
+            .get(&self.writer.id)
+            .cloned()
+            .unwrap_or_else(SystemTime::now);
+
+        self.drop_expired_values();
+
+        // keep the markers from the files being compacted, ahead of the values
+        let mut compacted_file_ids: Vec<file::Id> = self
+            .readers
+            .keys()
+            .filter(|&&id| id < compaction_file_id)
+            .cloned()
+            .collect();
+        compacted_file_ids.sort_unstable();
+        for file_id in compacted_file_ids {
+            let (markers, _) = leading_markers(
+                file::new_reader(&self.dirs, file_id)?,
+                self.file_format(file_id),
+            )?;
+            for marker in markers {
+                self.format.write(
+                    &mut compacted_log_writer,
+                    &marker.with_checksum(self.checksum_algorithm),
+                )?;
+            }
+        }
+
         // switch writer
         self.uncompacted = Bytes(0);
+        self.compaction_threshold.adjust();
         self.writer = new_log_writer;
 
         for val_info in self.index.values_mut() {
@@ -160,164 +2623,526 @@ impl InternalKvStore {
             let reader = self
                 .readers
                 .get_mut(&val_info.file_id)
-                .expect("Reader not found for file ID");
-            reader.seek(SeekFrom::Start(val_info.file_offset.0))?;
-
+                .expect("Reader not found for file ID")
+                .get_mut()
+                .unwrap();
             let new_offset = compacted_log_writer.offset;
 
-            let bytes_copied =
-                std::io::copy(&mut reader.take(val_info.size.0), &mut compacted_log_writer)?;
+            let bytes_copied = copy_value(
+                reader,
+                val_info,
+                self.legacy_formats
+                    .get(&val_info.file_id)
+                    .unwrap_or(&self.format),
+                &self.format,
+                self.checksum_algorithm,
+                &mut compacted_log_writer,
+            )?;
+            self.compaction_bytes_written
+                .fetch_add(bytes_copied, Ordering::SeqCst);
+            stats.keys_copied += 1;
+            stats.bytes_copied += bytes_copied;
 
             // update index
-            *val_info = ValueInfo {
-                file_id: compaction_file_id,
-                file_offset: Bytes(new_offset),
-                size: Bytes(bytes_copied),
-            }
+            val_info.file_id = compaction_file_id;
+            val_info.file_offset = Bytes(new_offset);
+            val_info.size = Bytes(bytes_copied);
         }
-        self.writer.flush()?;
+        // only remove old files once the compacted log is safely in place
+        #[cfg(feature = "fault_injection")]
+        fault::inject(self.fault, FaultPoint::BeforeCommittingCompaction);
+        compacted_log_writer.commit()?;
+        self.disk_bytes = Bytes(stats.bytes_copied);
+        self.index_generation += 1;
+        // only the copied values are left, one set each
+        let num_operations = self.estimated_num_operations.load(Ordering::SeqCst);
+        self.estimated_num_operations.fetch_sub(
+            num_operations.saturating_sub(stats.keys_copied as u64),
+            Ordering::SeqCst,
+        );
+        self.readers.insert(
+            compaction_file_id,
+            Mutex::new(file::new_reader(&self.dirs, compaction_file_id)?),
+        );
+        self.file_times
+            .insert(compaction_file_id, compaction_file_time);
 
-        // remove all unused files
-        let file_ids_to_rm: Vec<_> = self
+        // remove all unused files, oldest first, so a crash part way through can't leave
+        // a value behind without the later tombstone which removed it
+        let mut file_ids_to_rm: Vec<_> = self
             .readers
             .keys()
             .filter(|&&id| id < compaction_file_id)
             .cloned()
             .collect();
-        for id in file_ids_to_rm {
+        file_ids_to_rm.sort_unstable();
+        let bytes_reclaimed = self
+            .log_files_bytes(&file_ids_to_rm)?
+            .saturating_sub(self.log_files_bytes(&[compaction_file_id])?);
+        #[cfg(feature = "fault_injection")]
+        fault::inject(self.fault, FaultPoint::BeforeRemovingCompactedFiles);
+        for &id in &file_ids_to_rm {
             self.readers.remove(&id);
-            file::remove(&self.path, id)?;
+            self.file_times.remove(&id);
+            self.legacy_formats.remove(&id);
+            file::remove(&self.dirs, id)?;
+            stats.files_removed += 1;
+            #[cfg(feature = "fault_injection")]
+            fault::inject(self.fault, FaultPoint::AfterRemovingFirstCompactedFile);
         }
 
+        #[cfg(feature = "fault_injection")]
+        fault::inject(self.fault, FaultPoint::BeforeSavingIndex);
+        save_index(
+            &self.path,
+            compaction_file_id,
+            compaction_file_time,
+            &self.index,
+        )?;
+        // drop the keys removed since the last rebuild
+        #[cfg(feature = "bloom_filter")]
+        self.rebuild_bloom_filter();
+
+        let finished_at = self.next_timestamp()?;
+        self.write_compaction_record(CompactionRecord {
+            files_removed: file_ids_to_rm,
+            keys_preserved: stats.keys_copied as u64,
+            bytes_reclaimed,
+            started_at,
+            finished_at,
+        })?;
+        stats.duration = start.elapsed();
+        self.telemetry.on_compact(&stats);
+        Ok(bytes_reclaimed)
+    }
+}
+
+impl Drop for InternalKvStore {
+    fn drop(&mut self) {
+        // best effort, there is nobody left to report an error to
+        let _ = self.flush_pending_writes();
+    }
+}
+
+impl KvStoreExt for KvStore {
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let start = Instant::now();
+        let mut store = self.store.lock();
+
+        store
+            .set_with_ttl(key.clone(), value, ttl)
+            .map_err(|e| store.on_write_error(e))?;
+        store.telemetry.on_set(&key, start.elapsed());
         Ok(())
     }
 }
 
 impl KvsEngine for KvStore {
+    /// Shares the store's lock with other calls to `get`, only taking it exclusively if the
+    /// store has to change first, see `InternalKvStore::try_get`.
     fn get(&self, key: String) -> Result<Option<String>> {
-        let mut store = self.store.lock().unwrap();
+        let _reader = self.readers.enter();
+        let start = Instant::now();
+        let store = self.store.read();
+        if let Some(value) = store.try_get(&key) {
+            return store.finish_get(&key, value?, start);
+        }
+        drop(store);
 
-        if let Some(&ValueInfo {
-            file_offset,
-            file_id,
-            size,
-        }) = store.index.get(&key)
-        {
-            let reader = store
-                .readers
-                .get_mut(&file_id)
-                .expect("Reader not found for file ID");
-            reader.seek(SeekFrom::Start(file_offset.0))?;
+        let mut store = self.store.lock();
+        let value = store.get(&key)?;
+        store.finish_get(&key, value, start)
+    }
 
-            let Command { value, .. } = serde_json::from_reader(reader.take(size.0))?;
-            match value {
-                None => Err(KvsError::UnexpectedCommand.into()),
-                Some(_) => Ok(value),
-            }
-        } else {
-            Ok(None)
-        }
+    /// Only looks in the index, so the value is never read from disk.
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        self.store.lock().contains_key(key)
+    }
+
+    /// Looks up every key while holding the lock once, rather than once per key.
+    fn get_bulk(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let start = Instant::now();
+        let mut store = self.store.lock();
+
+        keys.iter()
+            .map(|key| {
+                let value = store.get(key)?;
+                store
+                    .telemetry
+                    .on_get(key, value.is_some(), start.elapsed());
+
+                match value {
+                    None if store.strict_mode => Err(KvsError::KeyNotFound.into()),
+                    value => Ok(value),
+                }
+            })
+            .collect()
+    }
+
+    /// Only finding the keys holds the lock. Values not in memory are read afterwards, from
+    /// readers opened while it was held.
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let _reader = self.readers.enter();
+        let scan = self.store.lock().scan_range(KeyRange::Prefix(prefix))?;
+        scan.read_values()
+    }
+
+    /// Only finding the keys holds the lock, as for `scan`.
+    fn get_range(&self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        let _reader = self.readers.enter();
+        let scan = self
+            .store
+            .lock()
+            .scan_range(KeyRange::Between(start, end))?;
+        scan.read_values()
+    }
+
+    /// Counts the keys in the index, so no values are read from disk.
+    fn count_range(&self, start: &str, end: &str) -> Result<u64> {
+        self.store.lock().count_range(KeyRange::Between(start, end))
+    }
+
+    /// Lists the keys in the index, so no values are read from disk.
+    fn keys(&self) -> Result<Vec<String>> {
+        self.store.lock().range_keys(KeyRange::Prefix(""))
+    }
+
+    /// The summary from `KvsEngine`, where `KvStore::detailed_stats` has more detail.
+    fn stats(&self) -> Result<StoreStats> {
+        let store = self.store.lock();
+        let file_ids: Vec<file::Id> = store.readers.keys().cloned().collect();
+        Ok(StoreStats {
+            num_keys: store.index.len(),
+            num_log_files: file_ids.len(),
+            total_bytes_on_disk: store.log_files_bytes(&file_ids)?,
+            uncompacted_bytes: store.uncompacted.0,
+            compaction_count: store.index_generation,
+        })
+    }
+
+    /// Holds the lock throughout, so every write waits for compaction to finish.
+    fn compact(&self) -> Result<u64> {
+        let mut store = self.store.lock();
+        store.apply_queued_writes()?;
+        store.flush_pending_writes()?;
+        store.compact()
     }
 
     fn set(&self, key: String, value: String) -> Result<()> {
-        let mut store = self.store.lock().unwrap();
+        let start = Instant::now();
+        let mut store = self.store.lock();
 
-        let write_pos = store.writer.offset;
+        store
+            .set(key.clone(), value)
+            .map_err(|e| store.on_write_error(e))?;
+        store.telemetry.on_set(&key, start.elapsed());
+        Ok(())
+    }
 
-        serde_json::to_writer(
-            &mut store.writer,
-            &Command {
-                key: key.clone(),
-                value: Some(value.clone()),
-            },
-        )?;
-        store.writer.flush()?;
+    /// Writes every command before flushing once, and holds the lock throughout, so no
+    /// reader sees part of the batch. A crash part way through can still leave the first
+    /// few commands on disk.
+    fn batch_write(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let start = Instant::now();
+        let mut store = self.store.lock();
 
-        let cmd_len = store.writer.offset - write_pos;
+        let keys: Vec<String> = pairs.iter().map(|(key, _)| key.clone()).collect();
+        store
+            .batch_write(pairs)
+            .map_err(|e| store.on_write_error(e))?;
+        for key in &keys {
+            store.telemetry.on_set(key, start.elapsed());
+        }
+        Ok(())
+    }
+
+    /// Holds the lock from looking up the key until the default is written, so no other
+    /// write can come in between.
+    fn get_or_insert(&self, key: String, default: String) -> Result<String> {
+        let start = Instant::now();
+        let mut store = self.store.lock();
 
-        if let Some(&ValueInfo { size, .. }) = store.index.get(&key) {
-            store.uncompacted += size;
+        if let Some(value) = store.get(&key)? {
+            store.telemetry.on_get(&key, true, start.elapsed());
+            return Ok(value);
         }
+        store
+            .set(key.clone(), default.clone())
+            .map_err(|e| store.on_write_error(e))?;
+        store.telemetry.on_set(&key, start.elapsed());
+        Ok(default)
+    }
 
-        let writer_id = store.writer.id;
-        store.index.insert(
-            key,
-            ValueInfo {
-                file_offset: Bytes(write_pos),
-                size: Bytes(cmd_len),
-                file_id: writer_id,
-            },
-        );
+    /// Holds the lock from reading the current value until the new one is written.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new_value: Option<String>,
+    ) -> Result<bool> {
+        let start = Instant::now();
+        let mut store = self.store.lock();
 
-        if store.uncompacted > MAX_UNCOMPACTED {
-            store.compact()?
+        let current = store.get(&key)?;
+        store
+            .telemetry
+            .on_get(&key, current.is_some(), start.elapsed());
+        if current != expected {
+            return Ok(false);
+        }
+        match new_value {
+            Some(value) => {
+                store
+                    .set(key.clone(), value)
+                    .map_err(|e| store.on_write_error(e))?;
+                store.telemetry.on_set(&key, start.elapsed());
+            }
+            // there is nothing to remove if it had no value
+            None if current.is_some() => {
+                store
+                    .remove(key.clone())
+                    .map_err(|e| store.on_write_error(e))?;
+                store.telemetry.on_remove(&key, start.elapsed());
+            }
+            None => {}
         }
+        Ok(true)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let start = Instant::now();
+        let mut store = self.store.lock();
 
+        store
+            .remove(key.clone())
+            .map_err(|e| store.on_write_error(e))?;
+        store.telemetry.on_remove(&key, start.elapsed());
         Ok(())
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        let mut store = self.store.lock().unwrap();
+    /// Holds the lock while every tombstone is written, so readers see all the keys in the
+    /// range or none of them.
+    fn delete_range(&self, start: &str, end: &str) -> Result<usize> {
+        let start_time = Instant::now();
+        let mut store = self.store.lock();
 
-        match store.index.get(&key) {
-            None => Err(KvsError::KeyNotFound.into()),
+        let keys = store
+            .delete_range(start, end)
+            .map_err(|e| store.on_write_error(e))?;
+        for key in &keys {
+            store.telemetry.on_remove(key, start_time.elapsed());
+        }
+        Ok(keys.len())
+    }
+}
 
-            Some(&ValueInfo {
-                size: prev_cmd_size,
-                ..
-            }) => {
-                let write_pos = store.writer.offset;
+/// Nanoseconds since the Unix epoch, as the log's timestamps are written.
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_nanos() as u64)
+}
 
-                serde_json::to_writer(
-                    &mut store.writer,
-                    &Command {
-                        key: key.clone(),
-                        value: None,
-                    },
-                )?;
-                store.writer.flush()?;
+/// Has a time to live ending at `expires_at` run out by `now`?
+fn past_ttl(expires_at: Option<u64>, now: u64) -> bool {
+    matches!(expires_at, Some(expires_at) if expires_at <= now)
+}
 
-                let cmd_len = store.writer.offset - write_pos;
-                store.uncompacted = store.uncompacted + prev_cmd_size + Bytes(cmd_len);
+/// Copy the commands holding a value to `writer` for compaction, returning the number of bytes
+/// written.
+///
+/// They are read in `source_format`, the format of the file they are in, and written in
+/// `format`.
+///
+/// Each command is checksummed again with `algorithm`, so entries written before it was
+/// chosen get one. A command which doesn't match its checksum is copied as it is, so the
+/// corruption is still found when it is read rather than given a valid checksum.
+fn copy_value(
+    reader: &mut BufReader<File>,
+    val_info: &ValueInfo,
+    source_format: &Format,
+    format: &Format,
+    algorithm: ChecksumAlgorithm,
+    writer: &mut KvsWriter,
+) -> Result<u64> {
+    reader.seek(SeekFrom::Start(val_info.file_offset.0))?;
+    let start = writer.offset;
+    let mut commands = source_format.reader(reader.take(val_info.size.0));
+    for _ in 0..val_info.chunks {
+        let command = match commands.next() {
+            Some(command) => command?,
+            None => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+        };
+        if command.checksum_matches() {
+            format.write(writer, &command.with_checksum(algorithm))?;
+        } else {
+            format.write(writer, &command)?;
+        }
+    }
+    Ok(writer.offset - start)
+}
 
-                store.index.remove(&key);
+/// The open markers and compaction records at the start of a log file, which come before
+/// any other command, and the number of bytes they take up.
+fn leading_markers(reader: BufReader<File>, format: &Format) -> Result<(Vec<Command>, Bytes)> {
+    let mut commands = format.reader(reader);
+    let mut markers = Vec::new();
+    let mut markers_len = 0;
+    while let Some(Ok(command)) = commands.next() {
+        if !command.is_marker() {
+            break;
+        }
+        markers.push(command);
+        markers_len = commands.byte_offset();
+    }
+    Ok((markers, Bytes(markers_len)))
+}
 
-                if store.uncompacted > MAX_UNCOMPACTED {
-                    store.compact()?
-                }
+/// Fraction of `disk_bytes` which isn't redundant, or 1.0 if there is nothing on disk.
+fn live_ratio(disk_bytes: Bytes, uncompacted: Bytes) -> f64 {
+    if disk_bytes.0 == 0 {
+        return 1.0;
+    }
+    let redundant = uncompacted.0.min(disk_bytes.0);
+    (disk_bytes.0 - redundant) as f64 / disk_bytes.0 as f64
+}
 
-                Ok(())
-            }
+/// Split `value` into pieces of at most `max_len` bytes, without splitting any characters.
+fn split_value(mut value: &str, max_len: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    while value.len() > max_len && max_len > 0 {
+        let mut end = max_len;
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            // a single character is longer than the limit
+            end = value.chars().next().map_or(value.len(), char::len_utf8);
         }
+        let (chunk, rest) = value.split_at(end);
+        chunks.push(chunk);
+        value = rest;
     }
+    chunks.push(value);
+    chunks
 }
 
-/// Operations which can be performed on the database.
-/// A 'remove' command has `value` equal to `None`.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Command {
-    #[serde(rename = "k")]
-    key: String,
+/// Find the start of the next serialised `Command` at or after `from`, or the end of `contents`.
+fn find_next_command(contents: &[u8], from: usize) -> usize {
+    const COMMAND_START: &[u8] = br#"{"k":"#;
 
-    #[serde(rename = "v")]
-    value: Option<String>,
+    contents
+        .get(from..)
+        .and_then(|rest| {
+            rest.windows(COMMAND_START.len())
+                .position(|window| window == COMMAND_START)
+        })
+        .map_or(contents.len(), |position| from + position)
 }
 
 fn load_file_into_index(
     file_id: file::Id,
     reader: &mut BufReader<File>,
     index: &mut Index,
-) -> Result<Bytes> {
-    let deserializer = serde_json::Deserializer::from_reader(reader);
-    let mut commands = deserializer.into_iter::<Command>();
+    format: &Format,
+    options: &KvStoreOptions,
+) -> Result<(Bytes, u64)> {
+    let file_len = Bytes(reader.get_ref().metadata()?.len());
+    let mut commands = format.reader(reader);
 
+    let now = now_nanos();
     let mut uncompacted = Bytes(0);
+    let mut num_operations = 0;
     let mut file_offset = Bytes(0);
+    // start offset and next expected chunk index of a split value being read
+    let mut partial: Option<(String, Bytes, u32)> = None;
     while let Some(command) = commands.next() {
-        let next_file_offset: Bytes = commands.byte_offset().try_into()?;
+        let next_file_offset = Bytes(commands.byte_offset());
         let cmd_size = next_file_offset - file_offset;
 
-        let Command { key, value } = command?;
+        let Command {
+            key,
+            value,
+            chunk,
+            written_at,
+            nonce,
+            open,
+            expires_at,
+            compaction,
+            ..
+        } = match (command, options.validation_mode) {
+            (Ok(command), _) if command.checksum_matches() => command,
+            // a write cut short by a crash rather than corruption, so everything before it is fine
+            (Err(ReadError::Truncated), _) => {
+                options.sink.on_truncated_log(file_id, file_offset.0);
+                uncompacted += file_len - file_offset;
+                break;
+            }
+            (Err(e), ValidationMode::ErrorOnCorrupt) => return Err(e.into()),
+            (Ok(_), ValidationMode::ErrorOnCorrupt) => {
+                return Err(KvsError::ChecksumMismatch {
+                    file_id,
+                    offset: file_offset.0,
+                }
+                .into())
+            }
+            (_, mode) => {
+                if mode == ValidationMode::ReportCorrupt {
+                    options.sink.on_corrupt_entry(file_id, file_offset.0);
+                }
+                // nothing after the corrupt entry can be trusted, and compaction will drop it
+                uncompacted += file_len - file_offset;
+                break;
+            }
+        };
+        if open || compaction.is_some() {
+            // only there for `KvStore::list_server_restarts` and `KvStore::list_compactions`,
+            // and kept by compaction
+            file_offset = next_file_offset;
+            continue;
+        }
+
+        let (start_offset, chunks) = match chunk {
+            None => (file_offset, 1),
+            Some(Chunk { index, count }) => {
+                let start_offset = match partial.take() {
+                    Some((partial_key, start, next)) if partial_key == key && next == index => {
+                        Some(start)
+                    }
+                    abandoned => {
+                        // chunks of a value which was never completely written
+                        if let Some((_, start, _)) = abandoned {
+                            uncompacted += file_offset - start;
+                        }
+                        if index == 0 {
+                            Some(file_offset)
+                        } else {
+                            uncompacted += cmd_size;
+                            None
+                        }
+                    }
+                };
+
+                match start_offset {
+                    Some(start) if index + 1 == count => (start, count),
+                    Some(start) => {
+                        partial = Some((key, start, index + 1));
+                        file_offset = next_file_offset;
+                        continue;
+                    }
+                    None => {
+                        file_offset = next_file_offset;
+                        continue;
+                    }
+                }
+            }
+        };
+
+        // any split value still being read was interrupted by this command
+        if let Some((_, start, _)) = partial.take() {
+            uncompacted += file_offset - start;
+        }
+        num_operations += 1;
 
         // value is being overwritten
         if let Some(ValueInfo {
@@ -329,14 +3154,29 @@ fn load_file_into_index(
         }
 
         match value {
+            // Set, which has since run out of time to live, so reads as a remove
+            Some(_) if past_ttl(expires_at, now) => {
+                uncompacted += next_file_offset - start_offset;
+                index.remove(&key);
+            }
             // Set
-            Some(_) => {
+            Some(value) => {
                 index.insert(
                     key,
                     ValueInfo {
-                        file_offset,
-                        size: cmd_size,
+                        file_offset: start_offset,
+                        size: next_file_offset - start_offset,
+                        chunks,
                         file_id,
+                        written_at,
+                        nonce,
+                        expires_at,
+                        // split values are too big to be worth keeping in memory
+                        cached_value: if chunks == 1 {
+                            inline_value(&value, options.inline_values)
+                        } else {
+                            None
+                        },
                     },
                 );
             }
@@ -350,5 +3190,130 @@ fn load_file_into_index(
         file_offset = next_file_offset;
     }
 
-    Ok(uncompacted)
+    if let Some((_, start, _)) = partial {
+        uncompacted += file_offset - start;
+    }
+
+    Ok((uncompacted, num_operations))
+}
+
+/// Fail with `KvsError::ReadOnlyFilesystem` unless a file can be created in `dir`.
+fn check_writable(dir: &Path) -> Result<()> {
+    let probe_path = dir.join(".kvs-write-check.tmp");
+    match File::create(&probe_path) {
+        Ok(probe) => {
+            drop(probe);
+            Ok(fs::remove_file(probe_path)?)
+        }
+        Err(_e) => Err(KvsError::ReadOnlyFilesystem {
+            path: dir.to_path_buf(),
+        }
+        .into()),
+    }
+}
+
+/// Take an advisory lock on `dir`, exclusive unless `shared`, or fail with
+/// `KvsError::AlreadyOpen` if another store holds a conflicting lock. The lock lasts until the
+/// returned file is closed.
+fn lock_dir(dir: &Path, shared: bool) -> Result<File> {
+    let dir_file = File::open(dir)?;
+    let locked = if shared {
+        // not the standard library's method of the same name, which returns another error type
+        FileExt::try_lock_shared(&dir_file)
+    } else {
+        dir_file.try_lock_exclusive()
+    };
+    match locked {
+        Ok(()) => Ok(dir_file),
+        Err(ref e) if e.kind() == lock_contended_error().kind() => Err(KvsError::AlreadyOpen {
+            path: dir.to_path_buf(),
+        }
+        .into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Record the permanently deleted keys, replacing the previous record.
+fn save_deleted(kvs_dir: &Path, keys: &BTreeSet<String>) -> Result<()> {
+    let tmp_path = kvs_dir.join(format!("{}.tmp", DELETED_FILE));
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer(&mut writer, keys)?;
+    writer.flush()?;
+    drop(writer);
+
+    Ok(fs::rename(tmp_path, kvs_dir.join(DELETED_FILE))?)
+}
+
+/// Load the permanently deleted keys, if any have been recorded.
+fn load_deleted(kvs_dir: &Path) -> Result<BTreeSet<String>> {
+    match File::open(kvs_dir.join(DELETED_FILE)) {
+        Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Record the highest log file ID used so far, before any file with that ID is created.
+fn save_max_file_id(kvs_dir: &Path, id: file::Id) -> Result<()> {
+    let tmp_path = kvs_dir.join(format!("{}.tmp", MAX_FILE_ID_FILE));
+    fs::write(&tmp_path, id.to_string())?;
+
+    Ok(fs::rename(tmp_path, kvs_dir.join(MAX_FILE_ID_FILE))?)
+}
+
+/// Load the highest log file ID used so far, or 0 for a store which hasn't recorded one.
+fn load_max_file_id(kvs_dir: &Path) -> Result<file::Id> {
+    match fs::read_to_string(kvs_dir.join(MAX_FILE_ID_FILE)) {
+        Ok(id) => Ok(id.trim().parse()?),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Save the index after compaction, when every value is in the compacted log `compacted_file_id`.
+///
+/// The file is written to the side and renamed into place, so a crash can't leave it half written.
+fn save_index(
+    kvs_dir: &Path,
+    compacted_file_id: file::Id,
+    compacted_file_time: SystemTime,
+    index: &Index,
+) -> Result<()> {
+    let tmp_path = kvs_dir.join(format!("{}.tmp", INDEX_FILE));
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    bincode::serialize_into(
+        &mut writer,
+        &(compacted_file_id, compacted_file_time, index),
+    )?;
+    writer.flush()?;
+    drop(writer);
+
+    Ok(fs::rename(tmp_path, kvs_dir.join(INDEX_FILE))?)
+}
+
+/// Load the index saved by the last compaction, if it still matches the log files.
+///
+/// Logs written since then still need loading on top. If the saved index is missing, unreadable or
+/// out of date, for example because a later compaction didn't finish, the logs are replayed in full.
+fn load_saved_index(
+    kvs_dir: &Path,
+    file_ids: &[file::Id],
+    inline_values: bool,
+) -> Option<(file::Id, SystemTime, Index)> {
+    let reader = BufReader::new(File::open(kvs_dir.join(INDEX_FILE)).ok()?);
+    let (compacted_file_id, compacted_file_time, mut index): (file::Id, SystemTime, Index) =
+        bincode::deserialize_from(reader).ok()?;
+
+    // compaction removes every older log, and the compacted log itself must still exist
+    if file_ids.first() != Some(&compacted_file_id) {
+        return None;
+    }
+
+    for val_info in index.values_mut() {
+        if !inline_values {
+            val_info.cached_value = None;
+        }
+    }
+
+    Some((compacted_file_id, compacted_file_time, index))
 }
@@ -0,0 +1,67 @@
+use super::file;
+use super::stats::CompactionStats;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Receives events about operations on a `KvStore`, for collecting metrics.
+///
+/// Install a sink with `KvStoreOptions::sink`. Events are reported after each successful operation,
+/// from the thread which made it, so implementations should be quick. Every method does nothing by
+/// default, so only the events of interest need implementing.
+pub trait TelemetrySink {
+    /// A `get` found a value for `key` or, if `found` is false, didn't.
+    fn on_get(&self, _key: &str, _found: bool, _duration: Duration) {}
+
+    /// A `set` wrote a value for `key`.
+    fn on_set(&self, _key: &str, _duration: Duration) {}
+
+    /// A `remove` deleted `key`.
+    fn on_remove(&self, _key: &str, _duration: Duration) {}
+
+    /// Compaction finished.
+    fn on_compact(&self, _stats: &CompactionStats) {}
+
+    /// Opening the store found an unreadable entry at `offset` in a log file, and skipped the
+    /// rest of the file. Only reported with `ValidationMode::ReportCorrupt`.
+    fn on_corrupt_entry(&self, _file_id: file::Id, _offset: u64) {}
+
+    /// Opening the store found a log file ending part way through an entry at `offset`, as left
+    /// by a crash during a write. Everything before it is used, whatever the `ValidationMode`.
+    fn on_truncated_log(&self, _file_id: file::Id, _offset: u64) {}
+}
+
+/// Discards every event, used when no sink is installed.
+struct NoopSink;
+
+impl TelemetrySink for NoopSink {}
+
+/// A shared `TelemetrySink`, wrapped so it can live in types which derive `Debug`.
+#[derive(Clone)]
+pub(super) struct Sink(Arc<dyn TelemetrySink + Send + Sync>);
+
+impl Sink {
+    pub(super) fn new(sink: Arc<dyn TelemetrySink + Send + Sync>) -> Sink {
+        Sink(sink)
+    }
+}
+
+impl Default for Sink {
+    fn default() -> Self {
+        Sink(Arc::new(NoopSink))
+    }
+}
+
+impl std::ops::Deref for Sink {
+    type Target = dyn TelemetrySink + Send + Sync;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl fmt::Debug for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TelemetrySink")
+    }
+}
@@ -1,11 +1,25 @@
 //! Implementations of the `KvsEngine` trait.
 
-mod sled;
 mod kvs;
+mod shadow;
+mod sled;
+mod stats;
 
+#[cfg(feature = "fault_injection")]
+pub use self::kvs::FaultPoint;
+#[cfg(feature = "testing")]
+pub use self::kvs::KvsWriter;
+pub use self::kvs::{
+    BackupHandle, BincodeCommandSerializer, ChecksumAlgorithm, Command, CommandSerializer,
+    CompactionRecord, CompactionStats, FileNamingScheme, IsolationLevel, JsonCommandSerializer,
+    KeyGuard, KvStore, KvStoreExt, KvStoreOptions, KvStoreStats, LogFileId, LogFileStats,
+    LogFormat, Snapshot, TelemetrySink, ValidationMode, KVS_DIR,
+};
+pub use self::shadow::ShadowEngine;
 pub use self::sled::{SledKvsEngine, SLED_DIR};
-pub use self::kvs::{KvStore, KVS_DIR};
+pub use self::stats::StoreStats;
 
+use crate::errors::KvsError;
 use crate::Result;
 
 /// Interface for a simple key-value store.
@@ -15,6 +29,90 @@ pub trait KvsEngine: Clone + Send + 'static {
     fn set(&self, key: String, value: String) -> Result<()>;
     /// Set the value for the given key, overwriting the previous value if it existed.
     fn get(&self, key: String) -> Result<Option<String>>;
+    /// Get the value for the given key, returning `KvsError::KeyNotFound` if it does not exist.
+    fn get_strict(&self, key: String) -> Result<String> {
+        self.get(key)?.ok_or_else(|| KvsError::KeyNotFound.into())
+    }
+    /// Does the given key have a value?
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        match self.get(key.to_owned()) {
+            Ok(value) => Ok(value.is_some()),
+            // engines in strict mode report missing keys as errors
+            Err(e) => match e.downcast::<KvsError>() {
+                Ok(KvsError::KeyNotFound) => Ok(false),
+                Ok(e) => Err(e.into()),
+                Err(e) => Err(e),
+            },
+        }
+    }
+    /// Get the values for several keys, in the same order as `keys`.
+    fn get_bulk(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        keys.iter().map(|key| self.get(key.clone())).collect()
+    }
+    /// Get the value for the given key, first setting it to `default` if it has none.
+    ///
+    /// This is atomic, so when several callers race to insert, every one of them gets the
+    /// same value back.
+    fn get_or_insert(&self, key: String, default: String) -> Result<String>;
+    /// Set the value for the given key to `new_value`, or remove it if that is `None`, only if
+    /// its current value is `expected`, with `None` meaning it has no value. Returns whether
+    /// it was swapped.
+    ///
+    /// This is atomic, so of several callers racing to swap the same value only one succeeds.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new_value: Option<String>,
+    ) -> Result<bool>;
+    /// Set every pair, as if by `set`, with none of them visible to other callers until they all
+    /// are. If a key appears more than once, the last value is kept.
+    fn batch_write(&self, pairs: Vec<(String, String)>) -> Result<()>;
     /// Remove the value for the given key. Will error if the key does not exist.
     fn remove(&self, key: String) -> Result<()>;
+    /// Remove every key from `start` up to, but not including, `end`, returning how many
+    /// there were. Unlike `remove`, finding no keys isn't an error.
+    fn delete_range(&self, start: &str, end: &str) -> Result<usize>;
+    /// Get every key starting with `prefix`, with its value, sorted by key.
+    /// An empty prefix gets the whole store.
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>>;
+    /// Get every key from `start` up to, but not including, `end`, with its value, sorted
+    /// by key. Empty if `end` doesn't sort after `start`.
+    fn get_range(&self, start: &str, end: &str) -> Result<Vec<(String, String)>>;
+    /// Count the keys from `start` up to, but not including, `end`, as `get_range` would
+    /// return them, ideally without reading their values.
+    fn count_range(&self, start: &str, end: &str) -> Result<u64> {
+        Ok(self.get_range(start, end)?.len() as u64)
+    }
+    /// Get every key in the store, sorted, ideally without reading their values.
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(self
+            .scan("")?
+            .into_iter()
+            .map(|(key, _value)| key)
+            .collect())
+    }
+    /// Reclaim the space taken up by overwritten and removed values now, rather than when the
+    /// engine next decides to, returning how many bytes were freed. Engines which reclaim
+    /// space as they go do nothing, and free 0 bytes.
+    fn compact(&self) -> Result<u64> {
+        Ok(0)
+    }
+    /// Get statistics about the engine's storage, or `KvsError::Unsupported` if it doesn't
+    /// keep them.
+    fn stats(&self) -> Result<StoreStats> {
+        Err(KvsError::Unsupported.into())
+    }
+    /// Move the value for `old_key` to `new_key`, overwriting any value `new_key` already has.
+    /// Will error if `old_key` does not exist.
+    ///
+    /// This is a get, set and remove, so other clients can see both keys part way through.
+    fn rename(&self, old_key: String, new_key: String) -> Result<()> {
+        let value = self.get_strict(old_key.clone())?;
+        if old_key != new_key {
+            self.set(new_key, value)?;
+            self.remove(old_key)?;
+        }
+        Ok(())
+    }
 }
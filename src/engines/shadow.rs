@@ -0,0 +1,162 @@
+use super::{KvsEngine, StoreStats};
+use crate::Result;
+use slog::Logger;
+use std::fmt::Debug;
+
+/// Sends every operation to two engines, returning the result from `primary` and logging a
+/// warning whenever `shadow` disagrees with it.
+///
+/// Useful when migrating between engines, to gain confidence that the new one behaves the
+/// same before switching over. The shadow should start with the same data as the primary.
+#[derive(Debug, Clone)]
+pub struct ShadowEngine<Primary: KvsEngine, Shadow: KvsEngine> {
+    primary: Primary,
+    shadow: Shadow,
+    log: Logger,
+}
+
+impl<Primary: KvsEngine, Shadow: KvsEngine> ShadowEngine<Primary, Shadow> {
+    /// Create a new shadowing engine, logging discrepancies to `log`.
+    pub fn new(primary: Primary, shadow: Shadow, log: Logger) -> ShadowEngine<Primary, Shadow> {
+        ShadowEngine {
+            primary,
+            shadow,
+            log,
+        }
+    }
+
+    fn compare<T: Debug>(&self, op: &str, key: &str, primary: &Result<T>, shadow: &Result<T>) {
+        let primary = describe(primary);
+        let shadow = describe(shadow);
+        if primary != shadow {
+            warn!(self.log, "Shadow engine disagrees with primary";
+                "op" => op,
+                "key" => key,
+                "primary" => primary,
+                "shadow" => shadow
+            );
+        }
+    }
+}
+
+/// Describe a result, so results including errors can be compared and logged.
+fn describe<T: Debug>(result: &Result<T>) -> String {
+    match result {
+        Ok(value) => format!("{:?}", value),
+        Err(e) => format!("error: {}", e),
+    }
+}
+
+impl<Primary: KvsEngine, Shadow: KvsEngine> KvsEngine for ShadowEngine<Primary, Shadow> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let primary = self.primary.get(key.clone());
+        let shadow = self.shadow.get(key.clone());
+        self.compare("get", &key, &primary, &shadow);
+        primary
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        let primary = self.primary.contains_key(key);
+        let shadow = self.shadow.contains_key(key);
+        self.compare("contains_key", key, &primary, &shadow);
+        primary
+    }
+
+    fn batch_write(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let first_key = pairs
+            .first()
+            .map_or_else(String::new, |(key, _)| key.clone());
+        let primary = self.primary.batch_write(pairs.clone());
+        let shadow = self.shadow.batch_write(pairs);
+        self.compare("batch_write", &first_key, &primary, &shadow);
+        primary
+    }
+
+    fn get_or_insert(&self, key: String, default: String) -> Result<String> {
+        let primary = self.primary.get_or_insert(key.clone(), default.clone());
+        let shadow = self.shadow.get_or_insert(key.clone(), default);
+        self.compare("get_or_insert", &key, &primary, &shadow);
+        primary
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new_value: Option<String>,
+    ) -> Result<bool> {
+        let primary =
+            self.primary
+                .compare_and_swap(key.clone(), expected.clone(), new_value.clone());
+        let shadow = self
+            .shadow
+            .compare_and_swap(key.clone(), expected, new_value);
+        self.compare("compare_and_swap", &key, &primary, &shadow);
+        primary
+    }
+
+    fn delete_range(&self, start: &str, end: &str) -> Result<usize> {
+        let primary = self.primary.delete_range(start, end);
+        let shadow = self.shadow.delete_range(start, end);
+        self.compare("delete_range", start, &primary, &shadow);
+        primary
+    }
+
+    fn get_range(&self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        let primary = self.primary.get_range(start, end);
+        let shadow = self.shadow.get_range(start, end);
+        self.compare("get_range", start, &primary, &shadow);
+        primary
+    }
+
+    fn count_range(&self, start: &str, end: &str) -> Result<u64> {
+        let primary = self.primary.count_range(start, end);
+        let shadow = self.shadow.count_range(start, end);
+        self.compare("count_range", start, &primary, &shadow);
+        primary
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let primary = self.primary.keys();
+        let shadow = self.shadow.keys();
+        self.compare("keys", "", &primary, &shadow);
+        primary
+    }
+
+    /// Compacts both engines, without comparing how much they freed, which is expected to
+    /// differ.
+    fn compact(&self) -> Result<u64> {
+        let primary = self.primary.compact();
+        let shadow = self.shadow.compact();
+        if let Err(ref e) = shadow {
+            warn!(self.log, "Shadow engine failed to compact"; "error" => %e);
+        }
+        primary
+    }
+
+    /// The primary's statistics, as the engines store data differently.
+    fn stats(&self) -> Result<StoreStats> {
+        self.primary.stats()
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let primary = self.primary.set(key.clone(), value.clone());
+        let shadow = self.shadow.set(key.clone(), value);
+        self.compare("set", &key, &primary, &shadow);
+        primary
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let primary = self.primary.scan(prefix);
+        let shadow = self.shadow.scan(prefix);
+        self.compare("scan", prefix, &primary, &shadow);
+        primary
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let primary = self.primary.remove(key.clone());
+        let shadow = self.shadow.remove(key.clone());
+        self.compare("remove", &key, &primary, &shadow);
+        primary
+    }
+}
@@ -1,7 +1,7 @@
 use super::KvsEngine;
 use crate::errors::KvsError;
 use crate::Result;
-use sled::Db;
+use sled::{Batch, Db, IVec};
 use std::fs;
 use std::path::PathBuf;
 use std::str;
@@ -45,6 +45,12 @@ impl KvsEngine for SledKvsEngine {
         }
     }
 
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        let store = self.db.lock().unwrap();
+
+        Ok(store.contains_key(key)?)
+    }
+
     fn set(&self, key: String, value: String) -> Result<()> {
         let store = self.db.lock().unwrap();
 
@@ -53,6 +59,118 @@ impl KvsEngine for SledKvsEngine {
         Ok(())
     }
 
+    fn batch_write(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let store = self.db.lock().unwrap();
+
+        let mut batch = Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.as_bytes(), value.into_bytes());
+        }
+        store.apply_batch(batch)?;
+        store.flush()?;
+        Ok(())
+    }
+
+    fn get_or_insert(&self, key: String, default: String) -> Result<String> {
+        let store = self.db.lock().unwrap();
+
+        // only swaps if there is no value yet, otherwise returns the current one
+        match store.compare_and_swap(&key, None::<&[u8]>, Some(default.as_bytes()))? {
+            Ok(()) => {
+                store.flush()?;
+                Ok(default)
+            }
+            Err(e) => {
+                let buf = e.current.expect("swap only fails if the key has a value");
+                Ok(String::from_utf8(buf.to_vec())?)
+            }
+        }
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new_value: Option<String>,
+    ) -> Result<bool> {
+        let store = self.db.lock().unwrap();
+
+        match store.compare_and_swap(&key, expected.as_deref(), new_value.as_deref())? {
+            Ok(()) => {
+                store.flush()?;
+                Ok(true)
+            }
+            Err(_current) => Ok(false),
+        }
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        // sled iterates in key order, and only the traversal needs the lock
+        let entries = {
+            let store = self.db.lock().unwrap();
+            store
+                .scan_prefix(prefix)
+                .collect::<sled::Result<Vec<_>>>()?
+        };
+
+        decode_entries(entries)
+    }
+
+    fn delete_range(&self, start: &str, end: &str) -> Result<usize> {
+        if start >= end {
+            return Ok(0);
+        }
+        let store = self.db.lock().unwrap();
+
+        // sled has no range removal, but a batch is still applied atomically
+        let mut batch = Batch::default();
+        let mut count = 0;
+        for key in store.range(start..end).keys() {
+            batch.remove(key?);
+            count += 1;
+        }
+        store.apply_batch(batch)?;
+        store.flush()?;
+        Ok(count)
+    }
+
+    fn get_range(&self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        let entries = {
+            let store = self.db.lock().unwrap();
+            store.range(start..end).collect::<sled::Result<Vec<_>>>()?
+        };
+
+        decode_entries(entries)
+    }
+
+    fn count_range(&self, start: &str, end: &str) -> Result<u64> {
+        if start >= end {
+            return Ok(0);
+        }
+        let store = self.db.lock().unwrap();
+
+        let mut count = 0;
+        for key in store.range(start..end).keys() {
+            key?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let keys = {
+            let store = self.db.lock().unwrap();
+            store.iter().keys().collect::<sled::Result<Vec<_>>>()?
+        };
+
+        keys.into_iter()
+            .map(|key| Ok(String::from_utf8(key.to_vec())?))
+            .collect()
+    }
+
     fn remove(&self, key: String) -> Result<()> {
         let store = self.db.lock().unwrap();
 
@@ -65,3 +183,16 @@ impl KvsEngine for SledKvsEngine {
         }
     }
 }
+
+/// Convert entries read from sled back into strings.
+fn decode_entries(entries: Vec<(IVec, IVec)>) -> Result<Vec<(String, String)>> {
+    entries
+        .into_iter()
+        .map(|(key, value)| {
+            Ok((
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            ))
+        })
+        .collect()
+}
@@ -0,0 +1,23 @@
+use serde::{Deserialize, Serialize};
+
+/// Statistics any engine can report, returned by `KvsEngine::stats`.
+///
+/// `KvStore::detailed_stats` gives more detail about a `KvStore`.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreStats {
+    /// Keys with a value
+    pub num_keys: usize,
+
+    /// Log files on disk, including the one being written to
+    pub num_log_files: usize,
+
+    /// Size of every log file
+    pub total_bytes_on_disk: u64,
+
+    /// Bytes of log which the next compaction would reclaim
+    pub uncompacted_bytes: u64,
+
+    /// Compactions since the store was opened
+    pub compaction_count: u64,
+}
@@ -1,25 +1,122 @@
-use failure;
+use std::io;
+use std::path::PathBuf;
 use std::result;
 
 /// Convenience Result type.
-pub type Result<T> = result::Result<T, failure::Error>;
+pub type Result<T> = result::Result<T, anyhow::Error>;
 
 /// Errors
-#[derive(Debug, failure::Fail, Clone, Copy)]
+#[derive(Debug, thiserror::Error, Clone)]
 pub enum KvsError {
     /// An attempt was made to open the KV store in a non-directory file path
-    #[fail(display = "Not a directory")]
+    #[error("Not a directory")]
     NotADirectory,
 
     /// A key was not found in the database
-    #[fail(display = "Key not found")]
+    #[error("Key not found")]
     KeyNotFound,
 
     /// An unexpected command was found in the database - probably a program error
-    #[fail(display = "Unexpected command found in log")]
+    #[error("Unexpected command found in log")]
     UnexpectedCommand,
 
     /// An unexpected file name was found
-    #[fail(display = "Unexpected file name, should be an integer")]
+    #[error("Unexpected file name, should be an integer")]
     UnexpectedFileName,
+
+    /// A snapshot was read after compaction removed the data it refers to
+    #[error("Snapshot expired")]
+    SnapshotExpired,
+
+    /// The store has no log file with the given ID
+    #[error("Log file not found")]
+    LogFileNotFound,
+
+    /// The key's value is in a log file older than `KvStoreOptions::max_log_age`
+    #[error("Data expired")]
+    DataExpired,
+
+    /// No secondary index was added with the given name
+    #[error("Secondary index not found")]
+    IndexNotFound,
+
+    /// The data directory can't be written to, for example because its filesystem is read-only
+    #[error("Data directory {path:?} is not writable")]
+    ReadOnlyFilesystem {
+        /// The directory which couldn't be written to
+        path: PathBuf,
+    },
+
+    /// The key was removed with `KvStoreOptions::permanent_delete` enabled, so can't be set again
+    #[error("Key permanently deleted")]
+    PermanentlyDeleted,
+
+    /// Compaction removed a log file before `KvStore::backup_nonblocking` could link it
+    #[error("Backup interrupted by compaction")]
+    BackupInterrupted,
+
+    /// Another `KvStore` already has the data directory open, in this process or another
+    #[error("Data directory {path:?} is already in use")]
+    AlreadyOpen {
+        /// The directory which is locked
+        path: PathBuf,
+    },
+
+    /// There is no space left on the device holding the logs.
+    /// The store stops accepting writes until it is reopened.
+    #[error("Disk full")]
+    DiskFull,
+
+    /// A log entry's contents don't match the checksum written with it,
+    /// see `KvStoreOptions::checksum_algorithm`
+    #[error("Checksum mismatch in log file {file_id} at offset {offset}")]
+    ChecksumMismatch {
+        /// The log file holding the entry
+        file_id: u64,
+        /// Where the entry starts in the file, in bytes
+        offset: u64,
+    },
+
+    /// The store's logs were written in a format it can't be converted from to the one it was
+    /// opened with, see `KvStoreOptions::serializer`
+    #[error("Log format mismatch")]
+    FormatMismatch,
+
+    /// Setting a new key would grow the index past `KvStoreOptions::max_index_memory_bytes`
+    #[error("Index memory limit exceeded")]
+    IndexMemoryLimitExceeded,
+
+    /// The system ran out of memory during an I/O operation
+    #[error("Out of memory")]
+    OutOfMemory,
+
+    /// The engine doesn't support the operation, see `KvsEngine::stats`
+    #[error("Not supported by this engine")]
+    Unsupported,
+
+    /// A write was made to a store opened with `KvStore::open_read_only`
+    #[error("Store is read only")]
+    ReadOnly,
+
+    /// A thread pool was asked for no threads, so would never run any jobs
+    #[error("Thread pool needs at least one thread")]
+    NoThreads,
+}
+
+/// Replace an I/O error meaning the system has run out of space or memory with `DiskFull` or
+/// `OutOfMemory`, including one from serialising a command. Other errors are returned as they are.
+pub(crate) fn classify_io_error(e: anyhow::Error) -> anyhow::Error {
+    let io_error = match e.downcast::<io::Error>() {
+        Ok(io_error) => io_error,
+        Err(e) => match e.downcast::<serde_json::Error>() {
+            Ok(json_error) if json_error.is_io() => io::Error::from(json_error),
+            Ok(json_error) => return json_error.into(),
+            Err(e) => return e,
+        },
+    };
+    match io_error.kind() {
+        io::ErrorKind::StorageFull => KvsError::DiskFull.into(),
+        io::ErrorKind::OutOfMemory => KvsError::OutOfMemory.into(),
+        _ => io_error.into(),
+    }
 }
@@ -18,12 +18,28 @@ extern crate slog;
 
 mod engines;
 mod errors;
+mod metrics;
 mod network;
 pub mod thread_pool;
 
-pub use self::network::KvsClient;
-pub use self::engines::SledKvsEngine;
+#[cfg(feature = "fault_injection")]
+pub use self::engines::FaultPoint;
 pub use self::engines::KvStore;
 pub use self::engines::KvsEngine;
-pub use self::errors::Result;
-pub use self::network::{existing_engine, EngineType, KvsServer};
+#[cfg(feature = "testing")]
+pub use self::engines::KvsWriter;
+pub use self::engines::ShadowEngine;
+pub use self::engines::SledKvsEngine;
+pub use self::engines::{
+    BackupHandle, BincodeCommandSerializer, ChecksumAlgorithm, Command, CommandSerializer,
+    CompactionRecord, CompactionStats, FileNamingScheme, IsolationLevel, JsonCommandSerializer,
+    KeyGuard, KvStoreExt, KvStoreOptions, KvStoreStats, LogFileId, LogFileStats, LogFormat,
+    Snapshot, StoreStats, TelemetrySink, ValidationMode,
+};
+pub use self::errors::{KvsError, Result};
+pub use self::metrics::LatencyHistogram;
+pub use self::network::{
+    existing_engine, BoundKvsServer, EngineType, KvsServer, KvsServerHandle,
+    DEFAULT_MAX_RESPONSE_BYTES, DEFAULT_SLOW_REQUEST_THRESHOLD, SHUTDOWN,
+};
+pub use self::network::{KvsClient, KvsClientBuilder, RetryPolicy};
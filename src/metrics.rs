@@ -0,0 +1,73 @@
+//! Latency measurements
+
+use std::time::Duration;
+
+/// Upper bounds of every bucket but the last, which holds everything slower
+const BUCKET_BOUNDS: [Duration; 4] = [
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+];
+
+/// Counts of operations by how long they took, returned by `KvStore::get_latency_histogram`.
+///
+/// The buckets are 0–10µs, 10–100µs, 100µs–1ms, 1–10ms and over 10ms. Percentiles are
+/// reported as the upper bound of the bucket they fall in, or the slowest operation recorded
+/// if that is sooner, so they are only as precise as the buckets.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LatencyHistogram {
+    counts: [u64; BUCKET_BOUNDS.len() + 1],
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    pub(crate) fn record(&mut self, latency: Duration) {
+        let bucket = BUCKET_BOUNDS
+            .iter()
+            .position(|&bound| latency < bound)
+            .unwrap_or(BUCKET_BOUNDS.len());
+        self.counts[bucket] += 1;
+        self.max = self.max.max(latency);
+    }
+
+    /// The number of operations recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The number of operations recorded in each bucket, fastest first.
+    pub fn bucket_counts(&self) -> [u64; BUCKET_BOUNDS.len() + 1] {
+        self.counts
+    }
+
+    /// The latency which a fraction `quantile` of operations took no longer than, for example
+    /// 0.99 for the 99th percentile. Zero if nothing has been recorded.
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        let rank = ((quantile * self.count() as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let bound = BUCKET_BOUNDS.get(bucket).copied().unwrap_or(self.max);
+                return bound.min(self.max);
+            }
+        }
+        Duration::from_secs(0)
+    }
+
+    /// The median latency.
+    pub fn p50(&self) -> Duration {
+        self.percentile(0.5)
+    }
+
+    /// The 99th percentile latency.
+    pub fn p99(&self) -> Duration {
+        self.percentile(0.99)
+    }
+
+    /// The 99.9th percentile latency.
+    pub fn p999(&self) -> Duration {
+        self.percentile(0.999)
+    }
+}
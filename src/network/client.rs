@@ -1,93 +1,364 @@
 use super::data::{ErrorType, NetworkCommand, NetworkResponse};
+use super::retry::RetryPolicy;
+use crate::engines::StoreStats;
 use crate::Result;
-use std::net::{TcpStream, ToSocketAddrs};
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
 
 /// Client for accessing KVS over a network connection.
 #[allow(clippy::module_name_repetitions)]
 #[derive(Debug)]
 pub struct KvsClient {
+    addrs: Vec<SocketAddr>,
     connection: TcpStream,
+    retry_policy: RetryPolicy,
+    /// The timeouts for reconnecting when retrying
+    builder: KvsClientBuilder,
 }
 
 impl KvsClient {
-    /// Create a connection to the KVS server.
+    /// Create a connection to the KVS server, without any timeouts.
+    ///
+    /// Use `KvsClientBuilder` to stop a stalled server hanging the client forever.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<KvsClient> {
-        Ok(KvsClient {
-            connection: TcpStream::connect(addr)?,
-        })
+        KvsClientBuilder::new().connect(addr)
     }
+
+    /// Retry operations which fail because of network problems, reconnecting for each attempt.
+    ///
+    /// The initial connection made by `connect` is not retried.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
     #[allow(missing_docs)]
     pub fn get(self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&self.connection, &NetworkCommand::Get { key })?;
-        let mut responses =
-            serde_json::Deserializer::from_reader(&self.connection).into_iter::<NetworkResponse>();
-
-        match responses.next() {
-            Some(response) => match response {
-                Ok(response) => match response {
-                    NetworkResponse::Error { code } => Err(code.into()),
-                    NetworkResponse::Empty => Ok(None),
-                    NetworkResponse::Value(value) => Ok(Some(value)),
-                },
-                Err(_e) => Err((Error::ResponseDeserialisation).into()),
-            },
-            None => Err((Error::NoResponse).into()),
+        match self.request(&NetworkCommand::Get { key })? {
+            NetworkResponse::Error { code } => Err(code.into()),
+            NetworkResponse::Empty => Ok(None),
+            NetworkResponse::Value(value) => Ok(Some(value)),
+            _ => Err(Error::UnexpectedResponse.into()),
+        }
+    }
+    /// Does the key have a value? Unlike `get`, the value is not sent back.
+    pub fn exists(self, key: String) -> Result<bool> {
+        match self.request(&NetworkCommand::Exists { key })? {
+            NetworkResponse::Error { code } => Err(code.into()),
+            NetworkResponse::Empty => Ok(false),
+            NetworkResponse::Bool(exists) => Ok(exists),
+            _ => Err(Error::UnexpectedResponse.into()),
+        }
+    }
+    /// Get the value for the key, first setting it to `default` if it has none.
+    /// The server does both at once, so no other client can set the key in between.
+    pub fn get_or_insert(self, key: String, default: String) -> Result<String> {
+        match self.request(&NetworkCommand::GetOrInsert { key, default })? {
+            NetworkResponse::Error { code } => Err(code.into()),
+            NetworkResponse::Value(value) => Ok(value),
+            _ => Err(Error::UnexpectedResponse.into()),
+        }
+    }
+    /// Set `key` to `new_value`, or remove it if that is `None`, only if its value is
+    /// `expected`. Returns whether it was swapped.
+    pub fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        new_value: Option<String>,
+    ) -> Result<bool> {
+        match self.request(&NetworkCommand::Cas {
+            key,
+            expected,
+            new_value,
+        })? {
+            NetworkResponse::Error { code } => Err(code.into()),
+            NetworkResponse::Bool(swapped) => Ok(swapped),
+            _ => Err(Error::UnexpectedResponse.into()),
+        }
+    }
+    /// Get every key from `start` up to, but not including, `end`, with its value, sorted
+    /// by key.
+    pub fn get_range(self, start: String, end: String) -> Result<Vec<(String, String)>> {
+        match self.request(&NetworkCommand::GetRange { start, end })? {
+            NetworkResponse::Error { code } => Err(code.into()),
+            NetworkResponse::Entries(entries) => Ok(entries),
+            _ => Err(Error::UnexpectedResponse.into()),
+        }
+    }
+    /// Count the keys from `start` up to, but not including, `end`.
+    pub fn count_range(self, start: String, end: String) -> Result<u64> {
+        match self.request(&NetworkCommand::CountRange { start, end })? {
+            NetworkResponse::Error { code } => Err(code.into()),
+            NetworkResponse::Count(count) => Ok(count),
+            _ => Err(Error::UnexpectedResponse.into()),
+        }
+    }
+    /// Get every key in the store, sorted.
+    ///
+    /// Fails if the list is larger than the server allows, see
+    /// `KvsServer::with_max_response_bytes`.
+    pub fn keys(self) -> Result<Vec<String>> {
+        match self.request(&NetworkCommand::Keys {})? {
+            NetworkResponse::Error { code } => Err(code.into()),
+            NetworkResponse::KeyList(keys) => Ok(keys),
+            _ => Err(Error::UnexpectedResponse.into()),
+        }
+    }
+    /// Check the server is answering, returning how long it took to reply.
+    pub fn ping(self) -> Result<Duration> {
+        let start = Instant::now();
+        match self.request(&NetworkCommand::Ping {})? {
+            NetworkResponse::Error { code } => Err(code.into()),
+            NetworkResponse::Pong => Ok(start.elapsed()),
+            _ => Err(Error::UnexpectedResponse.into()),
+        }
+    }
+    /// Get statistics about the server's store.
+    pub fn stats(self) -> Result<StoreStats> {
+        match self.request(&NetworkCommand::Stats {})? {
+            NetworkResponse::Error { code } => Err(code.into()),
+            NetworkResponse::Value(stats) => {
+                Ok(serde_json::from_str(&stats).map_err(|_e| Error::ResponseDeserialisation)?)
+            }
+            _ => Err(Error::UnexpectedResponse.into()),
+        }
+    }
+    /// Compact the server's store now, returning how many bytes were freed.
+    ///
+    /// This is an admin command, so `token` has to match the server's, see
+    /// `KvsServer::with_admin_token`.
+    pub fn compact(self, token: String) -> Result<u64> {
+        match self.request(&NetworkCommand::Compact { token })? {
+            NetworkResponse::Error { code } => Err(code.into()),
+            NetworkResponse::Value(bytes_freed) => Ok(bytes_freed
+                .parse()
+                .map_err(|_e| Error::UnexpectedResponse)?),
+            _ => Err(Error::UnexpectedResponse.into()),
+        }
+    }
+    /// Set every pair at once, so other clients see either none of them or all of them.
+    pub fn set_multi(self, pairs: Vec<(String, String)>) -> Result<()> {
+        match self.request(&NetworkCommand::MultiSet { pairs })? {
+            NetworkResponse::Error { code } => Err(code.into()),
+            NetworkResponse::Empty => Ok(()),
+            _ => Err(Error::UnexpectedResponse.into()),
         }
     }
     #[allow(missing_docs)]
     pub fn set(self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&self.connection, &NetworkCommand::Set { key, value })?;
-        let mut responses =
-            serde_json::Deserializer::from_reader(&self.connection).into_iter::<NetworkResponse>();
-
-        match responses.next() {
-            Some(response) => match response {
-                Ok(response) => match response {
-                    NetworkResponse::Error { code } => Err(code.into()),
-                    NetworkResponse::Empty => Ok(()),
-                    NetworkResponse::Value { .. } => Err(Error::UnexpectedResponse.into()),
-                },
-                Err(_e) => Err((Error::ResponseDeserialisation).into()),
-            },
-            None => Err((Error::NoResponse).into()),
+        match self.request(&NetworkCommand::Set { key, value })? {
+            NetworkResponse::Error { code } => Err(code.into()),
+            NetworkResponse::Empty => Ok(()),
+            _ => Err(Error::UnexpectedResponse.into()),
         }
     }
     #[allow(missing_docs)]
     pub fn remove(self, key: String) -> Result<()> {
-        serde_json::to_writer(&self.connection, &NetworkCommand::Rm { key })?;
+        match self.request(&NetworkCommand::Rm { key })? {
+            NetworkResponse::Error { code } => match code {
+                ErrorType::KeyNotFound => Err(Error::KeyNotFound.into()),
+                _ => Err(code.into()),
+            },
+            NetworkResponse::Empty => Ok(()),
+            _ => Err(Error::UnexpectedResponse.into()),
+        }
+    }
+    /// Remove every key from `start` up to, but not including, `end`, returning how many
+    /// there were.
+    pub fn delete_range(self, start: String, end: String) -> Result<u64> {
+        match self.request(&NetworkCommand::DeleteRange { start, end })? {
+            NetworkResponse::Error { code } => Err(code.into()),
+            NetworkResponse::Count(count) => Ok(count),
+            _ => Err(Error::UnexpectedResponse.into()),
+        }
+    }
+    /// Move the value for `old_key` to `new_key`, overwriting any value `new_key` already has.
+    pub fn rename(self, old_key: String, new_key: String) -> Result<()> {
+        match self.request(&NetworkCommand::Rename { old_key, new_key })? {
+            NetworkResponse::Error { code } => match code {
+                ErrorType::KeyNotFound => Err(Error::KeyNotFound.into()),
+                _ => Err(code.into()),
+            },
+            NetworkResponse::Empty => Ok(()),
+            _ => Err(Error::UnexpectedResponse.into()),
+        }
+    }
+
+    /// Send a command and wait for the response, retrying according to the retry policy.
+    ///
+    /// A command which isn't idempotent is only retried if it was never sent, as the server may
+    /// have run it even though the response was lost.
+    fn request(self, command: &NetworkCommand) -> Result<NetworkResponse> {
+        let attempts = self.retry_policy.attempts();
+        let mut connection = Some(self.connection);
+
+        for attempt in 1.. {
+            let mut sent = false;
+            let result = match connection.take() {
+                Some(connection) => Ok(connection),
+                None => self.builder.open(&self.addrs),
+            }
+            .and_then(|connection| {
+                KvsClient::send(&connection, command)?;
+                sent = true;
+                KvsClient::receive(&connection)
+            });
+
+            match result {
+                Err(e)
+                    if attempt < attempts
+                        && is_transient(&e)
+                        && (!sent || command.is_idempotent()) =>
+                {
+                    thread::sleep(self.retry_policy.delay(attempt));
+                }
+                result => return result,
+            }
+        }
+
+        unreachable!()
+    }
+
+    /// Write the whole command, or fail having sent too little of it for the server to run.
+    fn send(connection: &TcpStream, command: &NetworkCommand) -> Result<()> {
+        serde_json::to_writer(connection, command).map_err(|e| network_error(io::Error::from(e)))
+    }
+
+    fn receive(connection: &TcpStream) -> Result<NetworkResponse> {
         let mut responses =
-            serde_json::Deserializer::from_reader(&self.connection).into_iter::<NetworkResponse>();
+            serde_json::Deserializer::from_reader(connection).into_iter::<NetworkResponse>();
 
         match responses.next() {
-            Some(response) => match response {
-                Ok(response) => match response {
-                    NetworkResponse::Error { code } => match code {
-                        ErrorType::KeyNotFound => Err(Error::KeyNotFound.into()),
-                        _ => Err(code.into()),
-                    },
-                    NetworkResponse::Empty => Ok(()),
-                    NetworkResponse::Value { .. } => Err(Error::UnexpectedResponse.into()),
-                },
-                Err(_e) => Err((Error::ResponseDeserialisation).into()),
-            },
+            Some(Ok(response)) => Ok(response),
+            Some(Err(e)) if e.is_io() => Err(network_error(io::Error::from(e))),
+            Some(Err(_e)) => Err((Error::ResponseDeserialisation).into()),
             None => Err((Error::NoResponse).into()),
         }
     }
 }
 
+/// Connects a `KvsClient` with timeouts, so a stalled server can't hang it forever.
+///
+/// Every timeout is off unless it is set, as with `KvsClient::connect`. They also apply to the
+/// connections made when retrying, see `KvsClient::with_retry_policy`.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use kvs::KvsClientBuilder;
+/// # use std::time::Duration;
+/// let client = KvsClientBuilder::new()
+///     .connect_timeout(Duration::from_secs(1))
+///     .read_timeout(Duration::from_secs(5))
+///     .connect("127.0.0.1:4000")?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KvsClientBuilder {
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl KvsClientBuilder {
+    /// A builder with no timeouts.
+    pub fn new() -> KvsClientBuilder {
+        KvsClientBuilder::default()
+    }
+
+    /// Give up connecting to each of the server's addresses after `timeout`.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up on a response when nothing more of it arrives for `timeout`. Must not be zero.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up on a request when nothing more of it can be sent for `timeout`. Must not be
+    /// zero.
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Create a connection to the KVS server.
+    pub fn connect<A: ToSocketAddrs>(self, addr: A) -> Result<KvsClient> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        Ok(KvsClient {
+            connection: self.open(&addrs)?,
+            addrs,
+            retry_policy: RetryPolicy::default(),
+            builder: self,
+        })
+    }
+
+    fn open(&self, addrs: &[SocketAddr]) -> Result<TcpStream> {
+        let connection = match self.connect_timeout {
+            Some(timeout) => connect_timeout(addrs, timeout),
+            None => TcpStream::connect(addrs),
+        }
+        .map_err(network_error)?;
+        connection.set_read_timeout(self.read_timeout)?;
+        connection.set_write_timeout(self.write_timeout)?;
+        Ok(connection)
+    }
+}
+
+/// Connect to the first of `addrs` which answers within `timeout`, like `TcpStream::connect`.
+fn connect_timeout(addrs: &[SocketAddr], timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(connection) => return Ok(connection),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to")
+    }))
+}
+
+/// Report a socket timing out as `Error::Timeout`, and any other network problem as it is.
+fn network_error(e: io::Error) -> anyhow::Error {
+    match e.kind() {
+        // a read timeout is reported as `WouldBlock` on some platforms
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::Timeout.into(),
+        _ => e.into(),
+    }
+}
+
+/// Could this error be caused by a network problem, which might go away if retried?
+fn is_transient(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>().is_some()
+        || matches!(
+            e.downcast_ref::<Error>(),
+            Some(Error::NoResponse) | Some(Error::Timeout)
+        )
+}
+
 /// Errors which can be thrown in the client.
-#[derive(Debug, Clone, Copy, failure::Fail)]
+#[derive(Debug, Clone, Copy, thiserror::Error)]
 #[allow(missing_docs)]
 pub enum Error {
-    #[fail(display = "Failed to deserialise response")]
+    #[error("Failed to deserialise response")]
     ResponseDeserialisation,
 
-    #[fail(display = "Unexpected response")]
+    #[error("Unexpected response")]
     UnexpectedResponse,
 
-    #[fail(display = "Key not found")]
+    #[error("Key not found")]
     KeyNotFound,
 
-    #[fail(display = "No response from server")]
+    #[error("No response from server")]
     NoResponse,
+
+    #[error("Timed out waiting for the server")]
+    Timeout,
 }
@@ -1,4 +1,3 @@
-use failure;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::fmt::Display;
@@ -20,6 +19,65 @@ pub enum NetworkCommand {
         #[serde(rename = "k")]
         key: String,
     },
+    Rename {
+        #[serde(rename = "o")]
+        old_key: String,
+        #[serde(rename = "n")]
+        new_key: String,
+    },
+    Exists {
+        #[serde(rename = "k")]
+        key: String,
+    },
+    GetOrInsert {
+        #[serde(rename = "k")]
+        key: String,
+        #[serde(rename = "d")]
+        default: String,
+    },
+    /// Answered with whether the value was swapped, see `KvsEngine::compare_and_swap`
+    Cas {
+        #[serde(rename = "k")]
+        key: String,
+        #[serde(rename = "x")]
+        expected: Option<String>,
+        #[serde(rename = "v")]
+        new_value: Option<String>,
+    },
+    GetRange {
+        #[serde(rename = "s")]
+        start: String,
+        #[serde(rename = "e")]
+        end: String,
+    },
+    MultiSet {
+        #[serde(rename = "p")]
+        pairs: Vec<(String, String)>,
+    },
+    DeleteRange {
+        #[serde(rename = "s")]
+        start: String,
+        #[serde(rename = "e")]
+        end: String,
+    },
+    CountRange {
+        #[serde(rename = "s")]
+        start: String,
+        #[serde(rename = "e")]
+        end: String,
+    },
+    /// An empty struct rather than a unit variant, so it is sent as a JSON object like the
+    /// other commands
+    Keys {},
+    /// Answered with the engine's `StoreStats` as JSON in a `Value`
+    Stats {},
+    /// Answered with `Pong` straight away, without using the engine
+    Ping {},
+    /// Only run if `token` matches the server's, see `KvsServer::with_admin_token`
+    Compact {
+        #[serde(rename = "t")]
+        token: String,
+    },
 }
 
 impl Display for NetworkCommand {
@@ -28,6 +86,88 @@ impl Display for NetworkCommand {
             NetworkCommand::Get { key } => write!(f, "Get '{}'", key),
             NetworkCommand::Set { key, value } => write!(f, "Set '{}' to '{}'", key, value),
             NetworkCommand::Rm { key } => write!(f, "Remove '{}'", key),
+            NetworkCommand::Rename { old_key, new_key } => {
+                write!(f, "Rename '{}' to '{}'", old_key, new_key)
+            }
+            NetworkCommand::Exists { key } => write!(f, "Exists '{}'", key),
+            NetworkCommand::GetOrInsert { key, default } => {
+                write!(f, "GetOrInsert '{}' default '{}'", key, default)
+            }
+            NetworkCommand::Cas {
+                key,
+                expected,
+                new_value,
+            } => write!(f, "Cas '{}' from {:?} to {:?}", key, expected, new_value),
+            NetworkCommand::GetRange { start, end } => {
+                write!(f, "GetRange '{}' to '{}'", start, end)
+            }
+            NetworkCommand::MultiSet { pairs } => write!(f, "MultiSet {} keys", pairs.len()),
+            NetworkCommand::DeleteRange { start, end } => {
+                write!(f, "DeleteRange '{}' to '{}'", start, end)
+            }
+            NetworkCommand::CountRange { start, end } => {
+                write!(f, "CountRange '{}' to '{}'", start, end)
+            }
+            NetworkCommand::Keys {} => write!(f, "Keys"),
+            NetworkCommand::Stats {} => write!(f, "Stats"),
+            NetworkCommand::Ping {} => write!(f, "Ping"),
+            // never the token, as commands are logged
+            NetworkCommand::Compact { .. } => write!(f, "Compact"),
+        }
+    }
+}
+
+impl NetworkCommand {
+    /// The variant's name, for logging.
+    pub(crate) fn command_type(&self) -> &'static str {
+        match self {
+            NetworkCommand::Get { .. } => "Get",
+            NetworkCommand::Set { .. } => "Set",
+            NetworkCommand::Rm { .. } => "Rm",
+            NetworkCommand::Rename { .. } => "Rename",
+            NetworkCommand::Exists { .. } => "Exists",
+            NetworkCommand::GetOrInsert { .. } => "GetOrInsert",
+            NetworkCommand::Cas { .. } => "Cas",
+            NetworkCommand::GetRange { .. } => "GetRange",
+            NetworkCommand::MultiSet { .. } => "MultiSet",
+            NetworkCommand::DeleteRange { .. } => "DeleteRange",
+            NetworkCommand::CountRange { .. } => "CountRange",
+            NetworkCommand::Keys {} => "Keys",
+            NetworkCommand::Stats {} => "Stats",
+            NetworkCommand::Ping {} => "Ping",
+            NetworkCommand::Compact { .. } => "Compact",
+        }
+    }
+
+    /// Does running the command twice have the same effect, and get the same response, as
+    /// running it once? Only these are retried once they have been sent.
+    pub(crate) fn is_idempotent(&self) -> bool {
+        match self {
+            NetworkCommand::Get { .. }
+            | NetworkCommand::Set { .. }
+            | NetworkCommand::Exists { .. }
+            | NetworkCommand::GetRange { .. }
+            | NetworkCommand::CountRange { .. }
+            | NetworkCommand::Keys {}
+            | NetworkCommand::Stats {}
+            | NetworkCommand::Ping {} => true,
+            // a retried `Rm` finds the key already gone, a retried `Cas` finds it already
+            // swapped, and so on
+            _ => false,
+        }
+    }
+
+    /// The single key the command reads or writes, if it has one. For `Rename`, the old key.
+    pub(crate) fn key(&self) -> Option<&str> {
+        match self {
+            NetworkCommand::Get { key }
+            | NetworkCommand::Set { key, .. }
+            | NetworkCommand::Rm { key }
+            | NetworkCommand::Exists { key }
+            | NetworkCommand::GetOrInsert { key, .. }
+            | NetworkCommand::Cas { key, .. } => Some(key),
+            NetworkCommand::Rename { old_key, .. } => Some(old_key),
+            _ => None,
         }
     }
 }
@@ -37,16 +177,33 @@ pub enum NetworkResponse {
     Error { code: ErrorType },
     Empty,
     Value(String),
+    Bool(bool),
+    Entries(Vec<(String, String)>),
+    Count(u64),
+    KeyList(Vec<String>),
+    Pong,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, failure::Fail)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, thiserror::Error)]
 pub enum ErrorType {
-    #[fail(display = "Command failed to deserialise")]
+    #[error("Command failed to deserialise")]
     CommandDeserialisation,
 
-    #[fail(display = "Key not found")]
+    #[error("Key not found")]
     KeyNotFound,
 
-    #[fail(display = "Unknown error")]
+    #[error("Too many connections from this address")]
+    TooManyConnections,
+
+    #[error("Response larger than the server allows")]
+    ResponseTooLarge,
+
+    #[error("Server has too many connections")]
+    ServerBusy,
+
+    #[error("Not authorised to run this command")]
+    Unauthorised,
+
+    #[error("Unknown error")]
     Unknown,
 }
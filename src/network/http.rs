@@ -0,0 +1,166 @@
+//! A minimal HTTP/1.1 front end to KVS commands, for use with `curl` or a browser.
+//!
+//! Each connection carries a single request. Only the parts of HTTP needed for
+//! `GET`, `PUT` and `DELETE` on `/key/{key}` are understood.
+
+use super::data::{ErrorType, NetworkCommand, NetworkResponse};
+use crate::Result;
+use std::io::{BufRead, Read, Write};
+
+const KEY_PATH: &str = "/key/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Status {
+    Ok,
+    NoContent,
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+    TooManyRequests,
+    InternalServerError,
+    ServiceUnavailable,
+}
+
+impl Status {
+    fn code(self) -> u16 {
+        match self {
+            Status::Ok => 200,
+            Status::NoContent => 204,
+            Status::BadRequest => 400,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::TooManyRequests => 429,
+            Status::InternalServerError => 500,
+            Status::ServiceUnavailable => 503,
+        }
+    }
+
+    pub(super) fn reason(self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::NoContent => "No Content",
+            Status::BadRequest => "Bad Request",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::TooManyRequests => "Too Many Requests",
+            Status::InternalServerError => "Internal Server Error",
+            Status::ServiceUnavailable => "Service Unavailable",
+        }
+    }
+}
+
+/// Read a request, returning the command it asks for or the status to reject it with.
+pub(super) fn read_request<R: BufRead>(
+    reader: &mut R,
+) -> Result<std::result::Result<NetworkCommand, Status>> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method.to_owned(), path.to_owned())
+        }
+        _ => return Ok(Err(Status::BadRequest)),
+    };
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(Err(Status::BadRequest));
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = split_header(header) {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = match value.parse() {
+                    Ok(length) => length,
+                    Err(_e) => return Ok(Err(Status::BadRequest)),
+                };
+            }
+        }
+    }
+
+    let mut body = Vec::new();
+    reader.take(content_length).read_to_end(&mut body)?;
+
+    let key = match path.strip_prefix(KEY_PATH).and_then(percent_decode) {
+        Some(key) if !key.is_empty() => key,
+        _ => return Ok(Err(Status::NotFound)),
+    };
+
+    Ok(match method.as_str() {
+        "GET" => Ok(NetworkCommand::Get { key }),
+        "PUT" => match String::from_utf8(body) {
+            Ok(value) => Ok(NetworkCommand::Set { key, value }),
+            Err(_e) => Err(Status::BadRequest),
+        },
+        "DELETE" => Ok(NetworkCommand::Rm { key }),
+        _ => Err(Status::MethodNotAllowed),
+    })
+}
+
+/// The HTTP status and body to reply with for a command's response.
+pub(super) fn to_http(command: &NetworkCommand, response: NetworkResponse) -> (Status, String) {
+    match (command, response) {
+        (_, NetworkResponse::Value(value)) => (Status::Ok, value),
+        (_, NetworkResponse::Bool(value)) => (Status::Ok, value.to_string()),
+        (_, NetworkResponse::Count(count)) => (Status::Ok, count.to_string()),
+        (_, NetworkResponse::Entries(entries)) => match serde_json::to_string(&entries) {
+            Ok(body) => (Status::Ok, body),
+            Err(e) => (Status::InternalServerError, e.to_string()),
+        },
+        (_, NetworkResponse::Pong) => (Status::Ok, "PONG".to_owned()),
+        (_, NetworkResponse::KeyList(keys)) => match serde_json::to_string(&keys) {
+            Ok(body) => (Status::Ok, body),
+            Err(e) => (Status::InternalServerError, e.to_string()),
+        },
+        (NetworkCommand::Get { .. }, NetworkResponse::Empty) => {
+            (Status::NotFound, "Key not found".to_owned())
+        }
+        (_, NetworkResponse::Empty) => (Status::NoContent, String::new()),
+        (
+            _,
+            NetworkResponse::Error {
+                code: ErrorType::KeyNotFound,
+            },
+        ) => (Status::NotFound, "Key not found".to_owned()),
+        (_, NetworkResponse::Error { code }) => (Status::InternalServerError, code.to_string()),
+    }
+}
+
+pub(super) fn write_response<W: Write>(writer: &mut W, status: Status, body: &str) -> Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status.code(),
+        status.reason(),
+        body.len(),
+        body
+    )?;
+    Ok(writer.flush()?)
+}
+
+fn split_header(header: &str) -> Option<(&str, &str)> {
+    let colon = header.find(':')?;
+    Some((header[..colon].trim(), header[colon + 1..].trim()))
+}
+
+/// Decode `%XX` escapes in a path segment, or `None` if it isn't valid UTF-8 once decoded.
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' && tail.len() >= 2 {
+            let hex = std::str::from_utf8(&tail[..2]).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
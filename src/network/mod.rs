@@ -2,7 +2,14 @@
 
 mod client;
 mod data;
+#[cfg(feature = "http")]
+mod http;
+mod retry;
 mod server;
 
-pub use self::client::KvsClient;
-pub use self::server::{existing_engine, EngineType, KvsServer};
+pub use self::client::{KvsClient, KvsClientBuilder};
+pub use self::retry::RetryPolicy;
+pub use self::server::{
+    existing_engine, BoundKvsServer, EngineType, KvsServer, KvsServerHandle,
+    DEFAULT_MAX_RESPONSE_BYTES, DEFAULT_SLOW_REQUEST_THRESHOLD, SHUTDOWN,
+};
@@ -0,0 +1,99 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Controls how `KvsClient` retries operations which fail because of network problems.
+///
+/// Only failures to reach the server, or to get a response from it, are retried. Errors
+/// reported by the server, such as a missing key, are returned straight away. Once a command
+/// such as `remove` or `compare_and_swap` has been sent, it isn't retried, as the server may
+/// already have run it.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use kvs::{KvsClient, RetryPolicy};
+/// # use std::time::Duration;
+/// let policy = RetryPolicy::exponential_backoff(5, Duration::from_millis(10))
+///     .max_delay(Duration::from_secs(1));
+/// let client = KvsClient::connect("127.0.0.1:4000")?.with_retry_policy(policy);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+    jitter_factor: f64,
+}
+
+impl Default for RetryPolicy {
+    /// Make each operation once, without retrying.
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            initial_delay: Duration::from_millis(0),
+            max_delay: Duration::from_millis(0),
+            jitter_factor: 0.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Make up to `max_attempts` attempts, doubling the delay after each one.
+    ///
+    /// Delays are capped at 30 seconds and vary by up to 10% so that clients don't retry in lockstep.
+    pub fn exponential_backoff(max_attempts: u32, initial_delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_delay,
+            max_delay: Duration::from_secs(30),
+            jitter_factor: 0.1,
+        }
+    }
+
+    /// The most attempts to make at each operation, including the first one.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// How long to wait before the first retry.
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    /// The longest to wait between attempts.
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Reduce each delay by a random fraction of itself, up to `jitter_factor` (between 0 and 1).
+    pub fn jitter_factor(mut self, jitter_factor: f64) -> Self {
+        self.jitter_factor = jitter_factor.clamp(0.0, 1.0);
+        self
+    }
+
+    pub(super) fn attempts(&self) -> u32 {
+        self.max_attempts.max(1)
+    }
+
+    /// How long to wait after the given failed attempt, counting from 1.
+    pub(super) fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_delay
+            .checked_mul(2u32.saturating_pow(attempt - 1))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+
+        backoff.mul_f64(1.0 - self.jitter_factor * random_fraction())
+    }
+}
+
+/// A random number in `[0, 1)`, good enough for spreading out retries.
+fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -1,4 +1,6 @@
 use super::data::{ErrorType, NetworkCommand, NetworkResponse};
+#[cfg(feature = "http")]
+use super::http;
 use crate::engines::KvsEngine;
 use crate::engines::KVS_DIR;
 use crate::engines::SLED_DIR;
@@ -8,60 +10,309 @@ use crate::Result;
 use serde_json;
 use slog;
 use slog::Logger;
+use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Display;
+use std::io;
+use std::io::BufRead;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::Write;
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+};
 use std::path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Requests taking longer than this are logged as slow, unless configured otherwise.
+pub const DEFAULT_SLOW_REQUEST_THRESHOLD: Duration = Duration::from_millis(100);
+
+/// Responses are limited to this many bytes, unless configured otherwise.
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Set to stop every `KvsServer` accepting connections, for example when shutting down.
+///
+/// This is checked between connections, so a server waiting in `accept` stops after the next one.
+pub static SHUTDOWN: AtomicBool = AtomicBool::new(false);
 
 /// Listens for KVS commands over a TCP connection.
 #[allow(clippy::module_name_repetitions, missing_debug_implementations)]
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     log: Logger,
-    engine: E,
+    /// Shared with the workers, so handling a connection doesn't need to clone the engine
+    engine: Arc<E>,
     pool: P,
+    slow_request_threshold: Duration,
+    max_response_bytes: usize,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    /// Shared secret for admin commands, which are refused without one
+    admin_token: Option<Arc<str>>,
+    access_log: bool,
+    /// Open connections from each client address
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    /// Shared with any `KvsServerHandle` from `with_shutdown`
+    shutdown: Arc<ShutdownState>,
 }
 
 impl<E, P> KvsServer<E, P>
 where
-    E: KvsEngine,
+    E: KvsEngine + Sync,
     P: ThreadPool,
 {
     /// Create a new KVS server
     pub fn new(log: Logger, engine: E, pool: P) -> Result<KvsServer<E, P>> {
-        Ok(KvsServer { log, engine, pool })
+        Ok(KvsServer {
+            log,
+            engine: Arc::new(engine),
+            pool,
+            slow_request_threshold: DEFAULT_SLOW_REQUEST_THRESHOLD,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            max_connections: None,
+            max_connections_per_ip: None,
+            admin_token: None,
+            access_log: true,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            shutdown: Arc::new(ShutdownState::new()),
+        })
+    }
+
+    /// Log a warning for any request which takes longer than `threshold` to handle.
+    pub fn with_slow_request_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_request_threshold = threshold;
+        self
+    }
+
+    /// Answer requests whose response would be larger than `max` bytes, such as `Keys` on a
+    /// large store, with a `ResponseTooLarge` error instead.
+    pub fn with_max_response_bytes(mut self, max: usize) -> Self {
+        self.max_response_bytes = max;
+        self
+    }
+
+    /// Refuse connections while `max` are already open, so heavy load can't exhaust file
+    /// descriptors. Refused connections are still accepted, so the OS queue doesn't fill up,
+    /// but get a `ServerBusy` error and are closed straight away.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Refuse connections from an IP address which already has `max` open, so a single client
+    /// can't starve the others. Refused connections get a `TooManyConnections` error.
+    pub fn with_max_connections_per_ip(mut self, max: usize) -> Self {
+        self.max_connections_per_ip = Some(max);
+        self
+    }
+
+    /// Accept admin commands, such as `Compact`, which carry `token`. Without this they are
+    /// always refused with an `Unauthorised` error.
+    pub fn with_admin_token(mut self, token: impl Into<String>) -> Self {
+        self.admin_token = Some(Arc::from(token.into()));
+        self
+    }
+
+    /// Log every request at info level, with where it came from, the command and key, how long
+    /// it took and whether it succeeded. On by default, but it can be turned off where the cost
+    /// matters more, such as in benchmarks.
+    pub fn with_access_log(mut self, enabled: bool) -> Self {
+        self.access_log = enabled;
+        self
+    }
+
+    /// Also return a handle which can stop the server from another thread, however it is run.
+    pub fn with_shutdown(self) -> (Self, KvsServerHandle) {
+        let handle = KvsServerHandle {
+            shutdown: Arc::clone(&self.shutdown),
+        };
+        (self, handle)
     }
 
     /// Bind to a socket and start listening
     pub fn run<A: ToSocketAddrs>(&self, addr: A) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
+        self.accept(
+            &listener,
+            KvsServer::<E, P>::handle_req,
+            reject_req,
+            &self.shutdown.requested,
+        )
+    }
+
+    /// Bind to a socket and listen until `shutdown` is set, then wait for the requests already
+    /// being handled to finish.
+    ///
+    /// Like `SHUTDOWN`, this is checked between connections, so a server waiting in `accept`
+    /// stops after the next one. `KvsServerHandle::stop` makes that connection itself.
+    pub fn run_until<A: ToSocketAddrs>(&self, addr: A, shutdown: Arc<AtomicBool>) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        self.accept(
+            &listener,
+            KvsServer::<E, P>::handle_req,
+            reject_req,
+            &shutdown,
+        )
+    }
+
+    /// Bind to a socket and start listening for HTTP requests.
+    ///
+    /// `GET /key/{key}` returns the value, `PUT /key/{key}` sets it to the request body,
+    /// and `DELETE /key/{key}` removes it. Missing keys get a 404 response.
+    #[cfg(feature = "http")]
+    pub fn run_http<A: ToSocketAddrs>(&self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        self.accept(
+            &listener,
+            KvsServer::<E, P>::handle_http_req,
+            reject_http_req,
+            &self.shutdown.requested,
+        )
+    }
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let eng = self.engine.clone();
-                    let log = self.log.clone();
-                    self.pool.spawn(move || {
-                        KvsServer::<E, P>::handle_req(&stream, &eng).unwrap_or_else(|_e| {
-                            error!(log, "Error handling request");
-                        })
-                    })
+    /// Bind to a socket without listening yet.
+    ///
+    /// Returns the address actually bound, which is useful when binding to port 0.
+    pub fn bind<A: ToSocketAddrs>(self, addr: A) -> Result<(BoundKvsServer<E, P>, SocketAddr)> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        Ok((
+            BoundKvsServer {
+                server: self,
+                listener,
+            },
+            local_addr,
+        ))
+    }
+
+    fn accept(
+        &self,
+        listener: &TcpListener,
+        handler: RequestHandler<E>,
+        reject: RejectHandler,
+        until: &AtomicBool,
+    ) -> Result<()> {
+        self.shutdown.listening_on(listener.local_addr()?);
+        // checked after `listening_on`, so a `KvsServerHandle::stop` which found the server not
+        // yet listening is still seen
+        let stop_requested = || {
+            SHUTDOWN.load(Ordering::SeqCst)
+                || self.shutdown.requested.load(Ordering::SeqCst)
+                || until.load(Ordering::SeqCst)
+        };
+
+        if !stop_requested() {
+            for stream in listener.incoming() {
+                if stop_requested() {
+                    break;
                 }
-                Err(_e) => error!(self.log, "Error on connection stream"),
+                self.handle_connection(stream, handler, reject);
             }
         }
 
+        info!(self.log, "Shutting down");
+        // let the requests already being handled finish
+        self.pool.join();
+        self.shutdown.stopped();
         Ok(())
     }
 
-    fn handle_req(stream: &TcpStream, engine: &E) -> Result<()> {
-        let reader = BufReader::new(stream);
+    fn handle_connection(
+        &self,
+        stream: io::Result<TcpStream>,
+        handler: RequestHandler<E>,
+        reject: RejectHandler,
+    ) {
+        match stream {
+            Ok(stream) => {
+                let connection = match self.open_connection(&stream) {
+                    Ok(connection) => connection,
+                    Err(reason) => {
+                        // not on the pool, which may be busy with this client's connections
+                        warn!(self.log, "Refusing connection";
+                            "reason" => %reason,
+                            "ip" => %connection_ip(&stream)
+                        );
+                        reject(&stream, reason).unwrap_or_else(|_e| {
+                            error!(self.log, "Error rejecting connection");
+                        });
+                        return;
+                    }
+                };
+
+                let eng = Arc::clone(&self.engine);
+                let log = self.log.clone();
+                let slow_request_threshold = self.slow_request_threshold;
+                let max_response_bytes = self.max_response_bytes;
+                let admin_token = self.admin_token.clone();
+                let access_log = self.access_log;
+                self.pool.spawn(move || {
+                    handler(
+                        &stream,
+                        &eng,
+                        &log,
+                        slow_request_threshold,
+                        max_response_bytes,
+                        admin_token.as_deref(),
+                        access_log,
+                    )
+                    .unwrap_or_else(|_e| {
+                        error!(log, "Error handling request");
+                    });
+                    drop(connection);
+                })
+            }
+            Err(_e) => error!(self.log, "Error on connection stream"),
+        }
+    }
+
+    /// Count a new connection against its IP address, or the error to refuse it with if the
+    /// server or that address has too many.
+    fn open_connection(
+        &self,
+        stream: &TcpStream,
+    ) -> std::result::Result<ConnectionGuard, ErrorType> {
+        let ip = connection_ip(stream);
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(max) = self.max_connections {
+            if connections.values().sum::<usize>() >= max {
+                return Err(ErrorType::ServerBusy);
+            }
+        }
+        let count = connections.get(&ip).cloned().unwrap_or(0);
+        match self.max_connections_per_ip {
+            Some(max) if count >= max => Err(ErrorType::TooManyConnections),
+            _ => {
+                connections.insert(ip, count + 1);
+                Ok(ConnectionGuard {
+                    ip,
+                    connections: self.connections.clone(),
+                })
+            }
+        }
+    }
+
+    fn handle_req(
+        stream: &TcpStream,
+        engine: &E,
+        log: &Logger,
+        slow_request_threshold: Duration,
+        max_response_bytes: usize,
+        admin_token: Option<&str>,
+        access_log: bool,
+    ) -> Result<()> {
+        let mut reader = DelimitedReader::new(BufReader::new(stream));
         let mut writer = BufWriter::new(stream);
-        let commands = serde_json::Deserializer::from_reader(reader).into_iter::<NetworkCommand>();
 
-        for command in commands {
+        loop {
+            let (command, framed) = match reader.next_object() {
+                Ok(None) => break,
+                Ok(Some(object)) => (serde_json::from_slice::<NetworkCommand>(&object), true),
+                // the rest of the stream can't be split into commands
+                Err(e) => (Err(serde_json::Error::io(e)), false),
+            };
+
             match command {
                 Err(_e) => {
                     serde_json::to_writer(
@@ -71,14 +322,39 @@ where
                         },
                     )
                     .expect("Failed to write to TCP stream");
+                    writer.flush().expect("Failed to flush TCP stream");
+
+                    if !framed {
+                        break;
+                    }
                 }
                 Ok(cmd) => {
-                    let response = KvsServer::<E, P>::handle_command(&cmd, &engine);
+                    let start = Instant::now();
+
+                    let response = KvsServer::<E, P>::handle_command(
+                        &cmd,
+                        engine,
+                        max_response_bytes,
+                        admin_token,
+                    );
+
+                    // the time taken to handle the command, not to send the response, as for HTTP
+                    let elapsed = start.elapsed();
 
                     serde_json::to_writer(&mut writer, &response)
                         .expect("Failed to write to TCP stream");
 
                     writer.flush().expect("Failed to flush TCP stream");
+
+                    if elapsed > slow_request_threshold {
+                        warn!(log, "Slow request";
+                            "duration_ms" => elapsed.as_millis(),
+                            "command" => %cmd
+                        );
+                    }
+                    if access_log {
+                        log_access(log, stream, &cmd, elapsed, &response);
+                    }
                 }
             }
         }
@@ -86,15 +362,64 @@ where
         Ok(())
     }
 
-    fn handle_command(cmd: &NetworkCommand, engine: &E) -> NetworkResponse {
+    #[cfg(feature = "http")]
+    fn handle_http_req(
+        stream: &TcpStream,
+        engine: &E,
+        log: &Logger,
+        slow_request_threshold: Duration,
+        max_response_bytes: usize,
+        admin_token: Option<&str>,
+        access_log: bool,
+    ) -> Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut writer = BufWriter::new(stream);
+        let start = Instant::now();
+
+        let (status, body) = match http::read_request(&mut reader)? {
+            Ok(cmd) => {
+                let response = KvsServer::<E, P>::handle_command(
+                    &cmd,
+                    engine,
+                    max_response_bytes,
+                    admin_token,
+                );
+                let elapsed = start.elapsed();
+                if elapsed > slow_request_threshold {
+                    warn!(log, "Slow request";
+                        "duration_ms" => elapsed.as_millis(),
+                        "command" => %cmd
+                    );
+                }
+                if access_log {
+                    log_access(log, stream, &cmd, elapsed, &response);
+                }
+                http::to_http(&cmd, response)
+            }
+            Err(status) => (status, status.reason().to_owned()),
+        };
+
+        http::write_response(&mut writer, status, &body)
+    }
+
+    fn handle_command(
+        cmd: &NetworkCommand,
+        engine: &E,
+        max_response_bytes: usize,
+        admin_token: Option<&str>,
+    ) -> NetworkResponse {
         match cmd {
             NetworkCommand::Get { key } => match engine.get(key.to_string()) {
                 Ok(v) => match v {
                     Some(value) => NetworkResponse::Value(value),
                     None => NetworkResponse::Empty,
                 },
-                _ => NetworkResponse::Error {
-                    code: ErrorType::Unknown,
+                // engines in strict mode report missing keys as errors
+                Err(e) => match e.downcast::<KvsError>() {
+                    Ok(KvsError::KeyNotFound) => NetworkResponse::Empty,
+                    _ => NetworkResponse::Error {
+                        code: ErrorType::Unknown,
+                    },
                 },
             },
             NetworkCommand::Set { key, value } => {
@@ -116,10 +441,396 @@ where
                     },
                 },
             },
+            NetworkCommand::Rename { old_key, new_key } => {
+                match engine.rename(old_key.to_string(), new_key.to_string()) {
+                    Ok(()) => NetworkResponse::Empty,
+                    Err(e) => match e.downcast::<KvsError>() {
+                        Ok(KvsError::KeyNotFound) => NetworkResponse::Error {
+                            code: ErrorType::KeyNotFound,
+                        },
+                        _ => NetworkResponse::Error {
+                            code: ErrorType::Unknown,
+                        },
+                    },
+                }
+            }
+            NetworkCommand::Exists { key } => match engine.contains_key(key) {
+                Ok(true) => NetworkResponse::Bool(true),
+                Ok(false) => NetworkResponse::Empty,
+                Err(_) => NetworkResponse::Error {
+                    code: ErrorType::Unknown,
+                },
+            },
+            NetworkCommand::Cas {
+                key,
+                expected,
+                new_value,
+            } => match engine.compare_and_swap(key.clone(), expected.clone(), new_value.clone()) {
+                Ok(swapped) => NetworkResponse::Bool(swapped),
+                Err(_) => NetworkResponse::Error {
+                    code: ErrorType::Unknown,
+                },
+            },
+            NetworkCommand::MultiSet { pairs } => match engine.batch_write(pairs.clone()) {
+                Ok(()) => NetworkResponse::Empty,
+                Err(_) => NetworkResponse::Error {
+                    code: ErrorType::Unknown,
+                },
+            },
+            NetworkCommand::DeleteRange { start, end } => match engine.delete_range(start, end) {
+                Ok(count) => NetworkResponse::Count(count as u64),
+                Err(_) => NetworkResponse::Error {
+                    code: ErrorType::Unknown,
+                },
+            },
+            NetworkCommand::CountRange { start, end } => match engine.count_range(start, end) {
+                Ok(count) => NetworkResponse::Count(count),
+                Err(_) => NetworkResponse::Error {
+                    code: ErrorType::Unknown,
+                },
+            },
+            NetworkCommand::Keys {} => match engine.keys() {
+                Ok(keys) => {
+                    let response = NetworkResponse::KeyList(keys);
+                    if json_len(&response) > max_response_bytes {
+                        NetworkResponse::Error {
+                            code: ErrorType::ResponseTooLarge,
+                        }
+                    } else {
+                        response
+                    }
+                }
+                Err(_) => NetworkResponse::Error {
+                    code: ErrorType::Unknown,
+                },
+            },
+            NetworkCommand::Ping {} => NetworkResponse::Pong,
+            NetworkCommand::Stats {} => match engine
+                .stats()
+                .and_then(|stats| serde_json::to_string(&stats).map_err(anyhow::Error::from))
+            {
+                Ok(stats) => NetworkResponse::Value(stats),
+                Err(_) => NetworkResponse::Error {
+                    code: ErrorType::Unknown,
+                },
+            },
+            NetworkCommand::Compact { token } => match admin_token {
+                Some(admin_token) if tokens_match(token, admin_token) => match engine.compact() {
+                    Ok(bytes_freed) => NetworkResponse::Value(bytes_freed.to_string()),
+                    Err(_) => NetworkResponse::Error {
+                        code: ErrorType::Unknown,
+                    },
+                },
+                _ => NetworkResponse::Error {
+                    code: ErrorType::Unauthorised,
+                },
+            },
+            NetworkCommand::GetRange { start, end } => match engine.get_range(start, end) {
+                Ok(entries) => NetworkResponse::Entries(entries),
+                Err(_) => NetworkResponse::Error {
+                    code: ErrorType::Unknown,
+                },
+            },
+            NetworkCommand::GetOrInsert { key, default } => {
+                match engine.get_or_insert(key.to_string(), default.to_string()) {
+                    Ok(value) => NetworkResponse::Value(value),
+                    Err(_) => NetworkResponse::Error {
+                        code: ErrorType::Unknown,
+                    },
+                }
+            }
         }
     }
 }
 
+/// Splits a stream into whole JSON objects, however the bytes of each one arrive.
+///
+/// Object boundaries are found by counting braces outside of strings, so a command is only
+/// parsed once all of it has been read.
+struct DelimitedReader<R> {
+    reader: R,
+}
+
+impl<R: BufRead> DelimitedReader<R> {
+    fn new(reader: R) -> DelimitedReader<R> {
+        DelimitedReader { reader }
+    }
+
+    /// Read the next object, or `None` if the stream ended cleanly between objects.
+    fn next_object(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut object = Vec::new();
+        let mut depth = 0_usize;
+        let mut in_string = false;
+        let mut escaped = false;
+
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return if object.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(io::ErrorKind::UnexpectedEof.into())
+                };
+            }
+
+            let mut consumed = 0;
+            let mut complete = false;
+            for &byte in buf {
+                consumed += 1;
+                if depth == 0 {
+                    if byte.is_ascii_whitespace() {
+                        continue;
+                    }
+                    if byte != b'{' {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "expected a JSON object",
+                        ));
+                    }
+                }
+
+                object.push(byte);
+                if in_string {
+                    if escaped {
+                        escaped = false;
+                    } else if byte == b'\\' {
+                        escaped = true;
+                    } else if byte == b'"' {
+                        in_string = false;
+                    }
+                    continue;
+                }
+                match byte {
+                    b'"' => in_string = true,
+                    b'{' => depth += 1,
+                    b'}' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            complete = true;
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            self.reader.consume(consumed);
+            if complete {
+                return Ok(Some(object));
+            }
+        }
+    }
+}
+
+/// Handles every request on a single connection.
+type RequestHandler<E> =
+    fn(&TcpStream, &E, &Logger, Duration, usize, Option<&str>, bool) -> Result<()>;
+
+/// Log a request handled with `response`, see `KvsServer::with_access_log`.
+fn log_access(
+    log: &Logger,
+    stream: &TcpStream,
+    cmd: &NetworkCommand,
+    elapsed: Duration,
+    response: &NetworkResponse,
+) {
+    let remote_addr = stream
+        .peer_addr()
+        .map_or_else(|_e| "unknown".to_owned(), |addr| addr.to_string());
+    info!(log, "Request";
+        "remote_addr" => remote_addr,
+        "command_type" => cmd.command_type(),
+        "key" => cmd.key(),
+        "latency_us" => elapsed.as_micros(),
+        "success" => !matches!(response, NetworkResponse::Error { .. })
+    );
+}
+
+/// Compare a token from a client with the admin token, taking as long however much of
+/// it matches, so it can't be guessed a byte at a time.
+fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The number of bytes `value` takes up as JSON, without holding them all in memory.
+fn json_len(value: &impl serde::Serialize) -> usize {
+    struct Counter(usize);
+
+    impl Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    // writing to a counter can't fail, and the response types always serialise
+    let _ = serde_json::to_writer(&mut counter, value);
+    counter.0
+}
+
+/// Tells a client why its connection has been refused, before it is closed.
+type RejectHandler = fn(&TcpStream, ErrorType) -> Result<()>;
+
+fn reject_req(mut stream: &TcpStream, reason: ErrorType) -> Result<()> {
+    serde_json::to_writer(stream, &NetworkResponse::Error { code: reason })?;
+    stream.flush()?;
+    Ok(stream.shutdown(Shutdown::Both)?)
+}
+
+#[cfg(feature = "http")]
+fn reject_http_req(mut stream: &TcpStream, reason: ErrorType) -> Result<()> {
+    let status = match reason {
+        ErrorType::ServerBusy => http::Status::ServiceUnavailable,
+        _ => http::Status::TooManyRequests,
+    };
+    http::write_response(&mut stream, status, &reason.to_string())?;
+    Ok(stream.shutdown(Shutdown::Both)?)
+}
+
+/// The client's IP address, or unspecified if the connection has already gone.
+fn connection_ip(stream: &TcpStream) -> IpAddr {
+    stream
+        .peer_addr()
+        .map(|addr| addr.ip())
+        .unwrap_or_else(|_e| IpAddr::from([0, 0, 0, 0]))
+}
+
+/// Stops counting a connection against its IP address, and the server, when dropped.
+struct ConnectionGuard {
+    ip: IpAddr,
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Stops a running `KvsServer` from another thread, created by `KvsServer::with_shutdown`.
+#[derive(Debug, Clone)]
+pub struct KvsServerHandle {
+    shutdown: Arc<ShutdownState>,
+}
+
+impl KvsServerHandle {
+    /// Stop the server accepting connections, and wait for every request already being
+    /// handled to finish and the pool's threads to stop.
+    ///
+    /// A connection is handled until the client closes it, so this also waits for open
+    /// connections to close. The server can't be run again afterwards.
+    pub fn stop(&self) {
+        self.shutdown.requested.store(true, Ordering::SeqCst);
+
+        let mut listening = self.shutdown.listening.lock().unwrap();
+        if let Listening::On(addr) = *listening {
+            // wakes the server if it is waiting for a connection, so it sees the request
+            TcpStream::connect(reachable(addr)).map(drop).unwrap_or(());
+        }
+        while let Listening::On(_) = *listening {
+            listening = self.shutdown.changed.wait(listening).unwrap();
+        }
+    }
+}
+
+/// Whether a server has stopped, shared with its `KvsServerHandle`.
+#[derive(Debug)]
+struct ShutdownState {
+    requested: AtomicBool,
+    listening: Mutex<Listening>,
+    /// Notified when the server stops
+    changed: Condvar,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Listening {
+    NotYet,
+    On(SocketAddr),
+    Stopped,
+}
+
+impl ShutdownState {
+    fn new() -> ShutdownState {
+        ShutdownState {
+            requested: AtomicBool::new(false),
+            listening: Mutex::new(Listening::NotYet),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn listening_on(&self, addr: SocketAddr) {
+        *self.listening.lock().unwrap() = Listening::On(addr);
+    }
+
+    fn stopped(&self) {
+        *self.listening.lock().unwrap() = Listening::Stopped;
+        self.changed.notify_all();
+    }
+}
+
+/// An address to connect to a server listening on `addr`, which may be unspecified.
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if v4.ip().is_unspecified() => {
+            SocketAddr::from((Ipv4Addr::LOCALHOST, v4.port()))
+        }
+        SocketAddr::V6(v6) if v6.ip().is_unspecified() => {
+            SocketAddr::from((Ipv6Addr::LOCALHOST, v6.port()))
+        }
+        _ => addr,
+    }
+}
+
+/// A `KvsServer` which has been bound to a socket, created by `KvsServer::bind`.
+#[allow(missing_debug_implementations)]
+pub struct BoundKvsServer<E: KvsEngine, P: ThreadPool> {
+    server: KvsServer<E, P>,
+    listener: TcpListener,
+}
+
+impl<E, P> BoundKvsServer<E, P>
+where
+    E: KvsEngine + Sync,
+    P: ThreadPool,
+{
+    /// Start listening on the bound socket
+    pub fn serve(&self) -> Result<()> {
+        self.server.accept(
+            &self.listener,
+            KvsServer::<E, P>::handle_req,
+            reject_req,
+            &self.server.shutdown.requested,
+        )
+    }
+
+    /// Start listening for HTTP requests on the bound socket, see `KvsServer::run_http`
+    #[cfg(feature = "http")]
+    pub fn serve_http(&self) -> Result<()> {
+        self.server.accept(
+            &self.listener,
+            KvsServer::<E, P>::handle_http_req,
+            reject_http_req,
+            &self.server.shutdown.requested,
+        )
+    }
+}
+
 #[allow(missing_docs)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EngineType {
@@ -3,11 +3,16 @@
 mod naive;
 mod rayon;
 mod shared_queue;
+#[cfg(feature = "testing")]
+mod test_pool;
 
 pub use self::naive::NaiveThreadPool;
 pub use self::rayon::RayonThreadPool;
 pub use self::shared_queue::SharedQueueThreadPool;
+#[cfg(feature = "testing")]
+pub use self::test_pool::TestThreadPool;
 
+use self::naive::PanicGuard;
 use crate::Result;
 
 /// A thread pool.
@@ -25,6 +30,24 @@ pub trait ThreadPool {
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Wait for every job spawned so far to finish, then stop the pool's threads. Jobs spawned
+    /// afterwards may never run.
+    ///
+    /// Pools which finish each job before `spawn` returns have nothing to wait for, so by default
+    /// this returns immediately.
+    fn join(&self) {}
+
+    /// Spawn a function into the threadpool, catching and logging any panic so it never
+    /// reaches the pool, even one such as `RayonThreadPool` which would pass it on to the
+    /// caller of `spawn`.
+    fn spawn_with_catch<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let guard = PanicGuard::new(job, false);
+        self.spawn(move || guard.run());
+    }
 }
 
 enum ThreadPoolMessage {
@@ -1,21 +1,83 @@
 use super::ThreadPool;
 use crate::Result;
-use std::thread;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
 
 /// Not really a pool, spawns a thread for every job.
+///
+/// A job which panics is caught and logged, see `with_resume_panics`.
 #[allow(clippy::module_name_repetitions)]
-#[derive(Debug, Clone, Copy)]
-pub struct NaiveThreadPool;
+#[derive(Debug)]
+pub struct NaiveThreadPool {
+    /// Threads which may still be running, for `join`
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    resume_panics: bool,
+}
+
+impl NaiveThreadPool {
+    /// Let a job's panic carry on unwinding its thread once it is logged, rather than
+    /// stopping there.
+    pub fn with_resume_panics(mut self, resume: bool) -> Self {
+        self.resume_panics = resume;
+        self
+    }
+}
 
 impl ThreadPool for NaiveThreadPool {
     fn new(_: u32) -> Result<Self> {
-        Ok(NaiveThreadPool)
+        Ok(NaiveThreadPool {
+            threads: Mutex::new(Vec::new()),
+            resume_panics: false,
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        let guard = PanicGuard::new(job, self.resume_panics);
+        let mut threads = self.threads.lock().unwrap();
+        threads.retain(|thread| !thread.is_finished());
+        threads.push(thread::spawn(move || guard.run()));
+    }
+
+    fn join(&self) {
+        let threads: Vec<_> = self.threads.lock().unwrap().drain(..).collect();
+        for thread in threads {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+/// Runs a job, catching and logging any panic, then resuming it if `resume` is set.
+pub(super) struct PanicGuard<F: FnOnce()> {
+    job: F,
+    resume: bool,
+}
+
+impl<F: FnOnce()> PanicGuard<F> {
+    pub(super) fn new(job: F, resume: bool) -> PanicGuard<F> {
+        PanicGuard { job, resume }
+    }
+
+    pub(super) fn run(self) {
+        // nothing the job captured is used again here after a panic
+        if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(self.job)) {
+            eprintln!("Thread pool job panicked: {}", panic_message(&*panic));
+            if self.resume {
+                panic::resume_unwind(panic);
+            }
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map_or("unknown panic", String::as_str),
     }
 }
@@ -1,14 +1,18 @@
 use super::{ThreadPool, ThreadPoolMessage};
-use crate::Result;
+use crate::{KvsError, Result};
 use crossbeam_channel::{unbounded, Receiver, Sender};
-use std::sync::Arc;
-use std::thread;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
 
 #[derive(Debug)]
 struct PoolData {
     sender: Sender<ThreadPoolMessage>,
     receiver: Receiver<ThreadPoolMessage>,
-    num_threads: u32,
+    /// Threads currently live, or due to be once a resize has taken effect
+    num_threads: AtomicU32,
+    /// Every thread started, including those replacing threads which panicked
+    threads: Mutex<Vec<JoinHandle<()>>>,
 }
 
 /// A simple home-grown threadpool using `crossbeam`'s unbounded channel for distributing work.
@@ -20,12 +24,16 @@ pub struct SharedQueueThreadPool {
 
 impl ThreadPool for SharedQueueThreadPool {
     fn new(num_threads: u32) -> Result<Self> {
+        if num_threads == 0 {
+            return Err(KvsError::NoThreads.into());
+        }
         let (s, r) = unbounded::<ThreadPoolMessage>();
 
         let pool = Arc::new(PoolData {
             sender: s,
             receiver: r,
-            num_threads,
+            num_threads: AtomicU32::new(num_threads),
+            threads: Mutex::new(Vec::new()),
         });
 
         for _ in 0..num_threads {
@@ -44,22 +52,66 @@ impl ThreadPool for SharedQueueThreadPool {
             .send(ThreadPoolMessage::RunJob(Box::new(job)))
             .unwrap_or_else(|_| println!("Unable to spawn job: channel disconnected"));
     }
+
+    fn join(&self) {
+        // queued behind every job already spawned
+        self.data
+            .shutdown(self.data.num_threads.load(Ordering::SeqCst));
+
+        // not locked while joining, as a thread which panics adds its replacement
+        loop {
+            let thread = self.data.threads.lock().unwrap().pop();
+            match thread {
+                Some(thread) => thread.join().unwrap_or(()),
+                None => break,
+            }
+        }
+    }
+}
+
+impl SharedQueueThreadPool {
+    /// Change the number of threads in the pool.
+    ///
+    /// Growing the pool spawns the extra threads immediately. Shrinking it queues a shutdown for
+    /// each surplus thread, so those threads exit once they reach it, after finishing their
+    /// current job and any spawned before the resize.
+    ///
+    /// Fails with `KvsError::NoThreads` if `new_size` is zero.
+    pub fn resize(&self, new_size: u32) -> Result<()> {
+        if new_size == 0 {
+            return Err(KvsError::NoThreads.into());
+        }
+        let current = self.data.num_threads.swap(new_size, Ordering::SeqCst);
+        if new_size > current {
+            for _ in current..new_size {
+                spawn(self.data.clone());
+            }
+        } else {
+            self.data.shutdown(current - new_size);
+        }
+        Ok(())
+    }
 }
 
 impl Drop for SharedQueueThreadPool {
     fn drop(&mut self) {
-        for _ in 0..self.data.num_threads {
-            self.data
-                .sender
-                .send(ThreadPoolMessage::Shutdown)
-                .unwrap_or(());
+        self.data
+            .shutdown(self.data.num_threads.load(Ordering::SeqCst));
+    }
+}
+
+impl PoolData {
+    fn shutdown(&self, num_threads: u32) {
+        for _ in 0..num_threads {
+            self.sender.send(ThreadPoolMessage::Shutdown).unwrap_or(());
         }
     }
 }
 
 fn spawn(pool: Arc<PoolData>) {
     let receiver = pool.receiver.clone();
-    thread::spawn(move || {
+    let threads = Arc::clone(&pool);
+    let thread = thread::spawn(move || {
         let _sentinel = Sentinel { pool };
         loop {
             match receiver.recv() {
@@ -71,6 +123,7 @@ fn spawn(pool: Arc<PoolData>) {
             }
         }
     });
+    threads.threads.lock().unwrap().push(thread);
 }
 
 struct Sentinel {
@@ -0,0 +1,24 @@
+use super::ThreadPool;
+use crate::Result;
+use std::panic::{self, AssertUnwindSafe};
+
+/// Not a pool at all, runs every job to completion in the calling thread.
+///
+/// Useful for deterministic tests: by the time `spawn` returns the job has finished.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, Copy)]
+pub struct TestThreadPool;
+
+impl ThreadPool for TestThreadPool {
+    fn new(_: u32) -> Result<Self> {
+        Ok(TestThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // a panicking job must not take down the caller, as it would with any other pool
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+    }
+}
@@ -1,4 +1,5 @@
 use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsError};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -151,6 +152,17 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+#[test]
+fn server_cli_invalid_slow_request_threshold() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--slow-request-threshold-ms", "not-a-number"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -171,6 +183,52 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+// A store can't be opened while a server in another process has its directory open
+#[test]
+fn cli_data_directory_locked() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let err = KvStore::open(temp_dir.path()).expect_err("opened a locked directory");
+    assert!(matches!(
+        err.downcast_ref::<KvsError>(),
+        Some(KvsError::AlreadyOpen { .. })
+    ));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    KvStore::open(temp_dir.path()).expect("lock not released when the server exited");
+}
+
+// A read only store can't share the directory with a server writing to it
+#[test]
+fn cli_data_directory_locked_read_only() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let err = KvStore::open_read_only(temp_dir.path()).expect_err("opened a locked directory");
+    assert!(matches!(
+        err.downcast_ref::<KvsError>(),
+        Some(KvsError::AlreadyOpen { .. })
+    ));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    KvStore::open_read_only(temp_dir.path()).expect("lock not released when the server exited");
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -227,6 +285,14 @@ fn cli_access_server(engine: &str, addr: &str) {
     });
     thread::sleep(Duration::from_secs(1));
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ping", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("PONG (RTT: "));
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["set", "key1", "value1", "--addr", addr])
@@ -0,0 +1,268 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsClientBuilder, KvsEngine, KvsServer, Result, RetryPolicy};
+use slog::{o, Discard, Logger};
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Start a fake server which hangs up on the first `dropped` connections, then answers
+/// every request with `response`. Returns its address and a count of connections accepted.
+fn fake_server(dropped: usize, response: &'static str) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    let accepted = connections.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            if accepted.fetch_add(1, Ordering::SeqCst) < dropped {
+                continue;
+            }
+            // read the whole request, so closing the stream doesn't reset the connection
+            let _request = serde_json::Deserializer::from_reader(&stream)
+                .into_iter::<serde_json::Value>()
+                .next();
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+
+    (addr, connections)
+}
+
+#[test]
+fn no_retry_by_default() -> Result<()> {
+    let (addr, connections) = fake_server(1, r#""Empty""#);
+
+    assert!(KvsClient::connect(addr)?
+        .set("key1".to_owned(), "value1".to_owned())
+        .is_err());
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[test]
+fn retry_dropped_connections() -> Result<()> {
+    let (addr, connections) = fake_server(2, r#"{"Value":"value1"}"#);
+    let policy = RetryPolicy::exponential_backoff(3, Duration::from_millis(1));
+
+    let value = KvsClient::connect(addr)?
+        .with_retry_policy(policy)
+        .get("key1".to_owned())?;
+    assert_eq!(value, Some("value1".to_owned()));
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+
+    Ok(())
+}
+
+#[test]
+fn retry_gives_up_after_max_attempts() -> Result<()> {
+    let (addr, connections) = fake_server(5, r#""Empty""#);
+    let policy = RetryPolicy::exponential_backoff(3, Duration::from_millis(1));
+
+    assert!(KvsClient::connect(addr)?
+        .with_retry_policy(policy)
+        .set("key1".to_owned(), "value1".to_owned())
+        .is_err());
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+
+    Ok(())
+}
+
+// Errors reported by the server are not retried
+#[test]
+fn no_retry_key_not_found() -> Result<()> {
+    let (addr, connections) = fake_server(0, r#"{"Error":{"code":"KeyNotFound"}}"#);
+    let policy = RetryPolicy::exponential_backoff(3, Duration::from_millis(1));
+
+    assert!(KvsClient::connect(addr)?
+        .with_retry_policy(policy)
+        .remove("key1".to_owned())
+        .is_err());
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+// Each way a response can be wrong has its own error
+#[test]
+fn client_error_display() -> Result<()> {
+    let (addr, _) = fake_server(0, "nope");
+    let err = KvsClient::connect(addr)?
+        .get("key1".to_owned())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Failed to deserialise response");
+
+    let (addr, _) = fake_server(0, r#"{"Value":"value1"}"#);
+    let err = KvsClient::connect(addr)?
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Unexpected response");
+
+    let (addr, _) = fake_server(0, r#"{"Error":{"code":"KeyNotFound"}}"#);
+    let err = KvsClient::connect(addr)?
+        .remove("key1".to_owned())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Key not found");
+
+    let (addr, _) = fake_server(0, r#"{"Error":{"code":"TooManyConnections"}}"#);
+    let err = KvsClient::connect(addr)?
+        .get("key1".to_owned())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Too many connections from this address");
+
+    let (addr, _) = fake_server(0, "");
+    let err = KvsClient::connect(addr)?
+        .get("key1".to_owned())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "No response from server");
+    assert!(std::error::Error::source(&*err).is_none());
+
+    Ok(())
+}
+
+/// Start a proxy to `upstream` which passes on each request, but hangs up instead of
+/// passing on the response for the first `dropped` connections.
+fn reply_dropping_proxy(upstream: SocketAddr, dropped: usize) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        for (i, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            let request: serde_json::Value = match serde_json::Deserializer::from_reader(&stream)
+                .into_iter()
+                .next()
+            {
+                Some(Ok(request)) => request,
+                _ => continue,
+            };
+
+            let mut server = TcpStream::connect(upstream).unwrap();
+            serde_json::to_writer(&mut server, &request).unwrap();
+            let response: serde_json::Value = serde_json::Deserializer::from_reader(&server)
+                .into_iter()
+                .next()
+                .unwrap()
+                .unwrap();
+
+            if i >= dropped {
+                serde_json::to_writer(&mut stream, &response).unwrap();
+            }
+        }
+    });
+
+    addr
+}
+
+// A command which isn't idempotent isn't retried once sent, as the server may already have
+// run it
+#[test]
+fn no_retry_after_lost_response() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        store,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+    let policy = RetryPolicy::exponential_backoff(3, Duration::from_millis(1));
+
+    // a retry would find the key already removed
+    let err = KvsClient::connect(reply_dropping_proxy(addr, 1))?
+        .with_retry_policy(policy)
+        .remove("key1".to_owned())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "No response from server");
+    assert_eq!(KvsClient::connect(addr)?.get("key1".to_owned())?, None);
+
+    // idempotent commands are still retried
+    KvsClient::connect(addr)?.set("key2".to_owned(), "value2".to_owned())?;
+    let value = KvsClient::connect(reply_dropping_proxy(addr, 1))?
+        .with_retry_policy(policy)
+        .get("key2".to_owned())?;
+    assert_eq!(value, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+/// Start a proxy to `upstream` which passes on each request, but only the first half of the
+/// response, and then stalls.
+fn stalling_proxy(upstream: SocketAddr) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let request: serde_json::Value = serde_json::Deserializer::from_reader(&stream)
+                .into_iter()
+                .next()
+                .unwrap()
+                .unwrap();
+
+            let mut server = TcpStream::connect(upstream).unwrap();
+            serde_json::to_writer(&mut server, &request).unwrap();
+            let response: serde_json::Value = serde_json::Deserializer::from_reader(&server)
+                .into_iter()
+                .next()
+                .unwrap()
+                .unwrap();
+
+            let response = serde_json::to_vec(&response).unwrap();
+            stream.write_all(&response[..response.len() / 2]).unwrap();
+            thread::spawn(move || {
+                // hold the connection open without sending the rest
+                thread::sleep(Duration::from_secs(5));
+                drop(stream);
+            });
+        }
+    });
+
+    addr
+}
+
+// A server which stops part way through a response times the client out, rather than
+// hanging it
+#[test]
+fn read_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        store,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+    let proxy = stalling_proxy(addr);
+
+    let start = Instant::now();
+    let err = KvsClientBuilder::new()
+        .connect_timeout(Duration::from_secs(1))
+        .read_timeout(Duration::from_millis(100))
+        .write_timeout(Duration::from_millis(100))
+        .connect(proxy)?
+        .get("key1".to_owned())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Timed out waiting for the server");
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    // without the proxy the same client gets the value
+    let value = KvsClientBuilder::new()
+        .read_timeout(Duration::from_millis(100))
+        .connect(addr)?
+        .get("key1".to_owned())?;
+    assert_eq!(value, Some("value1".to_owned()));
+
+    Ok(())
+}
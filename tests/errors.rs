@@ -0,0 +1,85 @@
+use kvs::{KvStore, KvsError, Result};
+use std::error::Error;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+#[test]
+fn kvs_error_display() {
+    let path = PathBuf::from("/data");
+    let cases = vec![
+        (KvsError::NotADirectory, "Not a directory"),
+        (KvsError::KeyNotFound, "Key not found"),
+        (
+            KvsError::UnexpectedCommand,
+            "Unexpected command found in log",
+        ),
+        (
+            KvsError::UnexpectedFileName,
+            "Unexpected file name, should be an integer",
+        ),
+        (KvsError::SnapshotExpired, "Snapshot expired"),
+        (KvsError::LogFileNotFound, "Log file not found"),
+        (KvsError::DataExpired, "Data expired"),
+        (KvsError::IndexNotFound, "Secondary index not found"),
+        (
+            KvsError::ReadOnlyFilesystem { path: path.clone() },
+            r#"Data directory "/data" is not writable"#,
+        ),
+        (KvsError::PermanentlyDeleted, "Key permanently deleted"),
+        (
+            KvsError::BackupInterrupted,
+            "Backup interrupted by compaction",
+        ),
+        (
+            KvsError::AlreadyOpen { path },
+            r#"Data directory "/data" is already in use"#,
+        ),
+        (KvsError::DiskFull, "Disk full"),
+        (
+            KvsError::ChecksumMismatch {
+                file_id: 3,
+                offset: 120,
+            },
+            "Checksum mismatch in log file 3 at offset 120",
+        ),
+        (KvsError::FormatMismatch, "Log format mismatch"),
+        (
+            KvsError::IndexMemoryLimitExceeded,
+            "Index memory limit exceeded",
+        ),
+        (KvsError::OutOfMemory, "Out of memory"),
+        (KvsError::Unsupported, "Not supported by this engine"),
+        (KvsError::ReadOnly, "Store is read only"),
+    ];
+    for (error, display) in cases {
+        assert_eq!(error.to_string(), display);
+        // none of them wrap another error
+        assert!(error.source().is_none(), "{:?}", error);
+    }
+}
+
+// Errors keep their cause when context is added, and can still be downcast
+#[test]
+fn error_source_chain() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file_path = temp_dir.path().join("file");
+    std::fs::write(&file_path, "")?;
+
+    let err = KvStore::open(&file_path)
+        .map_err(|e| e.context("opening the store"))
+        .expect_err("opened a store in a file");
+    assert_eq!(err.to_string(), "opening the store");
+    let source = err.source().expect("context has a source");
+    assert_eq!(source.to_string(), "Not a directory");
+    assert!(source.source().is_none());
+    assert!(matches!(
+        err.root_cause().downcast_ref(),
+        Some(KvsError::NotADirectory)
+    ));
+    assert!(matches!(
+        err.downcast::<KvsError>(),
+        Ok(KvsError::NotADirectory)
+    ));
+
+    Ok(())
+}
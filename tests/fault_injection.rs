@@ -0,0 +1,111 @@
+use kvs::{FaultPoint, KvStore, KvStoreOptions, KvsEngine, Result};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use tempfile::TempDir;
+
+/// Write to the store over a few opens, so there are several log files to compact, then
+/// crash while compacting at `point`, applying `options` on the last open.
+fn crash_during_compaction(dir: &Path, point: FaultPoint, options: KvStoreOptions) -> Result<()> {
+    let store = KvStore::open(dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(dir)?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let options = options
+        .compaction_live_ratio_threshold(0.9)
+        .inject_fault(point);
+    let store = KvStore::open_with_options(dir, options)?;
+    let crashed = panic::catch_unwind(AssertUnwindSafe(|| {
+        // hide the expected panic message
+        panic_control::disable_hook_in_current_thread();
+        // the tombstone is in the newest log, so has to outlive the value it removes
+        store.remove("key2".to_owned())
+    }));
+    assert!(crashed.is_err(), "expected a crash at {:?}", point);
+
+    Ok(())
+}
+
+fn temp_files(dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir.join(".kvs"))? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.ends_with(".tmp") {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+// Opening the store after a crash at any point during compaction recovers every write
+#[test]
+fn recover_from_crash_during_compaction() -> Result<()> {
+    let points = [
+        FaultPoint::BeforeCommittingCompaction,
+        FaultPoint::BeforeRemovingCompactedFiles,
+        FaultPoint::AfterRemovingFirstCompactedFile,
+        FaultPoint::BeforeSavingIndex,
+    ];
+    for &point in &points {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        crash_during_compaction(temp_dir.path(), point, KvStoreOptions::default())?;
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert!(temp_files(temp_dir.path())?.is_empty(), "at {:?}", point);
+        assert_eq!(
+            store.get("key1".to_owned())?,
+            Some("value3".to_owned()),
+            "at {:?}",
+            point
+        );
+        assert_eq!(store.get("key2".to_owned())?, None, "at {:?}", point);
+
+        // and compaction still works afterwards
+        store.set("key1".to_owned(), "value5".to_owned())?;
+        drop(store);
+        let options = KvStoreOptions::default().compaction_live_ratio_threshold(0.9);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        store.set("key2".to_owned(), "value6".to_owned())?;
+        assert!(
+            store.detailed_stats().compaction_bytes_written > 0,
+            "at {:?}",
+            point
+        );
+        assert_eq!(store.get("key1".to_owned())?, Some("value5".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value6".to_owned()));
+    }
+
+    Ok(())
+}
+
+// The same goes for leveled compaction, which has no index to save
+#[test]
+fn recover_from_crash_during_leveled_compaction() -> Result<()> {
+    let points = [
+        FaultPoint::BeforeCommittingCompaction,
+        FaultPoint::BeforeRemovingCompactedFiles,
+        FaultPoint::AfterRemovingFirstCompactedFile,
+    ];
+    for &point in &points {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default().leveled_compaction(10);
+        crash_during_compaction(temp_dir.path(), point, options)?;
+
+        let options = KvStoreOptions::default().leveled_compaction(10);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert!(temp_files(temp_dir.path())?.is_empty(), "at {:?}", point);
+        assert_eq!(
+            store.get("key1".to_owned())?,
+            Some("value3".to_owned()),
+            "at {:?}",
+            point
+        );
+        assert_eq!(store.get("key2".to_owned())?, None, "at {:?}", point);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,34 @@
+use kvs::{FileNamingScheme, KvsWriter, Result};
+use std::io::Write;
+use tempfile::TempDir;
+
+// The offset before each write is where that write starts in the file, even while buffered
+#[test]
+fn writer_offset_tracks_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().to_path_buf();
+    let mut writer = KvsWriter::new(&dir, 1, FileNamingScheme::Numeric)?;
+    assert_eq!(writer.offset, 0);
+
+    let commands = vec![
+        r#"{"k":"key1","v":"value1"}"#.to_owned(),
+        r#"{"k":"key2","v":null}"#.to_owned(),
+        format!(r#"{{"k":"key3","v":"{}"}}"#, "v".repeat(10_000)),
+        r#"{"k":"key4","v":"value4"}"#.to_owned(),
+    ];
+    let mut offsets = Vec::new();
+    for command in &commands {
+        offsets.push(writer.offset);
+        writer.write_all(command.as_bytes())?;
+    }
+    writer.flush()?;
+
+    let contents = std::fs::read(dir.join("1.log"))?;
+    assert_eq!(writer.offset, contents.len() as u64);
+    for (command, offset) in commands.iter().zip(offsets) {
+        let start = offset as usize;
+        assert_eq!(&contents[start..start + command.len()], command.as_bytes());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsServer, Result};
+use slog::{o, Discard, Logger};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use tempfile::TempDir;
+
+fn start_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve_http());
+    Ok(addr)
+}
+
+/// Send a request and return the status code and body of the response.
+fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> Result<(u16, String)> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    )?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let status = response[9..12].parse()?;
+    let body_start = response.find("\r\n\r\n").expect("no end to headers") + 4;
+    Ok((status, response[body_start..].to_owned()))
+}
+
+#[test]
+fn http_get_put_delete() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+
+    assert_eq!(request(addr, "GET", "/key/key1", "")?.0, 404);
+    assert_eq!(request(addr, "PUT", "/key/key1", "value1")?.0, 204);
+    assert_eq!(
+        request(addr, "GET", "/key/key1", "")?,
+        (200, "value1".to_owned())
+    );
+    assert_eq!(request(addr, "DELETE", "/key/key1", "")?.0, 204);
+    assert_eq!(request(addr, "DELETE", "/key/key1", "")?.0, 404);
+    assert_eq!(request(addr, "GET", "/key/key1", "")?.0, 404);
+
+    Ok(())
+}
+
+#[test]
+fn http_decodes_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+
+    assert_eq!(request(addr, "PUT", "/key/a%20key", "a value")?.0, 204);
+    assert_eq!(
+        request(addr, "GET", "/key/a%20key", "")?,
+        (200, "a value".to_owned())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn http_bad_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+
+    assert_eq!(request(addr, "GET", "/other/key1", "")?.0, 404);
+    assert_eq!(request(addr, "GET", "/key/", "")?.0, 404);
+    assert_eq!(request(addr, "POST", "/key/key1", "value1")?.0, 405);
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"nonsense\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 400"));
+
+    Ok(())
+}
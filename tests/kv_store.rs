@@ -1,6 +1,16 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use kvs::{
+    BincodeCommandSerializer, ChecksumAlgorithm, CompactionStats, FileNamingScheme, IsolationLevel,
+    JsonCommandSerializer, KvStore, KvStoreExt, KvStoreOptions, KvStoreStats, KvsEngine, KvsError,
+    LatencyHistogram, LogFileId, LogFormat, Result, SledKvsEngine, TelemetrySink, ValidationMode,
+};
+use slog::{o, Drain, Level, Logger, Never, OwnedKVList, Record, KV};
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -63,6 +73,90 @@ fn get_non_existent_value() -> Result<()> {
     Ok(())
 }
 
+// Should get `KeyNotFound` when getting a non-existent key in strict mode
+#[test]
+fn get_non_existent_value_strict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().strict_mode(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    match store.get("key2".to_owned()) {
+        Err(e) => assert!(matches!(
+            e.downcast::<KvsError>(),
+            Ok(KvsError::KeyNotFound)
+        )),
+        Ok(v) => panic!("expected KeyNotFound, got {:?}", v),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn get_strict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_strict("key1".to_owned())?, "value1".to_owned());
+    match store.get_strict("key2".to_owned()) {
+        Err(e) => assert!(matches!(
+            e.downcast::<KvsError>(),
+            Ok(KvsError::KeyNotFound)
+        )),
+        Ok(v) => panic!("expected KeyNotFound, got {:?}", v),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn get_bulk() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let keys = vec!["key2".to_owned(), "key3".to_owned(), "key1".to_owned()];
+    assert_eq!(
+        store.get_bulk(&keys)?,
+        vec![Some("value2".to_owned()), None, Some("value1".to_owned())]
+    );
+    assert_eq!(store.get_bulk(&[])?, vec![]);
+
+    Ok(())
+}
+
+#[test]
+fn get_reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().max_inline_value_bytes(100);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let large_value = "ab".repeat(1000);
+    store.set("large".to_owned(), large_value.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+
+    let mut value = String::new();
+    store
+        .get_reader("large".to_owned())?
+        .expect("large value should exist")
+        .read_to_string(&mut value)?;
+    assert_eq!(value, large_value);
+
+    let mut value = String::new();
+    store
+        .get_reader("small".to_owned())?
+        .expect("small value should exist")
+        .read_to_string(&mut value)?;
+    assert_eq!(value, "value");
+
+    assert!(store.get_reader("missing".to_owned())?.is_none());
+
+    Ok(())
+}
+
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -132,15 +226,16 @@ fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        handles.push(thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
-        });
+        }));
     }
     barrier.wait();
 
@@ -148,7 +243,10 @@ fn concurrent_set() -> Result<()> {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
 
-    // Open from disk again and check persistent data
+    // Open from disk again and check persistent data, once every handle has closed
+    for handle in handles {
+        handle.join().unwrap();
+    }
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
@@ -209,3 +307,2999 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Reads through a `RepeatableRead` snapshot should not see later writes
+#[test]
+fn repeatable_read_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().isolation_level(IsolationLevel::RepeatableRead);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let snapshot = store.snapshot()?;
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Reads through a `ReadCommitted` snapshot should see the latest state
+#[test]
+fn read_committed_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let snapshot = store.snapshot()?;
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn live_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.live_ratio(), 1.0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.live_ratio(), 1.0);
+
+    // the first value is now redundant, and is the same size as the second
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.live_ratio(), 0.5);
+    assert_eq!(store.detailed_stats().live_ratio, 0.5);
+
+    // survives reopening
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.live_ratio(), 0.5);
+
+    Ok(())
+}
+
+// Compaction runs when too little of the log is live, however small the log is
+#[test]
+fn compaction_live_ratio_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .min_compaction_bytes(1024 * 1024)
+        .compaction_live_ratio_threshold(0.6);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.detailed_stats().compaction_bytes_written, 0);
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key2".to_owned(), "value4".to_owned())?;
+    assert!(store.detailed_stats().compaction_bytes_written > 0);
+    assert_eq!(store.live_ratio(), 1.0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn estimated_num_operations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.detailed_stats().estimated_num_operations, 4);
+
+    drop(store);
+    let options = KvStoreOptions::default().compaction_live_ratio_threshold(0.5);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.detailed_stats().estimated_num_operations, 4);
+
+    // less than half the log is live, so this compacts down to the two live values
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    assert!(store.detailed_stats().compaction_bytes_written > 0);
+    assert_eq!(store.detailed_stats().estimated_num_operations, 2);
+
+    Ok(())
+}
+
+#[test]
+fn io_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    // a new store already has the log file it writes to
+    let stats = KvStoreStats {
+        oldest_log_file_created_at: None,
+        ..store.detailed_stats()
+    };
+    assert_eq!(stats, KvStoreStats::default());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let set_bytes = store.detailed_stats().user_bytes_written;
+    assert!(set_bytes > 0);
+    assert_eq!(store.detailed_stats().overhead_bytes, 0);
+
+    // overwriting makes the first command redundant
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let stats = store.detailed_stats();
+    assert_eq!(stats.user_bytes_written, 2 * set_bytes);
+    assert_eq!(stats.overhead_bytes, set_bytes);
+
+    // removing makes both the value and the tombstone redundant
+    store.remove("key1".to_owned())?;
+    let stats = store.detailed_stats();
+    let rm_bytes = stats.user_bytes_written - 2 * set_bytes;
+    assert_eq!(stats.overhead_bytes, 2 * set_bytes + rm_bytes);
+    assert_eq!(stats.compaction_bytes_written, 0);
+
+    // write until compaction copies live values
+    let value = "v".repeat(1000);
+    for iter in 0..2000 {
+        store.set(format!("key{}", iter % 100), value.clone())?;
+        if store.detailed_stats().compaction_bytes_written > 0 {
+            return Ok(());
+        }
+    }
+    panic!("No compaction detected");
+}
+
+// Writes to the primary should eventually be applied to the replica
+#[test]
+fn replicate_writes() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let secondary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let secondary = KvStore::open(secondary_dir.path())?;
+    let primary = KvStore::open(primary_dir.path())?.with_replica(secondary.clone());
+
+    primary.set("key1".to_owned(), "value1".to_owned())?;
+    primary.set("key2".to_owned(), "value2".to_owned())?;
+    primary.remove("key1".to_owned())?;
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while secondary.get("key1".to_owned())?.is_some() || secondary.get("key2".to_owned())?.is_none()
+    {
+        assert!(Instant::now() < deadline, "writes were not replicated");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(secondary.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// With a zero lag limit, every write should wait for previous writes to reach the replica
+#[test]
+fn replica_lag_limit() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let secondary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let secondary = KvStore::open(secondary_dir.path())?;
+    let options = KvStoreOptions::default().replica_lag_limit(Duration::from_secs(0));
+    let primary =
+        KvStore::open_with_options(primary_dir.path(), options)?.with_replica(secondary.clone());
+
+    for i in 1..100 {
+        primary.set(format!("key{}", i), format!("value{}", i))?;
+        assert_eq!(
+            secondary.get(format!("key{}", i - 1))?,
+            if i == 1 {
+                None
+            } else {
+                Some(format!("value{}", i - 1))
+            }
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn merge_from() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let other = KvStore::open(other_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    other.set("key2".to_owned(), "value2".to_owned())?;
+    other.set("key3".to_owned(), "value3".to_owned())?;
+    other.set("key1".to_owned(), "other".to_owned())?;
+    other.set("key4".to_owned(), "value4".to_owned())?;
+    other.remove("key4".to_owned())?;
+
+    // key1 changed and key3 added, key2 is unchanged
+    assert_eq!(store.merge_from(&other)?, 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("other".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    assert_eq!(store.merge_from(&other)?, 0);
+    assert_eq!(store.merge_from(&store.clone())?, 0);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Small values are kept in the index, large ones are read from disk
+#[test]
+fn inline_values() -> Result<()> {
+    for &inline in &[true, false] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default().inline_values(inline);
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+        let small = "s".repeat(64);
+        let large = "l".repeat(65);
+        store.set("small".to_owned(), small.clone())?;
+        store.set("large".to_owned(), large.clone())?;
+        store.set("removed".to_owned(), "value".to_owned())?;
+        store.remove("removed".to_owned())?;
+        assert_eq!(store.get("small".to_owned())?, Some(small.clone()));
+        assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+        assert_eq!(store.get("removed".to_owned())?, None);
+
+        store.set("small".to_owned(), large.clone())?;
+        store.set("large".to_owned(), small.clone())?;
+        assert_eq!(store.get("small".to_owned())?, Some(large.clone()));
+        assert_eq!(store.get("large".to_owned())?, Some(small.clone()));
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("small".to_owned())?, Some(large));
+        assert_eq!(store.get("large".to_owned())?, Some(small));
+        assert_eq!(store.get("removed".to_owned())?, None);
+    }
+
+    Ok(())
+}
+
+// Repeated writes to a pending key should only write the final value
+#[test]
+fn coalesce_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().coalesce_writes(100);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    for i in 0..50 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+        assert_eq!(store.get("key1".to_owned())?, Some(format!("value{}", i)));
+    }
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.detailed_stats().user_bytes_written, 0);
+
+    store.flush_pending_writes()?;
+    let stats = store.detailed_stats();
+    assert!(stats.user_bytes_written > 0);
+    assert_eq!(stats.overhead_bytes, 0);
+
+    // Pending writes are flushed when the store is dropped
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value49".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn lock_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    // Locking the same key twice in one call doesn't deadlock
+    let guard = store.lock_keys(&["key1", "key1", "key2"]);
+
+    let locked = Arc::new(AtomicBool::new(false));
+    let handle = {
+        let store = store.clone();
+        let locked = locked.clone();
+        thread::spawn(move || {
+            let _guard = store.lock_keys(&["key2"]);
+            locked.store(true, Ordering::SeqCst);
+        })
+    };
+
+    thread::sleep(Duration::from_millis(100));
+    assert!(!locked.load(Ordering::SeqCst));
+
+    drop(guard);
+    handle.join().unwrap();
+    assert!(locked.load(Ordering::SeqCst));
+
+    Ok(())
+}
+
+#[test]
+fn compaction_threshold_bounds() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .min_compaction_bytes(4 * 1024)
+        .max_compaction_bytes(4 * 1024);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    for i in 0..200 {
+        store.set("key".to_owned(), format!("{:0>100}", i))?;
+    }
+
+    // Overwrites add up to well over the configured maximum, so compaction must have run
+    assert!(store.detailed_stats().compaction_bytes_written > 0);
+    assert_eq!(store.get("key".to_owned())?, Some(format!("{:0>100}", 199)));
+
+    Ok(())
+}
+
+#[test]
+fn saved_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .min_compaction_bytes(4 * 1024)
+        .max_compaction_bytes(4 * 1024);
+    let index_path = temp_dir.path().join(".kvs").join("index.bin");
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..200 {
+        store.set(format!("key{}", i % 10), format!("{:0>100}", i))?;
+    }
+    assert!(index_path.exists());
+
+    // Writes after the last compaction are loaded on top of the saved index
+    store.set("key0".to_owned(), "latest".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, Some("latest".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(
+            store.get("key9".to_owned())?,
+            Some(format!("{:0>100}", 199))
+        );
+        Ok(())
+    };
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    // A corrupt saved index falls back to replaying the logs
+    std::fs::write(&index_path, b"not an index")?;
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    // So does a missing one
+    std::fs::remove_file(&index_path)?;
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// An index saved before the latest compaction is ignored, as the log it points into is gone
+#[test]
+fn stale_saved_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .min_compaction_bytes(4 * 1024)
+        .max_compaction_bytes(4 * 1024);
+    let index_path = temp_dir.path().join(".kvs").join("index.bin");
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let mut writes = 0;
+    let mut stale_index = Vec::new();
+    while store.current_generation() < 2 {
+        if store.current_generation() == 1 && stale_index.is_empty() {
+            stale_index = fs::read(&index_path)?;
+        }
+        store.set(format!("key{}", writes % 10), format!("{:0>100}", writes))?;
+        writes += 1;
+    }
+    drop(store);
+
+    fs::write(&index_path, stale_index)?;
+    let store = KvStore::open(temp_dir.path())?;
+    for key in 0..10 {
+        let last = (0..writes).rev().find(|j| j % 10 == key).unwrap();
+        assert_eq!(
+            store.get(format!("key{}", key))?,
+            Some(format!("{:0>100}", last))
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().coalesce_writes(100);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.close()?;
+
+    // Without dropping the first store, as if the process were killed
+    let reopened = KvStore::open(temp_dir.path())?;
+    assert_eq!(reopened.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn verify_log_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    // A fresh store writes to log file 1
+    let stats = store.verify_log_file(1)?;
+    assert_eq!(stats.total_commands, 4);
+    assert_eq!(stats.live_commands, 1);
+    assert_eq!(stats.tombstones, 1);
+    assert_eq!(stats.corrupt_entries, 0);
+
+    // Damage the end of the file, followed by a readable command
+    let log_path = temp_dir.path().join(".kvs").join("1.log");
+    let mut contents = std::fs::read(&log_path)?;
+    contents.extend_from_slice(br#"{"k":"key3","v":"#);
+    contents.extend_from_slice(br#"{"k":"key4","v":"value4"}"#);
+    std::fs::write(&log_path, &contents)?;
+
+    let stats = store.verify_log_file(1)?;
+    assert_eq!(stats.total_commands, 5);
+    assert_eq!(stats.corrupt_entries, 1);
+    assert_eq!(stats.bytes_total, contents.len() as u64);
+
+    match store.verify_log_file(100) {
+        Err(e) => match e.downcast::<KvsError>() {
+            Ok(KvsError::LogFileNotFound) => {}
+            _ => panic!("unexpected error"),
+        },
+        Ok(_) => panic!("expected an error"),
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct RecordingSink {
+    events: Mutex<Vec<String>>,
+    compactions: Mutex<Vec<CompactionStats>>,
+}
+
+impl TelemetrySink for RecordingSink {
+    fn on_get(&self, key: &str, found: bool, _duration: Duration) {
+        let event = format!("get {} {}", key, found);
+        self.events.lock().unwrap().push(event);
+    }
+
+    fn on_set(&self, key: &str, _duration: Duration) {
+        self.events.lock().unwrap().push(format!("set {}", key));
+    }
+
+    fn on_remove(&self, key: &str, _duration: Duration) {
+        self.events.lock().unwrap().push(format!("remove {}", key));
+    }
+
+    fn on_compact(&self, stats: &CompactionStats) {
+        self.compactions.lock().unwrap().push(*stats);
+    }
+
+    fn on_corrupt_entry(&self, file_id: LogFileId, offset: u64) {
+        let event = format!("corrupt {} {}", file_id, offset);
+        self.events.lock().unwrap().push(event);
+    }
+
+    fn on_truncated_log(&self, file_id: LogFileId, offset: u64) {
+        let event = format!("truncated {} {}", file_id, offset);
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+#[test]
+fn telemetry_sink() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sink = Arc::new(RecordingSink::default());
+    let options = KvStoreOptions::default()
+        .sink(sink.clone())
+        .min_compaction_bytes(4 * 1024)
+        .max_compaction_bytes(4 * 1024);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.get("key1".to_owned())?;
+    // Failed operations are not reported
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    assert_eq!(
+        *sink.events.lock().unwrap(),
+        vec!["set key1", "get key1 true", "remove key1", "get key1 false"]
+    );
+
+    for i in 0..200 {
+        store.set(format!("key{}", i % 10), format!("{:0>100}", i))?;
+    }
+    let compactions = sink.compactions.lock().unwrap();
+    assert!(!compactions.is_empty());
+    assert!(compactions.iter().all(|stats| stats.keys_copied <= 10));
+    assert!(compactions.iter().any(|stats| stats.bytes_copied > 0));
+
+    Ok(())
+}
+
+#[test]
+fn split_large_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().max_inline_value_bytes(10);
+    let large_value = "abcdé€😀".repeat(20);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), large_value.clone())?;
+    store.set("key2".to_owned(), "small".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(large_value.clone()));
+
+    let stats = store.verify_log_file(1)?;
+    assert!(stats.total_commands > 2);
+    assert_eq!(stats.live_commands, stats.total_commands);
+    drop(store);
+
+    // A split value which was never completely written is ignored
+    let log_path = temp_dir.path().join(".kvs").join("1.log");
+    let mut contents = std::fs::read(&log_path)?;
+    contents.extend_from_slice(br#"{"k":"key1","v":"partial","c":{"i":0,"n":3}}"#);
+    std::fs::write(&log_path, &contents)?;
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(large_value.clone()));
+    assert_eq!(store.get("key2".to_owned())?, Some("small".to_owned()));
+
+    store.remove("key1".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn split_values_survive_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .max_inline_value_bytes(64)
+        .min_compaction_bytes(4 * 1024)
+        .max_compaction_bytes(4 * 1024);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    for i in 0..100 {
+        store.set(format!("key{}", i % 5), format!("{:0>500}", i))?;
+    }
+    assert!(store.detailed_stats().compaction_bytes_written > 0);
+    for i in 95..100 {
+        assert_eq!(
+            store.get(format!("key{}", i % 5))?,
+            Some(format!("{:0>500}", i))
+        );
+    }
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key4".to_owned())?, Some(format!("{:0>500}", 99)));
+
+    Ok(())
+}
+
+#[test]
+fn timestamped_file_names() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .file_naming(FileNamingScheme::TimestampedNumeric)
+        .min_compaction_bytes(4 * 1024)
+        .max_compaction_bytes(4 * 1024);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    for i in 0..100 {
+        store.set(format!("key{}", i % 5), format!("{:0>100}", i))?;
+    }
+    assert!(store.detailed_stats().compaction_bytes_written > 0);
+    drop(store);
+
+    let log_names: Vec<String> = std::fs::read_dir(temp_dir.path().join(".kvs"))?
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".log"))
+        .collect();
+    assert!(!log_names.is_empty());
+    for name in &log_names {
+        let stem = name.trim_end_matches(".log");
+        let (timestamp, id) = stem.split_at(stem.find('_').expect("no timestamp in file name"));
+        assert!(timestamp.parse::<u64>().is_ok());
+        assert!(id[1..].parse::<u64>().is_ok());
+    }
+
+    // Either scheme can read files named by the other
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key4".to_owned())?, Some(format!("{:0>100}", 99)));
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+// A compacted log which was never completely written is ignored and cleaned up
+#[test]
+fn unfinished_compaction_ignored() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let temp_log = temp_dir.path().join(".kvs").join("2.log.tmp");
+    std::fs::write(&temp_log, br#"{"k":"key1","v":"partial"}"#)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!temp_log.exists());
+
+    Ok(())
+}
+
+// Values in old log files expire, and old files with nothing live in them are deleted
+#[test]
+fn max_log_age() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().max_log_age(Duration::from_millis(200));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    thread::sleep(Duration::from_millis(300));
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    match store.get("key1".to_owned()) {
+        Err(e) => assert!(matches!(
+            e.downcast::<KvsError>(),
+            Ok(KvsError::DataExpired)
+        )),
+        Ok(v) => panic!("expected DataExpired, got {:?}", v),
+    }
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // the only live value in the first log is key1
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join(".kvs").join("1.log");
+    assert!(log_path.exists());
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(!log_path.exists());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// With fair locking, writes still get through while readers hammer the store
+#[test]
+fn fair_locking_writer_not_starved() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().fair_locking(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    store.get("key".to_owned()).unwrap();
+                }
+            })
+        })
+        .collect();
+
+    let start = Instant::now();
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    let elapsed = start.elapsed();
+
+    stop.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    assert!(
+        elapsed < Duration::from_secs(5),
+        "writes took {:?}",
+        elapsed
+    );
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+// Corrupt log entries can fail opening the store, or be skipped along with the rest of the file
+#[test]
+fn validate_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join(".kvs").join("1.log");
+    let mut contents = std::fs::read(&log_path)?;
+    let corrupt_offset = contents.len();
+    contents.extend_from_slice(br#"{"k":"key2","v":"val"#);
+    contents.extend_from_slice(br#"{"k":"key3","v":"value3"}"#);
+    std::fs::write(&log_path, &contents)?;
+
+    let options = KvStoreOptions::default().validate_on_open(ValidationMode::ErrorOnCorrupt);
+    assert!(KvStore::open_with_options(temp_dir.path(), options).is_err());
+
+    let options = KvStoreOptions::default().validate_on_open(ValidationMode::SkipCorrupt);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    drop(store);
+
+    let sink = Arc::new(RecordingSink::default());
+    let options = KvStoreOptions::default()
+        .validate_on_open(ValidationMode::ReportCorrupt)
+        .sink(sink.clone());
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        sink.events.lock().unwrap()[0],
+        format!("corrupt 1 {}", corrupt_offset)
+    );
+
+    Ok(())
+}
+
+// Full log files are sealed and a new one started, and values in sealed files can still be read
+#[test]
+fn max_log_file_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .inline_values(false)
+        .max_log_file_bytes(100);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let log_files = std::fs::read_dir(temp_dir.path().join(".kvs"))?
+        .filter(|entry| {
+            entry.as_ref().unwrap().path().extension() == Some(std::ffi::OsStr::new("log"))
+        })
+        .count();
+    assert!(
+        log_files > 1,
+        "expected several log files, got {}",
+        log_files
+    );
+
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for i in 1..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn set_nonblocking() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().nonblocking_flush_interval(Duration::from_millis(10));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    // applied in the background, without any other operation on the store
+    store.set_nonblocking("key1".to_owned(), "value1".to_owned());
+    let start = Instant::now();
+    while store.detailed_stats().user_bytes_written == 0 {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "write never applied"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // applied before later writes, so they aren't reordered
+    store.set_nonblocking("key2".to_owned(), "value2".to_owned());
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    store.set_nonblocking("key3".to_owned(), "value4".to_owned());
+    store.flush_pending_writes()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// A log cut off part way through an entry is used up to that entry, even when validating
+#[test]
+fn truncated_log_recovered() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join(".kvs").join("1.log");
+    let mut contents = std::fs::read(&log_path)?;
+    let truncated_offset = contents.len();
+    contents.extend_from_slice(br#"{"k":"key3","v":"val"#);
+    std::fs::write(&log_path, &contents)?;
+
+    let sink = Arc::new(RecordingSink::default());
+    let options = KvStoreOptions::default()
+        .validate_on_open(ValidationMode::ErrorOnCorrupt)
+        .sink(sink.clone());
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(
+        sink.events.lock().unwrap()[0],
+        format!("truncated 1 {}", truncated_offset)
+    );
+
+    Ok(())
+}
+
+// Syncing applies to the logs started by roll-over and compaction too, without changing what
+// is written
+#[test]
+fn sync_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .inline_values(false)
+        .max_log_file_bytes(100)
+        .sync_writes(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(store.stats()?.num_log_files > 1);
+    store.set("key1".to_owned(), "value1b".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    store.compact()?;
+    assert_eq!(store.stats()?.compaction_count, 1);
+    store.set("key3".to_owned(), "value3b".to_owned())?;
+    store.remove("key4".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1b".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3b".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+    for i in 5..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn secondary_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().with_secondary_index(
+        "first_letter",
+        Arc::new(|_key: &str, value: &str| value.chars().next().map(String::from)),
+    );
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    store.set("key1".to_owned(), "apple".to_owned())?;
+    store.set("key2".to_owned(), "avocado".to_owned())?;
+    store.set("key3".to_owned(), "banana".to_owned())?;
+    store.set("key4".to_owned(), "".to_owned())?;
+    assert_eq!(
+        store.query_secondary("first_letter", "a")?,
+        vec!["key1", "key2"]
+    );
+    assert_eq!(store.query_secondary("first_letter", "b")?, vec!["key3"]);
+
+    // overwritten and removed values are no longer indexed
+    store.set("key1".to_owned(), "blueberry".to_owned())?;
+    store.remove("key3".to_owned())?;
+    assert_eq!(store.query_secondary("first_letter", "a")?, vec!["key2"]);
+    assert_eq!(store.query_secondary("first_letter", "b")?, vec!["key1"]);
+
+    // rebuilt when reopened
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.query_secondary("first_letter", "a")?, vec!["key2"]);
+    assert_eq!(store.query_secondary("first_letter", "b")?, vec!["key1"]);
+    assert!(store.query_secondary("first_letter", "c")?.is_empty());
+
+    match store.query_secondary("missing", "a") {
+        Err(e) => assert!(matches!(
+            e.downcast::<KvsError>(),
+            Ok(KvsError::IndexNotFound)
+        )),
+        Ok(keys) => panic!("expected IndexNotFound, got {:?}", keys),
+    }
+
+    Ok(())
+}
+
+// Every write is timestamped, and earlier values can be read back as of a given time
+#[test]
+fn get_as_of() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().max_inline_value_bytes(4);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let (value, first) = store.get_with_metadata("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value1");
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let (_, second) = store.get_with_metadata("key1".to_owned())?.unwrap();
+    assert!(second > first);
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get_with_metadata("key1".to_owned())?, None);
+
+    assert_eq!(store.get_as_of("key1".to_owned(), first - 1)?, None);
+    assert_eq!(
+        store.get_as_of("key1".to_owned(), first)?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get_as_of("key1".to_owned(), second)?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.get_as_of("key1".to_owned(), u64::MAX)?, None);
+
+    // timestamps survive reopening
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    let (_, third) = store.get_with_metadata("key2".to_owned())?.unwrap();
+    assert!(third > second);
+    assert_eq!(
+        store.get_as_of("key1".to_owned(), second)?,
+        Some("value2".to_owned())
+    );
+
+    Ok(())
+}
+
+// Every open is recorded in the log, and kept through compaction
+#[test]
+fn list_server_restarts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.list_server_restarts()?.len(), 1);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let (_, written_at) = store.get_with_metadata("key1".to_owned())?.unwrap();
+    let restarts = store.list_server_restarts()?;
+    assert_eq!(restarts.len(), 2);
+    assert!(restarts[0] < restarts[1] && restarts[1] < written_at);
+
+    // markers aren't values, so don't get in the way of reading the key
+    assert_eq!(store.get("".to_owned())?, None);
+    assert_eq!(store.get_as_of("".to_owned(), u64::MAX)?, None);
+
+    drop(store);
+    let options = KvStoreOptions::default().compaction_live_ratio_threshold(0.9);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert!(store.detailed_stats().compaction_bytes_written > 0);
+    let after_compaction = store.list_server_restarts()?;
+    assert_eq!(after_compaction.len(), 3);
+    assert_eq!(after_compaction[..2], restarts[..]);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Compacting on demand replaces the logs written so far with a single compacted one
+#[test]
+fn compact_manual() -> Result<()> {
+    fn count_log_files(temp_dir: &TempDir) -> Result<usize> {
+        Ok(std::fs::read_dir(temp_dir.path().join(".kvs"))?
+            .filter(|entry| {
+                entry.as_ref().unwrap().path().extension() == Some(std::ffi::OsStr::new("log"))
+            })
+            .count())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .inline_values(false)
+        .max_log_file_bytes(100);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..20 {
+        store.set(format!("key{}", i % 5), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert!(count_log_files(&temp_dir)? > 2);
+    assert!(store.list_compactions()?.is_empty());
+
+    let bytes_freed = store.compact()?;
+    assert!(bytes_freed > 0);
+    // the compacted log and a new one to write to
+    assert_eq!(count_log_files(&temp_dir)?, 2);
+    assert_eq!(store.list_compactions()?[0].bytes_reclaimed, bytes_freed);
+
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for i in 1..5 {
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("value{}", 15 + i))
+        );
+    }
+
+    // engines which reclaim space as they go have nothing to compact
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.compact()?, 0);
+
+    Ok(())
+}
+
+// The engine statistics follow writes and compaction
+#[test]
+fn engine_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.num_keys, 0);
+    assert_eq!(stats.num_log_files, 1);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    assert_eq!(stats.compaction_count, 0);
+    let empty_bytes = stats.total_bytes_on_disk;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.num_keys, 2);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    assert!(stats.total_bytes_on_disk > empty_bytes);
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.num_keys, 1);
+    assert_eq!(stats.num_log_files, 1);
+    assert!(stats.uncompacted_bytes > 0);
+    let written_bytes = stats.total_bytes_on_disk;
+
+    let bytes_freed = store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.num_keys, 1);
+    // the compacted log and a new one to write to
+    assert_eq!(stats.num_log_files, 2);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    assert_eq!(stats.compaction_count, 1);
+    assert!(stats.total_bytes_on_disk < written_bytes);
+    assert!(bytes_freed > 0);
+
+    // sled doesn't keep them
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let error = SledKvsEngine::open(temp_dir.path())?.stats().unwrap_err();
+    assert!(matches!(
+        error.downcast::<KvsError>(),
+        Ok(KvsError::Unsupported)
+    ));
+
+    Ok(())
+}
+
+// Every compaction is recorded in the log, and kept through later compactions
+#[test]
+fn list_compactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(store.list_compactions()?.is_empty());
+
+    drop(store);
+    let options = KvStoreOptions::default().compaction_live_ratio_threshold(0.9);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    let compactions = store.list_compactions()?;
+    assert!(!compactions.is_empty());
+    let compaction = &compactions[0];
+    assert!(compaction.files_removed.contains(&1));
+    assert_eq!(compaction.keys_preserved, 2);
+    assert!(compaction.bytes_reclaimed > 0);
+    assert!(compaction.started_at <= compaction.finished_at);
+
+    // records aren't values, so don't get in the way of reading the key
+    assert_eq!(store.get("".to_owned())?, None);
+    assert_eq!(store.get_as_of("".to_owned(), u64::MAX)?, None);
+
+    store.set("key2".to_owned(), "value4".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key2".to_owned(), "value5".to_owned())?;
+    let after_compaction = store.list_compactions()?;
+    assert!(after_compaction.len() > compactions.len());
+    assert_eq!(after_compaction[..compactions.len()], compactions[..]);
+    for (earlier, later) in after_compaction.iter().zip(&after_compaction[1..]) {
+        assert!(earlier.finished_at < later.finished_at);
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+/// Holds the store's lock for a while on every `get`, so readers overlap.
+struct SlowGetSink;
+
+impl TelemetrySink for SlowGetSink {
+    fn on_get(&self, _key: &str, _found: bool, _duration: Duration) {
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+// Readers waiting for the lock are counted, showing contention
+#[test]
+fn max_concurrent_readers() -> Result<()> {
+    const READERS: usize = 32;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().sink(Arc::new(SlowGetSink));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.detailed_stats().max_concurrent_readers, 0);
+
+    let barrier = Arc::new(Barrier::new(READERS));
+    let handles: Vec<_> = (0..READERS)
+        .map(|_| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                store.get("key1".to_owned())
+            })
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap()?, Some("value1".to_owned()));
+    }
+
+    let stats = store.detailed_stats();
+    assert_eq!(stats.current_readers, 0);
+    assert!(stats.max_concurrent_readers >= 4);
+    assert!(stats.max_concurrent_readers <= READERS as u32);
+
+    Ok(())
+}
+
+/// Holds the store's lock on every `get` until `readers` calls are holding it at once, or
+/// a second has passed.
+struct RendezvousSink {
+    arrived: AtomicUsize,
+    readers: usize,
+}
+
+impl TelemetrySink for RendezvousSink {
+    fn on_get(&self, _key: &str, _found: bool, _duration: Duration) {
+        self.arrived.fetch_add(1, Ordering::SeqCst);
+        let start = Instant::now();
+        while self.arrived.load(Ordering::SeqCst) < self.readers
+            && start.elapsed() < Duration::from_secs(1)
+        {
+            thread::yield_now();
+        }
+    }
+}
+
+// Calls to `get` share the store's lock, with or without fair locking
+#[test]
+fn concurrent_gets() -> Result<()> {
+    const READERS: usize = 8;
+
+    for &fair in &[false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let sink = Arc::new(RendezvousSink {
+            arrived: AtomicUsize::new(0),
+            readers: READERS,
+        });
+        let options = KvStoreOptions::default()
+            .fair_locking(fair)
+            .inline_values(false)
+            .sink(sink);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for i in 0..READERS {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+
+        let start = Instant::now();
+        let handles: Vec<_> = (0..READERS)
+            .map(|i| {
+                let store = store.clone();
+                thread::spawn(move || store.get(format!("key{}", i)))
+            })
+            .collect();
+        for (i, handle) in handles.into_iter().enumerate() {
+            assert_eq!(handle.join().unwrap()?, Some(format!("value{}", i)));
+        }
+        // one at a time, all but the last would wait out the timeout
+        assert!(start.elapsed() < Duration::from_secs(1), "fair: {}", fair);
+    }
+
+    Ok(())
+}
+
+// Reads from several threads see every write from another, whole
+#[test]
+fn concurrent_gets_and_sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .inline_values(false)
+        .max_log_file_bytes(4 * 1024);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "value0".to_owned())?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            let stop = stop.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut last = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    let value = store.get("key".to_owned())?.expect("key not found");
+                    let i: u32 = value["value".len()..].parse()?;
+                    assert!(i >= last, "read value{} after value{}", i, last);
+                    last = i;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for i in 1..=500 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+        store.set(format!("other{}", i % 10), "x".repeat(100))?;
+    }
+    stop.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    assert_eq!(store.get("key".to_owned())?, Some("value500".to_owned()));
+
+    Ok(())
+}
+
+// Cached values are shared between reads, and kept up to date by writes
+#[test]
+fn arc_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().arc_cache(1);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let first = store.get_arc("key1".to_owned())?.unwrap();
+    let second = store.get_arc("key1".to_owned())?.unwrap();
+    assert_eq!(*first, "value1");
+    assert!(Arc::ptr_eq(&first, &second));
+
+    // the cache is full, so other keys are read afresh
+    let first = store.get_arc("key2".to_owned())?.unwrap();
+    let second = store.get_arc("key2".to_owned())?.unwrap();
+    assert_eq!(*second, "value2");
+    assert!(!Arc::ptr_eq(&first, &second));
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(*store.get_arc("key1".to_owned())?.unwrap(), "value3");
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get_arc("key1".to_owned())?, None);
+
+    // removing frees space in the cache
+    let first = store.get_arc("key2".to_owned())?.unwrap();
+    let second = store.get_arc("key2".to_owned())?.unwrap();
+    assert!(Arc::ptr_eq(&first, &second));
+
+    Ok(())
+}
+
+/// Keeps each log record as its message followed by its key-value pairs.
+#[derive(Clone, Default)]
+struct RecordingDrain(Arc<Mutex<Vec<String>>>);
+
+impl Drain for RecordingDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, _values: &OwnedKVList) -> std::result::Result<(), Never> {
+        let mut line = record.msg().to_string();
+        record
+            .kv()
+            .serialize(record, &mut LineSerializer(&mut line))
+            .unwrap();
+        self.0.lock().unwrap().push(line);
+        Ok(())
+    }
+}
+
+struct LineSerializer<'a>(&'a mut String);
+
+impl slog::Serializer for LineSerializer<'_> {
+    fn emit_arguments(&mut self, key: slog::Key, value: &fmt::Arguments) -> slog::Result {
+        self.0.push_str(&format!(" {}={}", key, value));
+        Ok(())
+    }
+}
+
+// Lookups and writes are logged at trace level, only when the logger is enabled for it
+#[test]
+fn trace_logging() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let drain = RecordingDrain::default();
+    let info_log = Logger::root(drain.clone().filter_level(Level::Info).fuse(), o!());
+    let store =
+        KvStore::open_with_options(temp_dir.path(), KvStoreOptions::default().logger(info_log))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.get("key1".to_owned())?;
+    assert!(drain.0.lock().unwrap().is_empty());
+    drop(store);
+
+    let trace_log = Logger::root(drain.clone().filter_level(Level::Trace).fuse(), o!());
+    let store =
+        KvStore::open_with_options(temp_dir.path(), KvStoreOptions::default().logger(trace_log))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let records = drain.0.lock().unwrap();
+    assert_eq!(records.len(), 3);
+    assert!(records[0].starts_with("get bytes="));
+    assert!(records[0].contains(" file_id=1 found=true key=key1"));
+    assert_eq!(records[1], "get found=false key=key2");
+    assert!(records[2].starts_with("set offset="));
+    assert!(records[2].ends_with(" file_id=2 key=key2"));
+
+    Ok(())
+}
+
+// Permanently deleted keys can't be set again, even after reopening
+#[test]
+fn permanent_delete() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().permanent_delete(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    match store.set("key1".to_owned(), "value3".to_owned()) {
+        Err(e) => assert!(matches!(
+            e.downcast::<KvsError>(),
+            Ok(KvsError::PermanentlyDeleted)
+        )),
+        Ok(()) => panic!("expected PermanentlyDeleted"),
+    }
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // a key which was never there isn't recorded
+    assert!(store.remove("key3".to_owned()).is_err());
+    store.set("key3".to_owned(), "value4".to_owned())?;
+
+    // still deleted without the option
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.set("key1".to_owned(), "value5".to_owned()).is_err());
+    store.set_nonblocking("key1".to_owned(), "value6".to_owned());
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // removing without the option is not permanent
+    store.remove("key2".to_owned())?;
+    store.set("key2".to_owned(), "value7".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value7".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn avg_entry_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.avg_entry_bytes(), 0.0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let written = store.detailed_stats().user_bytes_written as f64;
+    assert_eq!(store.avg_entry_bytes(), written);
+
+    store.remove("key1".to_owned())?;
+    let stats = store.detailed_stats();
+    assert_eq!(stats.avg_entry_bytes, stats.user_bytes_written as f64 / 2.0);
+    // a tombstone is smaller than a set
+    assert!(stats.avg_entry_bytes < written);
+
+    Ok(())
+}
+
+#[test]
+fn read_only_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let original = std::fs::metadata(temp_dir.path())?.permissions();
+    let mut read_only = original.clone();
+    read_only.set_readonly(true);
+    std::fs::set_permissions(temp_dir.path(), read_only)?;
+
+    // permissions don't apply to some users, such as root
+    let writable = std::fs::File::create(temp_dir.path().join("probe")).is_ok();
+    let result = KvStore::open(temp_dir.path());
+    std::fs::set_permissions(temp_dir.path(), original)?;
+    if writable {
+        return Ok(());
+    }
+
+    match result {
+        Err(e) => match e.downcast::<KvsError>() {
+            Ok(KvsError::ReadOnlyFilesystem { path }) => assert_eq!(path, temp_dir.path()),
+            other => panic!("expected ReadOnlyFilesystem, got {:?}", other),
+        },
+        Ok(_store) => panic!("expected ReadOnlyFilesystem"),
+    }
+
+    Ok(())
+}
+
+// New logs and compacted logs can be kept in separate directories
+#[test]
+fn write_and_compacted_paths() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let write_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compacted_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_files = |dir: &TempDir| -> usize {
+        WalkDir::new(dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .count()
+    };
+    let options = KvStoreOptions::default()
+        .write_path(write_dir.path())
+        .compacted_path(compacted_dir.path());
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(log_files(&write_dir), 1);
+    assert_eq!(log_files(&compacted_dir), 0);
+    assert_eq!(log_files(&temp_dir), 0);
+    drop(store);
+
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        options.clone().compaction_live_ratio_threshold(0.9),
+    )?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert!(store.detailed_stats().compaction_bytes_written > 0);
+    assert_eq!(log_files(&compacted_dir), 1);
+    drop(store);
+
+    // values are found in both directories
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Log file IDs aren't reused, even when the logs using them have been deleted
+#[test]
+fn file_ids_not_reused() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_files = || -> Vec<String> {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect()
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let old_logs = log_files();
+    for name in &old_logs {
+        fs::remove_file(temp_dir.path().join(".kvs").join(name))?;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let new_logs = log_files();
+    assert!(!new_logs.is_empty());
+    assert!(new_logs.iter().all(|name| !old_logs.contains(name)));
+
+    Ok(())
+}
+
+// A non-blocking backup holds the values written before it, and isn't affected by later writes
+#[test]
+fn backup_nonblocking() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().permanent_delete(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let backup = store.backup_nonblocking(backup_dir.path())?;
+    assert_eq!(backup.dest_dir(), backup_dir.path());
+    assert!(!backup.file_ids().is_empty());
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let backup_store = KvStore::open_with_options(backup_dir.path(), options)?;
+    assert_eq!(
+        backup_store.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(backup_store.get("key3".to_owned())?, None);
+    assert!(backup_store
+        .set("key2".to_owned(), "value5".to_owned())
+        .is_err());
+
+    Ok(())
+}
+
+// Keys are still found once the bloom filter has been rebuilt, and removed keys stay missing
+#[test]
+fn bloom_filter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().compaction_live_ratio_threshold(0.5);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    // more than the filter is first sized for
+    for i in 0..3000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..3000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(store.get(format!("missing{}", i))?, None);
+    }
+
+    for i in 0..2000 {
+        store.remove(format!("key{}", i))?;
+    }
+    assert!(store.detailed_stats().compaction_bytes_written > 0);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..3000 {
+        let expected = if i < 2000 {
+            None
+        } else {
+            Some(format!("value{}", i))
+        };
+        assert_eq!(store.get(format!("key{}", i))?, expected);
+    }
+
+    Ok(())
+}
+
+// Verifying a log file catches an index entry pointing at a different command than was written
+#[test]
+fn verify_nonces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.verify_log_file(1)?.index_mismatches, 0);
+
+    // rewrite the command in place with a different nonce, as if it were another entry
+    let log_path = temp_dir.path().join(".kvs").join("1.log");
+    let contents = fs::read_to_string(&log_path)?;
+    let start = contents.rfind(r#""n":""#).expect("no nonce written") + 5;
+    let mut replaced = contents.clone();
+    let nonce = if &contents[start..start + 16] == "0000000000000000" {
+        "ffffffffffffffff"
+    } else {
+        "0000000000000000"
+    };
+    replaced.replace_range(start..start + 16, nonce);
+    fs::write(&log_path, replaced)?;
+
+    let stats = store.verify_log_file(1)?;
+    assert_eq!(stats.live_commands, 1);
+    assert_eq!(stats.index_mismatches, 1);
+
+    Ok(())
+}
+
+// The oldest log is the compacted one, once compaction has removed the logs before it
+#[test]
+fn oldest_log_age() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first_created = store.detailed_stats().oldest_log_file_created_at;
+    assert!(first_created.is_some());
+    assert!(store.oldest_log_age().is_some());
+    drop(store);
+
+    thread::sleep(Duration::from_millis(20));
+    let options = KvStoreOptions::default().compaction_live_ratio_threshold(0.9);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(
+        store.detailed_stats().oldest_log_file_created_at,
+        first_created
+    );
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(store.detailed_stats().compaction_bytes_written > 0);
+    assert!(store.detailed_stats().oldest_log_file_created_at > first_created);
+    let since_first = SystemTime::now()
+        .duration_since(first_created.unwrap())
+        .ok();
+    assert!(store.oldest_log_age() < since_first);
+
+    Ok(())
+}
+
+// Swapping the path moves every file, and the store carries on from the new directory
+#[test]
+fn swap_path() -> Result<()> {
+    let old_dir = TempDir::new().expect("unable to create temporary working directory");
+    let new_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_files = |dir: &TempDir| -> usize {
+        WalkDir::new(dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .count()
+    };
+
+    let store = KvStore::open(old_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    store.swap_path(new_dir.path())?;
+    assert_eq!(log_files(&old_dir), 0);
+    assert!(log_files(&new_dir) > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // the old directory is free, and new writes go to the new one
+    drop(KvStore::open(old_dir.path())?);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(new_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Running out of disk space is reported as such, and stops any more writes
+#[cfg(target_os = "linux")]
+#[test]
+fn disk_full() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().max_log_file_bytes(1);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    // the log rolled over to next is always full
+    std::os::unix::fs::symlink("/dev/full", temp_dir.path().join(".kvs").join("2.log"))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let err = store
+        .set("key2".to_owned(), "value2".to_owned())
+        .expect_err("wrote to a full disk");
+    assert!(matches!(err.downcast_ref(), Some(KvsError::DiskFull)));
+    let err = store
+        .remove("key1".to_owned())
+        .expect_err("wrote to a full disk");
+    assert!(matches!(err.downcast_ref(), Some(KvsError::DiskFull)));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// The estimated cost of a set is what it writes, and predicts when it will compact
+#[test]
+fn estimate_set_cost() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .min_compaction_bytes(1024 * 1024)
+        .compaction_live_ratio_threshold(0.6)
+        .max_inline_value_bytes(4);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let mut compactions = 0;
+    for value in &["value1", "value2", "value3", "value4"] {
+        let cost = store.estimate_set_cost("key1", value);
+        let would_compact = store.set_would_compact("key1", value);
+
+        let before = store.detailed_stats();
+        store.set("key1".to_owned(), (*value).to_owned())?;
+        let after = store.detailed_stats();
+        assert_eq!(
+            after.user_bytes_written - before.user_bytes_written,
+            cost as u64
+        );
+
+        let compacted = after.compaction_bytes_written > before.compaction_bytes_written;
+        assert_eq!(would_compact, compacted, "setting {}", value);
+        compactions += compacted as u32;
+    }
+    assert!(compactions > 0);
+
+    Ok(())
+}
+
+// Rebuilding the index from the logs, compacted or not, gives back the same contents
+#[test]
+fn rebuild_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .min_compaction_bytes(1024 * 1024)
+        .compaction_live_ratio_threshold(0.6);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.rebuild_index()?, 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value0".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.detailed_stats().compaction_bytes_written > 0);
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let live_ratio = store.live_ratio();
+
+    assert_eq!(store.rebuild_index()?, 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.live_ratio(), live_ratio);
+
+    // and the store carries on as normal
+    store.set("key2".to_owned(), "value5".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+// Retrying interrupted writes doesn't change what ends up in the log
+#[test]
+fn retry_on_interrupt() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .retry_on_interrupt(true)
+        .compaction_live_ratio_threshold(0.6);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.detailed_stats().compaction_bytes_written > 0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+fn check_scan(engine: impl KvsEngine) -> Result<()> {
+    assert_eq!(engine.scan("")?, vec![]);
+
+    engine.set("user:2".to_owned(), "bob".to_owned())?;
+    engine.set("user:1".to_owned(), "alice".to_owned())?;
+    engine.set("user:10".to_owned(), "carol".to_owned())?;
+    engine.set("group:1".to_owned(), "admins".to_owned())?;
+    let pair = |key: &str, value: &str| (key.to_owned(), value.to_owned());
+
+    assert_eq!(
+        engine.scan("")?,
+        vec![
+            pair("group:1", "admins"),
+            pair("user:1", "alice"),
+            pair("user:10", "carol"),
+            pair("user:2", "bob"),
+        ]
+    );
+    assert_eq!(
+        engine.scan("user:1")?,
+        vec![pair("user:1", "alice"), pair("user:10", "carol")]
+    );
+    assert_eq!(engine.scan("group:1")?, vec![pair("group:1", "admins")]);
+    assert_eq!(engine.scan("nobody")?, vec![]);
+
+    engine.remove("user:1".to_owned())?;
+    assert_eq!(engine.scan("user:1")?, vec![pair("user:10", "carol")]);
+
+    Ok(())
+}
+
+// Scanning finds every key with the prefix, in order, whichever engine is used
+#[test]
+fn scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(KvStore::open(temp_dir.path())?)?;
+
+    // values read from disk, and ones not written yet
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().inline_values(false);
+    check_scan(KvStore::open_with_options(temp_dir.path(), options)?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().coalesce_writes(10);
+    check_scan(KvStore::open_with_options(temp_dir.path(), options)?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Each compaction starts a new generation, which snapshots taken before it can detect
+#[test]
+fn index_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .isolation_level(IsolationLevel::RepeatableRead)
+        .min_compaction_bytes(1024 * 1024)
+        .compaction_live_ratio_threshold(0.6);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.current_generation(), 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let snapshot = store.snapshot()?;
+    assert_eq!(snapshot.generation(), 0);
+    assert_eq!(store.current_generation(), 0);
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key2".to_owned(), "value4".to_owned())?;
+    assert!(store.detailed_stats().compaction_bytes_written > 0);
+    assert_eq!(store.current_generation(), 1);
+    assert_ne!(snapshot.generation(), store.current_generation());
+    assert_eq!(store.snapshot()?.generation(), 1);
+
+    Ok(())
+}
+
+// Leveled compaction keeps every value, and only rewrites the part of level 1 that changed
+#[test]
+fn leveled_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        KvStoreOptions::default()
+            .leveled_compaction(2)
+            .max_log_file_bytes(1024 * 1024)
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+
+    let value = |i: usize, version: usize| format!("{:04}-{}-{}", i, version, "v".repeat(1000));
+    let num_keys = 6000;
+    for i in 0..num_keys {
+        store.set(format!("key{:04}", i), value(i, 0))?;
+    }
+    let loaded = store.detailed_stats();
+    assert!(loaded.compaction_bytes_written > 0);
+
+    // overwrite and remove a few keys at the start of the range, until they are compacted
+    store.remove("key0010".to_owned())?;
+    let mut version = 1;
+    while store.detailed_stats().compaction_bytes_written == loaded.compaction_bytes_written {
+        for i in 0..10 {
+            store.set(format!("key{:04}", i), value(i, version))?;
+        }
+        version += 1;
+    }
+    let copied = store.detailed_stats().compaction_bytes_written - loaded.compaction_bytes_written;
+    assert!(
+        copied < loaded.user_bytes_written / 2,
+        "copied {} of {} bytes",
+        copied,
+        loaded.user_bytes_written
+    );
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..10 {
+            assert_eq!(
+                store.get(format!("key{:04}", i))?,
+                Some(value(i, version - 1))
+            );
+        }
+        assert_eq!(store.get("key0010".to_owned())?, None);
+        for &i in &[11, 2500, num_keys - 1] {
+            assert_eq!(store.get(format!("key{:04}", i))?, Some(value(i, 0)));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), options())?)?;
+    // and without leveled compaction
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// The first compaction happens at the configured threshold, rather than the default 1 MiB
+#[test]
+fn max_uncompacted_bytes() -> Result<()> {
+    let value = "v".repeat(10 * 1024);
+    // overwrite a single key until compaction runs, returning the redundant bytes written
+    let redundant_before_compaction = |store: &KvStore, limit: u64| -> Result<Option<u64>> {
+        store.set("key1".to_owned(), value.clone())?;
+        let first_write = store.detailed_stats().user_bytes_written;
+        while store.detailed_stats().compaction_bytes_written == 0 {
+            let redundant = store.detailed_stats().user_bytes_written - first_write;
+            if redundant > limit {
+                return Ok(None);
+            }
+            store.set("key1".to_owned(), value.clone())?;
+        }
+        Ok(Some(
+            store.detailed_stats().user_bytes_written - first_write,
+        ))
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().max_uncompacted_bytes(256 * 1024);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let redundant = redundant_before_compaction(&store, 2 * 1024 * 1024)?.expect("no compaction");
+    assert!(
+        redundant > 256 * 1024 && redundant < 300 * 1024,
+        "{}",
+        redundant
+    );
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().max_uncompacted_bytes(4 * 1024 * 1024);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(redundant_before_compaction(&store, 2 * 1024 * 1024)?, None);
+
+    // the defaults are the same as opening without options
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::default())?;
+    let with_defaults = redundant_before_compaction(&store, 2 * 1024 * 1024)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        redundant_before_compaction(&store, 2 * 1024 * 1024)?,
+        with_defaults
+    );
+    assert!(with_defaults.expect("no compaction") > 1024 * 1024);
+
+    Ok(())
+}
+
+// Every get is counted in the latency histogram, and its percentiles are in order
+#[test]
+fn get_latency_histogram() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_latency_histogram(), LatencyHistogram::default());
+    assert_eq!(store.get_latency_histogram().p99(), Duration::from_secs(0));
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    for _ in 0..100 {
+        store.get("key1".to_owned())?;
+        store.get("key2".to_owned())?;
+    }
+    let histogram = store.get_latency_histogram();
+    assert_eq!(histogram.count(), 200);
+    assert_eq!(histogram.bucket_counts().iter().sum::<u64>(), 200);
+    assert!(histogram.p50() > Duration::from_secs(0));
+    assert!(histogram.p50() <= histogram.p99());
+    assert!(histogram.p99() <= histogram.p999());
+    assert_eq!(store.detailed_stats().get_latency, histogram);
+
+    Ok(())
+}
+
+fn check_contains_key(engine: impl KvsEngine) -> Result<()> {
+    assert!(!engine.contains_key("key1")?);
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(engine.contains_key("key1")?);
+    assert!(!engine.contains_key("key2")?);
+
+    engine.remove("key1".to_owned())?;
+    assert!(!engine.contains_key("key1")?);
+
+    Ok(())
+}
+
+// Checking for a key gives the same answer as getting it
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_contains_key(KvStore::open(temp_dir.path())?)?;
+
+    // writes not applied yet, and missing keys reported as errors by `get`
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().coalesce_writes(10);
+    check_contains_key(KvStore::open_with_options(temp_dir.path(), options)?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().strict_mode(true);
+    check_contains_key(KvStore::open_with_options(temp_dir.path(), options)?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_contains_key(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// New keys are refused once the index is at its memory limit, but existing ones can change
+#[test]
+fn max_index_memory_bytes() -> Result<()> {
+    // every key and value is the same length, so each entry has the same estimated size
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.estimated_index_memory_bytes(), 0);
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    let entry_bytes = store.estimated_index_memory_bytes();
+    assert!(entry_bytes > "key0value0".len());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().max_index_memory_bytes(3 * entry_bytes);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 1..=3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert_eq!(store.estimated_index_memory_bytes(), 3 * entry_bytes);
+    match store.set("key4".to_owned(), "value4".to_owned()) {
+        Err(e) => assert!(matches!(
+            e.downcast::<KvsError>(),
+            Ok(KvsError::IndexMemoryLimitExceeded)
+        )),
+        Ok(()) => panic!("expected IndexMemoryLimitExceeded"),
+    }
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    store.set("key1".to_owned(), "value5".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value5".to_owned()));
+
+    // removing a key makes room for another
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.estimated_index_memory_bytes(), 2 * entry_bytes);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+
+    // the estimate is the same when the index is loaded from disk
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.estimated_index_memory_bytes(), 3 * entry_bytes);
+    assert!(store.set("key6".to_owned(), "value6".to_owned()).is_err());
+
+    Ok(())
+}
+
+fn check_get_or_insert(engine: impl KvsEngine) -> Result<()> {
+    assert_eq!(
+        engine.get_or_insert("key1".to_owned(), "value1".to_owned())?,
+        "value1"
+    );
+    assert_eq!(
+        engine.get_or_insert("key1".to_owned(), "value2".to_owned())?,
+        "value1"
+    );
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // racing callers all get the value from whichever inserted first
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let engine = engine.clone();
+            thread::spawn(move || engine.get_or_insert("key2".to_owned(), format!("value{}", i)))
+        })
+        .collect();
+    let values = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<Result<Vec<_>>>()?;
+    let stored = engine.get("key2".to_owned())?.unwrap();
+    assert!(values.iter().all(|value| *value == stored));
+
+    Ok(())
+}
+
+// Getting or inserting only writes the default if there is no value already
+#[test]
+fn get_or_insert() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_get_or_insert(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().coalesce_writes(10);
+    check_get_or_insert(KvStore::open_with_options(temp_dir.path(), options)?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_get_or_insert(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn check_compare_and_swap(engine: impl KvsEngine) -> Result<()> {
+    let some = |value: &str| Some(value.to_owned());
+
+    // no value, expecting none
+    assert!(engine.compare_and_swap("key1".to_owned(), None, some("value1"))?);
+    assert_eq!(engine.get("key1".to_owned())?, some("value1"));
+    // no value, expecting one
+    assert!(!engine.compare_and_swap("key2".to_owned(), some("value1"), some("value2"))?);
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    // a matching value
+    assert!(engine.compare_and_swap("key1".to_owned(), some("value1"), some("value2"))?);
+    assert_eq!(engine.get("key1".to_owned())?, some("value2"));
+    // a different value, or expecting none
+    assert!(!engine.compare_and_swap("key1".to_owned(), some("value1"), some("value3"))?);
+    assert!(!engine.compare_and_swap("key1".to_owned(), None, some("value3"))?);
+    assert_eq!(engine.get("key1".to_owned())?, some("value2"));
+
+    // swapping to none removes the key
+    assert!(engine.compare_and_swap("key1".to_owned(), some("value2"), None)?);
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(engine.compare_and_swap("key1".to_owned(), None, None)?);
+    assert_eq!(engine.get("key1".to_owned())?, None);
+
+    // of racing callers swapping the same value, only one succeeds
+    engine.set("counter".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (1..=8)
+        .map(|i| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                engine.compare_and_swap("counter".to_owned(), some("0"), Some(i.to_string()))
+            })
+        })
+        .collect();
+    let swapped = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(swapped.iter().filter(|&&swapped| swapped).count(), 1);
+
+    Ok(())
+}
+
+// Values are only swapped if they are what the caller expected
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().coalesce_writes(10);
+    check_compare_and_swap(KvStore::open_with_options(temp_dir.path(), options)?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Replace the first occurrence of `from` in the first log file with `to`, of the same length.
+fn overwrite_in_log(temp_dir: &TempDir, from: &str, to: &str) -> Result<()> {
+    let log_path = temp_dir.path().join(".kvs").join("1.log");
+    let contents = fs::read_to_string(&log_path)?;
+    assert!(contents.contains(from));
+    fs::write(&log_path, contents.replacen(from, to, 1))?;
+    Ok(())
+}
+
+// Checksums catch corruption which leaves the log entry readable
+#[test]
+fn checksum_algorithm() -> Result<()> {
+    for &algorithm in &[ChecksumAlgorithm::Crc32, ChecksumAlgorithm::XxHash3] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default()
+            .checksum_algorithm(algorithm)
+            .inline_values(false);
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+
+        // found when the value is read
+        overwrite_in_log(&temp_dir, "value2", "valueX")?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        match store.get("key2".to_owned()) {
+            Err(e) => assert!(matches!(
+                e.downcast::<KvsError>(),
+                Ok(KvsError::ChecksumMismatch { file_id: 1, .. })
+            )),
+            Ok(value) => panic!("expected ChecksumMismatch, got {:?}", value),
+        }
+        drop(store);
+
+        // and when the store is opened
+        let error = KvStore::open_with_options(temp_dir.path(), options.clone()).unwrap_err();
+        assert!(matches!(
+            error.downcast::<KvsError>(),
+            Ok(KvsError::ChecksumMismatch { file_id: 1, .. })
+        ));
+        let options = options.validate_on_open(ValidationMode::SkipCorrupt);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+    }
+
+    // entries are checked with the algorithm they were written with
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().checksum_algorithm(ChecksumAlgorithm::Crc32);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let options = KvStoreOptions::default().checksum_algorithm(ChecksumAlgorithm::XxHash3);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // without checksums the corrupt value is read back
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    overwrite_in_log(&temp_dir, "value1", "valueX")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("valueX".to_owned()));
+
+    Ok(())
+}
+
+fn check_get_range(engine: impl KvsEngine) -> Result<()> {
+    for key in &["a", "b", "b1", "c", "d"] {
+        engine.set((*key).to_owned(), format!("value {}", key))?;
+    }
+    let pair = |key: &str| (key.to_owned(), format!("value {}", key));
+
+    assert_eq!(
+        engine.get_range("b", "d")?,
+        vec![pair("b"), pair("b1"), pair("c")]
+    );
+    assert_eq!(engine.get_range("", "b")?, vec![pair("a")]);
+    assert_eq!(engine.get_range("c", "z")?, vec![pair("c"), pair("d")]);
+    assert_eq!(engine.get_range("b", "b")?, vec![]);
+    assert_eq!(engine.get_range("d", "a")?, vec![]);
+
+    engine.remove("b1".to_owned())?;
+    assert_eq!(engine.get_range("b", "d")?, vec![pair("b"), pair("c")]);
+
+    Ok(())
+}
+
+// Ranges include their start but not their end
+#[test]
+fn get_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_get_range(KvStore::open(temp_dir.path())?)?;
+
+    // values read from disk, and ones not written yet
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().inline_values(false);
+    check_get_range(KvStore::open_with_options(temp_dir.path(), options)?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().coalesce_writes(10);
+    check_get_range(KvStore::open_with_options(temp_dir.path(), options)?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_get_range(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Keys in a range can be listed from the index alone, including ones not written yet
+#[test]
+fn get_range_keys_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().coalesce_writes(10);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("b".to_owned(), "value1".to_owned())?;
+    store.set("a".to_owned(), "value2".to_owned())?;
+    store.flush_pending_writes()?;
+
+    // overwrites one key already written, and adds another
+    store.set("b".to_owned(), "value3".to_owned())?;
+    store.set("c".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get_range_keys_only("a", "z")?, vec!["a", "b", "c"]);
+    assert_eq!(store.get_range_keys_only("b", "c")?, vec!["b"]);
+    assert!(store.get_range_keys_only("c", "a")?.is_empty());
+
+    Ok(())
+}
+
+fn check_batch_write(engine: impl KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.batch_write(vec![
+        ("key1".to_owned(), "value2".to_owned()),
+        ("key2".to_owned(), "value3".to_owned()),
+        ("key2".to_owned(), "value4".to_owned()),
+    ])?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value4".to_owned()));
+
+    engine.batch_write(vec![])?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Batches are written as a whole, and last write wins within one
+#[test]
+fn batch_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_batch_write(KvStore::open(temp_dir.path())?)?;
+    // all still there when the log is replayed
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().inline_values(false);
+    check_batch_write(KvStore::open_with_options(temp_dir.path(), options)?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().coalesce_writes(10);
+    check_batch_write(KvStore::open_with_options(temp_dir.path(), options)?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_batch_write(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Readers never see part of a batch, and a batch with a key `set` would refuse is refused whole
+#[test]
+fn batch_write_all_or_nothing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().permanent_delete(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let keys = vec!["key1".to_owned(), "key2".to_owned()];
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 0..200 {
+                let value = format!("value{}", i);
+                store.batch_write(vec![
+                    ("key1".to_owned(), value.clone()),
+                    ("key2".to_owned(), value),
+                ])?;
+            }
+            Ok(())
+        })
+    };
+    while !writer.is_finished() {
+        let values = store.get_bulk(&keys)?;
+        assert_eq!(values[0], values[1]);
+    }
+    writer.join().unwrap()?;
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    assert!(store
+        .batch_write(vec![
+            ("key1".to_owned(), "new value".to_owned()),
+            ("key3".to_owned(), "new value".to_owned()),
+        ])
+        .is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value199".to_owned()));
+
+    Ok(())
+}
+
+fn check_delete_range(engine: impl KvsEngine) -> Result<()> {
+    for key in &["a", "b", "b1", "c", "d"] {
+        engine.set((*key).to_owned(), format!("value {}", key))?;
+    }
+
+    assert_eq!(engine.delete_range("b", "d")?, 3);
+    assert_eq!(engine.get("b".to_owned())?, None);
+    assert_eq!(engine.get("c".to_owned())?, None);
+    assert_eq!(engine.scan("")?.len(), 2);
+
+    // nothing left to delete isn't an error
+    assert_eq!(engine.delete_range("b", "d")?, 0);
+    assert_eq!(engine.delete_range("z", "a")?, 0);
+
+    Ok(())
+}
+
+// Deleting a range removes every key in it, and only those
+#[test]
+fn delete_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_delete_range(KvStore::open(temp_dir.path())?)?;
+    // tombstones were written for every key
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_range_keys_only("", "z")?, vec!["a", "d"]);
+    // the deleted values and their tombstones are garbage, until compacted
+    let uncompacted = KvsEngine::stats(&store)?.uncompacted_bytes;
+    assert!(uncompacted > 0);
+    store.set("e".to_owned(), "value e".to_owned())?;
+    assert_eq!(store.delete_range("d", "e")?, 1);
+    assert!(KvsEngine::stats(&store)?.uncompacted_bytes > uncompacted);
+    assert_eq!(store.get("e".to_owned())?, Some("value e".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().coalesce_writes(10);
+    check_delete_range(KvStore::open_with_options(temp_dir.path(), options)?)?;
+
+    // permanently deleted, like keys removed one at a time
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().permanent_delete(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check_delete_range(store.clone())?;
+    assert!(store.set("b".to_owned(), "value".to_owned()).is_err());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_delete_range(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn check_count_range(engine: impl KvsEngine) -> Result<()> {
+    for key in &["a", "b", "b1", "c", "d"] {
+        engine.set((*key).to_owned(), format!("value {}", key))?;
+    }
+    engine.remove("b1".to_owned())?;
+    engine.set("b2".to_owned(), "value b2".to_owned())?;
+
+    assert_eq!(engine.count_range("b", "d")?, 3);
+    assert_eq!(engine.count_range("", "z")?, 5);
+    assert_eq!(engine.count_range("e", "z")?, 0);
+    assert_eq!(engine.count_range("d", "a")?, 0);
+
+    Ok(())
+}
+
+// Counting a range agrees with the keys listed by `get_range`
+#[test]
+fn count_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_count_range(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().coalesce_writes(10);
+    check_count_range(KvStore::open_with_options(temp_dir.path(), options)?)?;
+
+    // values past their time to live aren't counted
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "b3".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    assert_eq!(store.count_range("b", "d")?, 4);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.count_range("b", "d")?, 3);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_count_range(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn check_keys(engine: impl KvsEngine) -> Result<()> {
+    assert!(engine.keys()?.is_empty());
+
+    engine.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(engine.keys()?, vec!["key".to_owned()]);
+
+    engine.remove("key".to_owned())?;
+    let mut expected: Vec<String> = (0..10_000).map(|i| format!("key{}", i)).collect();
+    for key in &expected {
+        engine.set(key.clone(), "value".to_owned())?;
+    }
+    expected.sort();
+    assert_eq!(engine.keys()?, expected);
+
+    Ok(())
+}
+
+// Every key can be listed, in order
+#[test]
+fn keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_keys(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().coalesce_writes(10);
+    check_keys(KvStore::open_with_options(temp_dir.path(), options)?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_keys(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Values read as removed once their time to live has passed
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(!store.contains_key("key1")?);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get_range_keys_only("", "z")?, vec!["key2", "key3"]);
+
+    // a plain set replaces the time to live
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value4".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set("key2".to_owned(), "value5".to_owned())?;
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key2".to_owned())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+// Values past their time to live are purged in the background, if asked
+#[test]
+fn purge_expired_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().purge_expired_interval(Duration::from_millis(20));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..10 {
+        store.set_with_ttl(
+            format!("key{}", i),
+            "value".to_owned(),
+            Duration::from_millis(100),
+        )?;
+    }
+    assert!(store.estimated_index_memory_bytes() > 0);
+
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(store.estimated_index_memory_bytes(), 0);
+    assert_eq!(store.purge_expired()?, 0);
+
+    Ok(())
+}
+
+// Values past their time to live are gone after reopening, without being read or purged
+#[test]
+fn set_with_ttl_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value2".to_owned(),
+        Duration::from_millis(500),
+    )?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    thread::sleep(Duration::from_millis(600));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.purge_expired()?, 0);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Logs written with another serializer are read back the same way, whatever they hold
+#[test]
+fn bincode_serializer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .serializer(Arc::new(BincodeCommandSerializer))
+        .checksum_algorithm(ChecksumAlgorithm::Crc32)
+        .inline_values(false)
+        .max_inline_value_bytes(16);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let long_value = "x".repeat(100);
+    store.set("long".to_owned(), long_value.clone())?;
+    store.set_with_ttl(
+        "ttl".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+    // overwrite until the first log is compacted away
+    let large_value = "v".repeat(10 * 1024);
+    while temp_dir.path().join(".kvs").join("1.log").exists() {
+        store.set("key".to_owned(), large_value.clone())?;
+    }
+    store.set("key".to_owned(), "value99".to_owned())?;
+    assert!(!store.list_compactions()?.is_empty());
+    assert_eq!(store.get("long".to_owned())?, Some(long_value.clone()));
+    let mut reader = store
+        .get_reader("long".to_owned())?
+        .expect("long not found");
+    let mut read_value = String::new();
+    reader.read_to_string(&mut read_value)?;
+    assert_eq!(read_value, long_value);
+    assert_eq!(store.list_server_restarts()?.len(), 1);
+    drop(store);
+
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join(".kvs").join("FORMAT"))?,
+        "bincode"
+    );
+
+    // the recorded format is used when none is given
+    for options in [options.clone(), KvStoreOptions::default()] {
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+        assert_eq!(store.get("long".to_owned())?, Some(long_value.clone()));
+        assert_eq!(store.get("ttl".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("removed".to_owned())?, None);
+    }
+
+    // converted to JSON when opened with it
+    let options = KvStoreOptions::default().serializer(Arc::new(JsonCommandSerializer));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("long".to_owned())?, Some(long_value.clone()));
+    assert_eq!(store.get("ttl".to_owned())?, Some("value".to_owned()));
+    drop(store);
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join(".kvs").join("FORMAT"))?,
+        "json"
+    );
+
+    Ok(())
+}
+
+// Stores written before the format was recorded are JSON
+#[test]
+fn serializer_missing_format_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::remove_file(temp_dir.path().join(".kvs").join("FORMAT"))?;
+
+    let options = KvStoreOptions::default().serializer(Arc::new(BincodeCommandSerializer));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join(".kvs").join("FORMAT"))?,
+        "bincode"
+    );
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Logs can only be converted from a format which is built in
+#[test]
+fn serializer_unknown_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::write(temp_dir.path().join(".kvs").join("FORMAT"), "custom")?;
+
+    for options in [
+        KvStoreOptions::default(),
+        KvStoreOptions::default().format(LogFormat::Bincode),
+    ] {
+        let error = KvStore::open_with_options(temp_dir.path(), options).unwrap_err();
+        assert!(matches!(
+            error.downcast::<KvsError>(),
+            Ok(KvsError::FormatMismatch)
+        ));
+    }
+
+    Ok(())
+}
+
+// Opening a store in another format compacts its logs into that format, leveled or not
+#[test]
+fn log_format_migration() -> Result<()> {
+    for leveled in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = if leveled {
+            KvStoreOptions::default().leveled_compaction(4)
+        } else {
+            KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key2".to_owned())?;
+        drop(store);
+
+        for (opened, format) in [LogFormat::Bincode, LogFormat::Json, LogFormat::Bincode]
+            .iter()
+            .copied()
+            .enumerate()
+        {
+            let store =
+                KvStore::open_with_options(temp_dir.path(), options.clone().format(format))?;
+            // each change of format compacts the store once
+            let compactions = store.list_compactions()?.len();
+            assert_eq!(compactions, opened + 1);
+            assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+            assert_eq!(store.get("key2".to_owned())?, None);
+            store.set("key3".to_owned(), "value3".to_owned())?;
+            drop(store);
+
+            // opening again in the same format doesn't convert anything
+            let store =
+                KvStore::open_with_options(temp_dir.path(), options.clone().format(format))?;
+            assert_eq!(store.list_compactions()?.len(), compactions);
+            assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+            // the open markers are kept through the conversions
+            assert_eq!(store.list_server_restarts()?.len(), 2 * opened + 3);
+        }
+    }
+
+    Ok(())
+}
+
+// Prefetched values are read from the cache rather than the log, until the key is written again
+#[test]
+fn prefetch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .inline_values(false)
+        .read_cache(10);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    store.prefetch(&["key1".to_owned(), "key2".to_owned(), "missing".to_owned()]);
+    thread::sleep(Duration::from_millis(200));
+    overwrite_in_log(&temp_dir, "value1", "valueX")?;
+    overwrite_in_log(&temp_dir, "value2", "valueY")?;
+    overwrite_in_log(&temp_dir, "value3", "valueZ")?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("valueZ".to_owned()));
+    assert_eq!(store.get("missing".to_owned())?, None);
+
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+
+    // without a read cache there is nothing to fill
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions::default().inline_values(false),
+    )?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.prefetch(&["key1".to_owned()]);
+    thread::sleep(Duration::from_millis(200));
+    overwrite_in_log(&temp_dir, "value1", "valueX")?;
+    assert_eq!(store.get("key1".to_owned())?, Some("valueX".to_owned()));
+
+    Ok(())
+}
+
+// Values read from disk are kept, evicting the least recently used
+#[test]
+fn read_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().inline_values(false).read_cache(2);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key in &["key1", "key2", "key3"] {
+        store.set((*key).to_owned(), format!("{}-value", key))?;
+    }
+    for key in &["key1", "key2", "key1", "key3"] {
+        store.get((*key).to_owned())?;
+    }
+
+    // key2 was used least recently when key3 was read
+    overwrite_in_log(&temp_dir, "key1-value", "key1-XXXXX")?;
+    overwrite_in_log(&temp_dir, "key2-value", "key2-XXXXX")?;
+    overwrite_in_log(&temp_dir, "key3-value", "key3-XXXXX")?;
+    assert_eq!(store.get("key1".to_owned())?, Some("key1-value".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("key3-value".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("key2-XXXXX".to_owned()));
+
+    Ok(())
+}
+
+// Overwriting or removing a key drops it from the read cache, rather than leaving a stale
+// value to take up a slot
+#[test]
+fn read_cache_invalidated_by_write() -> Result<()> {
+    let set = |store: &KvStore| store.set("key1".to_owned(), "key1-new".to_owned());
+    read_cache_frees_slot(KvStoreOptions::default(), set)?;
+    read_cache_frees_slot(KvStoreOptions::default().coalesce_writes(10), set)?;
+    read_cache_frees_slot(KvStoreOptions::default(), |store| {
+        store.remove("key1".to_owned())
+    })
+}
+
+fn read_cache_frees_slot(
+    options: KvStoreOptions,
+    write: impl FnOnce(&KvStore) -> Result<()>,
+) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = options.inline_values(false).read_cache(2);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key in &["key1", "key2", "key3"] {
+        store.set((*key).to_owned(), format!("{}-value", key))?;
+    }
+    store.flush_pending_writes()?;
+    store.get("key2".to_owned())?;
+    store.get("key1".to_owned())?;
+
+    // a stale key1 would be more recently used than key2, so reading key3 would evict key2
+    write(&store)?;
+    store.get("key3".to_owned())?;
+    overwrite_in_log(&temp_dir, "key2-value", "key2-XXXXX")?;
+    assert_eq!(store.get("key2".to_owned())?, Some("key2-value".to_owned()));
+
+    Ok(())
+}
+
+// A flipped bit in a checksummed entry is reported with where the entry is, and compaction
+// checksums the entries it copies
+#[test]
+fn checksum_bit_flip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join(".kvs").join("1.log");
+    let options = KvStoreOptions::default().checksum_algorithm(ChecksumAlgorithm::Crc32);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let mut contents = fs::read(&log_path)?;
+    let entry_offset = find_bytes(&contents, br#"{"k":"key2""#).expect("entry not found");
+    let value_offset = find_bytes(&contents, b"value2").expect("value not found");
+    contents[value_offset] ^= 0b10;
+    fs::write(&log_path, contents)?;
+
+    let error = KvStore::open_with_options(temp_dir.path(), options.clone()).unwrap_err();
+    match error.downcast::<KvsError>() {
+        Ok(KvsError::ChecksumMismatch { file_id, offset }) => {
+            assert_eq!(file_id, 1);
+            assert_eq!(offset, entry_offset as u64);
+        }
+        other => panic!("expected ChecksumMismatch, got {:?}", other),
+    }
+
+    // entries written without checksums are accepted, and checksummed when compacted
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let value = "v".repeat(10 * 1024);
+    while temp_dir.path().join(".kvs").join("1.log").exists() {
+        store.set("key2".to_owned(), value.clone())?;
+    }
+    drop(store);
+
+    // the saved index holds small values too, so load from the logs
+    fs::remove_file(temp_dir.path().join(".kvs").join("index.bin"))?;
+    let mut corrupted = false;
+    for entry in fs::read_dir(temp_dir.path().join(".kvs"))? {
+        let path = entry?.path();
+        let mut contents = fs::read(&path)?;
+        if let Some(value_offset) = find_bytes(&contents, b"value1") {
+            contents[value_offset] ^= 0b10;
+            fs::write(&path, contents)?;
+            corrupted = true;
+        }
+    }
+    assert!(corrupted);
+    let error = KvStore::open_with_options(temp_dir.path(), options).unwrap_err();
+    assert!(matches!(
+        error.downcast::<KvsError>(),
+        Ok(KvsError::ChecksumMismatch { .. })
+    ));
+
+    Ok(())
+}
+
+// A store opened read only shares the directory with other readers, and refuses writes
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+    let files_before: Vec<_> = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .collect();
+
+    let reader1 = KvStore::open_read_only(temp_dir.path())?;
+    let reader2 = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader1.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader2.get("key2".to_owned())?, None);
+
+    let results = vec![
+        reader1.set("key1".to_owned(), "value2".to_owned()),
+        reader1.remove("key1".to_owned()),
+        KvsEngine::compact(&reader1).map(|_| ()),
+    ];
+    for error in results {
+        assert!(matches!(
+            error.unwrap_err().downcast::<KvsError>(),
+            Ok(KvsError::ReadOnly)
+        ));
+    }
+    let error = KvStore::open(temp_dir.path()).unwrap_err();
+    assert!(matches!(
+        error.downcast::<KvsError>(),
+        Ok(KvsError::AlreadyOpen { .. })
+    ));
+
+    drop(reader1);
+    drop(reader2);
+    let files_after: Vec<_> = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .collect();
+    assert_eq!(files_before, files_after);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
@@ -0,0 +1,712 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsServer, Result, StoreStats};
+use serde_json::json;
+use slog::{o, Discard, Drain, Logger, Never, OwnedKVList, Record, KV};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Binding to port 0 should report the port chosen by the OS
+#[test]
+fn bind_to_any_port() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    assert_ne!(addr.port(), 0);
+    thread::spawn(move || server.serve());
+
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        KvsClient::connect(addr)?.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+
+    Ok(())
+}
+
+// Commands split across several writes are only handled once they are complete
+#[test]
+fn partial_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut responses =
+        serde_json::Deserializer::from_reader(stream.try_clone()?).into_iter::<serde_json::Value>();
+
+    stream.write_all(br#"{"Set":{"k":"key1","v":"va"#)?;
+    stream.flush()?;
+    thread::sleep(Duration::from_millis(50));
+    stream.write_all(br#"lue}1"}}"#)?;
+    assert_eq!(responses.next().unwrap()?, json!("Empty"));
+
+    // a bad command doesn't stop the next one being handled
+    stream.write_all(br#"{"Nope":{}} {"Get":{"k":"key1"}}"#)?;
+    assert_eq!(
+        responses.next().unwrap()?,
+        json!({"Error": {"code": "CommandDeserialisation"}})
+    );
+    assert_eq!(responses.next().unwrap()?, json!({"Value": "value}1"}));
+
+    Ok(())
+}
+
+// Connections over the limit for an address are refused, until earlier ones close
+#[test]
+fn max_connections_per_ip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?
+    .with_max_connections_per_ip(1);
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    let held = TcpStream::connect(addr)?;
+    let refused = TcpStream::connect(addr)?;
+    let mut responses =
+        serde_json::Deserializer::from_reader(refused).into_iter::<serde_json::Value>();
+    assert_eq!(
+        responses.next().unwrap()?,
+        json!({"Error": {"code": "TooManyConnections"}})
+    );
+    assert!(responses.next().is_none());
+
+    drop(held);
+    thread::sleep(Duration::from_millis(100));
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value1".to_owned())?;
+
+    Ok(())
+}
+
+// Connections over the limit for the server are refused as busy, until earlier ones close
+#[test]
+fn max_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?
+    .with_max_connections(2);
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    let held: Vec<TcpStream> = (0..2)
+        .map(|_| TcpStream::connect(addr))
+        .collect::<std::io::Result<_>>()?;
+    let refused = TcpStream::connect(addr)?;
+    let mut responses =
+        serde_json::Deserializer::from_reader(refused).into_iter::<serde_json::Value>();
+    assert_eq!(
+        responses.next().unwrap()?,
+        json!({"Error": {"code": "ServerBusy"}})
+    );
+    assert!(responses.next().is_none());
+
+    drop(held);
+    thread::sleep(Duration::from_millis(100));
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value1".to_owned())?;
+
+    Ok(())
+}
+
+// Renaming moves the value to the new key, and fails if the old key doesn't exist
+#[test]
+fn rename() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value1".to_owned())?;
+    KvsClient::connect(addr)?.set("key2".to_owned(), "value2".to_owned())?;
+    KvsClient::connect(addr)?.rename("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(KvsClient::connect(addr)?.get("key1".to_owned())?, None);
+    assert_eq!(
+        KvsClient::connect(addr)?.get("key2".to_owned())?,
+        Some("value1".to_owned())
+    );
+
+    assert!(KvsClient::connect(addr)?
+        .rename("key1".to_owned(), "key3".to_owned())
+        .is_err());
+    assert_eq!(KvsClient::connect(addr)?.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// Clients can check for a key without the value being sent back
+#[test]
+fn exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    assert!(!KvsClient::connect(addr)?.exists("key1".to_owned())?);
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(KvsClient::connect(addr)?.exists("key1".to_owned())?);
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut responses =
+        serde_json::Deserializer::from_reader(stream.try_clone()?).into_iter::<serde_json::Value>();
+    stream.write_all(br#"{"Exists":{"k":"key1"}} {"Exists":{"k":"key2"}}"#)?;
+    assert_eq!(responses.next().unwrap()?, json!({"Bool": true}));
+    assert_eq!(responses.next().unwrap()?, json!("Empty"));
+
+    Ok(())
+}
+
+// Getting or inserting returns the value already there, if there is one
+#[test]
+fn get_or_insert() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    assert_eq!(
+        KvsClient::connect(addr)?.get_or_insert("key1".to_owned(), "value1".to_owned())?,
+        "value1"
+    );
+    assert_eq!(
+        KvsClient::connect(addr)?.get_or_insert("key1".to_owned(), "value2".to_owned())?,
+        "value1"
+    );
+    assert_eq!(
+        KvsClient::connect(addr)?.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+
+    Ok(())
+}
+
+// Values are only swapped if they are what the client expected
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+    let some = |value: &str| Some(value.to_owned());
+
+    assert!(KvsClient::connect(addr)?.compare_and_swap("key1".to_owned(), None, some("value1"))?);
+    assert!(!KvsClient::connect(addr)?.compare_and_swap(
+        "key1".to_owned(),
+        some("value2"),
+        some("value3")
+    )?);
+    assert_eq!(
+        KvsClient::connect(addr)?.get("key1".to_owned())?,
+        some("value1")
+    );
+    assert!(KvsClient::connect(addr)?.compare_and_swap("key1".to_owned(), some("value1"), None)?);
+    assert_eq!(KvsClient::connect(addr)?.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Ranges of keys are returned with their values, in order
+#[test]
+fn get_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    for key in &["key3", "key1", "key2"] {
+        KvsClient::connect(addr)?.set((*key).to_owned(), format!("value of {}", key))?;
+    }
+    assert_eq!(
+        KvsClient::connect(addr)?.get_range("key1".to_owned(), "key3".to_owned())?,
+        vec![
+            ("key1".to_owned(), "value of key1".to_owned()),
+            ("key2".to_owned(), "value of key2".to_owned()),
+        ]
+    );
+
+    Ok(())
+}
+
+// Several keys can be set in one request
+#[test]
+fn set_multi() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    KvsClient::connect(addr)?.set_multi(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+    ])?;
+    assert_eq!(
+        KvsClient::connect(addr)?.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        KvsClient::connect(addr)?.get("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+
+    Ok(())
+}
+
+// Deleting a range reports how many keys were removed
+#[test]
+fn delete_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    for key in &["key1", "key2", "key3"] {
+        KvsClient::connect(addr)?.set((*key).to_owned(), "value".to_owned())?;
+    }
+    assert_eq!(
+        KvsClient::connect(addr)?.delete_range("key1".to_owned(), "key3".to_owned())?,
+        2
+    );
+    assert_eq!(KvsClient::connect(addr)?.get("key2".to_owned())?, None);
+    assert_eq!(
+        KvsClient::connect(addr)?.get("key3".to_owned())?,
+        Some("value".to_owned())
+    );
+
+    Ok(())
+}
+
+// Ranges of keys can be counted without returning them
+#[test]
+fn count_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    for key in &["key1", "key2", "key3"] {
+        KvsClient::connect(addr)?.set((*key).to_owned(), "value".to_owned())?;
+    }
+    assert_eq!(
+        KvsClient::connect(addr)?.count_range("key1".to_owned(), "key3".to_owned())?,
+        2
+    );
+
+    Ok(())
+}
+
+// Stopping the server through its handle makes `serve` return once requests have drained
+#[test]
+fn stop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (server, handle) = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?
+    .with_shutdown();
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    let (stopped_tx, stopped_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let result = server.serve();
+        drop(server);
+        stopped_tx.send(result.is_ok()).unwrap();
+    });
+
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value1".to_owned())?;
+    KvsClient::connect(addr)?.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        KvsClient::connect(addr)?.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+
+    handle.stop();
+    assert!(stopped_rx.recv_timeout(Duration::from_secs(5))?);
+    assert!(KvsClient::connect(addr).is_err());
+    // stopping again does nothing
+    handle.stop();
+
+    Ok(())
+}
+
+// `run_until` returns after the next connection once the flag is set
+#[test]
+fn run_until() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let (stopped_tx, stopped_rx) = mpsc::channel();
+    let server_shutdown = Arc::clone(&shutdown);
+    thread::spawn(move || {
+        let result = server.run_until(addr, server_shutdown);
+        drop(server);
+        stopped_tx.send(result.is_ok()).unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value1".to_owned())?;
+    shutdown.store(true, Ordering::SeqCst);
+    assert!(stopped_rx.try_recv().is_err());
+
+    drop(TcpStream::connect(addr)?);
+    assert!(stopped_rx.recv_timeout(Duration::from_secs(5))?);
+
+    Ok(())
+}
+
+// Every key can be listed, unless the list is too large to send
+#[test]
+fn keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?
+    .with_max_response_bytes(100 * 1024);
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    assert!(KvsClient::connect(addr)?.keys()?.is_empty());
+
+    KvsClient::connect(addr)?.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(KvsClient::connect(addr)?.keys()?, vec!["key".to_owned()]);
+
+    let mut expected: Vec<String> = (0..10_000).map(|i| format!("key{}", i)).collect();
+    let pairs = expected
+        .iter()
+        .map(|key| (key.clone(), "value".to_owned()))
+        .collect();
+    KvsClient::connect(addr)?.set_multi(pairs)?;
+    expected.push("key".to_owned());
+    expected.sort();
+    assert_eq!(KvsClient::connect(addr)?.keys()?, expected);
+
+    // about 110 KiB of keys
+    let pairs = (10_000..20_000)
+        .map(|i| (format!("key{}", i), "value".to_owned()))
+        .collect();
+    KvsClient::connect(addr)?.set_multi(pairs)?;
+    let error = KvsClient::connect(addr)?.keys().unwrap_err();
+    assert_eq!(error.to_string(), "Response larger than the server allows");
+
+    Ok(())
+}
+
+// Compact is refused unless it carries the server's admin token
+#[test]
+fn compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?
+    .with_admin_token("secret");
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    for i in 0..10 {
+        KvsClient::connect(addr)?.set("key".to_owned(), format!("value{}", i))?;
+    }
+
+    let error = KvsClient::connect(addr)?
+        .compact("wrong".to_owned())
+        .unwrap_err();
+    assert_eq!(error.to_string(), "Not authorised to run this command");
+
+    assert!(KvsClient::connect(addr)?.compact("secret".to_owned())? > 0);
+    assert_eq!(
+        KvsClient::connect(addr)?.get("key".to_owned())?,
+        Some("value9".to_owned())
+    );
+
+    // without a token, admin commands are always refused
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    let error = KvsClient::connect(addr)?
+        .compact(String::new())
+        .unwrap_err();
+    assert_eq!(error.to_string(), "Not authorised to run this command");
+
+    Ok(())
+}
+
+// A ping is answered quickly, and fails rather than waiting if nothing is listening
+#[test]
+fn ping() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    assert!(KvsClient::connect(addr)?.ping()? < Duration::from_secs(1));
+
+    let closed_addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let start = Instant::now();
+    assert!(KvsClient::connect(closed_addr)
+        .and_then(KvsClient::ping)
+        .is_err());
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    Ok(())
+}
+
+// Stats are sent as JSON, and decoded by the client
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        Logger::root(Discard, o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value1".to_owned())?;
+    KvsClient::connect(addr)?.set("key2".to_owned(), "value2".to_owned())?;
+    KvsClient::connect(addr)?.remove("key2".to_owned())?;
+
+    let stats = KvsClient::connect(addr)?.stats()?;
+    assert_eq!(
+        stats,
+        StoreStats {
+            num_keys: 1,
+            num_log_files: 1,
+            compaction_count: 0,
+            ..stats
+        }
+    );
+    assert!(stats.uncompacted_bytes > 0);
+    assert!(stats.total_bytes_on_disk > stats.uncompacted_bytes);
+
+    Ok(())
+}
+
+/// A log record's key-value pairs, as strings
+type Values = HashMap<String, String>;
+
+/// Keeps the key-value pairs of each log record, by the record's message.
+#[derive(Clone, Default)]
+struct RecordingDrain(Arc<Mutex<Vec<(String, Values)>>>);
+
+impl RecordingDrain {
+    /// The records with `msg`, waiting until there are at least `count`, as requests are
+    /// logged after the response is sent.
+    fn wait_for(&self, msg: &str, count: usize) -> Vec<Values> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let records: Vec<_> = self
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(record_msg, _)| record_msg == msg)
+                .map(|(_, values)| values.clone())
+                .collect();
+            if records.len() >= count || Instant::now() > deadline {
+                return records;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl Drain for RecordingDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, _values: &OwnedKVList) -> std::result::Result<(), Never> {
+        let mut values = HashMap::new();
+        record
+            .kv()
+            .serialize(record, &mut MapSerializer(&mut values))
+            .unwrap();
+        self.0
+            .lock()
+            .unwrap()
+            .push((record.msg().to_string(), values));
+        Ok(())
+    }
+}
+
+struct MapSerializer<'a>(&'a mut Values);
+
+impl slog::Serializer for MapSerializer<'_> {
+    fn emit_arguments(&mut self, key: slog::Key, value: &fmt::Arguments) -> slog::Result {
+        self.0.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+}
+
+// Every request is logged with what it was and how it went, unless turned off
+#[test]
+fn access_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let drain = RecordingDrain::default();
+    let server = KvsServer::new(
+        Logger::root(drain.clone().fuse(), o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?;
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value1".to_owned())?;
+    KvsClient::connect(addr)?.get("key1".to_owned())?;
+    assert!(KvsClient::connect(addr)?.remove("key2".to_owned()).is_err());
+    KvsClient::connect(addr)?.keys()?;
+
+    let records = drain.wait_for("Request", 4);
+    assert_eq!(records.len(), 4);
+    let fields = |record: &Values| {
+        (
+            record["command_type"].clone(),
+            record["key"].clone(),
+            record["success"].clone(),
+        )
+    };
+    let mut logged: Vec<_> = records.iter().map(fields).collect();
+    logged.sort();
+    assert_eq!(
+        logged,
+        vec![
+            ("Get".to_owned(), "key1".to_owned(), "true".to_owned()),
+            ("Keys".to_owned(), "".to_owned(), "true".to_owned()),
+            ("Rm".to_owned(), "key2".to_owned(), "false".to_owned()),
+            ("Set".to_owned(), "key1".to_owned(), "true".to_owned()),
+        ]
+    );
+    let set = records
+        .iter()
+        .find(|record| record["command_type"] == "Set")
+        .unwrap();
+    assert!(set["remote_addr"].starts_with("127.0.0.1:"));
+    assert!(set["latency_us"].parse::<u64>().is_ok());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let drain = RecordingDrain::default();
+    let server = KvsServer::new(
+        Logger::root(drain.clone().fuse(), o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?
+    .with_access_log(false);
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value1".to_owned())?;
+    thread::sleep(Duration::from_millis(100));
+    assert!(drain.wait_for("Request", 0).is_empty());
+
+    Ok(())
+}
+
+// Requests taking longer than the threshold are logged with what they were and how long they took
+#[test]
+fn slow_request_logged() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let drain = RecordingDrain::default();
+    let server = KvsServer::new(
+        Logger::root(drain.clone().fuse(), o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?
+    .with_slow_request_threshold(Duration::from_millis(0));
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value1".to_owned())?;
+    let records = drain.wait_for("Slow request", 1);
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["command"], "Set 'key1' to 'value1'");
+    assert!(records[0]["duration_ms"].parse::<u128>().is_ok());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let drain = RecordingDrain::default();
+    let server = KvsServer::new(
+        Logger::root(drain.clone().fuse(), o!()),
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )?
+    .with_slow_request_threshold(Duration::from_secs(60));
+    let (server, addr) = server.bind("127.0.0.1:0")?;
+    thread::spawn(move || server.serve());
+
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value1".to_owned())?;
+    // the request itself is logged after the slow request warning would have been
+    drain.wait_for("Request", 1);
+    assert!(drain.wait_for("Slow request", 0).is_empty());
+
+    Ok(())
+}
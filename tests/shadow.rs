@@ -0,0 +1,50 @@
+use kvs::{KvStore, KvsEngine, Result, ShadowEngine, SledKvsEngine};
+use slog::{o, Drain, Logger, Never, OwnedKVList, Record};
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+
+/// Keeps the message of every log record.
+#[derive(Clone, Default)]
+struct RecordingDrain(Arc<Mutex<Vec<String>>>);
+
+impl Drain for RecordingDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, _values: &OwnedKVList) -> std::result::Result<(), Never> {
+        self.0.lock().unwrap().push(record.msg().to_string());
+        Ok(())
+    }
+}
+
+#[test]
+fn shadow_engine_warns_on_discrepancy() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let shadow_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = KvStore::open(primary_dir.path())?;
+    let shadow = SledKvsEngine::open(shadow_dir.path())?;
+    let drain = RecordingDrain::default();
+
+    let engine = ShadowEngine::new(
+        primary.clone(),
+        shadow.clone(),
+        Logger::root(drain.clone(), o!()),
+    );
+
+    // both engines agree
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(shadow.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(drain.0.lock().unwrap().is_empty());
+
+    // the primary's result is returned, and the difference is logged
+    shadow.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert!(engine.remove("key2".to_owned()).is_err());
+    assert_eq!(
+        *drain.0.lock().unwrap(),
+        vec!["Shadow engine disagrees with primary"; 2]
+    );
+
+    Ok(())
+}
@@ -1,8 +1,11 @@
+use std::collections::HashSet;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::Duration;
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvsError, Result};
 
 use crossbeam_utils::sync::WaitGroup;
 
@@ -46,6 +49,52 @@ fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
     spawn_counter(pool)
 }
 
+fn spawn_with_catch_panic_task<P: ThreadPool>(pool: P) -> Result<()> {
+    const TASK_NUM: usize = 100;
+
+    for _ in 0..TASK_NUM {
+        pool.spawn_with_catch(move || {
+            panic_control::disable_hook_in_current_thread();
+
+            panic!();
+        })
+    }
+
+    spawn_counter(pool)
+}
+
+#[test]
+fn naive_thread_pool_spawn_with_catch() -> Result<()> {
+    spawn_with_catch_panic_task(NaiveThreadPool::new(4)?)
+}
+
+// Resumed panics still only end the job's own thread
+#[test]
+fn naive_thread_pool_resume_panics() -> Result<()> {
+    let pool = NaiveThreadPool::new(4)?.with_resume_panics(true);
+    for _ in 0..100 {
+        pool.spawn(move || {
+            panic_control::disable_hook_in_current_thread();
+
+            panic!();
+        })
+    }
+    pool.join();
+
+    spawn_counter(pool)
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_with_catch() -> Result<()> {
+    spawn_with_catch_panic_task(SharedQueueThreadPool::new(4)?)
+}
+
+// Without catching, rayon passes the panic on to the caller
+#[test]
+fn rayon_thread_pool_spawn_with_catch() -> Result<()> {
+    spawn_with_catch_panic_task(RayonThreadPool::new(4)?)
+}
+
 #[test]
 fn naive_thread_pool_spawn_counter() -> Result<()> {
     let pool = NaiveThreadPool::new(4)?;
@@ -58,13 +107,120 @@ fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+/// The distinct threads which ran `job_num` short jobs spawned into `pool`, once they have
+/// all finished.
+fn worker_threads(pool: &SharedQueueThreadPool, job_num: usize) -> HashSet<ThreadId> {
+    let wg = WaitGroup::new();
+    let threads = Arc::new(Mutex::new(HashSet::new()));
+    for _ in 0..job_num {
+        let threads = Arc::clone(&threads);
+        let wg = wg.clone();
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(5));
+            threads.lock().unwrap().insert(thread::current().id());
+            drop(wg);
+        })
+    }
+    wg.wait();
+    let threads = threads.lock().unwrap();
+    threads.clone()
+}
+
+#[test]
+fn shared_queue_thread_pool_resize() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    assert_eq!(worker_threads(&pool, 100).len(), 2);
+
+    pool.resize(8)?;
+    let grown = worker_threads(&pool, 400);
+    assert_eq!(grown.len(), 8);
+
+    // the surplus threads take their shutdowns before any job spawned after the resize
+    pool.resize(2)?;
+    let shrunk = worker_threads(&pool, 100);
+    assert_eq!(shrunk.len(), 2);
+    assert!(shrunk.is_subset(&grown));
+
+    match pool.resize(0) {
+        Err(e) => assert!(matches!(e.downcast::<KvsError>(), Ok(KvsError::NoThreads))),
+        Ok(()) => panic!("expected NoThreads"),
+    }
+    assert!(SharedQueueThreadPool::new(0).is_err());
+
+    spawn_counter(pool)
+}
+
 #[test]
 fn rayon_thread_pool_spawn_counter() -> Result<()> {
     let pool = RayonThreadPool::new(4)?;
     spawn_counter(pool)
 }
 
+#[test]
+fn test_thread_pool_spawn_counter() -> Result<()> {
+    let pool = TestThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn naive_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<NaiveThreadPool>()
+}
+
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn test_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<TestThreadPool>()
+}
+
+// Jobs should have finished by the time `spawn` returns
+#[test]
+fn test_thread_pool_runs_synchronously() -> Result<()> {
+    let pool = TestThreadPool::new(1)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    for i in 1..=10 {
+        let job_counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            job_counter.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(counter.load(Ordering::SeqCst), i);
+    }
+    Ok(())
+}
+
+fn join_waits_for_jobs<P: ThreadPool>() -> Result<()> {
+    const TASK_NUM: usize = 20;
+
+    let pool = P::new(4)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    pool.join();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_join() -> Result<()> {
+    join_waits_for_jobs::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_join() -> Result<()> {
+    join_waits_for_jobs::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_join() -> Result<()> {
+    join_waits_for_jobs::<RayonThreadPool>()
+}